@@ -0,0 +1,71 @@
+use bencode::{bencode, BencodeDict, BencodeEncoder, BencodeList, Value};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+/// Info dict of a torrent with 50k pieces spread over 1000 files
+fn large_info() -> BencodeDict {
+    let files: BencodeList = (0..1000)
+        .map(|i| bencode!({ "length" => i * 1_000_003, "path" => [format!("dir{i}"), "file"] }))
+        .collect();
+    let info = bencode!({
+        "files" => Value::List(files),
+        "name" => "large",
+        "piece length" => 262_144,
+        "pieces" => vec![0xab; 20 * 50_000],
+    });
+    match info {
+        Value::Dict(info) => info,
+        _ => unreachable!(),
+    }
+}
+
+fn encode_info(c: &mut Criterion) {
+    let info = large_info();
+    c.bench_function("encode_dict large info", |b| {
+        b.iter(|| {
+            let mut data = Vec::new();
+            BencodeEncoder::new(&mut data).encode_dict(black_box(&info));
+            data
+        })
+    });
+    c.bench_function("encode_dict large info, growing buffer", |b| {
+        b.iter(|| {
+            let mut data = Vec::new();
+            encode_growing(black_box(&info), &mut data);
+            data
+        })
+    });
+}
+
+/// Encoder without capacity reservation and with a temporary `String` per length
+fn encode_growing(dict: &BencodeDict, data: &mut Vec<u8>) {
+    fn value(item: &Value, data: &mut Vec<u8>) {
+        match item {
+            Value::Int(int) => {
+                data.push(b'i');
+                data.extend_from_slice(int.to_string().as_bytes());
+                data.push(b'e');
+            }
+            Value::String(bytes) => string(bytes, data),
+            Value::List(list) => {
+                data.push(b'l');
+                list.iter().for_each(|item| value(item, data));
+                data.push(b'e');
+            }
+            Value::Dict(dict) => encode_growing(dict, data),
+        }
+    }
+    fn string(bytes: &[u8], data: &mut Vec<u8>) {
+        data.extend_from_slice(bytes.len().to_string().as_bytes());
+        data.push(b':');
+        data.extend_from_slice(bytes);
+    }
+    data.push(b'd');
+    for (key, item) in dict {
+        string(key, data);
+        value(item, data);
+    }
+    data.push(b'e');
+}
+
+criterion_group!(benches, encode_info);
+criterion_main!(benches);
@@ -1,14 +1,15 @@
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
+use std::io;
 use std::num::TryFromIntError;
+use std::path::Path;
 use std::str::{from_utf8, FromStr, Utf8Error};
-use std::{i64, usize};
 use thiserror::Error;
 
 use crate::BencodeError::{
     InvalidDictionary, InvalidFormat, InvalidInteger, InvalidList, InvalidString, InvalidType,
-    UnexpectedEOF,
+    NestingTooDeep, NonCanonicalKey, StringLength, TrailingData, UnexpectedEOF,
 };
 
 pub type BencodeInt = i64;
@@ -67,6 +68,48 @@ impl Value {
         }
         false
     }
+    pub fn as_int(&self) -> Option<BencodeInt> {
+        if let Self::Int(int) = self {
+            return Some(*int);
+        }
+        None
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        if let Self::String(string) = self {
+            return Some(string);
+        }
+        None
+    }
+
+    /// String value if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&BencodeList> {
+        if let Self::List(list) = self {
+            return Some(list);
+        }
+        None
+    }
+
+    pub fn as_dict(&self) -> Option<&BencodeDict> {
+        if let Self::Dict(dict) = self {
+            return Some(dict);
+        }
+        None
+    }
+
+    /// Exact length of the encoded value, lets the encoder allocate once
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Value::Int(int) => int_encoded_len(*int),
+            Value::String(string) => bytes_encoded_len(string),
+            Value::List(list) => list_encoded_len(list),
+            Value::Dict(dict) => dict_encoded_len(dict),
+        }
+    }
 }
 
 impl Debug for Value {
@@ -100,6 +143,22 @@ pub enum BencodeError {
     InvalidType(&'static str, &'static str),
     #[error("Failed conversion {0}")]
     IntConversion(#[from] TryFromIntError),
+    #[error("Nesting depth exceeds {0}")]
+    NestingTooDeep(usize),
+    #[error("Invalid string length {0}, expected {1}")]
+    StringLength(usize, usize),
+    #[error("Dictionary key {0:?} is out of order or duplicated")]
+    NonCanonicalKey(String),
+    #[error("{0} bytes of trailing data after the value")]
+    TrailingData(usize),
+    #[error("Failed to read input: {0}")]
+    Io(io::ErrorKind),
+}
+
+impl From<io::Error> for BencodeError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value.kind())
+    }
 }
 
 impl TryFrom<Value> for BencodeInt {
@@ -173,6 +232,15 @@ impl TryFrom<Value> for BencodeDict {
     }
 }
 
+impl<const N: usize> TryFrom<Value> for [u8; N] {
+    type Error = BencodeError;
+    fn try_from(value: Value) -> Result<Self> {
+        let string = BencodeString::try_from(value)?;
+        let length = string.len();
+        string.try_into().map_err(|_| StringLength(length, N))
+    }
+}
+
 impl TryFrom<Value> for String {
     type Error = BencodeError;
     fn try_from(value: Value) -> std::result::Result<Self, Self::Error> {
@@ -192,24 +260,152 @@ impl From<String> for Value {
     }
 }
 
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.as_bytes().to_vec())
+    }
+}
+
 impl From<BencodeInt> for Value {
     fn from(value: BencodeInt) -> Self {
         Value::Int(value)
     }
 }
 
+/// Builds a [`Value`] from literal-like syntax.
+///
+/// Dictionaries are written as `{ "key" => value }`, lists as `[value, value]`,
+/// everything else is passed to `Value::from`.
+///
+/// ```
+/// use bencode::bencode;
+///
+/// let value = bencode!({
+///     "announce" => "http://tracker.example/announce",
+///     "info" => { "length" => 42, "path" => ["dir", "file"] },
+/// });
+/// assert_eq!(
+///     bencode::into_vec(&value),
+///     b"d8:announce31:http://tracker.example/announce4:infod6:lengthi42e4:pathl3:dir4:fileeee"
+/// );
+/// ```
+#[macro_export]
+macro_rules! bencode {
+    (@dict $dict:ident) => {};
+    (@dict $dict:ident $key:expr => { $($value:tt)* } $(, $($rest:tt)*)?) => {
+        $dict.insert(
+            AsRef::<[u8]>::as_ref(&$key).to_vec(),
+            $crate::bencode!({ $($value)* }),
+        );
+        $crate::bencode!(@dict $dict $($($rest)*)?);
+    };
+    (@dict $dict:ident $key:expr => [ $($value:tt)* ] $(, $($rest:tt)*)?) => {
+        $dict.insert(
+            AsRef::<[u8]>::as_ref(&$key).to_vec(),
+            $crate::bencode!([ $($value)* ]),
+        );
+        $crate::bencode!(@dict $dict $($($rest)*)?);
+    };
+    (@dict $dict:ident $key:expr => $value:expr $(, $($rest:tt)*)?) => {
+        $dict.insert(
+            AsRef::<[u8]>::as_ref(&$key).to_vec(),
+            $crate::Value::from($value),
+        );
+        $crate::bencode!(@dict $dict $($($rest)*)?);
+    };
+    (@list [$($items:expr,)*]) => {
+        vec![$($items,)*]
+    };
+    (@list [$($items:expr,)*] { $($value:tt)* } $(, $($rest:tt)*)?) => {
+        $crate::bencode!(@list [$($items,)* $crate::bencode!({ $($value)* }),] $($($rest)*)?)
+    };
+    (@list [$($items:expr,)*] [ $($value:tt)* ] $(, $($rest:tt)*)?) => {
+        $crate::bencode!(@list [$($items,)* $crate::bencode!([ $($value)* ]),] $($($rest)*)?)
+    };
+    (@list [$($items:expr,)*] $value:expr $(, $($rest:tt)*)?) => {
+        $crate::bencode!(@list [$($items,)* $crate::Value::from($value),] $($($rest)*)?)
+    };
+    ({ $($tt:tt)* }) => {{
+        #[allow(unused_mut)]
+        let mut dict = $crate::BencodeDict::new();
+        $crate::bencode!(@dict dict $($tt)*);
+        $crate::Value::Dict(dict)
+    }};
+    ([ $($tt:tt)* ]) => {
+        $crate::Value::List($crate::bencode!(@list [] $($tt)*))
+    };
+    ($value:expr) => {
+        $crate::Value::from($value)
+    };
+}
+
 pub fn from_slice(data: &[u8]) -> Result<Value> {
     let mut parser = BencodeDecoder::new(data);
     parser.parse()
 }
 
+/// What [`from_slice_exact`] accepts after the top-level value
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Trailing {
+    /// Nothing at all
+    #[default]
+    Reject,
+    /// ASCII whitespace, e.g. a newline some tools append to .torrent files
+    Whitespace,
+}
+
+/// Like [`from_slice`], but the value has to span the whole input
+pub fn from_slice_exact(data: &[u8], trailing: Trailing) -> Result<Value> {
+    let mut parser = BencodeDecoder::new(data);
+    let value = parser.parse()?;
+    let rest = match trailing {
+        Trailing::Reject => parser.data,
+        Trailing::Whitespace => parser.data.trim_ascii_end(),
+    };
+    if !rest.is_empty() {
+        return Err(TrailingData(rest.len()));
+    }
+    Ok(value)
+}
+
+/// Decodes the file at `path` as in [`from_slice_exact`]. With the `mmap` feature the file
+/// is memory-mapped rather than read into a buffer, falling back to a plain read when it
+/// can't be mapped, e.g. when it is a pipe
+pub fn from_path(path: impl AsRef<Path>, trailing: Trailing) -> Result<Value> {
+    let file = std::fs::File::open(path)?;
+    #[cfg(feature = "mmap")]
+    // Safety: the map is only read while decoding, a file truncated by another process
+    // meanwhile is outside of what we can guard against
+    if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+        return from_slice_exact(&map, trailing);
+    }
+    let mut data = Vec::new();
+    io::Read::read_to_end(&mut &file, &mut data)?;
+    from_slice_exact(&data, trailing)
+}
+
+/// Value that failed to decode, with whatever was decoded before the error
+pub type Partial = (Option<Value>, BencodeError);
+
+/// Like [`from_slice`], but a failure keeps the part decoded so far. A list or dictionary
+/// comes back with the elements before the broken one, which is handy for telling where
+/// e.g. a malformed tracker response goes wrong
+pub fn from_slice_partial(data: &[u8]) -> std::result::Result<Value, Partial> {
+    let mut parser = BencodeDecoder::new(data);
+    parser.parse_partial()
+}
+
+/// Lists and dictionaries nested deeper than this are rejected instead of overflowing the stack
+pub const MAX_DEPTH: usize = 256;
+
 struct BencodeDecoder<'a> {
     data: &'a [u8],
+    depth: usize,
 }
 
 impl<'a> BencodeDecoder<'a> {
     fn new(data: &'a [u8]) -> Self {
-        Self { data }
+        Self { data, depth: 0 }
     }
 
     fn parse_str(&mut self) -> Result<BencodeString> {
@@ -221,32 +417,48 @@ impl<'a> BencodeDecoder<'a> {
                 _ => return Err(InvalidString),
             }
         }
+        if len_str == 0 {
+            return Err(InvalidString);
+        }
         let len = usize::from_str(from_utf8(self.data.get(..len_str).ok_or(UnexpectedEOF)?)?)
             .map_err(|e| InvalidFormat(Cow::Owned(e.to_string())))?;
         let start_of_string = len_str + 1;
+        let end_of_string = start_of_string.checked_add(len).ok_or(UnexpectedEOF)?;
         let vec_data = self
             .data
-            .get(start_of_string..start_of_string + len)
+            .get(start_of_string..end_of_string)
             .ok_or(UnexpectedEOF)?
             .to_vec();
-        self.data = &self.data[start_of_string + len..];
+        self.data = &self.data[end_of_string..];
         Ok(vec_data)
     }
 
     fn parse_int(&mut self) -> Result<BencodeInt> {
         let mut len: usize = 0;
+        let mut terminated = false;
         for (num, byte) in self.data.iter().enumerate() {
             match (byte, num) {
                 (b'i', 0) => continue,
                 (b'0'..=b'9', _) | (b'-', 1) => len += 1,
-                (b'e', _) => break,
+                (b'e', _) => {
+                    terminated = true;
+                    break;
+                }
                 _ => return Err(InvalidInteger),
             }
         }
+        if !terminated {
+            return Err(UnexpectedEOF);
+        }
 
-        let ans = i64::from_str(from_utf8(self.data.get(1..1 + len).ok_or(UnexpectedEOF)?)?)
+        let digits = &self.data[1..1 + len];
+        // `ie` and `i-e` carry no digits at all
+        if digits.is_empty() || digits == b"-" {
+            return Err(InvalidInteger);
+        }
+        let ans = i64::from_str(from_utf8(digits)?)
             .map_err(|e| InvalidFormat(Cow::Owned(e.to_string())))?;
-        self.data = self.data.get(len + 2..).ok_or(UnexpectedEOF)?;
+        self.data = &self.data[len + 2..];
         Ok(ans)
     }
 
@@ -286,18 +498,101 @@ impl<'a> BencodeDecoder<'a> {
     fn parse(&mut self) -> Result<Value> {
         match self.data.first().ok_or(UnexpectedEOF)? {
             b'i' => self.parse_int().map(Value::Int),
-            b'l' => self.parse_list().map(Value::List),
-            b'd' => self.parse_dict().map(Value::Dict),
+            b'l' => self.nested(Self::parse_list).map(Value::List),
+            b'd' => self.nested(Self::parse_dict).map(Value::Dict),
             b'0'..=b'9' => self.parse_str().map(Value::String),
             char => Err(InvalidFormat(Cow::Owned(format!(
                 "unexpected char code: {char}"
             )))),
         }
     }
+
+    fn parse_partial(&mut self) -> std::result::Result<Value, Partial> {
+        let container = match self.data.first() {
+            Some(b'l') => Value::List(Vec::new()),
+            Some(b'd') => Value::Dict(BTreeMap::new()),
+            _ => return self.parse().map_err(|e| (None, e)),
+        };
+        if self.depth >= MAX_DEPTH {
+            return Err((None, NestingTooDeep(MAX_DEPTH)));
+        }
+        self.depth += 1;
+        self.data = &self.data[1..];
+        let result = self.fill_partial(container);
+        self.depth -= 1;
+        result
+    }
+
+    /// Decodes the elements of a list or dictionary whose opening byte was consumed
+    fn fill_partial(&mut self, mut container: Value) -> std::result::Result<Value, Partial> {
+        loop {
+            match self.data.first() {
+                Some(b'e') => {
+                    self.data = &self.data[1..];
+                    return Ok(container);
+                }
+                None => return Err((Some(container), UnexpectedEOF)),
+                Some(_) => {}
+            }
+            let (key, element) = match &container {
+                Value::Dict(_) => match self.parse() {
+                    Ok(Value::String(key)) => (Some(key), self.parse_partial()),
+                    _ => return Err((Some(container), InvalidDictionary)),
+                },
+                _ => (None, self.parse_partial()),
+            };
+            let (element, error) = match element {
+                Ok(element) => (Some(element), None),
+                Err((partial, e)) => (partial, Some(e)),
+            };
+            match (&mut container, key, element) {
+                (Value::List(list), _, Some(element)) => list.push(element),
+                (Value::Dict(dict), Some(key), Some(element)) => {
+                    dict.insert(key, element);
+                }
+                _ => {}
+            }
+            if let Some(e) = error {
+                return Err((Some(container), e));
+            }
+        }
+    }
+
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(NestingTooDeep(MAX_DEPTH));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+}
+
+fn int_encoded_len(int: BencodeInt) -> usize {
+    let digits = int.unsigned_abs().checked_ilog10().unwrap_or(0) as usize + 1;
+    digits + usize::from(int < 0) + 2
+}
+
+fn bytes_encoded_len(bytes: &[u8]) -> usize {
+    let digits = bytes.len().checked_ilog10().unwrap_or(0) as usize + 1;
+    digits + 1 + bytes.len()
+}
+
+fn list_encoded_len(list: &BencodeList) -> usize {
+    2 + list.iter().map(Value::encoded_len).sum::<usize>()
+}
+
+fn dict_encoded_len(dict: &BencodeDict) -> usize {
+    let entries: usize = dict
+        .iter()
+        .map(|(key, value)| bytes_encoded_len(key) + value.encoded_len())
+        .sum();
+    2 + entries
 }
 
 pub fn into_vec(value: &Value) -> Vec<u8> {
-    let mut res = Vec::new();
+    let mut res = Vec::with_capacity(value.encoded_len());
     let mut encoder = BencodeEncoder::new(&mut res);
     encoder.encode(value);
     res
@@ -313,43 +608,124 @@ impl<'a> BencodeEncoder<'a> {
     }
 
     pub fn encode(&mut self, value: &Value) {
-        match value {
-            Value::Int(int) => self.encode_int(int.to_owned()),
-            Value::String(str) => self.encode_bytes(str.as_slice()),
-            Value::List(list) => self.encode_list(list),
-            Value::Dict(dict) => self.encode_dict(dict),
-        }
+        self.data.reserve(value.encoded_len());
+        self.write(value);
     }
 
     pub fn encode_int(&mut self, int: BencodeInt) {
         self.data.push(b'i');
-        self.data.extend_from_slice(int.to_string().as_bytes());
+        self.write_number(int);
         self.data.push(b'e');
     }
 
     pub fn encode_bytes(&mut self, bytes: &[u8]) {
-        self.data
-            .extend_from_slice(bytes.len().to_string().as_bytes());
+        self.write_number(bytes.len());
         self.data.push(b':');
         self.data.extend_from_slice(bytes);
     }
 
     pub fn encode_list(&mut self, list: &BencodeList) {
+        self.data.reserve(list_encoded_len(list));
+        self.write_list(list);
+    }
+
+    /// Reserves the whole encoded length upfront, so an info dict with a huge `pieces`
+    /// string or many files doesn't reallocate while it's being encoded
+    pub fn encode_dict(&mut self, dict: &BencodeDict) {
+        self.data.reserve(dict_encoded_len(dict));
+        self.write_dict(dict);
+    }
+
+    /// Encodes dictionary entries in the given order, failing instead of emitting a key that
+    /// isn't strictly greater than the previous one. Nothing is written on failure,
+    /// hashes over the output (e.g. info hash) can rely on it being canonical
+    pub fn encode_canonical<'v, I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'v [u8], &'v Value)>,
+    {
+        let start = self.data.len();
+        self.data.push(b'd');
+        let mut previous: Option<&[u8]> = None;
+        for (key, value) in entries {
+            if previous.is_some_and(|previous| previous >= key) {
+                self.data.truncate(start);
+                return Err(NonCanonicalKey(key.escape_ascii().to_string()));
+            }
+            previous = Some(key);
+            self.encode_bytes(key);
+            self.encode(value);
+        }
+        self.data.push(b'e');
+        Ok(())
+    }
+
+    fn write(&mut self, value: &Value) {
+        match value {
+            Value::Int(int) => self.encode_int(int.to_owned()),
+            Value::String(str) => self.encode_bytes(str.as_slice()),
+            Value::List(list) => self.write_list(list),
+            Value::Dict(dict) => self.write_dict(dict),
+        }
+    }
+
+    fn write_list(&mut self, list: &BencodeList) {
         self.data.push(b'l');
         for item in list {
-            self.encode(item)
+            self.write(item)
         }
         self.data.push(b'e');
     }
 
-    pub fn encode_dict(&mut self, dict: &BencodeDict) {
+    fn write_dict(&mut self, dict: &BencodeDict) {
         self.data.push(b'd');
         for (key, value) in dict {
             self.encode_bytes(key);
-            self.encode(value);
+            self.write(value);
         }
         self.data.push(b'e');
     }
+
+    /// Formats straight into the output instead of going through a temporary `String`
+    fn write_number(&mut self, number: impl std::fmt::Display) {
+        use std::io::Write;
+        write!(self.data, "{number}").expect("writing to a Vec can't fail");
+    }
+}
+
+/// Callbacks of [`walk`], every one defaults to doing nothing
+pub trait Visitor {
+    fn visit_int(&mut self, _int: BencodeInt) {}
+    fn visit_bytes(&mut self, _bytes: &[u8]) {}
+    fn list_start(&mut self, _len: usize) {}
+    fn list_end(&mut self) {}
+    fn dict_start(&mut self, _len: usize) {}
+    /// Called with the key of every entry right before its value is walked
+    fn dict_entry(&mut self, _key: &[u8]) {}
+    fn dict_end(&mut self) {}
+}
+
+/// Walks the value depth first, dictionary entries in key order. Everything is passed
+/// by reference, so e.g. `pieces` of a big torrent can be extracted without copying the tree
+pub fn walk(value: &Value, visitor: &mut impl Visitor) {
+    match value {
+        Value::Int(int) => visitor.visit_int(*int),
+        Value::String(bytes) => visitor.visit_bytes(bytes),
+        Value::List(list) => {
+            visitor.list_start(list.len());
+            for item in list {
+                walk(item, visitor);
+            }
+            visitor.list_end();
+        }
+        Value::Dict(dict) => {
+            visitor.dict_start(dict.len());
+            for (key, value) in dict {
+                visitor.dict_entry(key);
+                walk(value, visitor);
+            }
+            visitor.dict_end();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -409,6 +785,24 @@ mod tests {
         assert_eq!(int, Err(UnexpectedEOF));
     }
 
+    #[test]
+    fn parse_empty_int() {
+        let mut parser = BencodeDecoder::new(b"ie");
+        assert_eq!(parser.parse_int(), Err(InvalidInteger));
+    }
+
+    #[test]
+    fn parse_sign_only_int() {
+        let mut parser = BencodeDecoder::new(b"i-e");
+        assert_eq!(parser.parse_int(), Err(InvalidInteger));
+    }
+
+    #[test]
+    fn parse_string_without_length() {
+        let mut parser = BencodeDecoder::new(b":abc");
+        assert_eq!(parser.parse_str(), Err(InvalidString));
+    }
+
     #[test]
     fn parse_valid_list() {
         let data = Vec::from(b"l4:spami42ee");
@@ -537,4 +931,282 @@ mod tests {
             b"d5:firsti3546e6:second11:go here dgfe"
         );
     }
+
+    #[test]
+    fn large_info_dict_encoding() {
+        let pieces: Vec<u8> = (0..20 * 50_000).map(|i| (i % 251) as u8).collect();
+        let files: BencodeList = (0..1000)
+            .map(|i| bencode!({ "length" => i * 1_000_003 - 7, "path" => [format!("dir{i}"), "file"] }))
+            .collect();
+        let info = bencode!({
+            "files" => List(files),
+            "name" => "large",
+            "piece length" => 262_144,
+            "pieces" => pieces.clone(),
+        });
+
+        let mut expected = b"d5:filesl".to_vec();
+        for i in 0..1000i64 {
+            let dir = format!("dir{i}");
+            let file = format!(
+                "d6:lengthi{}e4:pathl{}:{dir}4:fileee",
+                i * 1_000_003 - 7,
+                dir.len()
+            );
+            expected.extend_from_slice(file.as_bytes());
+        }
+        expected.extend_from_slice(b"e4:name5:large12:piece lengthi262144e6:pieces1000000:");
+        expected.extend_from_slice(&pieces);
+        expected.push(b'e');
+
+        let encoded = crate::into_vec(&info);
+        assert_eq!(encoded, expected);
+        assert_eq!(info.encoded_len(), expected.len());
+        assert_eq!(encoded.capacity(), expected.len());
+        for int in [0, 9, 10, -1, -10, i64::MAX, i64::MIN] {
+            assert_eq!(Int(int).encoded_len(), crate::into_vec(&Int(int)).len());
+        }
+    }
+
+    #[test]
+    fn macro_scalars() {
+        assert_eq!(bencode!(42), Int(42));
+        assert_eq!(bencode!(-7), Int(-7));
+        assert_eq!(bencode!("spam"), String(b"spam".to_vec()));
+        assert_eq!(bencode!(b"\x00\xff".to_vec()), String(vec![0, 255]));
+        assert_eq!(bencode!([]), List(vec![]));
+        assert_eq!(bencode!({}), Dict(BencodeDict::new()));
+    }
+
+    #[test]
+    fn macro_nested() {
+        let value = bencode!({
+            "announce" => "http://localhost/announce",
+            "info" => {
+                "files" => [{ "length" => 3, "path" => ["a", "b"] }, [1, -2]],
+                "piece length" => 16384,
+            },
+        });
+        let info = BencodeDict::from([
+            (
+                b"files".to_vec(),
+                List(vec![
+                    Dict(BencodeDict::from([
+                        (b"length".to_vec(), Int(3)),
+                        (
+                            b"path".to_vec(),
+                            List(vec![String(b"a".to_vec()), String(b"b".to_vec())]),
+                        ),
+                    ])),
+                    List(vec![Int(1), Int(-2)]),
+                ]),
+            ),
+            (b"piece length".to_vec(), Int(16384)),
+        ]);
+        let manual = Dict(BencodeDict::from([
+            (
+                b"announce".to_vec(),
+                String(b"http://localhost/announce".to_vec()),
+            ),
+            (b"info".to_vec(), Dict(info)),
+        ]));
+        assert_eq!(value, manual);
+        assert_eq!(
+            crate::into_vec(&value).as_slice(),
+            b"d8:announce25:http://localhost/announce4:infod5:filesld6:lengthi3e4:pathl1:a1:bee\
+            li1ei-2eee12:piece lengthi16384eee"
+                .as_slice()
+        );
+    }
+
+    #[test]
+    fn parse_too_deep() {
+        let data = [b'l'; MAX_DEPTH + 1];
+        assert_eq!(from_slice(&data), Err(NestingTooDeep(MAX_DEPTH)));
+    }
+
+    #[test]
+    fn fixed_size_array_conversion() {
+        let hash: [u8; 20] = String([7; 20].to_vec()).try_into().unwrap();
+        assert_eq!(hash, [7; 20]);
+        assert_eq!(
+            <[u8; 20]>::try_from(String(b"short".to_vec())),
+            Err(StringLength(5, 20))
+        );
+        assert_eq!(
+            <[u8; 20]>::try_from(Int(1)),
+            Err(InvalidType(INTEGER_NAME, STRING_NAME))
+        );
+    }
+
+    #[test]
+    fn canonical_encoding_rejects_unsorted_keys() {
+        let (one, two) = (Int(1), Int(2));
+        let mut data = b"l".to_vec();
+        let mut encoder = BencodeEncoder::new(&mut data);
+        encoder
+            .encode_canonical([(b"a".as_slice(), &one), (b"b".as_slice(), &two)])
+            .unwrap();
+        assert_eq!(
+            encoder.encode_canonical([(b"b".as_slice(), &two), (b"a".as_slice(), &one)]),
+            Err(NonCanonicalKey("a".to_string()))
+        );
+        assert_eq!(
+            encoder.encode_canonical([(b"\xff".as_slice(), &one), (b"\xff".as_slice(), &two)]),
+            Err(NonCanonicalKey("\\xff".to_string()))
+        );
+        assert_eq!(data, b"ld1:ai1e1:bi2ee");
+    }
+
+    #[test]
+    fn borrowing_accessors() {
+        let int = Int(42);
+        assert_eq!(int.as_int(), Some(42));
+        assert_eq!(int.as_bytes(), None);
+
+        let string = String(b"aboba".to_vec());
+        assert_eq!(string.as_bytes(), Some(b"aboba".as_slice()));
+        assert_eq!(string.as_str(), Some("aboba"));
+        assert_eq!(String(vec![0xff]).as_str(), None);
+        assert_eq!(string.as_list(), None);
+
+        let list = List(vec![Int(1)]);
+        assert_eq!(list.as_list(), Some(&vec![Int(1)]));
+        assert_eq!(list.as_dict(), None);
+
+        let dict = Dict(BencodeDict::from([(b"a".to_vec(), Int(1))]));
+        assert_eq!(dict.as_dict().unwrap().get(b"a".as_slice()), Some(&Int(1)));
+        assert_eq!(dict.as_int(), None);
+    }
+
+    #[test]
+    fn trailing_whitespace_tolerated() {
+        let data = b"d1:ai1ee\r\n";
+        assert_eq!(
+            from_slice_exact(data, Trailing::Reject),
+            Err(TrailingData(2))
+        );
+        let value = from_slice_exact(data, Trailing::Whitespace).unwrap();
+        assert_eq!(value.as_dict().unwrap().len(), 1);
+
+        assert_eq!(
+            from_slice_exact(b"d1:ai1ee \njunk\n", Trailing::Whitespace),
+            Err(TrailingData(6))
+        );
+        assert_eq!(from_slice_exact(b"i1e", Trailing::Reject), Ok(Int(1)));
+    }
+
+    #[test]
+    fn decoded_from_path() {
+        let pieces: Vec<u8> = (0..=255).cycle().take(20 * 64).collect();
+        let torrent = bencode!({
+            "announce" => "http://tracker.example/announce",
+            "info" => {
+                "length" => 1 << 20,
+                "name" => "file.bin",
+                "piece length" => 16384,
+                "pieces" => pieces,
+            },
+        });
+        let mut data = crate::into_vec(&torrent);
+        data.push(b'\n');
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.torrent");
+        std::fs::write(&path, &data).unwrap();
+
+        let mapped = from_path(&path, Trailing::Whitespace).unwrap();
+        assert_eq!(
+            mapped,
+            from_slice_exact(&data, Trailing::Whitespace).unwrap()
+        );
+        assert_eq!(mapped, torrent);
+        assert_eq!(from_path(&path, Trailing::Reject), Err(TrailingData(1)));
+        assert_eq!(
+            from_path(dir.path().join("missing"), Trailing::Reject),
+            Err(BencodeError::Io(io::ErrorKind::NotFound))
+        );
+    }
+
+    #[test]
+    fn partial_value_on_error() {
+        let (partial, error) = from_slice_partial(b"li1e3:abcli2eex5:abce").unwrap_err();
+        assert_eq!(
+            partial,
+            Some(List(vec![
+                Int(1),
+                String(b"abc".to_vec()),
+                List(vec![Int(2)])
+            ]))
+        );
+        assert_eq!(error, InvalidFormat("unexpected char code: 120".into()));
+
+        let (partial, error) = from_slice_partial(b"d1:ai1e1:bli1ei").unwrap_err();
+        let expected = bencode!({ "a" => 1, "b" => [1] });
+        assert_eq!(partial, Some(expected));
+        assert_eq!(error, UnexpectedEOF);
+
+        assert_eq!(
+            from_slice_partial(b"i1x").unwrap_err(),
+            (None, InvalidInteger)
+        );
+        assert_eq!(
+            from_slice_partial(b"l1:ae"),
+            Ok(List(vec![String(b"a".to_vec())]))
+        );
+    }
+
+    #[test]
+    fn walk_counts_nested() {
+        #[derive(Default)]
+        struct Counter {
+            ints: usize,
+            bytes: usize,
+            lists: usize,
+            list_ends: usize,
+            dicts: usize,
+            entries: Vec<Vec<u8>>,
+            dict_ends: usize,
+        }
+
+        impl Visitor for Counter {
+            fn visit_int(&mut self, _int: BencodeInt) {
+                self.ints += 1;
+            }
+            fn visit_bytes(&mut self, bytes: &[u8]) {
+                self.bytes += bytes.len();
+            }
+            fn list_start(&mut self, _len: usize) {
+                self.lists += 1;
+            }
+            fn list_end(&mut self) {
+                self.list_ends += 1;
+            }
+            fn dict_start(&mut self, _len: usize) {
+                self.dicts += 1;
+            }
+            fn dict_entry(&mut self, key: &[u8]) {
+                self.entries.push(key.to_vec());
+            }
+            fn dict_end(&mut self) {
+                self.dict_ends += 1;
+            }
+        }
+
+        let value = from_slice(
+            b"d5:filesld6:lengthi5e4:pathl1:aeed6:lengthi7e4:pathl3:sub1:beee6:pieces4:abcde",
+        )
+        .unwrap();
+        let mut counter = Counter::default();
+        walk(&value, &mut counter);
+        assert_eq!(counter.ints, 2);
+        // "a", "sub", "b" and "abcd"
+        assert_eq!(counter.bytes, 9);
+        assert_eq!((counter.lists, counter.list_ends), (3, 3));
+        assert_eq!((counter.dicts, counter.dict_ends), (3, 3));
+        assert_eq!(
+            counter.entries,
+            ["files", "length", "path", "length", "path", "pieces"]
+                .map(|key| key.as_bytes().to_vec())
+        );
+    }
 }
@@ -0,0 +1,68 @@
+use bencode::{from_slice, into_vec, Value};
+use proptest::prelude::*;
+use std::fs;
+use std::path::Path;
+
+/// Decoding must never panic, and whatever decodes must survive an encode/decode round trip
+fn check_round_trip(data: &[u8]) {
+    if let Ok(value) = from_slice(data) {
+        let encoded = into_vec(&value);
+        assert_eq!(from_slice(&encoded).as_ref(), Ok(&value));
+        assert_eq!(into_vec(&from_slice(&encoded).unwrap()), encoded);
+    }
+}
+
+fn value_strategy() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        any::<i64>().prop_map(Value::Int),
+        proptest::collection::vec(any::<u8>(), 0..32).prop_map(Value::String),
+    ];
+    leaf.prop_recursive(4, 64, 8, |inner| {
+        prop_oneof![
+            proptest::collection::vec(inner.clone(), 0..8).prop_map(Value::List),
+            proptest::collection::btree_map(
+                proptest::collection::vec(any::<u8>(), 0..8),
+                inner,
+                0..8
+            )
+            .prop_map(Value::Dict),
+        ]
+    })
+}
+
+#[test]
+fn corpus() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    for entry in fs::read_dir(corpus).unwrap() {
+        let path = entry.unwrap().path();
+        let data = fs::read(&path).unwrap();
+        check_round_trip(&data);
+    }
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+        check_round_trip(&data);
+    }
+
+    #[test]
+    fn bencode_like_bytes(data in proptest::collection::vec(
+        prop_oneof![Just(b'i'), Just(b'l'), Just(b'd'), Just(b'e'), Just(b':'), Just(b'-'), b'0'..=b'9'],
+        0..256,
+    )) {
+        check_round_trip(&data);
+    }
+
+    #[test]
+    fn mutated_values(value in value_strategy(), position in any::<usize>(), byte in any::<u8>()) {
+        let mut data = into_vec(&value);
+        prop_assert_eq!(from_slice(&data), Ok(value));
+        if !data.is_empty() {
+            let position = position % data.len();
+            data[position] = byte;
+            check_round_trip(&data);
+            check_round_trip(&data[..position]);
+        }
+    }
+}
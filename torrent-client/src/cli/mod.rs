@@ -1,8 +1,114 @@
+use bencode::BencodeError;
 use clap::Parser;
+use std::io;
 use std::path::PathBuf;
+use thiserror::Error;
+use torrent_client::client::{ClientError, ConfigError};
+use torrent_client::file::TorrentError;
+use torrent_client::peer::PeerId;
+use torrent_client::tracker::TrackerError;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 pub struct Args {
     pub torrent_file: PathBuf,
+    /// Fixed peer id, 20 ascii characters or 40 hex digits, random when omitted
+    #[arg(long, allow_hyphen_values = true)]
+    pub peer_id: Option<PeerId>,
+}
+
+/// Everything that ends the program, exit codes follow sysexits.h
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("Can't read the torrent file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Malformed torrent file: {0}")]
+    Bencode(#[from] BencodeError),
+    #[error("Invalid torrent: {0}")]
+    Torrent(#[from] TorrentError),
+    #[error("Tracker failed: {0}")]
+    Tracker(#[from] TrackerError),
+    #[error("Invalid configuration: {0}")]
+    Config(#[from] ConfigError),
+    #[error("Download failed: {0}")]
+    Client(ClientError),
+}
+
+impl From<ClientError> for AppError {
+    /// Errors the client only passes along are reported as their own category
+    fn from(value: ClientError) -> Self {
+        match value {
+            ClientError::Torrent(e) => Self::Torrent(e),
+            ClientError::PeersRetrieve(e) => Self::Tracker(e),
+            e => Self::Client(e),
+        }
+    }
+}
+
+impl AppError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AppError::Bencode(BencodeError::Io(_)) => 74,
+            AppError::Bencode(_) | AppError::Torrent(_) => 65,
+            AppError::Tracker(_) => 69,
+            AppError::Client(_) => 70,
+            AppError::Io(_) => 74,
+            AppError::Config(_) => 78,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{AppError, Args};
+    use clap::Parser;
+    use torrent_client::client::ClientError;
+    use torrent_client::file::TorrentError;
+    use torrent_client::tracker::TrackerError;
+
+    #[test]
+    fn peer_id_validated_when_parsed() {
+        let args = Args::try_parse_from(["vdk", "a.torrent", "--peer-id", "-VD0001-123456789012"])
+            .unwrap();
+        assert_eq!(
+            args.peer_id.unwrap().as_ref(),
+            b"-VD0001-123456789012".as_slice()
+        );
+        for invalid in [
+            "-VD0001-",
+            "aaaaaaaaaaaaaaaaaaaa",
+            "-VD0001-1234567890\u{e9}",
+        ] {
+            assert!(
+                Args::try_parse_from(["vdk", "a.torrent", "--peer-id", invalid]).is_err(),
+                "{invalid}"
+            );
+        }
+        assert!(Args::try_parse_from(["vdk", "a.torrent"])
+            .unwrap()
+            .peer_id
+            .is_none());
+    }
+
+    #[test]
+    fn errors_mapped_to_exit_codes() {
+        let error = AppError::from(TorrentError::MissingField("pieces".to_string()));
+        assert!(matches!(
+            error,
+            AppError::Torrent(TorrentError::MissingField(_))
+        ));
+        assert_eq!(error.exit_code(), 65);
+
+        let timeout = TrackerError::AnnounceRequestError("timed out".to_string());
+        let error = AppError::from(ClientError::PeersRetrieve(timeout));
+        assert!(matches!(error, AppError::Tracker(_)));
+        assert_eq!(error.exit_code(), 69);
+
+        let error = AppError::from(ClientError::IncompleteContent(1, 3));
+        assert_eq!(error.exit_code(), 70);
+        assert_eq!(
+            AppError::from(std::io::Error::other("gone")).exit_code(),
+            74
+        );
+    }
 }
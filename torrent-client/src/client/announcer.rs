@@ -0,0 +1,402 @@
+use crate::client::worker::PauseHandle;
+use crate::tracker::factory::announce_tier;
+use crate::tracker::TrackerError::UnsupportedProtocol;
+use crate::tracker::{
+    AnnounceParameters, AnnounceResponse, DeadSwarmPolicy, ScrapeStats, TrackerClient,
+    TrackerError, TrackerEvent, TrackerSession,
+};
+use std::net::IpAddr;
+use std::time::Instant;
+use url::Url;
+
+/// Keeps the announce state of a torrent, so that each event reaches the tracker once.
+/// Announces go to the tracker tiers in order until a tracker answers, see BEP 12
+pub struct Announcer {
+    tiers: Vec<Vec<Url>>,
+    params: AnnounceParameters,
+    completed: bool,
+    pause: PauseHandle,
+    session: TrackerSession,
+    announce_external_ip: bool,
+}
+
+impl Announcer {
+    pub fn new(tiers: Vec<Vec<Url>>, params: AnnounceParameters) -> Self {
+        Self {
+            tiers,
+            params,
+            completed: false,
+            pause: PauseHandle::default(),
+            session: TrackerSession::new(rand::random(), Instant::now()),
+            announce_external_ip: false,
+        }
+    }
+
+    /// Shares the pause state of the download, regular announces carry `event=paused` meanwhile
+    pub fn set_pause(&mut self, pause: PauseHandle) -> &mut Self {
+        self.pause = pause;
+        self
+    }
+
+    /// Sends the observed external address as the `ip` of the following announces,
+    /// for a client behind NAT that only knows its private address
+    pub fn set_announce_external_ip(&mut self, announce_external_ip: bool) -> &mut Self {
+        self.announce_external_ip = announce_external_ip;
+        self
+    }
+
+    /// Announces less often to a swarm without seeders, see [`TrackerSession::connection_attempts`]
+    pub fn set_dead_swarm_policy(&mut self, policy: Option<DeadSwarmPolicy>) -> &mut Self {
+        self.session.set_dead_swarm_policy(policy);
+        self
+    }
+
+    /// Our address as the peers reported it in their extended handshakes, see
+    /// [`TrackerSession::observe_peer_ips`]
+    pub fn observe_peer_ips<I>(&mut self, ips: I)
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        self.session.observe_peer_ips(ips);
+    }
+
+    pub fn params_mut(&mut self) -> &mut AnnounceParameters {
+        &mut self.params
+    }
+
+    /// What the announces so far told about the tracker, drives the re-announces
+    pub fn session(&self) -> &TrackerSession {
+        &self.session
+    }
+
+    pub fn is_seeding(&self) -> bool {
+        self.completed
+    }
+
+    pub fn started(
+        &mut self,
+        tracker: &dyn TrackerClient,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        self.announce(tracker, Some(TrackerEvent::Started))
+    }
+
+    /// Regular re-announce, without an event unless the download is paused
+    pub fn update(
+        &mut self,
+        tracker: &dyn TrackerClient,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let event = self.pause.is_paused().then_some(TrackerEvent::Paused);
+        self.announce(tracker, event)
+    }
+
+    /// Tells the tracker the download has finished, only the first call reaches the tracker,
+    /// afterwards the torrent is seeding
+    pub fn completed(
+        &mut self,
+        tracker: &dyn TrackerClient,
+    ) -> Result<Option<AnnounceResponse>, TrackerError> {
+        if self.completed {
+            return Ok(None);
+        }
+        self.params.set_left(0);
+        let response = self.announce(tracker, Some(TrackerEvent::Completed))?;
+        self.completed = true;
+        Ok(Some(response))
+    }
+
+    /// Tells the tracker we are leaving the swarm, e.g. once the seed ratio is reached
+    pub fn stopped(
+        &mut self,
+        tracker: &dyn TrackerClient,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        self.announce(tracker, Some(TrackerEvent::Stopped))
+    }
+
+    /// Asks the trackers for the size of the swarm, in tier order until one of them answers.
+    /// `None` when that tracker doesn't know the torrent, otherwise the counters are taken
+    /// into account for the following announces, see [`TrackerSession::scraped`]
+    pub fn scrape(
+        &mut self,
+        tracker: &dyn TrackerClient,
+    ) -> Result<Option<ScrapeStats>, TrackerError> {
+        let info_hash = self.params.info_hash().wire();
+        let mut last_error = None;
+        for url in self.tiers.iter().flatten() {
+            match tracker.scrape(url, &[info_hash]) {
+                Ok(mut response) => {
+                    let stats = response.files.remove(&info_hash);
+                    if let Some(stats) = &stats {
+                        self.session.scraped(stats);
+                    }
+                    return Ok(stats);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| UnsupportedProtocol(String::from("no tracker"))))
+    }
+
+    fn announce(
+        &mut self,
+        tracker: &dyn TrackerClient,
+        event: Option<TrackerEvent>,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        self.params.set_event(event);
+        self.session.apply(&mut self.params);
+        if let Some(ip) = self
+            .session
+            .external_ip()
+            .filter(|_| self.announce_external_ip)
+        {
+            self.params.set_ip(Some(ip));
+        }
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            match announce_tier(tracker, tier, &self.params) {
+                Ok(response) => {
+                    self.session.update(&response, Instant::now());
+                    return Ok(response);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        self.session.announce_failed(Instant::now());
+        Err(last_error.unwrap_or_else(|| UnsupportedProtocol(String::from("no tracker"))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::announcer::Announcer;
+    use crate::client::worker::PauseHandle;
+    use crate::peer::extension::PeerExtensionInfo;
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::{
+        AnnounceParameters, AnnounceResponse, DeadSwarmPolicy, ScrapeResponse, ScrapeStats,
+        TrackerError, TrackerEvent, MIN_ANNOUNCE_INTERVAL,
+    };
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use url::Url;
+
+    fn response(interval: u64, tracker_id: Option<&[u8]>) -> AnnounceResponse {
+        AnnounceResponse {
+            interval: Duration::from_secs(interval),
+            min_interval: None,
+            complete: Some(3),
+            incomplete: None,
+            peers: Vec::new(),
+            external_ip: None,
+            tracker_id: tracker_id.map(<[u8]>::to_vec),
+        }
+    }
+
+    #[test]
+    fn session_carried_between_announces() {
+        let tracker = MockTracker::default();
+        tracker
+            .push_response(Ok(response(900, Some(b"\xffabc"))))
+            .push_response(Ok(response(1200, None)));
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut announcer = Announcer::new(vec![vec![url]], AnnounceParameters::new([1; 20]));
+
+        announcer.started(&tracker).unwrap();
+        assert_eq!(
+            announcer.session().tracker_id(),
+            Some(b"\xffabc".as_slice())
+        );
+        assert_eq!(announcer.session().interval(), Duration::from_secs(900));
+        assert!(!announcer.session().is_due(Instant::now()));
+        announcer.update(&tracker).unwrap();
+
+        let announces = tracker.announces();
+        let (first, second) = (&announces[0].1, &announces[1].1);
+        assert_eq!(first.tracker_id(), None);
+        assert_eq!(second.tracker_id(), Some(b"\xffabc".as_slice()));
+        assert_eq!(first.key(), Some(announcer.session().key()));
+        assert_eq!(second.key(), first.key());
+        // the second response has no tracker id, the first one stays
+        assert_eq!(
+            announcer.session().tracker_id(),
+            Some(b"\xffabc".as_slice())
+        );
+        assert_eq!(announcer.session().interval(), Duration::from_secs(1200));
+        assert_eq!(announcer.session().counts(), (Some(3), None));
+    }
+
+    #[test]
+    fn next_tier_after_failed_one() {
+        let tracker = MockTracker::default();
+        tracker
+            .push_response(Err(TrackerError::AnnounceRequestError("timed out".into())))
+            .push_response(Err(TrackerError::AnnounceRequestError("timed out".into())));
+        let url = |url: &str| Url::parse(url).unwrap();
+        let tiers = vec![
+            vec![
+                url("udp://a.example:6969"),
+                url("http://b.example/announce"),
+            ],
+            vec![url("http://c.example/announce")],
+        ];
+        let mut announcer = Announcer::new(tiers, AnnounceParameters::new([1; 20]));
+
+        announcer.started(&tracker).unwrap();
+        announcer.update(&tracker).unwrap();
+        let urls: Vec<Url> = tracker
+            .announces()
+            .into_iter()
+            .map(|(url, _)| url)
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                url("udp://a.example:6969"),
+                url("http://b.example/announce"),
+                url("http://c.example/announce"),
+                // the first tier is always tried first again
+                url("udp://a.example:6969"),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_announce_retried_later() {
+        let tracker = MockTracker::default();
+        tracker.push_response(Err(TrackerError::AnnounceRequestError("timed out".into())));
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut announcer = Announcer::new(vec![vec![url]], AnnounceParameters::new([1; 20]));
+
+        let before = Instant::now();
+        assert!(announcer.started(&tracker).is_err());
+        assert!(!announcer
+            .session()
+            .is_due(before + MIN_ANNOUNCE_INTERVAL / 2));
+        assert!(announcer
+            .session()
+            .is_due(Instant::now() + MIN_ANNOUNCE_INTERVAL));
+    }
+
+    #[test]
+    fn dead_swarm_backs_off() {
+        let tracker = MockTracker::default();
+        let mut dead = response(900, None);
+        dead.complete = Some(0);
+        dead.incomplete = Some(1);
+        tracker.push_response(Ok(dead));
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut announcer = Announcer::new(vec![vec![url]], AnnounceParameters::new([1; 20]));
+        announcer.set_dead_swarm_policy(Some(DeadSwarmPolicy::default()));
+
+        let before = Instant::now();
+        announcer.started(&tracker).unwrap();
+        assert!(announcer.session().is_backing_off());
+        assert!(announcer.session().next_announce() >= before + Duration::from_secs(1800));
+        assert_eq!(announcer.session().connection_attempts(50), 25);
+    }
+
+    #[test]
+    fn scrape_feeds_dead_swarm_backoff() {
+        let tracker = MockTracker::default();
+        let stats = ScrapeStats {
+            complete: 0,
+            downloaded: 4,
+            incomplete: 1,
+        };
+        tracker
+            .push_scrape(Err(TrackerError::AnnounceRequestError("timed out".into())))
+            .push_scrape(Ok(ScrapeResponse {
+                files: HashMap::from([([1; 20], stats)]),
+                missing: Vec::new(),
+            }));
+        let tiers = vec![
+            vec![Url::parse("http://first.example/announce").unwrap()],
+            vec![Url::parse("http://second.example/announce").unwrap()],
+        ];
+        let mut announcer = Announcer::new(tiers, AnnounceParameters::new([1; 20]));
+        announcer.set_dead_swarm_policy(Some(DeadSwarmPolicy::default()));
+
+        assert_eq!(announcer.scrape(&tracker).unwrap(), Some(stats));
+        assert!(announcer.session().is_backing_off());
+        // the tracker doesn't know the torrent
+        assert_eq!(announcer.scrape(&tracker).unwrap(), None);
+    }
+
+    #[test]
+    fn external_ip_announced() {
+        let tracker = MockTracker::default();
+        let mut tracker_view = response(900, None);
+        tracker_view.external_ip = Some("198.51.100.1".parse().unwrap());
+        tracker
+            .push_response(Ok(response(900, None)))
+            .push_response(Ok(response(900, None)))
+            .push_response(Ok(tracker_view))
+            .push_response(Ok(response(900, None)));
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut announcer = Announcer::new(vec![vec![url]], AnnounceParameters::new([1; 20]));
+        announcer.set_announce_external_ip(true);
+
+        announcer.started(&tracker).unwrap();
+        let handshake =
+            PeerExtensionInfo::from_handshake(b"d1:pi6881e6:yourip4:\xcb\x00\x71\x07e").unwrap();
+        let reported = handshake.your_ip.unwrap();
+        let private = "192.168.1.5".parse().unwrap();
+        // a single peer can't make us announce its view, private addresses never count
+        announcer.observe_peer_ips([reported, private, private, private]);
+        announcer.update(&tracker).unwrap();
+        announcer.observe_peer_ips([reported, private, reported, reported]);
+        announcer.update(&tracker).unwrap();
+        // the tracker reported 198.51.100.1 with the previous announce
+        announcer.update(&tracker).unwrap();
+
+        let ips: Vec<_> = tracker
+            .announces()
+            .iter()
+            .map(|(_, params)| params.ip())
+            .collect();
+        assert_eq!(
+            ips,
+            vec![
+                None,
+                None,
+                Some("203.0.113.7".parse().unwrap()),
+                Some("198.51.100.1".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn completed_sent_once() {
+        let tracker = MockTracker::default();
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut announcer = Announcer::new(vec![vec![url]], AnnounceParameters::new([1; 20]));
+
+        announcer.started(&tracker).unwrap();
+        assert!(!announcer.is_seeding());
+        // last piece verified
+        assert!(announcer.completed(&tracker).unwrap().is_some());
+        assert!(announcer.completed(&tracker).unwrap().is_none());
+        announcer.update(&tracker).unwrap();
+        assert!(announcer.is_seeding());
+        let pause = PauseHandle::default();
+        announcer.set_pause(pause.clone());
+        pause.pause();
+        announcer.update(&tracker).unwrap();
+        announcer.stopped(&tracker).unwrap();
+
+        let events: Vec<Option<TrackerEvent>> = tracker
+            .announces()
+            .iter()
+            .map(|(_, params)| params.event().cloned())
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Some(TrackerEvent::Started),
+                Some(TrackerEvent::Completed),
+                None,
+                Some(TrackerEvent::Paused),
+                Some(TrackerEvent::Stopped),
+            ]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::client::announcer::Announcer;
+use crate::tracker::{TrackerClient, TrackerError};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// What a download does once its last piece is verified
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum CompletionAction {
+    /// Keep uploading to the swarm
+    #[default]
+    Seed,
+    /// Leave the swarm right away
+    Stop,
+    /// Run the program with the path of the downloaded content as its only argument,
+    /// then keep seeding
+    Exec(String),
+}
+
+/// Tells the tracker, if the torrent has one, the download completed and carries out
+/// `action`, `spawn` starts the program of [`CompletionAction::Exec`].
+/// Returns whether the torrent keeps seeding
+pub fn finish<S>(
+    action: &CompletionAction,
+    mut announcer: Option<&mut Announcer>,
+    tracker: &dyn TrackerClient,
+    content: &Path,
+    spawn: S,
+) -> Result<bool, TrackerError>
+where
+    S: FnOnce(&str, &Path) -> io::Result<()>,
+{
+    if let Some(announcer) = announcer.as_deref_mut() {
+        announcer.completed(tracker)?;
+    }
+    match action {
+        CompletionAction::Seed => Ok(true),
+        CompletionAction::Stop => {
+            if let Some(announcer) = announcer {
+                announcer.stopped(tracker)?;
+            }
+            Ok(false)
+        }
+        CompletionAction::Exec(program) => {
+            if let Err(e) = spawn(program, content) {
+                log::warn!("failed to run {program} for {}: {e}", content.display());
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Starts the program without waiting for it
+pub fn spawn_program(program: &str, content: &Path) -> io::Result<()> {
+    Command::new(program).arg(content).spawn().map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::announcer::Announcer;
+    use crate::client::completion::{finish, CompletionAction};
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::{AnnounceParameters, TrackerEvent};
+    use std::path::Path;
+    use url::Url;
+
+    fn finished(action: CompletionAction) -> (bool, Vec<Option<TrackerEvent>>, Vec<String>) {
+        let tracker = MockTracker::default();
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut announcer = Announcer::new(vec![vec![url]], AnnounceParameters::new([1; 20]));
+        let mut spawned = Vec::new();
+        let content = Path::new("/downloads/album");
+        let seeding = finish(
+            &action,
+            Some(&mut announcer),
+            &tracker,
+            content,
+            |program, path| {
+                spawned.push(format!("{program} {}", path.display()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        // completed is announced whatever the action
+        assert!(announcer.is_seeding());
+        let events = tracker
+            .announces()
+            .iter()
+            .map(|(_, params)| params.event().cloned())
+            .collect();
+        (seeding, events, spawned)
+    }
+
+    #[test]
+    fn completion_actions() {
+        assert_eq!(
+            finished(CompletionAction::Seed),
+            (true, vec![Some(TrackerEvent::Completed)], vec![])
+        );
+        assert_eq!(
+            finished(CompletionAction::Stop),
+            (
+                false,
+                vec![Some(TrackerEvent::Completed), Some(TrackerEvent::Stopped)],
+                vec![]
+            )
+        );
+        assert_eq!(
+            finished(CompletionAction::Exec("/usr/local/bin/notify".to_string())),
+            (
+                true,
+                vec![Some(TrackerEvent::Completed)],
+                vec!["/usr/local/bin/notify /downloads/album".to_string()]
+            )
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use crate::peer::Peer;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Address family to connect over when a peer is reachable over both IPv4 and IPv6
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AddressPreference {
+    /// Families alternate, IPv6 first, see RFC 8305
+    #[default]
+    HappyEyeballs,
+    Ipv4,
+    Ipv6,
+}
+
+/// Every address the peer is known by, a peer offered by different sources over both
+/// families shares its peer id between them
+pub fn dual_stack_addrs(peer: &Peer, known: &[Peer]) -> Vec<SocketAddr> {
+    let mut addrs = vec![peer.addr];
+    if let Some(peer_id) = &peer.peer_id {
+        let aliases = known
+            .iter()
+            .filter(|other| other.peer_id.as_ref() == Some(peer_id))
+            .map(|other| other.addr);
+        for addr in aliases {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+/// Opens a TCP connection to `addr`, from the local `source` address when one is given,
+/// e.g. to keep peer traffic on one interface of a multi-homed machine
+pub fn tcp_connect(
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let Some(source) = source else {
+        return TcpStream::connect_timeout(&addr, timeout);
+    };
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&SocketAddr::new(source, 0).into())?;
+    socket.connect_timeout(&addr.into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// Result of a connection attempt, a failure names the peer's address
+pub type Attempt<T, E> = Result<T, (SocketAddr, E)>;
+
+/// Tries the peers on at most `limit` threads, each taking the next peer once its attempt
+/// is over, so a big peer list doesn't open hundreds of sockets at once. Every attempt
+/// is sent to `attempts` as it ends, failures too, so those peers can be put on cooldown
+pub fn connect_bounded<T, E, F>(
+    peers: Vec<Peer>,
+    limit: usize,
+    connect: F,
+    attempts: mpsc::Sender<Attempt<T, E>>,
+) -> Vec<JoinHandle<()>>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+    F: Fn(&Peer) -> Result<T, E> + Send + Sync + 'static,
+{
+    let threads = limit.max(1).min(peers.len());
+    let queue = Arc::new(Mutex::new(VecDeque::from(peers)));
+    let connect = Arc::new(connect);
+    (0..threads)
+        .map(|_| {
+            let (queue, connect, attempts) = (queue.clone(), connect.clone(), attempts.clone());
+            thread::spawn(move || loop {
+                let Some(peer) = queue.lock().unwrap().pop_front() else {
+                    return;
+                };
+                let attempt = connect(&peer).map_err(|e| (peer.addr, e));
+                if attempts.send(attempt).is_err() {
+                    return;
+                }
+            })
+        })
+        .collect()
+}
+
+/// Connects to one of the addresses of a peer, preferred family first
+#[derive(Debug, Clone)]
+pub struct Connector {
+    preference: AddressPreference,
+    timeout: Duration,
+}
+
+impl Connector {
+    pub fn new(preference: AddressPreference, timeout: Duration) -> Self {
+        Self {
+            preference,
+            timeout,
+        }
+    }
+
+    /// Addresses in the order they are attempted, happy eyeballs alternates the families
+    pub fn order(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.iter().partition(|addr| addr.is_ipv6());
+        match self.preference {
+            AddressPreference::Ipv4 => v4.into_iter().chain(v6).collect(),
+            AddressPreference::Ipv6 => v6.into_iter().chain(v4).collect(),
+            AddressPreference::HappyEyeballs => {
+                let mut ordered = Vec::with_capacity(addrs.len());
+                let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+                loop {
+                    match (v6.next(), v4.next()) {
+                        (None, None) => break ordered,
+                        (first, second) => ordered.extend(first.into_iter().chain(second)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tries the addresses in [`Connector::order`] until one connects. Every attempt gets
+    /// the full timeout, a slow but working address isn't abandoned for the next one
+    pub fn connect<T, F>(&self, addrs: &[SocketAddr], mut connect: F) -> io::Result<(SocketAddr, T)>
+    where
+        F: FnMut(SocketAddr, Duration) -> io::Result<T>,
+    {
+        let ordered = self.order(addrs);
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect");
+        for addr in &ordered {
+            match connect(*addr, self.timeout) {
+                Ok(stream) => return Ok((*addr, stream)),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::connector::{
+        connect_bounded, dual_stack_addrs, tcp_connect, AddressPreference, Connector,
+    };
+    use crate::peer::{Peer, PeerId};
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn bound_to_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let source = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let stream = tcp_connect(addr, Some(source), Duration::from_secs(1)).unwrap();
+        let (_, remote) = listener.accept().unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), source);
+        assert_eq!(remote, stream.local_addr().unwrap());
+
+        // an IPv4 source can't reach an IPv6 peer
+        let v6: SocketAddr = "[::1]:1".parse().unwrap();
+        assert!(tcp_connect(v6, Some(source), Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn connect_attempts_bounded() {
+        let peers: Vec<Peer> = (1..=12)
+            .map(|port| Peer::new(None, SocketAddr::from(([10, 0, 0, 1], port))))
+            .collect();
+        let active = Arc::new(AtomicUsize::new(0));
+        let most_active = Arc::new(AtomicUsize::new(0));
+        let (sender, attempted) = mpsc::channel();
+        let (counter, most) = (active.clone(), most_active.clone());
+        let attempts = connect_bounded(
+            peers,
+            3,
+            move |peer| {
+                let now = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                // a connect that hangs for a while
+                thread::sleep(Duration::from_millis(20));
+                counter.fetch_sub(1, Ordering::SeqCst);
+                match peer.addr.port() % 4 {
+                    0 => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+                    _ => Ok(peer.addr),
+                }
+            },
+            sender,
+        );
+        // one thread per allowed attempt, not one per peer
+        assert_eq!(attempts.len(), 3);
+        attempts
+            .into_iter()
+            .for_each(|attempt| attempt.join().unwrap());
+
+        assert!((1..=3).contains(&most_active.load(Ordering::SeqCst)));
+        let (mut connected, mut failed) = (Vec::new(), Vec::new());
+        for attempt in attempted.try_iter() {
+            match attempt {
+                Ok(addr) => connected.push(addr.port()),
+                Err((addr, _)) => failed.push(addr.port()),
+            }
+        }
+        connected.sort_unstable();
+        failed.sort_unstable();
+        assert_eq!(connected, vec![1, 2, 3, 5, 6, 7, 9, 10, 11]);
+        assert_eq!(failed, vec![4, 8, 12]);
+    }
+
+    #[test]
+    fn preferred_family_attempted_first() {
+        let peer_id = PeerId::new(*b"-VD0001-abcdefghijkl");
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let known = [
+            Peer::new(None, "10.0.0.2:6881".parse().unwrap()),
+            Peer::new(Some(peer_id.clone()), v6),
+        ];
+        let addrs = dual_stack_addrs(&Peer::new(Some(peer_id), v4), &known);
+        assert_eq!(addrs, vec![v4, v6]);
+
+        let timeout = Duration::from_secs(5);
+        for (preference, expected) in [
+            (AddressPreference::Ipv4, [(v4, timeout), (v6, timeout)]),
+            (AddressPreference::Ipv6, [(v6, timeout), (v4, timeout)]),
+            (
+                AddressPreference::HappyEyeballs,
+                [(v6, timeout), (v4, timeout)],
+            ),
+        ] {
+            let mut attempts = Vec::new();
+            let connector = Connector::new(preference, timeout);
+            let connected = connector.connect(&addrs, |addr, timeout| {
+                attempts.push((addr, timeout));
+                match attempts.len() {
+                    1 => Err(io::Error::from(io::ErrorKind::TimedOut)),
+                    _ => Ok(()),
+                }
+            });
+            assert_eq!(connected.unwrap().0, expected[1].0);
+            assert_eq!(attempts, expected);
+        }
+    }
+}
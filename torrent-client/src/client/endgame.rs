@@ -0,0 +1,94 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Peers to drop and whether to ask the tracker for fresh ones
+#[derive(Debug, PartialEq)]
+pub struct Rotation {
+    pub dropped: Vec<SocketAddr>,
+    pub reannounce: bool,
+}
+
+/// Notices an endgame that stopped making progress, e.g. because every remaining block
+/// was requested from peers that choke us, and picks the slowest peers to replace
+#[derive(Debug)]
+pub struct EndgameWatchdog {
+    timeout: Duration,
+    last_progress: Instant,
+}
+
+impl EndgameWatchdog {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            timeout,
+            last_progress: now,
+        }
+    }
+
+    /// A block arrived
+    pub fn progress(&mut self, now: Instant) {
+        self.last_progress = now;
+    }
+
+    /// `rates` are download rates of the connected peers in bytes per second.
+    /// The slower half of them is dropped once the endgame stalls for the timeout
+    pub fn check(
+        &mut self,
+        now: Instant,
+        in_endgame: bool,
+        rates: &[(SocketAddr, u64)],
+    ) -> Option<Rotation> {
+        if !in_endgame || now.duration_since(self.last_progress) < self.timeout {
+            return None;
+        }
+        let mut rates = rates.to_vec();
+        rates.sort_by_key(|(_, rate)| *rate);
+        let dropped = rates
+            .iter()
+            .take(rates.len().div_ceil(2))
+            .map(|(addr, _)| *addr)
+            .collect();
+        // give the fresh peers a full timeout before judging again
+        self.last_progress = now;
+        Some(Rotation {
+            dropped,
+            reannounce: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::endgame::{EndgameWatchdog, Rotation};
+    use crate::client::picker::PiecePicker;
+    use crate::util::PieceBitfield;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn stalled_endgame_rotates_peers() {
+        let mut picker = PiecePicker::new(2, 8);
+        let all = PieceBitfield::from_bytes(&[0b1100_0000], 2);
+        picker.complete(0);
+        assert!(!picker.is_endgame());
+        picker.pick(&all);
+        assert!(picker.is_endgame());
+
+        let [fast, slow, stalled]: [SocketAddr; 3] =
+            ["1.1.1.1:1", "2.2.2.2:2", "3.3.3.3:3"].map(|addr| addr.parse().unwrap());
+        let rates = [(fast, 1000), (stalled, 0), (slow, 10)];
+        let timeout = Duration::from_secs(30);
+        let now = Instant::now();
+        let mut watchdog = EndgameWatchdog::new(timeout, now);
+
+        assert_eq!(watchdog.check(now + timeout / 2, true, &rates), None);
+        assert_eq!(watchdog.check(now + timeout, false, &rates), None);
+        assert_eq!(
+            watchdog.check(now + timeout, picker.is_endgame(), &rates),
+            Some(Rotation {
+                dropped: vec![stalled, slow],
+                reannounce: true
+            })
+        );
+        assert_eq!(watchdog.check(now + timeout, true, &rates), None);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::util::Sha1;
+use sha1::Digest;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+
+/// Hash function used to verify pieces
+pub type HashFn = Arc<dyn Fn(&[u8]) -> Sha1 + Send + Sync>;
+
+/// Verification result of a submitted piece
+#[derive(Debug)]
+pub struct Verified {
+    pub index: usize,
+    pub data: Vec<u8>,
+    pub valid: bool,
+}
+
+struct Job {
+    index: usize,
+    data: Vec<u8>,
+}
+
+/// Verifies pieces on dedicated threads, so hashing a large piece doesn't stall network I/O.
+/// Results come back in the order hashing finished, not in the order of submission
+pub struct HashPool {
+    jobs: Option<mpsc::Sender<Job>>,
+    results: mpsc::Receiver<Verified>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl HashPool {
+    pub fn new(threads: usize, piece_hashes: Arc<Vec<Sha1>>) -> Self {
+        Self::with_hasher(
+            threads,
+            piece_hashes,
+            Arc::new(|data| sha1::Sha1::digest(data).into()),
+        )
+    }
+
+    pub fn with_hasher(threads: usize, piece_hashes: Arc<Vec<Sha1>>, hasher: HashFn) -> Self {
+        let (jobs, received) = mpsc::channel::<Job>();
+        let (results, verified) = mpsc::channel();
+        let received = Arc::new(Mutex::new(received));
+        let workers = (0..threads.max(1))
+            .map(|number| {
+                let received = received.clone();
+                let results = results.clone();
+                let piece_hashes = piece_hashes.clone();
+                let hasher = hasher.clone();
+                thread::Builder::new()
+                    .name(format!("hasher-{number}"))
+                    .spawn(move || loop {
+                        let job = match received.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => return,
+                        };
+                        let valid = piece_hashes
+                            .get(job.index)
+                            .is_some_and(|expected| hasher(&job.data) == *expected);
+                        let verified = Verified {
+                            index: job.index,
+                            data: job.data,
+                            valid,
+                        };
+                        if results.send(verified).is_err() {
+                            return;
+                        }
+                    })
+                    .expect("failed to spawn hashing thread")
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            results: verified,
+            workers,
+        }
+    }
+
+    pub fn submit(&self, index: usize, data: Vec<u8>) {
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(Job { index, data });
+        }
+    }
+
+    /// Result that is already available, never blocks
+    pub fn try_recv(&self) -> Option<Verified> {
+        self.results.try_recv().ok()
+    }
+
+    /// Waits for the next result
+    pub fn recv(&self) -> Option<Verified> {
+        self.results.recv().ok()
+    }
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::hasher::HashPool;
+    use sha1::Digest;
+    use std::collections::HashMap;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn verified_off_calling_thread() {
+        let pieces: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 1024]).collect();
+        let hashes = pieces
+            .iter()
+            .map(|piece| sha1::Sha1::digest(piece).into())
+            .collect();
+        let (threads, hashed_on) = mpsc::channel();
+        let threads = Mutex::new(threads);
+        let pool = HashPool::with_hasher(
+            3,
+            Arc::new(hashes),
+            Arc::new(move |data| {
+                let name = thread::current().name().map(str::to_string);
+                threads.lock().unwrap().send(name).unwrap();
+                sha1::Sha1::digest(data).into()
+            }),
+        );
+
+        for (index, piece) in pieces.iter().enumerate() {
+            let mut data = piece.clone();
+            if index % 3 == 0 {
+                data[0] ^= 0xff;
+            }
+            pool.submit(index, data);
+        }
+        let results: HashMap<usize, bool> = (0..pieces.len())
+            .map(|_| pool.recv().unwrap())
+            .map(|verified| (verified.index, verified.valid))
+            .collect();
+        let expected = (0..pieces.len())
+            .map(|index| (index, index % 3 != 0))
+            .collect();
+        assert_eq!(results, expected);
+        assert!(pool.try_recv().is_none());
+
+        drop(pool);
+        let caller = thread::current().name().map(str::to_string);
+        let names: Vec<_> = hashed_on.iter().collect();
+        assert_eq!(names.len(), pieces.len());
+        assert!(names.iter().all(|name| name != &caller
+            && name
+                .as_ref()
+                .is_some_and(|name| name.starts_with("hasher-"))));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::client::ratio::RatioTracker;
+use crate::client::worker::Progress;
+use std::time::{Duration, Instant};
+
+/// Periodic one-line summary of a download for the log
+#[derive(Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    last: Instant,
+    downloaded: u64,
+    uploaded: u64,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            last: now,
+            downloaded: 0,
+            uploaded: 0,
+        }
+    }
+
+    /// Logs the summary at info level once `interval` passed since the previous one,
+    /// rates are averaged over that time. Returns the logged line
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        label: &str,
+        progress: &Progress,
+        ratio: &RatioTracker,
+        peers: usize,
+        left: usize,
+    ) -> Option<String> {
+        let elapsed = now.saturating_duration_since(self.last);
+        if elapsed < self.interval || elapsed.is_zero() {
+            return None;
+        }
+        let seconds = elapsed.as_secs_f64();
+        let down = ratio.downloaded().saturating_sub(self.downloaded) as f64 / seconds;
+        let up = ratio.uploaded().saturating_sub(self.uploaded) as f64 / seconds;
+        (self.last, self.downloaded, self.uploaded) = (now, ratio.downloaded(), ratio.uploaded());
+
+        let percent = match progress.pieces_total {
+            0 => 100.0,
+            total => progress.pieces_have as f64 * 100.0 / total as f64,
+        };
+        let eta = match (left, down > 0.0) {
+            (0, _) => "done".to_string(),
+            (_, true) => format!("{}s", (left as f64 / down).ceil() as u64),
+            (_, false) => "unknown".to_string(),
+        };
+        let line = format!(
+            "{label}: {percent:.1}% ({}/{} pieces), down {:.1} KiB/s, up {:.1} KiB/s, {peers} peers, ETA {eta}",
+            progress.pieces_have,
+            progress.pieces_total,
+            down / 1024.0,
+            up / 1024.0,
+        );
+        log::info!("{line}");
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::heartbeat::Heartbeat;
+    use crate::client::ratio::RatioTracker;
+    use crate::client::worker::Progress;
+    use crate::util::test_log;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn heartbeat_logged_every_interval() {
+        test_log::install();
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(Duration::from_millis(100), start);
+        let progress = Progress {
+            pieces_have: 1,
+            pieces_total: 4,
+            paused: false,
+            piece_sources: BTreeMap::new(),
+        };
+        let mut ratio = RatioTracker::new(None);
+        ratio.record_download(10 * 1024);
+        ratio.record_upload(2048);
+
+        let tick = |heartbeat: &mut Heartbeat, millis: u64| {
+            let now = start + Duration::from_millis(millis);
+            heartbeat.tick(now, "heartbeat-test", &progress, &ratio, 3, 30 * 1024)
+        };
+        assert_eq!(tick(&mut heartbeat, 50), None);
+        let line = tick(&mut heartbeat, 1000).unwrap();
+        assert_eq!(
+            line,
+            "heartbeat-test: 25.0% (1/4 pieces), down 10.0 KiB/s, up 2.0 KiB/s, 3 peers, ETA 3s"
+        );
+        assert_eq!(tick(&mut heartbeat, 1050), None);
+        assert_eq!(test_log::lines("heartbeat-test"), vec![line]);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::peer::connection::{ConnectionError, PeerConnection};
+use crate::peer::PeerId;
+use crate::util::Sha1;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// Inbound peers get this long to send their handshake, so a silent one can't stall the accept loop
+const INBOUND_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Source of inbound connections
+pub trait Listener {
+    type Stream: Read + Write;
+
+    /// Waits for the next connection, `None` once the listener is closed
+    fn accept(&self) -> Option<io::Result<Self::Stream>>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> Option<io::Result<Self::Stream>> {
+        let stream = TcpListener::accept(self).and_then(|(stream, _)| {
+            stream.set_read_timeout(Some(INBOUND_HANDSHAKE_TIMEOUT))?;
+            Ok(stream)
+        });
+        Some(stream)
+    }
+}
+
+/// Hands connections accepted on a shared listener to the download of the torrent
+/// the remote peer asked for in its handshake
+pub struct InboundRouter<T: Read + Write = TcpStream> {
+    peer_id: Arc<PeerId>,
+    torrents: Mutex<HashMap<Sha1, mpsc::Sender<PeerConnection<T>>>>,
+}
+
+impl<T: Read + Write> InboundRouter<T> {
+    pub fn new(peer_id: Arc<PeerId>) -> Self {
+        Self {
+            peer_id,
+            torrents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts accepting peers for the torrent, they arrive through the returned receiver
+    pub fn register(&self, info_hash: Sha1) -> mpsc::Receiver<PeerConnection<T>> {
+        let (sender, receiver) = mpsc::channel();
+        self.torrents.lock().unwrap().insert(info_hash, sender);
+        receiver
+    }
+
+    pub fn unregister(&self, info_hash: &Sha1) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    pub fn is_registered(&self, info_hash: &Sha1) -> bool {
+        self.torrents.lock().unwrap().contains_key(info_hash)
+    }
+
+    /// Completes the handshake of an inbound connection and passes it on,
+    /// returns the info hash the connection was routed to
+    pub fn route(&self, transport: T) -> Result<Sha1, ConnectionError> {
+        let (connection, info_hash) =
+            PeerConnection::accept(transport, &self.peer_id, |info_hash| {
+                self.is_registered(info_hash)
+            })?;
+        let mut torrents = self.torrents.lock().unwrap();
+        let sender = torrents
+            .get(&info_hash)
+            .ok_or(ConnectionError::UnknownInfoHash(info_hash))?;
+        if sender.send(connection).is_err() {
+            // download has finished and dropped its receiver
+            torrents.remove(&info_hash);
+            return Err(ConnectionError::UnknownInfoHash(info_hash));
+        }
+        Ok(info_hash)
+    }
+
+    /// Accept loop, routes every connection of the listener until it's closed.
+    /// Peers asking for torrents we don't serve are dropped
+    pub fn serve<L>(&self, listener: &L)
+    where
+        L: Listener<Stream = T>,
+    {
+        while let Some(stream) = listener.accept() {
+            if let Ok(stream) = stream {
+                let _ = self.route(stream);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::inbound::{InboundRouter, Listener};
+    use crate::peer::connection::{HandshakeMessage, ReservedBits};
+    use crate::peer::PeerId;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::io::{Cursor, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockListener {
+        streams: Mutex<VecDeque<MockStream>>,
+    }
+
+    impl MockListener {
+        /// Queues a peer that opens with a handshake for `info_hash`,
+        /// returns what we write back to it
+        fn connect(&self, info_hash: [u8; 20]) -> Arc<Mutex<Vec<u8>>> {
+            let handshake =
+                HandshakeMessage::new(ReservedBits::default(), info_hash, PeerId::random());
+            let output = Arc::new(Mutex::new(Vec::new()));
+            self.streams.lock().unwrap().push_back(MockStream {
+                input: Cursor::new(handshake.to_bytes().to_vec()),
+                output: output.clone(),
+            });
+            output
+        }
+    }
+
+    impl Listener for MockListener {
+        type Stream = MockStream;
+
+        fn accept(&self) -> Option<io::Result<Self::Stream>> {
+            self.streams.lock().unwrap().pop_front().map(Ok)
+        }
+    }
+
+    #[test]
+    fn accept_loop_matches_info_hash() {
+        let router = InboundRouter::new(Arc::new(PeerId::random()));
+        let inbound = router.register([1; 20]);
+        let listener = MockListener::default();
+        let matching = listener.connect([1; 20]);
+        let mismatching = listener.connect([2; 20]);
+
+        router.serve(&listener);
+
+        assert!(inbound.try_recv().is_ok());
+        assert!(inbound.try_recv().is_err());
+        let response = matching.lock().unwrap();
+        let response = HandshakeMessage::from_bytes(response.as_slice().try_into().unwrap());
+        assert_eq!(response.unwrap().info_hash(), &[1; 20]);
+        assert!(mismatching.lock().unwrap().is_empty());
+    }
+}
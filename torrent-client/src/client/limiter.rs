@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token bucket shared by every download of a session, so the limit is global
+/// and torrents compete for the same bandwidth. Zero rate means no limit
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: usize,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: usize) -> Self {
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_second
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes_per_second == 0
+    }
+
+    /// Takes `amount` bytes from the bucket if there is enough of them
+    pub fn try_acquire(&self, amount: usize) -> bool {
+        self.take(amount).is_none()
+    }
+
+    /// Blocks until `amount` bytes may be transferred. Amounts bigger than the bucket
+    /// are let through once the bucket is full, so huge blocks can't stall forever
+    pub fn acquire(&self, amount: usize) {
+        while let Some(wait) = self.take(amount) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Returns how long to wait before the bucket may hold `amount` bytes
+    fn take(&self, amount: usize) -> Option<Duration> {
+        if self.is_unlimited() {
+            return None;
+        }
+        let rate = self.bytes_per_second as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.refilled = now;
+
+        let needed = (amount as f64).min(rate);
+        if bucket.tokens >= needed {
+            bucket.tokens -= amount as f64;
+            None
+        } else {
+            Some(Duration::from_secs_f64((needed - bucket.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::limiter::RateLimiter;
+
+    #[test]
+    fn bucket_drains() {
+        let limiter = RateLimiter::new(1000);
+        assert!(limiter.try_acquire(600));
+        assert!(limiter.try_acquire(300));
+        assert!(!limiter.try_acquire(500));
+
+        let unlimited = RateLimiter::unlimited();
+        assert!(unlimited.try_acquire(usize::MAX));
+    }
+}
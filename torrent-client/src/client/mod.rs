@@ -1,14 +1,48 @@
+mod announcer;
+mod completion;
+mod connector;
+mod endgame;
+mod hasher;
+mod heartbeat;
+mod inbound;
+mod limiter;
+mod peers;
+mod picker;
+mod piece;
+mod ratio;
+pub mod session;
+mod stats;
 mod worker;
 
-use crate::client::worker::Downloader;
+use crate::client::announcer::Announcer;
+pub use crate::client::completion::CompletionAction;
+use crate::client::connector::AddressPreference;
+use crate::client::inbound::InboundRouter;
+use crate::client::limiter::RateLimiter;
+pub use crate::client::worker::PauseHandle;
+use crate::client::worker::{BitfieldPolicy, Downloader};
 use crate::client::ClientError::InboundConnection;
-use crate::file::TorrentFile;
+use crate::file::{Info, TorrentError, TorrentFile};
+use crate::peer::connection::ConnectionError;
+use crate::peer::mse::EncryptionMode;
 use crate::peer::{Peer, PeerId};
-use crate::tracker::{AnnounceParameters, RequestMode, TrackerClient, TrackerError};
+use crate::storage::cache::CachedStorage;
+use crate::storage::resume::ResumeState;
+use crate::storage::{AllocationStrategy, StorageError, StorageWriter};
+use crate::tracker::{
+    AnnounceParameters, AnnounceResponse, DeadSwarmPolicy, RequestMode, TrackerClient,
+    TrackerError, DEFAULT_TRACKER_TIMEOUT,
+};
+use crate::util::{PieceBitfield, Sha1};
 use std::borrow::Cow;
-use std::collections::VecDeque;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{mem, thread};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -18,28 +52,436 @@ pub enum ClientError {
 
     #[error("Inbound connection error {0}")]
     InboundConnection(Cow<'static, str>),
+
+    #[error("Inbound peer rejected {0}")]
+    InboundPeer(#[from] ConnectionError),
+
+    #[error("Malformed torrent {0}")]
+    Torrent(#[from] TorrentError),
+
+    #[error("Storage error {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("Content is incomplete, {0} of {1} pieces are missing or corrupt")]
+    IncompleteContent(usize, usize),
 }
 type Result<T> = std::result::Result<T, ClientError>;
 
-#[derive(Default, Debug)]
+#[derive(Error, Debug, PartialEq)]
+pub enum ConfigError {
+    #[error("Connection numbers cannot be zero")]
+    ZeroConnections,
+}
+
+/// Source of peers besides the tracker, like DHT or peer exchange
+pub trait PeerSource: Send + Sync {
+    fn peers(&self, info_hash: &Sha1) -> Vec<Peer>;
+}
+
+/// Peers of the announce together with peers of every other source. A failed announce
+/// only aborts the download when no other source knows any peer. Trackerless torrents
+/// have no announce
+fn collect_peers(
+    announced: Option<std::result::Result<AnnounceResponse, TrackerError>>,
+    sources: &[Box<dyn PeerSource>],
+    info_hash: &Sha1,
+) -> Result<Vec<Peer>> {
+    let mut peers: Vec<Peer> = sources
+        .iter()
+        .flat_map(|source| source.peers(info_hash))
+        .collect();
+    match announced {
+        None => {}
+        Some(Ok(response)) => peers.extend(response.peers),
+        Some(Err(e)) if peers.is_empty() => return Err(e.into()),
+        Some(Err(e)) => log::warn!(
+            "announce failed, continuing with {} peers: {e}",
+            peers.len()
+        ),
+    }
+    Ok(peers)
+}
+
+/// Files of the torrent under the download directory, created before anything is written
+fn open_storage(config: &Config, info: &Info) -> Result<CachedStorage<StorageWriter>> {
+    let mut writer = StorageWriter::new(config.download_dir(), info);
+    writer.set_allocation(config.allocation());
+    writer.create_files()?;
+    let mut storage = CachedStorage::new(writer, config.read_cache_size());
+    storage.set_piece_hashes(info.info_hash, info.pieces.clone());
+    Ok(storage)
+}
+
+/// State file of a download, next to its content
+fn resume_path(config: &Config, info: &Info) -> PathBuf {
+    let mut name = info.name.clone().into_os_string();
+    name.push(".resume");
+    config.download_dir().join(name)
+}
+
+const DEFAULT_CONNECTION_NUMBERS: usize = 25;
+const DEFAULT_READ_CACHE_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_PIECES_IN_FLIGHT: usize = 32;
+const DEFAULT_HASHING_THREADS: usize = 2;
+const DEFAULT_PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_PEER_STRIKES: u32 = 3;
+const DEFAULT_MAX_CONNECT_ATTEMPTS: usize = 8;
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest a dropped [`Client`] waits for its `stopped` announces
+const STOP_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone)]
 pub struct Config {
     connection_numbers: usize,
+    reject_bogus_peers: bool,
+    read_cache_size: usize,
+    rate_limit: usize,
+    handshake_timeout: Duration,
+    encryption: EncryptionMode,
+    max_pieces_in_flight: usize,
+    allocation: AllocationStrategy,
+    hashing_threads: usize,
+    peer_idle_timeout: Duration,
+    tracker_timeout: Duration,
+    seed_ratio_limit: Option<f64>,
+    address_preference: AddressPreference,
+    bitfield_policy: BitfieldPolicy,
+    request_timeout: Duration,
+    tcp_nodelay: bool,
+    dead_swarm_policy: Option<DeadSwarmPolicy>,
+    max_peer_strikes: Option<u32>,
+    download_dir: PathBuf,
+    on_complete: CompletionAction,
+    strict_metadata: bool,
+    max_connect_attempts: usize,
+    bind_address: Option<IpAddr>,
+    allow_loopback_peers: bool,
+    heartbeat_interval: Option<Duration>,
+    announce_external_ip: bool,
+    selected_files: Option<Vec<usize>>,
 }
 
 impl Config {
-    pub fn new(connection_numbers: usize) -> Self {
+    pub fn new(connection_numbers: usize) -> std::result::Result<Self, ConfigError> {
         if connection_numbers == 0 {
-            panic!("connection numbers cannot be zero")
+            return Err(ConfigError::ZeroConnections);
         }
-        Self { connection_numbers }
+        Ok(Self {
+            connection_numbers,
+            reject_bogus_peers: true,
+            read_cache_size: DEFAULT_READ_CACHE_SIZE,
+            rate_limit: 0,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            encryption: EncryptionMode::default(),
+            max_pieces_in_flight: DEFAULT_MAX_PIECES_IN_FLIGHT,
+            allocation: AllocationStrategy::default(),
+            hashing_threads: DEFAULT_HASHING_THREADS,
+            peer_idle_timeout: DEFAULT_PEER_IDLE_TIMEOUT,
+            tracker_timeout: DEFAULT_TRACKER_TIMEOUT,
+            seed_ratio_limit: None,
+            address_preference: AddressPreference::default(),
+            bitfield_policy: BitfieldPolicy::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            tcp_nodelay: true,
+            dead_swarm_policy: Some(DeadSwarmPolicy::default()),
+            max_peer_strikes: Some(DEFAULT_MAX_PEER_STRIKES),
+            download_dir: PathBuf::from("."),
+            on_complete: CompletionAction::default(),
+            strict_metadata: false,
+            max_connect_attempts: DEFAULT_MAX_CONNECT_ATTEMPTS,
+            bind_address: None,
+            allow_loopback_peers: false,
+            heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+            announce_external_ip: false,
+            selected_files: None,
+        })
+    }
+
+    pub fn set_reject_bogus_peers(&mut self, reject_bogus_peers: bool) -> &mut Self {
+        self.reject_bogus_peers = reject_bogus_peers;
+        self
+    }
+
+    pub fn reject_bogus_peers(&self) -> bool {
+        self.reject_bogus_peers
+    }
+
+    /// Amount of piece bytes kept in memory to serve requests while seeding
+    pub fn set_read_cache_size(&mut self, read_cache_size: usize) -> &mut Self {
+        self.read_cache_size = read_cache_size;
+        self
+    }
+
+    pub fn read_cache_size(&self) -> usize {
+        self.read_cache_size
+    }
+
+    /// Bytes per second shared by all torrents of a session, zero means unlimited
+    pub fn set_rate_limit(&mut self, rate_limit: usize) -> &mut Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn rate_limit(&self) -> usize {
+        self.rate_limit
+    }
+
+    /// Time a peer has to answer our handshake once the TCP connection is established
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) -> &mut Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    pub fn set_encryption(&mut self, encryption: EncryptionMode) -> &mut Self {
+        self.encryption = encryption;
+        self
+    }
+
+    pub fn encryption(&self) -> EncryptionMode {
+        self.encryption
+    }
+
+    /// Limit of partially downloaded pieces across all peers, bounds memory held by piece buffers
+    pub fn set_max_pieces_in_flight(&mut self, max_pieces_in_flight: usize) -> &mut Self {
+        self.max_pieces_in_flight = max_pieces_in_flight;
+        self
+    }
+
+    pub fn max_pieces_in_flight(&self) -> usize {
+        self.max_pieces_in_flight
+    }
+
+    /// Whether files are preallocated or left sparse, sparse by default
+    pub fn set_allocation(&mut self, allocation: AllocationStrategy) -> &mut Self {
+        self.allocation = allocation;
+        self
+    }
+
+    pub fn allocation(&self) -> AllocationStrategy {
+        self.allocation
+    }
+
+    /// Threads verifying piece hashes, off the threads talking to peers
+    pub fn set_hashing_threads(&mut self, hashing_threads: usize) -> &mut Self {
+        self.hashing_threads = hashing_threads;
+        self
+    }
+
+    pub fn hashing_threads(&self) -> usize {
+        self.hashing_threads
+    }
+
+    /// Peers that neither unchoke us nor send blocks for this long are dropped
+    pub fn set_peer_idle_timeout(&mut self, peer_idle_timeout: Duration) -> &mut Self {
+        self.peer_idle_timeout = peer_idle_timeout;
+        self
+    }
+
+    pub fn peer_idle_timeout(&self) -> Duration {
+        self.peer_idle_timeout
+    }
+
+    pub fn set_tracker_timeout(&mut self, tracker_timeout: Duration) -> &mut Self {
+        self.tracker_timeout = tracker_timeout;
+        self
+    }
+
+    pub fn tracker_timeout(&self) -> Duration {
+        self.tracker_timeout
+    }
+
+    /// Uploaded to downloaded ratio after which seeding stops, `None` seeds forever
+    pub fn set_seed_ratio_limit(&mut self, seed_ratio_limit: Option<f64>) -> &mut Self {
+        self.seed_ratio_limit = seed_ratio_limit;
+        self
+    }
+
+    pub fn seed_ratio_limit(&self) -> Option<f64> {
+        self.seed_ratio_limit
+    }
+
+    pub fn set_address_preference(&mut self, address_preference: AddressPreference) -> &mut Self {
+        self.address_preference = address_preference;
+        self
+    }
+
+    pub fn address_preference(&self) -> AddressPreference {
+        self.address_preference
+    }
+
+    pub fn set_bitfield_policy(&mut self, bitfield_policy: BitfieldPolicy) -> &mut Self {
+        self.bitfield_policy = bitfield_policy;
+        self
+    }
+
+    pub fn bitfield_policy(&self) -> BitfieldPolicy {
+        self.bitfield_policy
+    }
+
+    /// Block requests unanswered for this long are given up and requested again
+    pub fn set_request_timeout(&mut self, request_timeout: Duration) -> &mut Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// Disables Nagle's algorithm on peer sockets, connections batch writes on their own
+    pub fn set_tcp_nodelay(&mut self, tcp_nodelay: bool) -> &mut Self {
+        self.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    /// Announce and connect less often to swarms without seeders, `None` never backs off
+    pub fn set_dead_swarm_policy(
+        &mut self,
+        dead_swarm_policy: Option<DeadSwarmPolicy>,
+    ) -> &mut Self {
+        self.dead_swarm_policy = dead_swarm_policy;
+        self
+    }
+
+    pub fn dead_swarm_policy(&self) -> Option<DeadSwarmPolicy> {
+        self.dead_swarm_policy
+    }
+
+    /// Strikes for hash failures or protocol violations a peer may collect before it's
+    /// banned for the session, `None` never bans
+    pub fn set_max_peer_strikes(&mut self, max_peer_strikes: Option<u32>) -> &mut Self {
+        self.max_peer_strikes = max_peer_strikes;
+        self
+    }
+
+    pub fn max_peer_strikes(&self) -> Option<u32> {
+        self.max_peer_strikes
+    }
+
+    pub fn set_download_dir(&mut self, download_dir: PathBuf) -> &mut Self {
+        self.download_dir = download_dir;
+        self
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    pub fn set_on_complete(&mut self, on_complete: CompletionAction) -> &mut Self {
+        self.on_complete = on_complete;
+        self
+    }
+
+    pub fn on_complete(&self) -> &CompletionAction {
+        &self.on_complete
+    }
+
+    /// Rejects torrents with inconsistent metadata instead of fixing them up
+    pub fn set_strict_metadata(&mut self, strict_metadata: bool) -> &mut Self {
+        self.strict_metadata = strict_metadata;
+        self
+    }
+
+    pub fn strict_metadata(&self) -> bool {
+        self.strict_metadata
+    }
+
+    /// Outbound connection attempts running at the same time
+    pub fn set_max_connect_attempts(&mut self, max_connect_attempts: usize) -> &mut Self {
+        self.max_connect_attempts = max_connect_attempts;
+        self
+    }
+
+    pub fn max_connect_attempts(&self) -> usize {
+        self.max_connect_attempts
+    }
+
+    /// Local address outbound peer connections are made from, `None` lets the OS choose
+    pub fn set_bind_address(&mut self, bind_address: Option<IpAddr>) -> &mut Self {
+        self.bind_address = bind_address;
+        self
+    }
+
+    pub fn bind_address(&self) -> Option<IpAddr> {
+        self.bind_address
+    }
+
+    /// Connects to peers on loopback addresses too, normally they are skipped
+    pub fn set_allow_loopback_peers(&mut self, allow_loopback_peers: bool) -> &mut Self {
+        self.allow_loopback_peers = allow_loopback_peers;
+        self
+    }
+
+    pub fn allow_loopback_peers(&self) -> bool {
+        self.allow_loopback_peers
+    }
+
+    /// How often a progress summary is logged, `None` never logs one
+    pub fn set_heartbeat_interval(&mut self, heartbeat_interval: Option<Duration>) -> &mut Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat_interval
+    }
+
+    /// Announces our address as trackers and peers see it, for clients behind NAT
+    pub fn set_announce_external_ip(&mut self, announce_external_ip: bool) -> &mut Self {
+        self.announce_external_ip = announce_external_ip;
+        self
+    }
+
+    pub fn announce_external_ip(&self) -> bool {
+        self.announce_external_ip
+    }
+
+    /// Downloads only the files at these positions of [`Info::files`], `None` downloads
+    /// every file. Pieces shared with other files are downloaded whole
+    pub fn set_selected_files(&mut self, selected_files: Option<Vec<usize>>) -> &mut Self {
+        self.selected_files = selected_files;
+        self
+    }
+
+    pub fn selected_files(&self) -> Option<&[usize]> {
+        self.selected_files.as_deref()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONNECTION_NUMBERS).expect("default connection numbers are not zero")
     }
 }
 
 pub struct Client {
     client_id: Arc<PeerId>,
     config: Config,
-    tracker_client: Box<dyn TrackerClient>,
-    inbound: TcpListener,
+    tracker_client: Arc<dyn TrackerClient>,
+    router: Arc<InboundRouter>,
+    port: u16,
+    peer_sources: Vec<Box<dyn PeerSource>>,
+    pause: PauseHandle,
+    /// Shared by every download of the client, see [`Config::rate_limit`]
+    limiter: Arc<RateLimiter>,
+    /// Tracker sessions that still owe the tracker a `stopped` announce
+    started: Arc<Mutex<HashMap<Sha1, Announcer>>>,
+    /// Progress of finished and interrupted downloads, saved on shutdown
+    resume_states: Mutex<HashMap<Sha1, (PathBuf, ResumeState)>>,
+    /// Set by [`Client::cancel_recheck`], pausing doesn't stop a running check
+    recheck_cancel: AtomicBool,
+    /// Torrents served to inbound peers, seeded or completed ones, until the client is dropped
+    seeding: Mutex<Vec<(Sha1, JoinHandle<()>)>>,
 }
 
 impl Client {
@@ -51,26 +493,870 @@ impl Client {
         let inbound =
             TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 6881))
                 .map_err(|e| InboundConnection(Cow::Owned(e.to_string())))?;
+        Self::with_listener(client_id, config, tracker_client, inbound)
+    }
+
+    /// Client accepting peers on an already bound listener
+    pub fn with_listener(
+        client_id: PeerId,
+        config: Config,
+        tracker_client: Box<dyn TrackerClient>,
+        inbound: TcpListener,
+    ) -> Result<Self> {
+        let port = inbound
+            .local_addr()
+            .map_err(|e| InboundConnection(Cow::Owned(e.to_string())))?
+            .port();
+        let client_id = Arc::new(client_id);
+        let router = Arc::new(InboundRouter::new(client_id.clone()));
+        let accept_router = router.clone();
+        thread::spawn(move || accept_router.serve(&inbound));
         Ok(Self {
-            client_id: Arc::new(client_id),
+            client_id,
+            limiter: Arc::new(RateLimiter::new(config.rate_limit())),
             config,
-            tracker_client,
-            inbound,
+            tracker_client: Arc::from(tracker_client),
+            router,
+            port,
+            peer_sources: Vec::new(),
+            pause: PauseHandle::default(),
+            started: Arc::new(Mutex::new(HashMap::new())),
+            resume_states: Mutex::new(HashMap::new()),
+            recheck_cancel: AtomicBool::new(false),
+            seeding: Mutex::new(Vec::new()),
         })
     }
 
-    pub fn download(&self, meta: TorrentFile) -> Result<()> {
-        let mut params = AnnounceParameters::new(&meta.info.info_hash);
+    /// Stops requesting pieces, connections and the tracker session stay alive
+    pub fn pause(&self) {
+        self.pause.pause();
+    }
+
+    pub fn resume(&self) {
+        self.pause.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    pub fn add_peer_source(&mut self, source: Box<dyn PeerSource>) -> &mut Self {
+        self.peer_sources.push(source);
+        self
+    }
+
+    pub fn download(&self, mut meta: TorrentFile) -> Result<()> {
+        meta.info.fit_piece_length(self.config.strict_metadata())?;
+        let info_hash = meta.info.info_hash;
+        let content = self.config.download_dir().join(meta.info.content_path());
+        let resume_path = resume_path(&self.config, &meta.info);
+        let resumed = ResumeState::load(&resume_path)?;
+        let storage = open_storage(&self.config, &meta.info)?;
+        let have = storage.inner().resume(resumed.as_ref())?;
+        let tiers = meta.tracker_tiers();
+        let mut downloader = Downloader::new(
+            Vec::new(),
+            meta.info,
+            self.client_id.clone(),
+            self.config.clone(),
+        );
+        for index in have.iter_set() {
+            downloader.piece_verified(index);
+        }
+        // completed in an earlier run, the tracker has heard about it already
+        let complete_before = downloader.is_finished();
+        let mut params = AnnounceParameters::new(info_hash);
         params
-            .set_port(6881)
+            .set_port(self.port)
+            .set_left(downloader.left())
             .set_num_want(Some(100))
             .set_request_mode(RequestMode::Compact);
-        let torrent_info = self.tracker_client.announce(&meta.announce, params)?;
-        let peers: VecDeque<Peer> = torrent_info.peers.into_iter().collect();
-
-        let mut downloader = Downloader::new(peers, meta.info);
-        downloader.run();
+        let mut announcer = (!tiers.is_empty()).then(|| {
+            let mut announcer = Announcer::new(tiers, params);
+            announcer
+                .set_pause(self.pause.clone())
+                .set_announce_external_ip(self.config.announce_external_ip())
+                .set_dead_swarm_policy(self.config.dead_swarm_policy());
+            announcer
+        });
+        let announced = announcer
+            .as_mut()
+            .map(|announcer| announcer.started(self.tracker_client.as_ref()));
+        let peers = collect_peers(announced, &self.peer_sources, &info_hash)?;
+        downloader
+            .set_inbound(self.router.register(info_hash))
+            .set_pause(self.pause.clone())
+            .set_rate_limiter(self.limiter.clone())
+            .add_peers(peers);
+        let tracker = self.tracker_client.as_ref();
+        let result = downloader.run(
+            &storage,
+            announcer.as_mut().map(|announcer| (announcer, tracker)),
+        );
+        let ratio = downloader.ratio();
+        let (downloaded, uploaded) = resumed
+            .map(|state| (state.downloaded, state.uploaded))
+            .unwrap_or_default();
+        let state = storage.inner().resume_state(
+            downloader.have().clone(),
+            downloaded + ratio.downloaded(),
+            uploaded + ratio.uploaded(),
+        )?;
+        self.resume_states
+            .lock()
+            .unwrap()
+            .insert(info_hash, (resume_path, state));
+        result?;
+        if let Some(announcer) = announcer.as_mut() {
+            announcer
+                .params_mut()
+                .set_uploaded(ratio.uploaded() as usize)
+                .set_downloaded(ratio.downloaded() as usize)
+                .set_left(downloader.left());
+            announcer.observe_peer_ips(downloader.reported_ips());
+        }
+        let mut seeding = true;
+        if downloader.is_finished() && !complete_before {
+            seeding = completion::finish(
+                self.config.on_complete(),
+                announcer.as_mut(),
+                self.tracker_client.as_ref(),
+                &content,
+                completion::spawn_program,
+            )?;
+        }
+        // a torrent that stopped on completion has already left the swarm
+        if !seeding {
+            return Ok(());
+        }
+        if let Some(announcer) = announcer {
+            self.started.lock().unwrap().insert(info_hash, announcer);
+        }
+        if downloader.is_finished() {
+            self.spawn_seeder(info_hash, downloader, storage);
+        }
 
         Ok(())
     }
+
+    /// Seeds content already complete under `dir`, nothing is downloaded. Every piece is
+    /// verified first, the tracker only hears from us once all of them check out.
+    /// No `completed` is sent, the download didn't complete in this session.
+    /// Peers connecting for the torrent are served in the background
+    pub fn seed(&self, mut meta: TorrentFile, dir: &Path) -> Result<PieceBitfield> {
+        meta.info.fit_piece_length(self.config.strict_metadata())?;
+        let have = StorageWriter::new(dir, &meta.info).verify_existing()?;
+        let missing = have.iter_unset().count();
+        if missing > 0 {
+            return Err(ClientError::IncompleteContent(missing, have.len()));
+        }
+        let info_hash = meta.info.info_hash;
+        let tiers = meta.tracker_tiers();
+        self.serve(meta.info, dir, &have);
+        if tiers.is_empty() {
+            return Ok(have);
+        }
+        let mut params = AnnounceParameters::new(info_hash);
+        params
+            .set_port(self.port)
+            .set_left(0)
+            .set_request_mode(RequestMode::Compact);
+        let mut announcer = Announcer::new(tiers, params);
+        announcer
+            .set_pause(self.pause.clone())
+            .set_announce_external_ip(self.config.announce_external_ip())
+            .set_dead_swarm_policy(self.config.dead_swarm_policy());
+        announcer.started(self.tracker_client.as_ref())?;
+        self.started.lock().unwrap().insert(info_hash, announcer);
+        Ok(have)
+    }
+
+    /// Hashes every piece of a torrent in the download directory again, e.g. after a crash
+    /// or suspected corruption. The resume state is replaced by the result, so the next
+    /// [`Client::download`] fetches the pieces that no longer match, those are returned.
+    /// Reports pieces hashed and total as results come in
+    pub fn recheck<F>(&self, mut meta: TorrentFile, progress: F) -> Result<Vec<usize>>
+    where
+        F: FnMut(usize, usize),
+    {
+        meta.info.fit_piece_length(self.config.strict_metadata())?;
+        let path = resume_path(&self.config, &meta.info);
+        let resumed = ResumeState::load(&path)?;
+        let writer = StorageWriter::new(self.config.download_dir(), &meta.info);
+        let mut downloader = Downloader::new(
+            Vec::new(),
+            meta.info,
+            self.client_id.clone(),
+            self.config.clone(),
+        );
+        let (downloaded, uploaded) = match &resumed {
+            Some(state) if state.have.len() == downloader.have().len() => {
+                for index in state.have.iter_set() {
+                    downloader.piece_verified(index);
+                }
+                (state.downloaded, state.uploaded)
+            }
+            _ => (0, 0),
+        };
+        self.recheck_cancel.store(false, Ordering::Relaxed);
+        let lost = downloader.recheck(&writer, &self.recheck_cancel, progress)?;
+        let state = ResumeState {
+            have: downloader.have().clone(),
+            downloaded,
+            uploaded,
+            files: writer.file_states()?,
+        };
+        state.save(&path)?;
+        Ok(lost)
+    }
+
+    /// Stops a running [`Client::recheck`], it fails with [`StorageError::Cancelled`]
+    pub fn cancel_recheck(&self) {
+        self.recheck_cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// Accepts peers for a torrent we have the verified pieces `have` of under `dir`
+    fn serve(&self, info: Info, dir: &Path, have: &PieceBitfield) {
+        let mut storage = CachedStorage::new(
+            StorageWriter::new(dir, &info),
+            self.config.read_cache_size(),
+        );
+        storage.set_piece_hashes(info.info_hash, info.pieces.clone());
+        let info_hash = info.info_hash;
+        let mut downloader = Downloader::new(
+            Vec::new(),
+            info,
+            self.client_id.clone(),
+            self.config.clone(),
+        );
+        for index in have.iter_set() {
+            downloader.piece_verified(index);
+        }
+        downloader
+            .set_inbound(self.router.register(info_hash))
+            .set_pause(self.pause.clone())
+            .set_rate_limiter(self.limiter.clone());
+        self.spawn_seeder(info_hash, downloader, storage);
+    }
+
+    /// Keeps serving the inbound peers of `downloader` in the background, see
+    /// [`Downloader::seed`]. Once the seed ratio limit is reached the torrent leaves
+    /// the swarm, its tracker session gets the `stopped` announce right away
+    fn spawn_seeder(
+        &self,
+        info_hash: Sha1,
+        mut downloader: Downloader,
+        storage: CachedStorage<StorageWriter>,
+    ) {
+        let started = self.started.clone();
+        let tracker = self.tracker_client.clone();
+        let router = self.router.clone();
+        let seeder = thread::spawn(move || {
+            downloader.seed(&storage);
+            if !downloader.seed_limit_reached() {
+                return;
+            }
+            router.unregister(&info_hash);
+            let announcer = started.lock().unwrap().remove(&info_hash);
+            if let Some(mut announcer) = announcer {
+                let ratio = downloader.ratio();
+                announcer
+                    .params_mut()
+                    .set_uploaded(ratio.uploaded() as usize)
+                    .set_downloaded(ratio.downloaded() as usize);
+                if let Err(e) = announcer.stopped(tracker.as_ref()) {
+                    log::warn!("stopped announce failed: {e}");
+                }
+            }
+        });
+        self.seeding.lock().unwrap().push((info_hash, seeder));
+    }
+
+    /// Leaves every tracker session, dropping the client afterwards announces nothing.
+    /// Every session gets its `stopped` announce even when an earlier one fails,
+    /// the first failure is returned
+    pub fn shutdown(self) -> std::result::Result<(), TrackerError> {
+        self.save_resume_states();
+        let started: Vec<Announcer> = self
+            .started
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, announcer)| announcer)
+            .collect();
+        let mut result = Ok(());
+        for mut announcer in started {
+            let stopped = announcer.stopped(self.tracker_client.as_ref());
+            if let (Ok(()), Err(e)) = (&result, stopped) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Writes the progress of every download, see [`ResumeState`]. A state that can't be
+    /// written only costs a re-hash on the next start
+    fn save_resume_states(&self) {
+        for (path, state) in self.resume_states.lock().unwrap().drain().map(|(_, s)| s) {
+            if let Err(e) = state.save(&path) {
+                log::warn!("failed to save {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+impl Drop for Client {
+    /// Saves the resume states, stops serving seeded torrents and sends best effort `stopped`
+    /// announces for sessions [`Client::shutdown`] didn't end, given up after
+    /// [`STOP_ANNOUNCE_TIMEOUT`] so a dead tracker can't hang the drop
+    fn drop(&mut self) {
+        self.save_resume_states();
+        let seeding = self.seeding.get_mut().map(mem::take).unwrap_or_default();
+        for (info_hash, seeder) in seeding {
+            self.router.unregister(&info_hash);
+            let _ = seeder.join();
+        }
+        let started: Vec<Announcer> = self
+            .started
+            .lock()
+            .map(|mut started| started.drain().map(|(_, announcer)| announcer).collect())
+            .unwrap_or_default();
+        if started.is_empty() {
+            return;
+        }
+        let tracker = self.tracker_client.clone();
+        let (done, wait) = mpsc::channel();
+        thread::spawn(move || {
+            for mut announcer in started {
+                let _ = announcer.stopped(tracker.as_ref());
+            }
+            let _ = done.send(());
+        });
+        let _ = wait.recv_timeout(STOP_ANNOUNCE_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::completion::CompletionAction;
+    use crate::client::{Client, ClientError, Config, ConfigError};
+    use crate::file::{File, Info, TorrentFile};
+    use crate::peer::connection::{BlockRequest, Message, PeerConnection, Piece};
+    use crate::peer::mse::EncryptionMode;
+    use crate::peer::{Peer, PeerId};
+    use crate::storage::resume::ResumeState;
+    use crate::storage::AllocationStrategy;
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::{AnnounceResponse, TrackerError, TrackerEvent};
+    use crate::util::PieceBitfield;
+    use sha1::Digest;
+    use std::fs;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant};
+    use url::Url;
+
+    #[test]
+    fn zero_connections_rejected() {
+        assert_eq!(Config::new(0).unwrap_err(), ConfigError::ZeroConnections);
+        assert_eq!(Config::new(25).unwrap().connection_numbers, 25);
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let config = Config::default();
+        assert!(config.connection_numbers > 0);
+        assert!(config.max_connect_attempts() > 0);
+        assert!(config.hashing_threads() > 0);
+        assert_eq!(config.handshake_timeout(), Duration::from_secs(10));
+    }
+
+    fn client(tracker: &MockTracker, dir: &Path) -> Client {
+        let mut config = Config::new(1).unwrap();
+        config.set_download_dir(dir.to_path_buf());
+        client_with(tracker, config)
+    }
+
+    fn client_with(tracker: &MockTracker, config: Config) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        Client::with_listener(
+            PeerId::random(),
+            config,
+            Box::new(tracker.clone()),
+            listener,
+        )
+        .unwrap()
+    }
+
+    fn torrent(announce: Option<&str>) -> TorrentFile {
+        TorrentFile {
+            announce: announce.map(|url| Url::parse(url).unwrap()),
+            announce_list: Vec::new(),
+            nodes: Vec::new(),
+            info: Info {
+                files: vec![File::new(4, PathBuf::from("file"))],
+                name: PathBuf::from("torrent"),
+                info_hash: [1; 20],
+                piece_length: 4,
+                pieces: vec![[0; 20]],
+            },
+        }
+    }
+
+    fn started_client(tracker: &MockTracker, dir: &Path) -> Client {
+        let client = client(tracker, dir);
+        client
+            .download(torrent(Some("http://tracker.example/announce")))
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn trackerless_download_without_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracker = MockTracker::default();
+        let client = client(&tracker, dir.path());
+        client.download(torrent(None)).unwrap();
+        assert!(tracker.announces().is_empty());
+        assert!(dir.path().join("torrent").join("file").is_file());
+    }
+
+    /// Serves `content` to the first peer that connects, like a seeder would
+    fn scripted_seeder(content: Vec<u8>, piece_length: usize) -> (SocketAddr, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seeder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut connection, _) =
+                PeerConnection::accept(stream, &PeerId::random(), |_| true).unwrap();
+            let pieces = content.len().div_ceil(piece_length);
+            let have = PieceBitfield::from_bytes(&vec![0xff; pieces.div_ceil(8)], pieces);
+            connection.send_availability(&have).unwrap();
+            while let Ok(message) = connection.recv() {
+                let reply = match message {
+                    Message::Interested => Message::UnChoke,
+                    Message::Request(request) => {
+                        let start =
+                            request.index() as usize * piece_length + request.begin() as usize;
+                        let block = content[start..start + request.length() as usize].to_vec();
+                        Message::Piece(Piece::new(request.index(), request.begin(), block))
+                    }
+                    _ => continue,
+                };
+                if connection.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+        (addr, seeder)
+    }
+
+    /// Torrent of a single file with `content`, in pieces of 4 bytes
+    fn content_torrent(content: &[u8], announce: Option<&str>) -> TorrentFile {
+        let mut torrent = torrent(announce);
+        torrent.info.files = vec![File::new(content.len(), PathBuf::from("file"))];
+        torrent.info.pieces = content
+            .chunks(4)
+            .map(|piece| sha1::Sha1::digest(piece).into())
+            .collect();
+        torrent
+    }
+
+    /// Downloads `content` in pieces of 4 bytes from a [`scripted_seeder`]
+    fn scripted_download(content: &[u8], dir: &Path, rate_limit: usize) -> MockTracker {
+        let torrent = content_torrent(content, Some("http://tracker.example/announce"));
+        let (addr, seeder) = scripted_seeder(content.to_vec(), 4);
+        let tracker = MockTracker::default();
+        tracker.push_peers(vec![Peer::new(None, addr)]);
+        let mut config = Config::new(1).unwrap();
+        config
+            .set_download_dir(dir.to_path_buf())
+            .set_allow_loopback_peers(true)
+            .set_encryption(EncryptionMode::Disabled)
+            .set_rate_limit(rate_limit);
+        let client = client_with(&tracker, config);
+
+        client.download(torrent).unwrap();
+        seeder.join().unwrap();
+        let path = dir.join("torrent").join("file");
+        assert_eq!(fs::read(path).unwrap(), content);
+        client.shutdown().unwrap();
+        tracker
+    }
+
+    #[test]
+    fn download_selected_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (1..=12).collect();
+        let mut torrent = content_torrent(&content, Some("http://tracker.example/announce"));
+        torrent.info.files = vec![
+            File::new(4, PathBuf::from("skipped")),
+            File::new(8, PathBuf::from("selected")),
+        ];
+        let (addr, seeder) = scripted_seeder(content.clone(), 4);
+        let tracker = MockTracker::default();
+        tracker.push_peers(vec![Peer::new(None, addr)]);
+        let mut config = Config::new(1).unwrap();
+        config
+            .set_download_dir(dir.path().to_path_buf())
+            .set_allow_loopback_peers(true)
+            .set_encryption(EncryptionMode::Disabled)
+            .set_selected_files(Some(vec![1]));
+        let client = client_with(&tracker, config);
+
+        client.download(torrent).unwrap();
+        seeder.join().unwrap();
+        let torrent_dir = dir.path().join("torrent");
+        assert_eq!(
+            fs::read(torrent_dir.join("selected")).unwrap(),
+            &content[4..]
+        );
+        assert_ne!(
+            fs::read(torrent_dir.join("skipped")).unwrap_or_default(),
+            &content[..4]
+        );
+        assert_eq!(tracker.announces()[0].1.left(), 8);
+        assert_eq!(
+            events(&tracker),
+            vec![Some(TrackerEvent::Started), Some(TrackerEvent::Completed)]
+        );
+    }
+
+    #[test]
+    fn download_from_scripted_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        let tracker = scripted_download(&content, dir.path(), 0);
+        assert_eq!(
+            events(&tracker),
+            vec![
+                Some(TrackerEvent::Started),
+                Some(TrackerEvent::Completed),
+                Some(TrackerEvent::Stopped)
+            ]
+        );
+    }
+
+    #[test]
+    fn peers_from_regular_announce() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        let torrent = content_torrent(&content, Some("http://tracker.example/announce"));
+        let (addr, seeder) = scripted_seeder(content.clone(), 4);
+        let tracker = MockTracker::default();
+        // no peers yet, but the next announce is due right away
+        tracker.push_response(Ok(AnnounceResponse {
+            interval: Duration::ZERO,
+            min_interval: None,
+            complete: None,
+            incomplete: None,
+            peers: Vec::new(),
+            external_ip: None,
+            tracker_id: None,
+        }));
+        tracker.push_peers(vec![Peer::new(None, addr)]);
+        let mut config = Config::new(1).unwrap();
+        config
+            .set_download_dir(dir.path().to_path_buf())
+            .set_allow_loopback_peers(true)
+            .set_encryption(EncryptionMode::Disabled);
+        let client = client_with(&tracker, config);
+
+        client.download(torrent).unwrap();
+        seeder.join().unwrap();
+        let path = dir.path().join("torrent").join("file");
+        assert_eq!(fs::read(path).unwrap(), content);
+        assert_eq!(
+            events(&tracker),
+            vec![
+                Some(TrackerEvent::Started),
+                None,
+                Some(TrackerEvent::Completed)
+            ]
+        );
+    }
+
+    #[test]
+    fn download_resumed_from_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        scripted_download(&content, dir.path(), 0);
+        let path = dir.path().join("torrent.resume");
+        let state = ResumeState::load(&path).unwrap().unwrap();
+        assert_eq!(state.have.iter_unset().count(), 0);
+        assert_eq!(state.downloaded, 10);
+
+        // nothing left to download and no peer needed, the completion isn't announced again
+        let tracker = MockTracker::default();
+        let client = client(&tracker, dir.path());
+        let torrent = content_torrent(&content, Some("http://tracker.example/announce"));
+        client.download(torrent).unwrap();
+        assert_eq!(events(&tracker), vec![Some(TrackerEvent::Started)]);
+        assert_eq!(tracker.announces()[0].1.left(), 0);
+        fs::remove_file(&path).unwrap();
+        drop(client);
+        assert_eq!(ResumeState::load(&path).unwrap().unwrap(), state);
+    }
+
+    #[test]
+    fn recheck_drops_corrupted_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        scripted_download(&content, dir.path(), 0);
+        let mut corrupted = content.clone();
+        corrupted[5] = 0xff;
+        fs::write(dir.path().join("torrent").join("file"), corrupted).unwrap();
+
+        let client = client(&MockTracker::default(), dir.path());
+        let mut reports = Vec::new();
+        let lost = client
+            .recheck(content_torrent(&content, None), |hashed, total| {
+                reports.push((hashed, total))
+            })
+            .unwrap();
+        assert_eq!(lost, vec![1]);
+        assert_eq!(reports.last(), Some(&(3, 3)));
+        let state = ResumeState::load(&dir.path().join("torrent.resume"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.have.iter_unset().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(state.downloaded, 10);
+    }
+
+    #[test]
+    fn download_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let start = Instant::now();
+        // the first 8 bytes fit the bucket, the rest has to wait for it to refill
+        scripted_download(&[7; 12], dir.path(), 8);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn download_preallocates_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new(1).unwrap();
+        config
+            .set_download_dir(dir.path().to_path_buf())
+            .set_allocation(AllocationStrategy::Full);
+        let client = client_with(&MockTracker::default(), config);
+        client.download(torrent(None)).unwrap();
+        let path = dir.path().join("torrent").join("file");
+        assert_eq!(fs::metadata(path).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn download_with_only_unconnectable_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracker = MockTracker::default();
+        tracker.push_peers(vec![Peer::new(None, "127.0.0.1:6881".parse().unwrap())]);
+        let client = client(&tracker, dir.path());
+        client
+            .download(torrent(Some("http://tracker.example/announce")))
+            .unwrap();
+        assert_eq!(tracker.announces().len(), 1);
+    }
+
+    fn events(tracker: &MockTracker) -> Vec<Option<TrackerEvent>> {
+        tracker
+            .announces()
+            .iter()
+            .map(|(_, params)| params.event().cloned())
+            .collect()
+    }
+
+    #[test]
+    fn dropped_client_announces_stopped_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracker = MockTracker::default();
+        drop(started_client(&tracker, dir.path()));
+        assert_eq!(
+            events(&tracker),
+            vec![Some(TrackerEvent::Started), Some(TrackerEvent::Stopped)]
+        );
+
+        let tracker = MockTracker::default();
+        started_client(&tracker, dir.path()).shutdown().unwrap();
+        assert_eq!(
+            events(&tracker),
+            vec![Some(TrackerEvent::Started), Some(TrackerEvent::Stopped)]
+        );
+    }
+
+    #[test]
+    fn shutdown_stops_every_session_despite_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracker = MockTracker::default();
+        let client = client(&tracker, dir.path());
+        for info_hash in [[1; 20], [2; 20]] {
+            let mut torrent = torrent(Some("http://tracker.example/announce"));
+            torrent.info.info_hash = info_hash;
+            client.download(torrent).unwrap();
+        }
+        tracker.push_response(Err(TrackerError::AnnounceRequestError("timed out".into())));
+        assert!(client.shutdown().is_err());
+        let stopped: Vec<[u8; 20]> = tracker
+            .announces()
+            .iter()
+            .filter(|(_, params)| params.event() == Some(&TrackerEvent::Stopped))
+            .map(|(_, params)| params.info_hash().wire())
+            .collect();
+        assert_eq!(stopped.len(), 2);
+        assert!(stopped.contains(&[1; 20]) && stopped.contains(&[2; 20]));
+    }
+
+    #[test]
+    fn seed_complete_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        let torrent = || TorrentFile {
+            announce: Some(Url::parse("http://tracker.example/announce").unwrap()),
+            announce_list: Vec::new(),
+            nodes: Vec::new(),
+            info: Info {
+                files: vec![File::new(10, PathBuf::from("file"))],
+                name: PathBuf::from("torrent"),
+                info_hash: [1; 20],
+                piece_length: 4,
+                pieces: content
+                    .chunks(4)
+                    .map(|piece| sha1::Sha1::digest(piece).into())
+                    .collect(),
+            },
+        };
+        let tracker = MockTracker::default();
+        let client = client(&tracker, dir.path());
+        fs::create_dir(dir.path().join("torrent")).unwrap();
+        let path = dir.path().join("torrent").join("file");
+        fs::write(&path, &content[..9]).unwrap();
+        assert!(matches!(
+            client.seed(torrent(), dir.path()),
+            Err(ClientError::IncompleteContent(1, 3))
+        ));
+        assert!(tracker.announces().is_empty());
+
+        fs::write(&path, &content).unwrap();
+        let have = client.seed(torrent(), dir.path()).unwrap();
+        assert_eq!(have.iter_set().count(), 3);
+        let announces = tracker.announces();
+        assert_eq!(announces.len(), 1);
+        assert_eq!(announces[0].1.left(), 0);
+        assert_eq!(announces[0].1.event(), Some(&TrackerEvent::Started));
+
+        client.shutdown().unwrap();
+        assert_eq!(
+            events(&tracker),
+            vec![Some(TrackerEvent::Started), Some(TrackerEvent::Stopped)]
+        );
+    }
+
+    #[test]
+    fn seed_serves_inbound_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        let torrent = content_torrent(&content, None);
+        fs::create_dir(dir.path().join("torrent")).unwrap();
+        fs::write(dir.path().join("torrent").join("file"), &content).unwrap();
+        let client = client(&MockTracker::default(), dir.path());
+        client.seed(torrent, dir.path()).unwrap();
+
+        let stream = TcpStream::connect(("127.0.0.1", client.port)).unwrap();
+        let mut connection = PeerConnection::handshake(stream, [1; 20], &PeerId::random()).unwrap();
+        assert!(matches!(connection.recv().unwrap(), Message::HaveAll));
+        connection.send(Message::Interested).unwrap();
+        while !matches!(connection.recv().unwrap(), Message::UnChoke) {}
+        connection
+            .send(Message::Request(BlockRequest::new(1, 0, 4)))
+            .unwrap();
+        let block = loop {
+            if let Message::Piece(piece) = connection.recv().unwrap() {
+                break piece;
+            }
+        };
+        assert_eq!((block.index(), block.data()), (1, &content[4..8]));
+        // dropping the client stops serving
+        drop(client);
+        assert!(connection.recv().is_err());
+    }
+
+    #[test]
+    fn completed_download_keeps_seeding() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        let torrent = content_torrent(&content, Some("http://tracker.example/announce"));
+        let (addr, seeder) = scripted_seeder(content.clone(), 4);
+        let tracker = MockTracker::default();
+        tracker.push_peers(vec![Peer::new(None, addr)]);
+        let mut config = Config::new(1).unwrap();
+        config
+            .set_download_dir(dir.path().to_path_buf())
+            .set_allow_loopback_peers(true)
+            .set_encryption(EncryptionMode::Disabled)
+            .set_on_complete(CompletionAction::Seed);
+        let client = client_with(&tracker, config);
+        client.download(torrent).unwrap();
+        seeder.join().unwrap();
+
+        let stream = TcpStream::connect(("127.0.0.1", client.port)).unwrap();
+        let mut connection = PeerConnection::handshake(stream, [1; 20], &PeerId::random()).unwrap();
+        assert!(matches!(connection.recv().unwrap(), Message::HaveAll));
+        connection.send(Message::Interested).unwrap();
+        while !matches!(connection.recv().unwrap(), Message::UnChoke) {}
+        connection
+            .send(Message::Request(BlockRequest::new(2, 0, 2)))
+            .unwrap();
+        let block = loop {
+            if let Message::Piece(piece) = connection.recv().unwrap() {
+                break piece;
+            }
+        };
+        assert_eq!((block.index(), block.data()), (2, &content[8..]));
+    }
+
+    #[test]
+    fn seeding_stops_at_ratio_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        let torrent = content_torrent(&content, Some("http://tracker.example/announce"));
+        let (addr, seeder) = scripted_seeder(content.clone(), 4);
+        let tracker = MockTracker::default();
+        tracker.push_peers(vec![Peer::new(None, addr)]);
+        let mut config = Config::new(1).unwrap();
+        config
+            .set_download_dir(dir.path().to_path_buf())
+            .set_allow_loopback_peers(true)
+            .set_encryption(EncryptionMode::Disabled)
+            .set_seed_ratio_limit(Some(0.5));
+        let client = client_with(&tracker, config);
+        client.download(torrent).unwrap();
+        seeder.join().unwrap();
+        assert_eq!(
+            events(&tracker),
+            vec![Some(TrackerEvent::Started), Some(TrackerEvent::Completed)]
+        );
+
+        let stream = TcpStream::connect(("127.0.0.1", client.port)).unwrap();
+        let mut connection = PeerConnection::handshake(stream, [1; 20], &PeerId::random()).unwrap();
+        connection.send(Message::Interested).unwrap();
+        while !matches!(connection.recv().unwrap(), Message::UnChoke) {}
+        // 8 of the 10 downloaded bytes go back, past the limit of 5
+        for index in 0..2 {
+            connection
+                .send(Message::Request(BlockRequest::new(index, 0, 4)))
+                .unwrap();
+            while !matches!(connection.recv().unwrap(), Message::Piece(_)) {}
+        }
+        while connection.recv().is_ok() {}
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events(&tracker).len() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(events(&tracker)[2], Some(TrackerEvent::Stopped));
+        assert_eq!(tracker.announces()[2].1.uploaded(), 8);
+        // the session already ended, shutting down announces nothing
+        client.shutdown().unwrap();
+        assert_eq!(events(&tracker).len(), 3);
+    }
 }
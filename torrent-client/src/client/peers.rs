@@ -0,0 +1,466 @@
+use crate::client::connector::dual_stack_addrs;
+use crate::peer::connection::ConnectionError;
+use crate::peer::Peer;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Healthy peers that disconnected are tried again after this delay
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Cap of the exponential backoff, in doublings of the base delay
+const MAX_BACKOFF_SHIFT: u32 = 4;
+/// Peers the pool remembers, a big swarm shouldn't grow it without bound
+const DEFAULT_POOL_CAPACITY: usize = 1000;
+
+/// Misbehaviour counted against a peer, enough of them ban it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strike {
+    /// Contributed to a piece that failed its hash check
+    HashFailure,
+    OversizedMessage,
+    ProtocolViolation,
+}
+
+impl Strike {
+    /// Strike earned by a connection error, `None` for errors that aren't the peer's fault
+    pub fn for_error(error: &ConnectionError) -> Option<Self> {
+        match error {
+            ConnectionError::PayloadLength(_)
+            | ConnectionError::MessageId(_)
+            | ConnectionError::LateBitfield
+            | ConnectionError::InvalidBitfield(..)
+            | ConnectionError::ExtendedHandshake(_) => Some(Strike::ProtocolViolation),
+            ConnectionError::OversizedMessage(_) => Some(Strike::OversizedMessage),
+            _ => None,
+        }
+    }
+}
+
+/// What we have learned about a peer from our connections to it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerHealth {
+    pub successes: u32,
+    pub consecutive_failures: u32,
+    pub round_trip: Option<Duration>,
+    /// Set while the peer waits to be reconnected
+    pub retry_at: Option<Instant>,
+}
+
+impl PeerHealth {
+    fn score(&self) -> i64 {
+        self.successes as i64 - 4 * self.consecutive_failures as i64
+    }
+}
+
+/// Remembers peers we've been connected to, so that after a disconnect good peers
+/// come back sooner than flaky ones
+#[derive(Debug)]
+pub struct PeerPool {
+    peers: HashMap<SocketAddr, PeerHealth>,
+    reconnect_delay: Duration,
+    failure_cooldown: Duration,
+    capacity: usize,
+}
+
+impl PeerPool {
+    pub fn new(reconnect_delay: Duration, failure_cooldown: Duration) -> Self {
+        Self {
+            peers: HashMap::new(),
+            reconnect_delay,
+            failure_cooldown,
+            capacity: DEFAULT_POOL_CAPACITY,
+        }
+    }
+
+    /// Peers remembered at most, a new one replaces the least healthy peer
+    /// that isn't waiting for a reconnect
+    #[cfg(test)]
+    pub fn set_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = capacity;
+        self
+    }
+
+    fn entry(&mut self, addr: SocketAddr) -> &mut PeerHealth {
+        if !self.peers.contains_key(&addr) && self.peers.len() >= self.capacity {
+            let evicted = self
+                .peers
+                .iter()
+                .min_by_key(|(_, health)| (health.retry_at.is_some(), health.score()))
+                .map(|(addr, _)| *addr);
+            if let Some(evicted) = evicted {
+                self.peers.remove(&evicted);
+            }
+        }
+        self.peers.entry(addr).or_default()
+    }
+
+    pub fn record_success(&mut self, addr: SocketAddr, round_trip: Duration) {
+        let health = self.entry(addr);
+        health.successes += 1;
+        health.consecutive_failures = 0;
+        health.round_trip = Some(round_trip);
+        health.retry_at = None;
+    }
+
+    /// Schedules a retry that backs off exponentially with repeated failures,
+    /// returns when the peer may be tried again
+    pub fn record_failure(&mut self, addr: SocketAddr, now: Instant) -> Instant {
+        let failure_cooldown = self.failure_cooldown;
+        let health = self.entry(addr);
+        health.consecutive_failures += 1;
+        let shift = (health.consecutive_failures - 1).min(MAX_BACKOFF_SHIFT);
+        let retry_at = now + failure_cooldown * 2u32.pow(shift);
+        health.retry_at = Some(retry_at);
+        retry_at
+    }
+
+    /// Schedules a reconnect to a peer that went away
+    pub fn disconnected(&mut self, addr: SocketAddr, now: Instant) {
+        let reconnect_delay = self.reconnect_delay;
+        let health = self.entry(addr);
+        let shift = health.consecutive_failures.min(MAX_BACKOFF_SHIFT);
+        health.retry_at = Some(now + reconnect_delay * 2u32.pow(shift));
+    }
+
+    /// Drops a pending reconnect, e.g. when the tracker offered the peer again
+    pub fn cancel_retry(&mut self, addr: &SocketAddr) {
+        if let Some(health) = self.peers.get_mut(addr) {
+            health.retry_at = None;
+        }
+    }
+
+    /// Healthiest peer whose retry is due, the faster one wins among equally healthy peers
+    pub fn next_ready(&mut self, now: Instant) -> Option<SocketAddr> {
+        let (addr, health) = self
+            .peers
+            .iter_mut()
+            .filter(|(_, health)| health.retry_at.is_some_and(|retry_at| retry_at <= now))
+            .max_by_key(|(_, health)| {
+                let round_trip = health.round_trip.unwrap_or(Duration::MAX);
+                (health.score(), std::cmp::Reverse(round_trip))
+            })?;
+        health.retry_at = None;
+        Some(*addr)
+    }
+
+    #[cfg(test)]
+    pub fn health(&self, addr: &SocketAddr) -> Option<&PeerHealth> {
+        self.peers.get(addr)
+    }
+
+    /// State of every peer in the pool, for diagnostics
+    #[cfg(test)]
+    pub fn snapshot(&self) -> Vec<(SocketAddr, PeerHealth)> {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(addr, health)| (*addr, health.clone()))
+            .collect();
+        peers.sort_by_key(|(addr, _)| *addr);
+        peers
+    }
+}
+
+/// Queue of peers waiting for a connection, which remembers every address it has handed out
+/// so that overlapping announce results don't produce duplicate connections
+#[derive(Debug)]
+pub struct PeerQueue {
+    queue: VecDeque<Peer>,
+    known: HashSet<SocketAddr>,
+    /// Failed peers with the moment they may be enqueued again
+    failed: HashMap<SocketAddr, Instant>,
+    pool: PeerPool,
+    strikes: HashMap<SocketAddr, u32>,
+    /// Strikes a peer may collect, the next one bans it, `None` never bans
+    max_strikes: Option<u32>,
+    /// Never connected again for the rest of the session
+    banned: HashSet<SocketAddr>,
+    /// Same peers known by another address, by the address they are queued under
+    aliases: HashMap<SocketAddr, Vec<Peer>>,
+}
+
+impl PeerQueue {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            known: HashSet::new(),
+            failed: HashMap::new(),
+            pool: PeerPool::new(RECONNECT_DELAY, cooldown),
+            strikes: HashMap::new(),
+            max_strikes: None,
+            banned: HashSet::new(),
+            aliases: HashMap::new(),
+        }
+    }
+
+    pub fn set_max_strikes(&mut self, max_strikes: Option<u32>) -> &mut Self {
+        self.max_strikes = max_strikes;
+        self
+    }
+
+    /// Counts a strike against the peer, returns true once the peer is banned.
+    /// A banned peer is dropped from the queue and never enqueued again
+    pub fn strike(&mut self, addr: SocketAddr, strike: Strike) -> bool {
+        let strikes = self.strikes.entry(addr).or_default();
+        *strikes += 1;
+        log::debug!("{addr} strike {strikes} for {strike:?}");
+        if self.max_strikes.is_some_and(|max| *strikes > max) && self.banned.insert(addr) {
+            self.queue.retain(|peer| peer.addr != addr);
+            self.forget(&addr);
+            self.failed.remove(&addr);
+            self.pool.cancel_retry(&addr);
+        }
+        self.banned.contains(&addr)
+    }
+
+    #[cfg(test)]
+    pub fn strikes(&self, addr: &SocketAddr) -> u32 {
+        self.strikes.get(addr).copied().unwrap_or_default()
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.banned.contains(addr)
+    }
+
+    /// Enqueues peers that are neither queued, connected, nor cooling down after a failure.
+    /// A peer whose id is queued already only adds an address to it, see [`PeerQueue::addrs`].
+    /// Returns the number of peers actually enqueued
+    pub fn merge<T>(&mut self, peers: T) -> usize
+    where
+        T: IntoIterator<Item = Peer>,
+    {
+        let now = Instant::now();
+        self.failed.retain(|_, retry_at| *retry_at > now);
+
+        let mut added = 0;
+        for peer in peers {
+            if self.banned.contains(&peer.addr)
+                || self.failed.contains_key(&peer.addr)
+                || !self.known.insert(peer.addr)
+            {
+                continue;
+            }
+            let queued = self
+                .queue
+                .iter()
+                .find(|queued| peer.peer_id.is_some() && queued.peer_id == peer.peer_id);
+            if let Some(queued) = queued {
+                self.aliases.entry(queued.addr).or_default().push(peer);
+                continue;
+            }
+            self.pool.cancel_retry(&peer.addr);
+            self.queue.push_back(peer);
+            added += 1;
+        }
+        added
+    }
+
+    /// Takes the next peer to connect to, it stays known until [`PeerQueue::release`] or
+    /// [`PeerQueue::mark_failed`] is called. Reconnects that are due go first
+    pub fn pop(&mut self) -> Option<Peer> {
+        let now = Instant::now();
+        while let Some(addr) = self.pool.next_ready(now) {
+            if !self.banned.contains(&addr) && self.known.insert(addr) {
+                self.failed.remove(&addr);
+                return Some(Peer::new(None, addr));
+            }
+        }
+        self.queue.pop_front()
+    }
+
+    /// Every address a popped peer is known by, to connect over whichever works
+    pub fn addrs(&self, peer: &Peer) -> Vec<SocketAddr> {
+        let aliases = self.aliases.get(&peer.addr).map_or(&[][..], Vec::as_slice);
+        dual_stack_addrs(peer, aliases)
+    }
+
+    /// Records a working connection and how fast the peer answered
+    pub fn connected(&mut self, addr: SocketAddr, round_trip: Duration) {
+        self.pool.record_success(addr, round_trip);
+    }
+
+    /// Forgets a peer that disconnected cleanly, so the next announce may offer it again,
+    /// meanwhile the pool schedules a reconnect
+    pub fn release(&mut self, addr: &SocketAddr) {
+        self.forget(addr);
+        self.pool.disconnected(*addr, Instant::now());
+    }
+
+    /// Forgets a peer and refuses to enqueue it again until the cooldown passes,
+    /// the cooldown grows with every failure in a row
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        self.forget(&addr);
+        if self.banned.contains(&addr) {
+            return;
+        }
+        let retry_at = self.pool.record_failure(addr, Instant::now());
+        self.failed.insert(addr, retry_at);
+    }
+
+    /// Lets a peer be enqueued again, under any of its addresses
+    fn forget(&mut self, addr: &SocketAddr) {
+        self.known.remove(addr);
+        for alias in self.aliases.remove(addr).unwrap_or_default() {
+            self.known.remove(&alias.addr);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::peers::{PeerPool, PeerQueue, Strike};
+    use crate::peer::connection::ConnectionError;
+    use crate::peer::Peer;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn peers(addrs: &[&str]) -> Vec<Peer> {
+        addrs
+            .iter()
+            .map(|addr| Peer::new(None, addr.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn merge_overlapping_lists() {
+        let mut queue = PeerQueue::new(Duration::from_secs(60));
+        assert_eq!(queue.merge(peers(&["1.1.1.1:1", "2.2.2.2:2"])), 2);
+        assert_eq!(
+            queue.merge(peers(&["2.2.2.2:2", "3.3.3.3:3", "1.1.1.1:1", "3.3.3.3:3"])),
+            1
+        );
+
+        // connected peer is still known and must not be enqueued again
+        let connected = queue.pop().unwrap();
+        assert_eq!(queue.merge(peers(&["1.1.1.1:1"])), 0);
+
+        let mut addrs = vec![connected.addr];
+        while let Some(peer) = queue.pop() {
+            addrs.push(peer.addr);
+        }
+        let expected: Vec<SocketAddr> = ["1.1.1.1:1", "2.2.2.2:2", "3.3.3.3:3"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(addrs, expected);
+    }
+
+    #[test]
+    fn failed_peer_cooldown() {
+        let mut queue = PeerQueue::new(Duration::from_secs(60));
+        queue.merge(peers(&["1.1.1.1:1"]));
+        let peer = queue.pop().unwrap();
+        queue.mark_failed(peer.addr);
+        assert_eq!(queue.merge(peers(&["1.1.1.1:1"])), 0);
+
+        let mut queue = PeerQueue::new(Duration::ZERO);
+        queue.merge(peers(&["1.1.1.1:1"]));
+        let peer = queue.pop().unwrap();
+        queue.mark_failed(peer.addr);
+        assert_eq!(queue.merge(peers(&["1.1.1.1:1"])), 1);
+    }
+
+    #[test]
+    fn banned_after_strike_threshold() {
+        let mut queue = PeerQueue::new(Duration::ZERO);
+        queue.set_max_strikes(Some(2));
+        queue.merge(peers(&["1.1.1.1:1", "2.2.2.2:2"]));
+        let peer = queue.pop().unwrap();
+
+        assert!(!queue.strike(peer.addr, Strike::HashFailure));
+        assert!(!queue.strike(peer.addr, Strike::ProtocolViolation));
+        assert!(queue.strike(peer.addr, Strike::OversizedMessage));
+        assert!(queue.is_banned(&peer.addr));
+        assert_eq!(queue.strikes(&peer.addr), 3);
+
+        // disconnected and offered again by the tracker, it stays out
+        queue.mark_failed(peer.addr);
+        assert_eq!(queue.merge(peers(&["1.1.1.1:1"])), 0);
+        assert_eq!(queue.pop().unwrap().addr, "2.2.2.2:2".parse().unwrap());
+        assert!(queue.pop().is_none());
+
+        let mut lenient = PeerQueue::new(Duration::ZERO);
+        for _ in 0..10 {
+            assert!(!lenient.strike(peer.addr, Strike::HashFailure));
+        }
+    }
+
+    #[test]
+    fn strikes_for_connection_errors() {
+        assert_eq!(
+            Strike::for_error(&ConnectionError::OversizedMessage(1 << 20)),
+            Some(Strike::OversizedMessage)
+        );
+        assert_eq!(
+            Strike::for_error(&ConnectionError::MessageId(99)),
+            Some(Strike::ProtocolViolation)
+        );
+        assert_eq!(Strike::for_error(&ConnectionError::UnexpectedEOF), None);
+    }
+
+    #[test]
+    fn released_peer_can_be_enqueued_again() {
+        let mut queue = PeerQueue::new(Duration::from_secs(60));
+        queue.merge(peers(&["1.1.1.1:1"]));
+        let peer = queue.pop().unwrap();
+        queue.release(&peer.addr);
+        assert_eq!(queue.merge(peers(&["1.1.1.1:1"])), 1);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn healthy_peers_reconnect_first() {
+        let [fast, slow, flaky]: [SocketAddr; 3] =
+            ["1.1.1.1:1", "2.2.2.2:2", "3.3.3.3:3"].map(|addr| addr.parse().unwrap());
+        let base = Duration::from_secs(10);
+        let mut pool = PeerPool::new(base, base);
+        let now = Instant::now();
+
+        pool.record_success(slow, Duration::from_millis(200));
+        pool.record_success(fast, Duration::from_millis(20));
+        pool.record_success(flaky, Duration::from_millis(20));
+        pool.record_failure(flaky, now);
+        pool.record_failure(flaky, now);
+        pool.disconnected(slow, now);
+        pool.disconnected(fast, now);
+        pool.disconnected(flaky, now);
+
+        assert_eq!(pool.next_ready(now), None);
+        let later = now + base;
+        assert_eq!(pool.next_ready(later), Some(fast));
+        assert_eq!(pool.next_ready(later), Some(slow));
+        // two failures in a row push the flaky peer back
+        assert_eq!(pool.next_ready(later), None);
+        assert_eq!(pool.next_ready(now + base * 4), Some(flaky));
+
+        let snapshot = pool.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot[2].1.consecutive_failures, 2);
+    }
+
+    #[test]
+    fn pool_capacity_evicts_least_healthy() {
+        let [good, flaky, waiting, new]: [SocketAddr; 4] =
+            ["1.1.1.1:1", "2.2.2.2:2", "3.3.3.3:3", "4.4.4.4:4"].map(|addr| addr.parse().unwrap());
+        let mut pool = PeerPool::new(Duration::from_secs(10), Duration::from_secs(10));
+        pool.set_capacity(3);
+        let now = Instant::now();
+
+        pool.record_success(good, Duration::from_millis(20));
+        pool.record_success(flaky, Duration::from_millis(20));
+        pool.record_failure(flaky, now);
+        pool.next_ready(now + Duration::from_secs(60));
+        pool.record_failure(waiting, now);
+        pool.record_success(new, Duration::from_millis(20));
+
+        assert_eq!(pool.snapshot().len(), 3);
+        assert!(pool.health(&flaky).is_none());
+        assert!(pool.health(&waiting).is_some());
+        assert!(pool.health(&good).is_some());
+        assert!(pool.health(&new).is_some());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::util::PieceBitfield;
+use std::collections::BTreeSet;
+
+/// Decides which piece a peer should download next. Pieces already in flight are
+/// shared first, new pieces are started rarest first, but only while fewer than
+/// `max_in_flight` pieces are partially downloaded
+#[derive(Debug)]
+pub struct PiecePicker {
+    have: PieceBitfield,
+    availability: Vec<u32>,
+    in_flight: BTreeSet<usize>,
+    max_in_flight: usize,
+    /// Pieces to download, `None` downloads everything
+    wanted: Option<PieceBitfield>,
+}
+
+impl PiecePicker {
+    pub fn new(pieces_count: usize, max_in_flight: usize) -> Self {
+        Self {
+            have: PieceBitfield::new(pieces_count),
+            availability: vec![0; pieces_count],
+            in_flight: BTreeSet::new(),
+            max_in_flight,
+            wanted: None,
+        }
+    }
+
+    /// Restricts the download to some pieces, e.g. those of the selected files
+    pub fn set_wanted(&mut self, wanted: Option<PieceBitfield>) -> &mut Self {
+        self.wanted = wanted;
+        self
+    }
+
+    pub fn is_wanted(&self, index: usize) -> bool {
+        self.wanted.as_ref().is_none_or(|wanted| wanted.has(index))
+    }
+
+    /// Wanted pieces we don't have yet
+    fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        self.have
+            .iter_unset()
+            .filter(|index| self.is_wanted(*index))
+    }
+
+    pub fn have(&self) -> &PieceBitfield {
+        &self.have
+    }
+
+    #[cfg(test)]
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Every wanted piece is verified
+    pub fn is_finished(&self) -> bool {
+        self.missing().next().is_none()
+    }
+
+    /// Every missing piece is already being downloaded
+    pub fn is_endgame(&self) -> bool {
+        !self.is_finished() && self.missing().all(|index| self.in_flight.contains(&index))
+    }
+
+    /// Peer has a wanted piece we are still missing
+    pub fn wants(&self, peer_has: &PieceBitfield) -> bool {
+        peer_has
+            .iter_set()
+            .any(|index| !self.have.has(index) && self.is_wanted(index))
+    }
+
+    /// Counts pieces of a peer that joined the swarm or announced more pieces
+    pub fn add_availability(&mut self, pieces: impl IntoIterator<Item = usize>) {
+        for index in pieces {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn remove_availability(&mut self, pieces: impl IntoIterator<Item = usize>) {
+        for index in pieces {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Next piece to download from a peer with the `peer_has` pieces
+    #[cfg(test)]
+    pub fn pick(&mut self, peer_has: &PieceBitfield) -> Option<usize> {
+        let in_flight = self.in_flight_of(peer_has).next();
+        in_flight.or_else(|| self.start(peer_has))
+    }
+
+    /// Pieces in flight the peer has, in index order
+    pub fn in_flight_of<'a>(
+        &'a self,
+        peer_has: &'a PieceBitfield,
+    ) -> impl Iterator<Item = usize> + 'a {
+        self.in_flight
+            .iter()
+            .copied()
+            .filter(|index| peer_has.has(*index))
+    }
+
+    /// Starts the rarest wanted piece of the peer that isn't in flight yet, unless the
+    /// in flight cap is reached
+    pub fn start(&mut self, peer_has: &PieceBitfield) -> Option<usize> {
+        if self.in_flight.len() >= self.max_in_flight {
+            return None;
+        }
+        let index = peer_has
+            .iter_set()
+            .filter(|index| !self.have.has(*index) && !self.in_flight.contains(index))
+            .filter(|index| self.is_wanted(*index))
+            .min_by_key(|index| self.availability.get(*index).copied().unwrap_or_default())?;
+        self.in_flight.insert(index);
+        Some(index)
+    }
+
+    /// Piece was verified and stored, its slot goes to a new piece
+    pub fn complete(&mut self, index: usize) {
+        self.in_flight.remove(&index);
+        self.have.set(index);
+    }
+
+    /// Piece we had turned out to be corrupted on disk, it's downloaded again
+    pub fn lose(&mut self, index: usize) {
+        self.have.unset(index);
+    }
+
+    /// Piece failed verification, it may be picked again from scratch
+    pub fn abort(&mut self, index: usize) {
+        self.in_flight.remove(&index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::picker::PiecePicker;
+    use crate::util::PieceBitfield;
+
+    #[test]
+    fn in_flight_cap() {
+        let mut picker = PiecePicker::new(4, 2);
+        let first = PieceBitfield::from_bytes(&[0b1100_0000], 4);
+        let second = PieceBitfield::from_bytes(&[0b0011_0000], 4);
+        picker.add_availability(first.iter_set());
+        picker.add_availability(second.iter_set());
+
+        assert_eq!(picker.pick(&first), Some(0));
+        // peer having the in-flight piece joins it instead of starting a new one
+        assert_eq!(picker.pick(&first), Some(0));
+        assert_eq!(picker.pick(&second), Some(2));
+        assert_eq!(picker.in_flight(), 2);
+
+        let third = PieceBitfield::from_bytes(&[0b0101_0000], 4);
+        assert_eq!(picker.pick(&third), None);
+        picker.complete(0);
+        assert_eq!(picker.pick(&third), Some(1));
+        assert!(!picker.is_finished());
+    }
+
+    #[test]
+    fn only_wanted_pieces_picked() {
+        let mut picker = PiecePicker::new(4, 4);
+        picker.set_wanted(Some(PieceBitfield::from_bytes(&[0b0110_0000], 4)));
+        let only_unwanted = PieceBitfield::from_bytes(&[0b1001_0000], 4);
+        let all = PieceBitfield::from_bytes(&[0b1111_0000], 4);
+
+        assert!(!picker.wants(&only_unwanted));
+        assert_eq!(picker.pick(&only_unwanted), None);
+        assert_eq!(picker.pick(&all), Some(1));
+        picker.complete(1);
+        assert_eq!(picker.pick(&all), Some(2));
+        assert!(picker.is_endgame());
+        picker.complete(2);
+        assert!(picker.is_finished());
+        assert!(!picker.wants(&all));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::client::piece::PieceError::{BlockLength, DuplicateBlock, Incomplete, OutOfRange};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use thiserror::Error;
+
+pub const BLOCK_SIZE: usize = 16384;
+
+type Result<T> = std::result::Result<T, PieceError>;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum PieceError {
+    #[error("Piece {0} doesn't exist")]
+    InvalidIndex(usize),
+    #[error("Block at offset {0} is out of piece range or misaligned")]
+    OutOfRange(usize),
+    #[error("Block at offset {0} has length {1}, expected {2}")]
+    BlockLength(usize, usize, usize),
+    #[error("Block at offset {0} already received")]
+    DuplicateBlock(usize),
+    #[error("Piece is missing {0} blocks")]
+    Incomplete(usize),
+}
+
+/// Collects blocks of a single piece until all of them arrived
+#[derive(Debug)]
+pub struct PieceBuffer {
+    data: Vec<u8>,
+    received: Vec<bool>,
+    missing: usize,
+    /// Peers that supplied the blocks, in order of their first block
+    sources: Vec<SocketAddr>,
+}
+
+impl PieceBuffer {
+    pub fn new(length: usize) -> Self {
+        let blocks = length.div_ceil(BLOCK_SIZE);
+        Self {
+            data: vec![0; length],
+            received: vec![false; blocks],
+            missing: blocks,
+            sources: Vec::new(),
+        }
+    }
+
+    /// Expected length of the block, only the last block of a piece may be shorter.
+    /// Zero for blocks past the end of the piece
+    pub fn block_length(&self, block: usize) -> usize {
+        block
+            .checked_mul(BLOCK_SIZE)
+            .and_then(|begin| self.data.len().checked_sub(begin))
+            .map_or(0, |rest| BLOCK_SIZE.min(rest))
+    }
+
+    pub fn has_block(&self, block: usize) -> bool {
+        self.received.get(block).copied().unwrap_or(false)
+    }
+
+    /// Offsets of blocks that still need to be requested
+    pub fn missing_blocks(&self) -> impl Iterator<Item = usize> + '_ {
+        self.received
+            .iter()
+            .enumerate()
+            .filter(|(_, received)| !**received)
+            .map(|(block, _)| block * BLOCK_SIZE)
+    }
+
+    /// Stores a block, returns true once the piece is complete
+    pub fn add_block(&mut self, begin: usize, block: &[u8]) -> Result<bool> {
+        if !begin.is_multiple_of(BLOCK_SIZE) || begin >= self.data.len() {
+            return Err(OutOfRange(begin));
+        }
+        let block_index = begin / BLOCK_SIZE;
+        let expected = self.block_length(block_index);
+        if block.len() != expected {
+            return Err(BlockLength(begin, block.len(), expected));
+        }
+        if self.received[block_index] {
+            return Err(DuplicateBlock(begin));
+        }
+        self.data[begin..begin + expected].copy_from_slice(block);
+        self.received[block_index] = true;
+        self.missing -= 1;
+        Ok(self.is_complete())
+    }
+
+    /// Same as [`PieceBuffer::add_block`], remembering the peer that sent the block
+    pub fn add_block_from(
+        &mut self,
+        source: SocketAddr,
+        begin: usize,
+        block: &[u8],
+    ) -> Result<bool> {
+        let complete = self.add_block(begin, block)?;
+        if !self.sources.contains(&source) {
+            self.sources.push(source);
+        }
+        Ok(complete)
+    }
+
+    pub fn sources(&self) -> &[SocketAddr] {
+        &self.sources
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing == 0
+    }
+
+    /// Yields the assembled piece
+    pub fn into_data(self) -> Result<Vec<u8>> {
+        if !self.is_complete() {
+            return Err(Incomplete(self.missing));
+        }
+        Ok(self.data)
+    }
+}
+
+/// Partial pieces keyed by piece index, a piece stays here when its peer goes away,
+/// so another peer can continue where the previous one stopped
+#[derive(Debug, Default)]
+pub struct PieceBuffers {
+    buffers: HashMap<u32, PieceBuffer>,
+}
+
+impl PieceBuffers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_insert(&mut self, index: u32, length: usize) -> &mut PieceBuffer {
+        self.buffers
+            .entry(index)
+            .or_insert_with(|| PieceBuffer::new(length))
+    }
+
+    pub fn get(&self, index: u32) -> Option<&PieceBuffer> {
+        self.buffers.get(&index)
+    }
+
+    pub fn remove(&mut self, index: u32) -> Option<PieceBuffer> {
+        self.buffers.remove(&index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::piece::{PieceBuffer, PieceBuffers, PieceError, BLOCK_SIZE};
+
+    fn piece_data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn assemble_out_of_order() {
+        let length = BLOCK_SIZE * 2 + 100;
+        let data = piece_data(length);
+        let mut buffer = PieceBuffer::new(length);
+        assert_eq!(buffer.missing_blocks().count(), 3);
+
+        assert_eq!(
+            buffer.add_block(BLOCK_SIZE * 2, &data[BLOCK_SIZE * 2..]),
+            Ok(false)
+        );
+        assert_eq!(buffer.add_block(0, &data[..BLOCK_SIZE]), Ok(false));
+        assert_eq!(
+            buffer.missing_blocks().collect::<Vec<_>>(),
+            vec![BLOCK_SIZE]
+        );
+        assert_eq!(
+            buffer.add_block(BLOCK_SIZE, &data[BLOCK_SIZE..BLOCK_SIZE * 2]),
+            Ok(true)
+        );
+
+        assert!(buffer.is_complete());
+        assert_eq!(buffer.into_data(), Ok(data));
+    }
+
+    #[test]
+    fn reject_invalid_blocks() {
+        let length = BLOCK_SIZE + 10;
+        let data = piece_data(length);
+        let mut buffer = PieceBuffer::new(length);
+
+        assert_eq!(
+            buffer.add_block(BLOCK_SIZE * 2, &data[..10]),
+            Err(PieceError::OutOfRange(BLOCK_SIZE * 2))
+        );
+        assert_eq!(
+            buffer.add_block(10, &data[10..20]),
+            Err(PieceError::OutOfRange(10))
+        );
+        assert_eq!(
+            buffer.add_block(BLOCK_SIZE, &data[..BLOCK_SIZE]),
+            Err(PieceError::BlockLength(BLOCK_SIZE, BLOCK_SIZE, 10))
+        );
+        assert_eq!(buffer.add_block(0, &data[..BLOCK_SIZE]), Ok(false));
+        assert_eq!(
+            buffer.add_block(0, &data[..BLOCK_SIZE]),
+            Err(PieceError::DuplicateBlock(0))
+        );
+        assert_eq!(buffer.into_data(), Err(PieceError::Incomplete(1)));
+    }
+
+    #[test]
+    fn block_length_past_the_end() {
+        let buffer = PieceBuffer::new(BLOCK_SIZE + 10);
+        assert_eq!(buffer.block_length(0), BLOCK_SIZE);
+        assert_eq!(buffer.block_length(1), 10);
+        assert_eq!(buffer.block_length(2), 0);
+        assert_eq!(buffer.block_length(usize::MAX), 0);
+    }
+
+    #[test]
+    fn buffers_survive_peer_switch() {
+        let data = piece_data(BLOCK_SIZE * 2);
+        let mut buffers = PieceBuffers::new();
+        buffers
+            .get_or_insert(5, data.len())
+            .add_block(0, &data[..BLOCK_SIZE])
+            .unwrap();
+
+        let buffer = buffers.get_or_insert(5, data.len());
+        assert!(buffer.has_block(0));
+        assert_eq!(buffer.add_block(BLOCK_SIZE, &data[BLOCK_SIZE..]), Ok(true));
+        assert_eq!(buffers.remove(5).unwrap().into_data(), Ok(data));
+        assert!(buffers.get(5).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+/// Cumulative payload bytes of a torrent, decides when seeding has gone on long enough
+#[derive(Debug, Default, Clone)]
+pub struct RatioTracker {
+    uploaded: u64,
+    downloaded: u64,
+    limit: Option<f64>,
+}
+
+impl RatioTracker {
+    pub fn new(limit: Option<f64>) -> Self {
+        Self {
+            uploaded: 0,
+            downloaded: 0,
+            limit,
+        }
+    }
+
+    pub fn record_upload(&mut self, bytes: usize) {
+        self.uploaded += bytes as u64;
+    }
+
+    pub fn record_download(&mut self, bytes: usize) {
+        self.downloaded += bytes as u64;
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Uploaded to downloaded bytes, `None` until something was downloaded
+    pub fn ratio(&self) -> Option<f64> {
+        (self.downloaded > 0).then(|| self.uploaded as f64 / self.downloaded as f64)
+    }
+
+    pub fn limit(&self) -> Option<f64> {
+        self.limit
+    }
+
+    /// Whether the configured seed ratio is reached and the torrent may stop
+    pub fn limit_reached(&self) -> bool {
+        match (self.limit, self.ratio()) {
+            (Some(limit), Some(ratio)) => ratio >= limit,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::ratio::RatioTracker;
+
+    #[test]
+    fn limit_reached_at_ratio() {
+        let mut ratio = RatioTracker::new(Some(1.5));
+        assert!(!ratio.limit_reached());
+        ratio.record_upload(4096);
+        // nothing downloaded yet, the ratio is undefined
+        assert_eq!(ratio.ratio(), None);
+        assert!(!ratio.limit_reached());
+
+        ratio.record_download(4000);
+        assert!(!ratio.limit_reached());
+        ratio.record_upload(1904);
+        assert_eq!(ratio.ratio(), Some(1.5));
+        assert!(ratio.limit_reached());
+
+        let mut unlimited = RatioTracker::new(None);
+        unlimited.record_download(1);
+        unlimited.record_upload(1000);
+        assert!(!unlimited.limit_reached());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::client::announcer::Announcer;
+use crate::client::inbound::InboundRouter;
+use crate::client::limiter::RateLimiter;
+use crate::client::worker::Downloader;
+use crate::client::ClientError::InboundConnection;
+use crate::client::{collect_peers, open_storage, Config, PeerSource, Result};
+use crate::file::TorrentFile;
+use crate::peer::PeerId;
+use crate::storage::cache::CachedStorage;
+use crate::storage::StorageWriter;
+use crate::tracker::{AnnounceParameters, RequestMode, TrackerClient};
+use crate::util::Sha1;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+/// Runs several torrents at once behind a single listening socket,
+/// all of them share one global rate limit
+pub struct Session {
+    client_id: Arc<PeerId>,
+    config: Config,
+    tracker_client: Box<dyn TrackerClient>,
+    limiter: Arc<RateLimiter>,
+    router: Arc<InboundRouter>,
+    listener: TcpListener,
+    downloads: HashMap<Sha1, (Downloader, CachedStorage<StorageWriter>)>,
+    peer_sources: Vec<Box<dyn PeerSource>>,
+}
+
+impl Session {
+    pub fn new(
+        client_id: PeerId,
+        config: Config,
+        tracker_client: Box<dyn TrackerClient>,
+        listener: TcpListener,
+    ) -> Self {
+        let client_id = Arc::new(client_id);
+        Self {
+            limiter: Arc::new(RateLimiter::new(config.rate_limit())),
+            router: Arc::new(InboundRouter::new(client_id.clone())),
+            client_id,
+            config,
+            tracker_client,
+            listener,
+            downloads: HashMap::new(),
+            peer_sources: Vec::new(),
+        }
+    }
+
+    pub fn add_peer_source(&mut self, source: Box<dyn PeerSource>) -> &mut Self {
+        self.peer_sources.push(source);
+        self
+    }
+
+    pub fn limiter(&self) -> &Arc<RateLimiter> {
+        &self.limiter
+    }
+
+    pub fn port(&self) -> Result<u16> {
+        self.listener
+            .local_addr()
+            .map(|addr| addr.port())
+            .map_err(|e| InboundConnection(Cow::Owned(e.to_string())))
+    }
+
+    /// Announces the torrent and prepares its download, returns the info hash it's known by.
+    /// A failing tracker is tolerated as long as another peer source has peers
+    pub fn add(&mut self, meta: TorrentFile) -> Result<Sha1> {
+        let info_hash = meta.info.info_hash;
+        let mut params = AnnounceParameters::new(info_hash);
+        params
+            .set_port(self.port()?)
+            .set_num_want(Some(100))
+            .set_request_mode(RequestMode::Compact);
+        let tiers = meta.tracker_tiers();
+        let announced = (!tiers.is_empty())
+            .then(|| Announcer::new(tiers, params).update(self.tracker_client.as_ref()));
+        let peers = collect_peers(announced, &self.peer_sources, &info_hash)?;
+        let storage = open_storage(&self.config, &meta.info)?;
+        let mut downloader = Downloader::new(
+            peers,
+            meta.info,
+            self.client_id.clone(),
+            self.config.clone(),
+        );
+        downloader
+            .set_inbound(self.router.register(info_hash))
+            .set_rate_limiter(self.limiter.clone());
+        self.downloads.insert(info_hash, (downloader, storage));
+        Ok(info_hash)
+    }
+
+    pub fn download(&mut self, info_hash: &Sha1) -> Option<&mut Downloader> {
+        self.downloads
+            .get_mut(info_hash)
+            .map(|(downloader, _)| downloader)
+    }
+
+    /// Accepts a single inbound connection and routes it to its torrent
+    pub fn accept(&self) -> Result<Sha1> {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .and_then(|(stream, addr)| {
+                stream.set_nodelay(self.config.tcp_nodelay())?;
+                Ok((stream, addr))
+            })
+            .map_err(|e| InboundConnection(Cow::Owned(e.to_string())))?;
+        Ok(self.router.route(stream)?)
+    }
+
+    /// Runs every added download on its own thread until all of them finish
+    pub fn run(self) {
+        let router = self.router;
+        let listener = self.listener;
+        thread::spawn(move || router.serve(&listener));
+
+        let workers: Vec<_> = self
+            .downloads
+            .into_values()
+            .map(|(mut downloader, storage)| {
+                thread::spawn(move || {
+                    if let Err(e) = downloader.run(&storage, None) {
+                        log::error!("download failed: {e}");
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::session::Session;
+    use crate::client::{ClientError, Config, PeerSource};
+    use crate::file::{File, Info, TorrentFile};
+    use crate::peer::connection::PeerConnection;
+    use crate::peer::{Peer, PeerId};
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::TrackerError;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use url::Url;
+
+    /// Stands in for DHT
+    struct StaticPeers(Vec<&'static str>);
+
+    impl PeerSource for StaticPeers {
+        fn peers(&self, _info_hash: &[u8; 20]) -> Vec<Peer> {
+            self.0
+                .iter()
+                .map(|addr| Peer::new(None, addr.parse().unwrap()))
+                .collect()
+        }
+    }
+
+    fn torrent(info_hash: [u8; 20]) -> TorrentFile {
+        TorrentFile {
+            announce: Some(Url::parse("http://tracker.example/announce").unwrap()),
+            announce_list: Vec::new(),
+            nodes: Vec::new(),
+            info: Info {
+                files: vec![File::new(4, PathBuf::from("file"))],
+                name: PathBuf::from("torrent"),
+                info_hash,
+                piece_length: 4,
+                pieces: vec![[0; 20]],
+            },
+        }
+    }
+
+    fn config(dir: &Path) -> Config {
+        let mut config = Config::new(1).unwrap();
+        config.set_download_dir(dir.to_path_buf());
+        config
+    }
+
+    #[test]
+    fn inbound_routed_by_info_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut session = Session::new(
+            PeerId::random(),
+            config(dir.path()),
+            Box::new(MockTracker::default()),
+            listener,
+        );
+        let first = session.add(torrent([1; 20])).unwrap();
+        let second = session.add(torrent([2; 20])).unwrap();
+        let port = session.port().unwrap();
+
+        let remote = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            PeerConnection::handshake(stream, second, &PeerId::random()).unwrap()
+        });
+        assert_eq!(session.accept().unwrap(), second);
+        remote.join().unwrap();
+
+        assert!(session.download(&first).unwrap().next_inbound().is_none());
+        assert!(session.download(&second).unwrap().next_inbound().is_some());
+
+        let remote = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            PeerConnection::handshake(stream, [3; 20], &PeerId::random())
+        });
+        assert!(session.accept().is_err());
+        assert!(remote.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn failing_tracker_with_dht_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker = MockTracker::default();
+        tracker.push_response(Err(TrackerError::AnnounceRequestError(
+            "connection refused".to_string(),
+        )));
+        let mut session = Session::new(
+            PeerId::random(),
+            config(dir.path()),
+            Box::new(tracker),
+            listener,
+        );
+        assert!(matches!(
+            session.add(torrent([1; 20])),
+            Err(ClientError::PeersRetrieve(_))
+        ));
+
+        session.add_peer_source(Box::new(StaticPeers(vec![
+            "10.0.0.1:6881",
+            "10.0.0.2:6881",
+        ])));
+        let info_hash = session.add(torrent([1; 20])).unwrap();
+        assert_eq!(session.download(&info_hash).unwrap().queued_peers(), 2);
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Weight of a new sample in the moving average, as in TCP's smoothed RTT
+const RTT_SAMPLE_WEIGHT: u32 = 8;
+
+/// Request latency of a peer, drives pipeline sizing and peer scoring
+#[derive(Debug, Default, Clone)]
+pub struct PeerStats {
+    /// When each outstanding block, as piece index and offset, was requested
+    pending: HashMap<(usize, usize), Instant>,
+    average_rtt: Option<Duration>,
+    samples: usize,
+}
+
+impl PeerStats {
+    pub fn request_sent(&mut self, index: usize, begin: usize, now: Instant) {
+        self.pending.insert((index, begin), now);
+    }
+
+    /// Records the round trip of a requested block, unsolicited blocks give no sample
+    pub fn block_received(&mut self, index: usize, begin: usize, now: Instant) -> Option<Duration> {
+        let sent = self.pending.remove(&(index, begin))?;
+        let sample = now.saturating_duration_since(sent);
+        self.average_rtt = Some(match self.average_rtt {
+            None => sample,
+            Some(average) if sample >= average => average + (sample - average) / RTT_SAMPLE_WEIGHT,
+            Some(average) => average - (average - sample) / RTT_SAMPLE_WEIGHT,
+        });
+        self.samples += 1;
+        Some(sample)
+    }
+
+    /// Forgets a request the peer refused to serve, it gives no sample
+    pub fn request_rejected(&mut self, index: usize, begin: usize) {
+        self.pending.remove(&(index, begin));
+    }
+
+    /// Drops requests that got no answer within `timeout` and returns them
+    pub fn timed_out(&mut self, now: Instant, timeout: Duration) -> Vec<(usize, usize)> {
+        let mut expired = Vec::new();
+        self.pending.retain(|block, sent| {
+            let alive = now.saturating_duration_since(*sent) < timeout;
+            if !alive {
+                expired.push(*block);
+            }
+            alive
+        });
+        expired.sort_unstable();
+        expired
+    }
+
+    /// Moving average of the request round trips, `None` before the first answer
+    pub fn average_rtt(&self) -> Option<Duration> {
+        self.average_rtt
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::stats::PeerStats;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn moving_average_rtt() {
+        let mut stats = PeerStats::default();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+
+        stats.request_sent(0, 0, start);
+        stats.request_sent(0, 16384, start);
+        stats.request_sent(1, 0, start + ms(100));
+        stats.request_sent(2, 0, start);
+        assert_eq!(stats.block_received(0, 0, start + ms(80)), Some(ms(80)));
+        assert_eq!(stats.average_rtt(), Some(ms(80)));
+        // 80 + (240 - 80) / 8
+        stats.block_received(0, 16384, start + ms(240));
+        assert_eq!(stats.average_rtt(), Some(ms(100)));
+        // 100 - (100 - 20) / 8
+        stats.block_received(1, 0, start + ms(120));
+        assert_eq!(stats.average_rtt(), Some(ms(90)));
+        assert_eq!(stats.block_received(1, 0, start + ms(130)), None);
+        assert_eq!(stats.samples(), 3);
+
+        assert_eq!(stats.timed_out(start + ms(500), ms(1000)), vec![]);
+        assert_eq!(stats.timed_out(start + ms(1000), ms(1000)), vec![(2, 0)]);
+        assert_eq!(stats.pending(), 0);
+    }
+}
@@ -1,57 +1,2012 @@
+use crate::client::announcer::Announcer;
+use crate::client::connector::{connect_bounded, tcp_connect, Attempt, Connector};
+use crate::client::endgame::{EndgameWatchdog, Rotation};
+use crate::client::hasher::{HashPool, Verified};
+use crate::client::heartbeat::Heartbeat;
+use crate::client::limiter::RateLimiter;
+use crate::client::peers::{PeerQueue, Strike};
+use crate::client::picker::PiecePicker;
+use crate::client::piece::{PieceBuffers, PieceError, BLOCK_SIZE};
+use crate::client::ratio::RatioTracker;
+use crate::client::stats::PeerStats;
+use crate::client::Config;
 use crate::file::Info;
-use crate::peer::connection::{ConnectionError, PeerConnection};
+use crate::peer::connection::{BlockRequest, ConnectionError, Message, PeerConnection, Piece};
+use crate::peer::extension::PeerExtensionInfo;
+use crate::peer::mse::MseStream;
 use crate::peer::{Peer, PeerId};
-use std::collections::VecDeque;
-use std::net::TcpStream;
+use crate::storage::cache::CachedStorage;
+use crate::storage::{PieceStorage, StorageError};
+use crate::tracker::TrackerClient;
+use crate::util::PieceBitfield;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub struct Task {}
+const PEER_RETRY_COOLDOWN: Duration = Duration::from_secs(300);
+const ENDGAME_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+/// Blocks requested from a peer at a time, unless its `reqq` allows fewer
+const PIPELINE_DEPTH: usize = 16;
+/// How often the download loop checks on verified pieces, inbound peers and idle peers
+const LOOP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pauses a download from another thread, a paused download keeps its peers and
+/// tracker session but requests nothing new
+#[derive(Debug, Default, Clone)]
+pub struct PauseHandle(Arc<AtomicBool>);
+
+impl PauseHandle {
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do with a peer whose bitfield arrives after other messages,
+/// the spec only allows it right after the handshake
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum BitfieldPolicy {
+    /// Drop the peer
+    #[default]
+    Strict,
+    /// Merge the late bitfield into the pieces already announced with `Have`
+    Lenient,
+}
+
+/// Our view of a connected peer
+#[derive(Debug)]
+pub struct PeerState {
+    pub has: PieceBitfield,
+    /// Set by the first message after the handshake, keep-alives and extended messages aside
+    pub first_message_passed: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    /// Blocks requested from the peer, as piece index and offset
+    pub requested: HashSet<(usize, usize)>,
+    /// Last time the peer unchoked us or sent a block, keep-alives don't count
+    pub last_progress: Instant,
+    pub stats: PeerStats,
+    pub connected_at: Instant,
+    /// Bytes of the blocks the peer sent
+    pub downloaded: u64,
+}
+
+impl PeerState {
+    pub fn new(pieces_count: usize) -> Self {
+        Self {
+            has: PieceBitfield::new(pieces_count),
+            first_message_passed: false,
+            am_interested: false,
+            peer_choking: true,
+            requested: HashSet::new(),
+            last_progress: Instant::now(),
+            stats: PeerStats::default(),
+            connected_at: Instant::now(),
+            downloaded: 0,
+        }
+    }
+
+    /// Bytes per second the peer sent since it connected
+    pub fn download_rate(&self, now: Instant) -> u64 {
+        let seconds = now.saturating_duration_since(self.connected_at).as_secs();
+        self.downloaded / seconds.max(1)
+    }
+}
+
+/// What happened to the piece a received block belongs to
+#[derive(Debug, PartialEq)]
+pub enum BlockOutcome {
+    /// Piece still misses blocks, or the block wasn't needed anymore
+    Partial,
+    /// Piece is complete and handed to the hashing threads, see [`Downloader::try_hashed`]
+    Hashing,
+}
+
+/// Verification result of a complete piece
+#[derive(Debug, PartialEq)]
+pub enum PieceOutcome {
+    /// Piece matches its hash, the data is ready to be stored
+    Verified(usize, Vec<u8>),
+    /// Piece doesn't match its hash, with the peers that sent it
+    Corrupt(usize, Vec<SocketAddr>),
+}
+
+/// Snapshot of a download for progress reporting
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub pieces_have: usize,
+    pub pieces_total: usize,
+    pub paused: bool,
+    /// Peers that supplied the blocks of every completed piece
+    pub piece_sources: BTreeMap<usize, Vec<SocketAddr>>,
+}
 
 pub struct Downloader {
-    peers: VecDeque<Peer>,
+    peers: PeerQueue,
     peer_id: Arc<PeerId>,
     info: Arc<Info>,
+    config: Arc<Config>,
+    inbound: Option<mpsc::Receiver<PeerConnection>>,
+    limiter: Arc<RateLimiter>,
+    picker: PiecePicker,
+    watchdog: EndgameWatchdog,
+    buffers: PieceBuffers,
+    hasher: HashPool,
+    /// Sources of the complete pieces being hashed
+    hashing: HashMap<usize, Vec<SocketAddr>>,
+    piece_sources: BTreeMap<usize, Vec<SocketAddr>>,
+    /// Pieces verified in this session, in the order they completed
+    completed: Vec<usize>,
+    /// Sources of the last failed attempt of every piece that failed its hash check
+    suspects: HashMap<usize, Vec<SocketAddr>>,
+    pause: PauseHandle,
+    ratio: RatioTracker,
+    heartbeat: Option<Heartbeat>,
+    /// Our address as every peer reported it in its extended handshake
+    reported_ips: HashMap<SocketAddr, IpAddr>,
+    /// State of every connected peer
+    connected: HashMap<SocketAddr, PeerState>,
 }
 
 impl Downloader {
-    pub fn run(&mut self) {
-        let peer = self.peers.pop_front().unwrap();
+    /// Downloads into `storage` from the queued peers and those connecting to us, every
+    /// peer is talked to on its own thread. Returns once the download is finished, or when
+    /// there is nobody left to download from, e.g. for a trackerless torrent without other
+    /// peer sources. Connections still open then are shut down.
+    /// With a tracker the announces are repeated as its session asks for, and a dead swarm
+    /// gets fewer connections, see [`TrackerSession::connection_attempts`]. A stalled
+    /// endgame disconnects its slowest peers and announces right away for fresh ones, see
+    /// [`Downloader::rotate_stalled_peers`]
+    pub fn run<S>(
+        &mut self,
+        storage: &CachedStorage<S>,
+        mut tracker: Option<(&mut Announcer, &dyn TrackerClient)>,
+    ) -> Result<(), StorageError>
+    where
+        S: PieceStorage + Sync,
+    {
+        let peering = Arc::new(Peering {
+            peer_id: self.peer_id.clone(),
+            info: self.info.clone(),
+            config: self.config.clone(),
+        });
+        let connection_numbers = self.config.connection_numbers;
+        let (attempt_sender, attempts) = mpsc::channel();
+        let (left_sender, left) = mpsc::channel();
+        let shared = Mutex::new(self);
+        thread::scope(|scope| {
+            let mut sockets: HashMap<SocketAddr, TcpStream> = HashMap::new();
+            let mut dialing = 0;
+            let mut rotated = false;
+            let result = loop {
+                if let Some((announcer, tracker)) = tracker.as_mut() {
+                    if rotated || announcer.session().is_due(Instant::now()) {
+                        reannounce(&shared, announcer, *tracker);
+                    }
+                }
+                rotated = false;
+                let max_connections = tracker
+                    .as_ref()
+                    .map_or(connection_numbers, |(announcer, _)| {
+                        announcer.session().connection_attempts(connection_numbers)
+                    });
+                let mut downloader = shared.lock().unwrap();
+                let hashed: Vec<PieceOutcome> =
+                    std::iter::from_fn(|| downloader.try_hashed()).collect();
+                downloader.heartbeat(Instant::now());
+                for addr in downloader.idle_peers(Instant::now()) {
+                    if let Some(socket) = sockets.get(&addr) {
+                        let _ = socket.shutdown(Shutdown::Both);
+                    }
+                }
+                if let Some(rotation) = downloader.rotate_stalled_peers(Instant::now()) {
+                    for addr in &rotation.dropped {
+                        if let Some(socket) = sockets.get(addr) {
+                            let _ = socket.shutdown(Shutdown::Both);
+                        }
+                    }
+                    rotated = rotation.reannounce;
+                }
+                let room = max_connections.saturating_sub(sockets.len() + dialing);
+                if dialing == 0 && room > 0 {
+                    let peering = peering.clone();
+                    dialing = downloader.connect_queued(
+                        room,
+                        move |addrs: &[SocketAddr]| peering.connect(addrs),
+                        attempt_sender.clone(),
+                    );
+                }
+                let inbound: Vec<PeerConnection> =
+                    std::iter::from_fn(|| downloader.next_inbound()).collect();
+                let finished = downloader.is_finished();
+                let exhausted = sockets.is_empty()
+                    && dialing == 0
+                    && inbound.is_empty()
+                    && downloader.queued_peers() == 0
+                    && downloader.hashing.is_empty();
+                drop(downloader);
+
+                if let Err(e) = hashed
+                    .into_iter()
+                    .try_for_each(|outcome| store(storage, outcome))
+                {
+                    break Err(e);
+                }
+                if finished || exhausted {
+                    break Ok(());
+                }
+                for connection in inbound {
+                    if sockets.len() < max_connections {
+                        spawn_peer(
+                            scope,
+                            &shared,
+                            storage,
+                            connection,
+                            &mut sockets,
+                            &left_sender,
+                        );
+                    }
+                }
+                match attempts.recv_timeout(LOOP_INTERVAL) {
+                    Ok(Ok(connection)) => {
+                        dialing -= 1;
+                        spawn_peer(
+                            scope,
+                            &shared,
+                            storage,
+                            connection,
+                            &mut sockets,
+                            &left_sender,
+                        );
+                    }
+                    Ok(Err((addr, e))) => {
+                        dialing -= 1;
+                        log::debug!("{addr} connection failed: {e}");
+                        shared.lock().unwrap().connect_failed(addr);
+                    }
+                    Err(_) => {}
+                }
+                while let Ok(addr) = left.try_recv() {
+                    sockets.remove(&addr);
+                }
+            };
+            for socket in sockets.values() {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            result
+        })
     }
 
-    pub fn new<T>(peers: T, info: Info) -> Self
+    /// Serves the pieces we have to peers connecting to us, nobody is dialed. Returns once
+    /// the torrent is no longer routed to us or the seed ratio limit is reached,
+    /// connections still open then are shut down
+    pub fn seed<S>(&mut self, storage: &CachedStorage<S>)
     where
-        T: Into<VecDeque<Peer>>,
+        S: PieceStorage + Sync,
     {
-        Self {
-            peers: peers.into(),
-            peer_id: Arc::new(PeerId::random()),
+        let Some(inbound) = self.inbound.take() else {
+            return;
+        };
+        let max_connections = self.config.connection_numbers;
+        let (left_sender, left) = mpsc::channel();
+        let shared = Mutex::new(self);
+        thread::scope(|scope| {
+            let mut sockets: HashMap<SocketAddr, TcpStream> = HashMap::new();
+            loop {
+                match inbound.recv_timeout(LOOP_INTERVAL) {
+                    Ok(connection) if sockets.len() < max_connections => spawn_peer(
+                        scope,
+                        &shared,
+                        storage,
+                        connection,
+                        &mut sockets,
+                        &left_sender,
+                    ),
+                    Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+                while let Ok(addr) = left.try_recv() {
+                    sockets.remove(&addr);
+                }
+                if shared.lock().unwrap().seed_limit_reached() {
+                    break;
+                }
+            }
+            for socket in sockets.values() {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        });
+    }
+
+    pub fn new<T>(peers: T, info: Info, peer_id: Arc<PeerId>, config: Config) -> Self
+    where
+        T: IntoIterator<Item = Peer>,
+    {
+        let mut queue = PeerQueue::new(PEER_RETRY_COOLDOWN);
+        queue.set_max_strikes(config.max_peer_strikes());
+        let mut picker = PiecePicker::new(info.pieces.len(), config.max_pieces_in_flight());
+        if let Some(files) = config.selected_files() {
+            picker.set_wanted(Some(info.pieces_for_files(files)));
+        }
+        let ratio = RatioTracker::new(config.seed_ratio_limit());
+        let heartbeat = config
+            .heartbeat_interval()
+            .map(|interval| Heartbeat::new(interval, Instant::now()));
+        let hasher = HashPool::new(config.hashing_threads(), Arc::new(info.pieces.clone()));
+        let mut downloader = Self {
+            peers: queue,
+            peer_id,
             info: Arc::new(info),
+            config: Arc::new(config),
+            inbound: None,
+            limiter: Arc::new(RateLimiter::unlimited()),
+            picker,
+            watchdog: EndgameWatchdog::new(ENDGAME_STALL_TIMEOUT, Instant::now()),
+            buffers: PieceBuffers::new(),
+            hasher,
+            hashing: HashMap::new(),
+            piece_sources: BTreeMap::new(),
+            completed: Vec::new(),
+            suspects: HashMap::new(),
+            pause: PauseHandle::default(),
+            ratio,
+            heartbeat,
+            reported_ips: HashMap::new(),
+            connected: HashMap::new(),
+        };
+        downloader.add_peers(peers);
+        downloader
+    }
+
+    /// Queues peers to connect to, e.g. from a re-announce. Peers we can't connect to
+    /// and those already known are skipped, returns the number of peers queued
+    pub fn add_peers<T>(&mut self, peers: T) -> usize
+    where
+        T: IntoIterator<Item = Peer>,
+    {
+        let allow_loopback = self.config.allow_loopback_peers();
+        self.peers.merge(
+            peers
+                .into_iter()
+                .filter(|peer| peer.is_connectable(allow_loopback)),
+        )
+    }
+
+    /// Downloads only the files at the given positions of [`Info::files`], the download
+    /// is finished once their pieces are verified, see [`Config::set_selected_files`]
+    pub fn select_files(&mut self, files: &[usize]) -> &mut Self {
+        let wanted = self.info.pieces_for_files(files);
+        self.picker.set_wanted(Some(wanted));
+        self
+    }
+
+    /// Bytes of the wanted pieces not verified yet, what the tracker gets as `left`
+    pub fn left(&self) -> usize {
+        let have = self.picker.have();
+        (0..self.info.pieces.len())
+            .filter(|index| !have.has(*index) && self.picker.is_wanted(*index))
+            .filter_map(|index| self.piece_size(index))
+            .sum()
+    }
+
+    /// Channel delivering peers that connected to us and asked for this torrent
+    pub fn set_inbound(&mut self, inbound: mpsc::Receiver<PeerConnection>) -> &mut Self {
+        self.inbound = Some(inbound);
+        self
+    }
+
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) -> &mut Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Peers waiting for a connection
+    pub fn queued_peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn set_pause(&mut self, pause: PauseHandle) -> &mut Self {
+        self.pause = pause;
+        self
+    }
+
+    /// Requests to send to the peer to keep `depth` blocks in flight. Blocks are taken in
+    /// turn from every piece in flight the peer has, so one slow block doesn't hold up the
+    /// rest of the pipeline, a new piece is only started when those run out.
+    /// Nothing is requested while the download is paused or the peer chokes us
+    pub fn next_requests(&mut self, peer: &mut PeerState, depth: usize) -> Vec<Message> {
+        // pieces that are complete, being hashed or given up have no buffer anymore
+        let buffers = &self.buffers;
+        peer.requested.retain(|(index, begin)| {
+            buffers
+                .get(*index as u32)
+                .is_some_and(|buffer| !buffer.has_block(begin / BLOCK_SIZE))
+        });
+        if self.pause.is_paused() || peer.peer_choking || peer.requested.len() >= depth {
+            return Vec::new();
+        }
+        let in_flight: Vec<usize> = self
+            .picker
+            .in_flight_of(&peer.has)
+            .filter(|index| !self.hashing.contains_key(index))
+            .collect();
+        let mut pieces: Vec<VecDeque<BlockRequest>> = in_flight
+            .into_iter()
+            .map(|index| self.unrequested_blocks(peer, index))
+            .collect();
+        let mut requests = Vec::new();
+        while peer.requested.len() < depth {
+            if pieces.iter().all(VecDeque::is_empty) {
+                let Some(index) = self.picker.start(&peer.has) else {
+                    break;
+                };
+                pieces.push(self.unrequested_blocks(peer, index));
+            }
+            for blocks in &mut pieces {
+                if peer.requested.len() >= depth {
+                    break;
+                }
+                let Some(request) = blocks.pop_front() else {
+                    continue;
+                };
+                let (index, begin) = (request.index() as usize, request.begin() as usize);
+                peer.requested.insert((index, begin));
+                peer.stats.request_sent(index, begin, Instant::now());
+                requests.push(Message::Request(request));
+            }
+        }
+        requests
+    }
+
+    /// Missing blocks of the piece not requested from the peer yet
+    fn unrequested_blocks(&mut self, peer: &PeerState, index: usize) -> VecDeque<BlockRequest> {
+        let Some(length) = self.piece_size(index) else {
+            return VecDeque::new();
+        };
+        let buffer = self.buffers.get_or_insert(index as u32, length);
+        buffer
+            .missing_blocks()
+            .filter(|begin| !peer.requested.contains(&(index, *begin)))
+            .map(|begin| {
+                let length = buffer.block_length(begin / BLOCK_SIZE);
+                BlockRequest::new(index as u32, begin as u32, length as u32)
+            })
+            .collect()
+    }
+
+    /// Starts connecting to up to `count` queued peers, at most
+    /// [`Config::max_connect_attempts`] at a time, returns the number of peers tried.
+    /// `connect` gets every address the peer is known by. Attempts are sent to `attempts` as soon as they end, failed ones go through
+    /// [`Downloader::connect_failed`]
+    pub fn connect_queued<T, E, F>(
+        &mut self,
+        count: usize,
+        connect: F,
+        attempts: mpsc::Sender<Attempt<T, E>>,
+    ) -> usize
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+        F: Fn(&[SocketAddr]) -> Result<T, E> + Send + Sync + 'static,
+    {
+        let peers: Vec<Peer> = std::iter::from_fn(|| self.peers.pop())
+            .take(count)
+            .collect();
+        let tried = peers.len();
+        let addrs: HashMap<SocketAddr, Vec<SocketAddr>> = peers
+            .iter()
+            .map(|peer| (peer.addr, self.peers.addrs(peer)))
+            .collect();
+        connect_bounded(
+            peers,
+            self.config.max_connect_attempts(),
+            move |peer: &Peer| connect(&addrs[&peer.addr]),
+            attempts,
+        );
+        tried
+    }
+
+    /// Puts a peer that couldn't be connected on cooldown, it's retried later
+    pub fn connect_failed(&mut self, addr: SocketAddr) {
+        self.peers.mark_failed(addr);
+    }
+
+    pub fn next_inbound(&self) -> Option<PeerConnection> {
+        self.inbound.as_ref()?.try_recv().ok()
+    }
+
+    /// Marks a piece as downloaded and verified, returns true when it was the last missing one
+    pub fn piece_verified(&mut self, index: usize) -> bool {
+        let finished = self.is_finished();
+        self.picker.complete(index);
+        !finished && self.is_finished()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.picker.is_finished()
+    }
+
+    /// Verified pieces, from this session and earlier ones
+    pub fn have(&self) -> &PieceBitfield {
+        self.picker.have()
+    }
+
+    /// Hashes every piece on disk again, e.g. after a crash or suspected corruption.
+    /// Pieces that no longer match are marked missing so they get downloaded again,
+    /// those are returned. Reports pieces hashed and total as results come in, setting
+    /// `cancel` stops the check with [`StorageError::Cancelled`]
+    pub fn recheck<S, F>(
+        &mut self,
+        storage: &S,
+        cancel: &AtomicBool,
+        mut progress: F,
+    ) -> Result<Vec<usize>, StorageError>
+    where
+        S: PieceStorage,
+        F: FnMut(usize, usize),
+    {
+        let total = self.info.pieces.len();
+        let threads = self.config.hashing_threads();
+        let pool = HashPool::new(threads, Arc::new(self.info.pieces.clone()));
+        let mut lost = Vec::new();
+        let mut pending = 0;
+        let mut hashed = 0;
+        for index in 0..=total {
+            // every hashing thread gets a piece queued, the rest stays on disk
+            while pending > 0 && (pending >= threads * 2 || index == total) {
+                let verified = pool.recv().ok_or(StorageError::Cancelled)?;
+                pending -= 1;
+                hashed += 1;
+                self.rechecked(verified.index, verified.valid, &mut lost);
+                progress(hashed, total);
+            }
+            if index == total {
+                break;
+            }
+            if cancel.load(Ordering::Relaxed) {
+                return Err(StorageError::Cancelled);
+            }
+            match storage.read_piece(index) {
+                Ok(data) => {
+                    pool.submit(index, data);
+                    pending += 1;
+                }
+                Err(StorageError::Io(e))
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    hashed += 1;
+                    self.rechecked(index, false, &mut lost);
+                    progress(hashed, total);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        lost.sort_unstable();
+        Ok(lost)
+    }
+
+    fn rechecked(&mut self, index: usize, valid: bool, lost: &mut Vec<usize>) {
+        if valid {
+            self.picker.complete(index);
+        } else if self.picker.have().has(index) {
+            self.picker.lose(index);
+            lost.push(index);
+        }
+    }
+
+    /// Reads a block requested by a peer. A piece that fails verification is never served,
+    /// it's marked as missing so that it gets downloaded again
+    pub fn serve_block<S: PieceStorage>(
+        &mut self,
+        storage: &CachedStorage<S>,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, StorageError> {
+        if !self.picker.have().has(index) {
+            return Err(StorageError::MissingPiece(index));
+        }
+        let result = storage.read_block(index, begin, length);
+        match &result {
+            Ok(block) => self.ratio.record_upload(block.len()),
+            Err(StorageError::CorruptPiece(index)) => self.picker.lose(*index),
+            Err(_) => {}
+        }
+        result
+    }
+
+    pub fn ratio(&self) -> &RatioTracker {
+        &self.ratio
+    }
+
+    /// Download is complete and enough was uploaded, the torrent may shut down
+    pub fn seed_limit_reached(&self) -> bool {
+        self.is_finished() && self.ratio.limit_reached()
+    }
+
+    /// Requests to send to a connected peer, see [`Downloader::next_requests`]. Requests
+    /// the peer left unanswered for too long are given up first
+    fn requests_for(&mut self, addr: SocketAddr, depth: usize) -> Vec<Message> {
+        let Some(mut peer) = self.connected.remove(&addr) else {
+            return Vec::new();
+        };
+        self.expire_requests(&mut peer, Instant::now());
+        let requests = self.next_requests(&mut peer, depth);
+        self.connected.insert(addr, peer);
+        requests
+    }
+
+    /// Connected peers that became idle, they are put on cooldown and have to be disconnected
+    fn idle_peers(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let idle: Vec<SocketAddr> = self
+            .connected
+            .iter()
+            .filter(|(_, peer)| self.is_idle(peer, now))
+            .map(|(addr, _)| *addr)
+            .collect();
+        idle.iter().for_each(|addr| self.peers.mark_failed(*addr));
+        idle
+    }
+
+    /// Starts tracking a connected peer, it has no pieces until it tells us otherwise
+    pub fn peer_connected(&mut self, addr: SocketAddr) -> &mut PeerState {
+        let pieces_count = self.info.pieces.len();
+        self.connected
+            .entry(addr)
+            .or_insert_with(|| PeerState::new(pieces_count))
+    }
+
+    /// Forgets a peer and the pieces it contributed to the availability. Blocks it already
+    /// delivered stay in their piece buffers, whoever continues the piece fetches only the rest
+    pub fn peer_disconnected(&mut self, addr: &SocketAddr) {
+        if let Some(peer) = self.connected.remove(addr) {
+            self.picker.remove_availability(peer.has.iter_set());
+            // answering requests proves the peer worth reconnecting to first
+            if let Some(round_trip) = peer.stats.average_rtt() {
+                self.peers.connected(*addr, round_trip);
+            }
+        }
+    }
+
+    /// [`Downloader::handle_message`] for a peer registered with [`Downloader::peer_connected`],
+    /// protocol violations count as strikes against the peer
+    pub fn handle_peer_message(
+        &mut self,
+        addr: SocketAddr,
+        message: &Message,
+    ) -> Result<Option<Message>, ConnectionError> {
+        let mut peer = self
+            .connected
+            .remove(&addr)
+            .unwrap_or_else(|| PeerState::new(self.info.pieces.len()));
+        let result = self.handle_message(&mut peer, message);
+        self.connected.insert(addr, peer);
+        if let Some(strike) = result.as_ref().err().and_then(Strike::for_error) {
+            self.strike(addr, strike);
+        }
+        result
+    }
+
+    /// Gives up requests the peer left unanswered for the configured request timeout,
+    /// so that the blocks can be requested again
+    pub fn expire_requests(&self, peer: &mut PeerState, now: Instant) -> Vec<(usize, usize)> {
+        let expired = peer.stats.timed_out(now, self.config.request_timeout());
+        for block in &expired {
+            peer.requested.remove(block);
+        }
+        expired
+    }
+
+    /// Mean of the average round trips of connected peers that answered a request
+    pub fn average_rtt(&self) -> Option<Duration> {
+        let averages: Vec<Duration> = self
+            .connected
+            .values()
+            .filter_map(|peer| peer.stats.average_rtt())
+            .collect();
+        let count = u32::try_from(averages.len())
+            .ok()
+            .filter(|count| *count > 0)?;
+        Some(averages.iter().sum::<Duration>() / count)
+    }
+
+    /// Whether the connected peer announced the piece, false for unknown peers
+    pub fn peer_has_piece(&self, addr: &SocketAddr, index: usize) -> bool {
+        self.connected
+            .get(addr)
+            .is_some_and(|peer| peer.has.has(index))
+    }
+
+    /// Updates the peer and piece availability, returns `Interested` or `NotInterested`
+    /// when our interest in the peer changed and the peer has to be told. Of the fast
+    /// extension messages only `RejectRequest` matters, suggestions and allowed fast
+    /// pieces are hints we may ignore.
+    /// A late bitfield fails with [`ConnectionError::LateBitfield`] under the strict policy,
+    /// one of the wrong length or with spare bits set with [`ConnectionError::InvalidBitfield`]
+    pub fn handle_message(
+        &mut self,
+        peer: &mut PeerState,
+        message: &Message,
+    ) -> Result<Option<Message>, ConnectionError> {
+        let first_message = !peer.first_message_passed;
+        if !matches!(message, Message::KeepAlive | Message::Extended { .. }) {
+            peer.first_message_passed = true;
+        }
+        match message {
+            Message::Choke => peer.peer_choking = true,
+            Message::UnChoke => {
+                peer.peer_choking = false;
+                peer.last_progress = Instant::now();
+            }
+            Message::Piece(piece) => {
+                peer.last_progress = Instant::now();
+                peer.downloaded += piece.data().len() as u64;
+                let (index, begin) = (piece.index() as usize, piece.begin() as usize);
+                peer.stats.block_received(index, begin, peer.last_progress);
+                return Ok(None);
+            }
+            Message::RejectRequest(request) => {
+                // the block is requested again, from this peer or another one
+                let (index, begin) = (request.index() as usize, request.begin() as usize);
+                peer.requested.remove(&(index, begin));
+                peer.stats.request_rejected(index, begin);
+                return Ok(None);
+            }
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                let policy = self.config.bitfield_policy();
+                if !first_message && policy == BitfieldPolicy::Strict {
+                    return Err(ConnectionError::LateBitfield);
+                }
+                let pieces_count = self.info.pieces.len();
+                let has = match message {
+                    Message::Bitfield(fields) => {
+                        let bytes: Vec<u8> = fields.iter().map(|field| field.get_value()).collect();
+                        PieceBitfield::from_bytes_exact(&bytes, pieces_count)
+                            .ok_or(ConnectionError::InvalidBitfield(bytes.len(), pieces_count))?
+                    }
+                    Message::HaveAll => PieceBitfield::from_bytes(
+                        &vec![0xff; pieces_count.div_ceil(8)],
+                        pieces_count,
+                    ),
+                    _ => PieceBitfield::new(pieces_count),
+                };
+                if first_message {
+                    self.picker.remove_availability(peer.has.iter_set());
+                    self.picker.add_availability(has.iter_set());
+                    peer.has = has;
+                } else {
+                    let added: Vec<usize> = has.newly_set(&peer.has).collect();
+                    added.iter().for_each(|index| peer.has.set(*index));
+                    self.picker.add_availability(added);
+                }
+            }
+            Message::Have(index) => {
+                let index = *index as usize;
+                if index < peer.has.len() && !peer.has.has(index) {
+                    peer.has.set(index);
+                    self.picker.add_availability([index]);
+                }
+            }
+            _ => return Ok(None),
+        }
+        let interested = self.picker.wants(&peer.has);
+        if interested == peer.am_interested {
+            return Ok(None);
+        }
+        peer.am_interested = interested;
+        Ok(Some(if interested {
+            Message::Interested
+        } else {
+            Message::NotInterested
+        }))
+    }
+
+    /// Peer holds a connection slot without giving us anything, e.g. it only sends keep-alives
+    /// or never unchokes us. Peers are never idle once we are seeding
+    pub fn is_idle(&self, peer: &PeerState, now: Instant) -> bool {
+        !self.is_finished()
+            && now.saturating_duration_since(peer.last_progress) >= self.config.peer_idle_timeout()
+    }
+
+    /// Counts misbehaviour of a peer, returns true when it got banned and has to be
+    /// disconnected. A banned peer isn't connected again for the rest of the session
+    pub fn strike(&mut self, addr: SocketAddr, strike: Strike) -> bool {
+        let banned = self.peers.strike(addr, strike);
+        if banned {
+            self.peer_disconnected(&addr);
+        }
+        banned
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.peers.is_banned(addr)
+    }
+
+    /// Puts an idle peer on cooldown, returns true when the peer has to be disconnected
+    pub fn drop_if_idle(&mut self, addr: SocketAddr, peer: &PeerState, now: Instant) -> bool {
+        let idle = self.is_idle(peer, now);
+        if idle {
+            self.peers.mark_failed(addr);
+        }
+        idle
+    }
+
+    /// Stores a block sent by `peer`, once all blocks of the piece arrived it's verified
+    /// on the hashing threads. Blocks of pieces we have or are verifying are dropped
+    pub fn block_received(
+        &mut self,
+        peer: SocketAddr,
+        index: usize,
+        begin: usize,
+        block: &[u8],
+    ) -> Result<BlockOutcome, PieceError> {
+        let length = self
+            .piece_size(index)
+            .ok_or(PieceError::InvalidIndex(index))?;
+        if self.picker.have().has(index) || self.hashing.contains_key(&index) {
+            return Ok(BlockOutcome::Partial);
+        }
+        let buffer = self.buffers.get_or_insert(index as u32, length);
+        let complete = buffer.add_block_from(peer, begin, block)?;
+        self.ratio.record_download(block.len());
+        self.watchdog.progress(Instant::now());
+        if !complete {
+            return Ok(BlockOutcome::Partial);
+        }
+        let buffer = self
+            .buffers
+            .remove(index as u32)
+            .expect("buffer was just filled");
+        self.hashing.insert(index, buffer.sources().to_vec());
+        self.hasher.submit(index, buffer.into_data()?);
+        Ok(BlockOutcome::Hashing)
+    }
+
+    /// Verification result that is already available, never blocks
+    pub fn try_hashed(&mut self) -> Option<PieceOutcome> {
+        let verified = self.hasher.try_recv()?;
+        Some(self.piece_hashed(verified))
+    }
+
+    /// Waits for the next verification result, `None` when no piece is being hashed
+    pub fn wait_hashed(&mut self) -> Option<PieceOutcome> {
+        if self.hashing.is_empty() {
+            return None;
+        }
+        let verified = self.hasher.recv()?;
+        Some(self.piece_hashed(verified))
+    }
+
+    /// A corrupt piece only counts against a peer that sent all of it, or that sent some of
+    /// it again after the previous attempt failed too, other sources may be innocent
+    fn piece_hashed(&mut self, verified: Verified) -> PieceOutcome {
+        let index = verified.index;
+        let sources = self.hashing.remove(&index).unwrap_or_default();
+        if !verified.valid {
+            self.picker.abort(index);
+            let previous = self.suspects.insert(index, sources.clone());
+            for source in &sources {
+                let implicated = sources.len() == 1
+                    || previous
+                        .as_ref()
+                        .is_some_and(|previous| previous.contains(source));
+                if implicated {
+                    self.strike(*source, Strike::HashFailure);
+                }
+            }
+            return PieceOutcome::Corrupt(index, sources);
+        }
+        self.suspects.remove(&index);
+        self.piece_sources.insert(index, sources);
+        self.picker.complete(index);
+        self.completed.push(index);
+        PieceOutcome::Verified(index, verified.data)
+    }
+
+    /// Pieces verified in this session since the first `from` of them, in order,
+    /// every connected peer gets a `Have` for each
+    fn completed_since(&self, from: usize) -> &[usize] {
+        self.completed.get(from..).unwrap_or_default()
+    }
+
+    /// Peers that supplied the blocks of a completed piece
+    pub fn piece_sources(&self, index: usize) -> Option<&[SocketAddr]> {
+        self.piece_sources.get(&index).map(Vec::as_slice)
+    }
+
+    /// Remembers what the peer's extended handshake says about us, once enough peers
+    /// agree on a `yourip` it ends up in the announces when [`Config::announce_external_ip`]
+    /// is set
+    pub fn extension_handshake(&mut self, addr: SocketAddr, info: &PeerExtensionInfo) {
+        if let Some(ip) = info.your_ip {
+            self.reported_ips.insert(addr, ip);
+        }
+    }
+
+    /// Our address as the peers reported it, one address per peer
+    pub fn reported_ips(&self) -> Vec<IpAddr> {
+        self.reported_ips.values().copied().collect()
+    }
+
+    /// Logs the progress summary when the heartbeat interval passed, [`Downloader::run`]
+    /// checks on every pass of its loop
+    pub fn heartbeat(&mut self, now: Instant) -> Option<String> {
+        let progress = self.progress();
+        let left = self.left();
+        let label = self.info.name.display().to_string();
+        self.heartbeat.as_mut()?.tick(
+            now,
+            &label,
+            &progress,
+            &self.ratio,
+            self.connected.len(),
+            left,
+        )
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            pieces_have: self.picker.have().count(),
+            pieces_total: self.info.pieces.len(),
+            paused: self.pause.is_paused(),
+            piece_sources: self.piece_sources.clone(),
+        }
+    }
+
+    /// Length of the piece, only the last piece may be shorter
+    fn piece_size(&self, index: usize) -> Option<usize> {
+        let total: usize = self.info.files.iter().map(|file| file.length).sum();
+        let start = index.checked_mul(self.info.piece_length)?;
+        (index < self.info.pieces.len())
+            .then(|| self.info.piece_length.min(total.saturating_sub(start)))
+    }
+
+    /// Drops the slowest connected peers of a stalled endgame, judged by their download
+    /// rate. They go on cooldown and have to be disconnected, so that fresh peers from
+    /// the next announce take their place
+    pub fn rotate_stalled_peers(&mut self, now: Instant) -> Option<Rotation> {
+        let rates: Vec<(SocketAddr, u64)> = self
+            .connected
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.download_rate(now)))
+            .collect();
+        let rotation = self.watchdog.check(now, self.picker.is_endgame(), &rates)?;
+        for addr in &rotation.dropped {
+            self.peers.mark_failed(*addr);
+        }
+        Some(rotation)
+    }
+}
+
+/// Regular announce in the middle of a download, new peers are queued right away and
+/// the addresses peers reported for us so far go along with it.
+/// The tracker is contacted without holding the download's lock
+fn reannounce(
+    shared: &Mutex<&mut Downloader>,
+    announcer: &mut Announcer,
+    tracker: &dyn TrackerClient,
+) {
+    {
+        let downloader = shared.lock().unwrap();
+        let ratio = downloader.ratio();
+        announcer
+            .params_mut()
+            .set_uploaded(ratio.uploaded() as usize)
+            .set_downloaded(ratio.downloaded() as usize)
+            .set_left(downloader.left());
+        announcer.observe_peer_ips(downloader.reported_ips());
+    }
+    match announcer.update(tracker) {
+        Ok(response) => {
+            shared.lock().unwrap().add_peers(response.peers);
+        }
+        Err(e) => log::warn!("announce failed: {e}"),
+    }
+}
+
+/// Writes a verified piece, corrupt ones are downloaded again
+fn store<S: PieceStorage>(
+    storage: &CachedStorage<S>,
+    outcome: PieceOutcome,
+) -> Result<(), StorageError> {
+    match outcome {
+        PieceOutcome::Verified(index, data) => storage.write_piece(index, &data),
+        PieceOutcome::Corrupt(index, sources) => {
+            log::debug!("piece {index} from {sources:?} failed its hash check");
+            Ok(())
         }
     }
 }
 
-pub struct Peering {
-    received: Arc<Mutex<mpsc::Receiver<Peer>>>,
+/// Transport of a peer connection whose socket can be shut down from another thread
+trait Socket: Read + Write + Send {
+    fn socket(&self) -> io::Result<TcpStream>;
+}
+
+impl Socket for TcpStream {
+    fn socket(&self) -> io::Result<TcpStream> {
+        self.try_clone()
+    }
+}
+
+impl Socket for MseStream {
+    fn socket(&self) -> io::Result<TcpStream> {
+        self.get_ref().try_clone()
+    }
+}
+
+/// Talks to the peer on a thread of `scope`, its socket is kept in `sockets` so the
+/// connection can be shut down. The peer's address is sent to `left` once it's gone
+fn spawn_peer<'scope, 'env, T, S>(
+    scope: &'scope thread::Scope<'scope, 'env>,
+    shared: &'env Mutex<&mut Downloader>,
+    storage: &'env CachedStorage<S>,
+    connection: PeerConnection<T>,
+    sockets: &mut HashMap<SocketAddr, TcpStream>,
+    left: &mpsc::Sender<SocketAddr>,
+) where
+    T: Socket + 'scope,
+    S: PieceStorage + Sync,
+{
+    // inbound sockets still have the read timeout of the handshake
+    let socket = connection.get_ref().socket().and_then(|socket| {
+        socket.set_read_timeout(None)?;
+        Ok((socket.peer_addr()?, socket))
+    });
+    let (addr, socket) = match socket {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::debug!("dropping peer connection: {e}");
+            return;
+        }
+    };
+    sockets.insert(addr, socket);
+    let left = left.clone();
+    scope.spawn(move || {
+        let result = exchange(shared, storage, connection, addr);
+        let mut downloader = shared.lock().unwrap();
+        downloader.peer_disconnected(&addr);
+        match result {
+            Ok(()) => downloader.peers.release(&addr),
+            Err(e) => {
+                log::debug!("{addr} disconnected: {e}");
+                downloader.peers.mark_failed(addr);
+            }
+        }
+        let _ = left.send(addr);
+    });
+}
+
+/// Message loop of a connected peer, ends when the connection fails or the peer got banned.
+/// Requests go out before every read, served blocks and `Have`s of pieces we completed
+/// meanwhile along with them. Every interested peer gets unchoked. Blocks in both
+/// directions wait for the rate limiter, outside the lock of the download
+fn exchange<T, S>(
+    shared: &Mutex<&mut Downloader>,
+    storage: &CachedStorage<S>,
+    mut connection: PeerConnection<T>,
+    addr: SocketAddr,
+) -> Result<(), ConnectionError>
+where
+    T: Socket,
+    S: PieceStorage,
+{
+    let (have, mut haves_sent, limiter) = {
+        let mut downloader = shared.lock().unwrap();
+        downloader.peer_connected(addr);
+        let have = downloader.picker.have().clone();
+        (have, downloader.completed.len(), downloader.limiter.clone())
+    };
+    connection.send_availability(&have)?;
+    let mut outgoing = Vec::new();
+    if connection.extension_protocol() {
+        let ours = PeerExtensionInfo {
+            your_ip: Some(addr.ip()),
+            ..PeerExtensionInfo::default()
+        };
+        outgoing.push(Message::Extended {
+            ext_id: 0,
+            payload: ours.to_handshake(),
+        });
+    }
+    let mut am_choking = true;
+    loop {
+        {
+            let mut downloader = shared.lock().unwrap();
+            let completed = downloader.completed_since(haves_sent);
+            haves_sent += completed.len();
+            outgoing.extend(completed.iter().map(|index| Message::Have(*index as u32)));
+            let depth = connection.pipeline_depth(PIPELINE_DEPTH);
+            outgoing.extend(downloader.requests_for(addr, depth));
+        }
+        for message in outgoing.drain(..) {
+            if let Message::Piece(piece) = &message {
+                limiter.acquire(piece.data().len());
+            }
+            connection.queue(message)?;
+        }
+        let message = connection.recv()?;
+        if let Message::Piece(piece) = &message {
+            limiter.acquire(piece.data().len());
+        }
+        let mut downloader = shared.lock().unwrap();
+        match &message {
+            Message::Piece(piece) => {
+                let (index, begin) = (piece.index() as usize, piece.begin() as usize);
+                if let Err(e) = downloader.block_received(addr, index, begin, piece.data()) {
+                    log::debug!("{addr} sent a bad block: {e}");
+                }
+            }
+            Message::Extended { ext_id: 0, .. } => {
+                if let Some(info) = connection.extension_info() {
+                    downloader.extension_handshake(addr, info);
+                }
+            }
+            Message::Interested if am_choking => {
+                am_choking = false;
+                outgoing.push(Message::UnChoke);
+            }
+            Message::Request(request) if !am_choking => {
+                let (index, begin) = (request.index() as usize, request.begin() as usize);
+                match downloader.serve_block(storage, index, begin, request.length() as usize) {
+                    Ok(block) => outgoing.push(Message::Piece(Piece::new(
+                        request.index(),
+                        request.begin(),
+                        block,
+                    ))),
+                    Err(_) if connection.fast_extension() => {
+                        outgoing.push(Message::RejectRequest(request.clone()))
+                    }
+                    Err(_) => {}
+                }
+            }
+            _ => {}
+        }
+        outgoing.extend(downloader.handle_peer_message(addr, &message)?);
+        if downloader.is_banned(&addr) {
+            return Ok(());
+        }
+    }
+}
+
+/// Opens outbound connections of a download, shared by the connecting threads
+struct Peering {
     peer_id: Arc<PeerId>,
     info: Arc<Info>,
+    config: Arc<Config>,
 }
 
 impl Peering {
-    fn connect(&self, peer: &Peer) -> Result<PeerConnection, ConnectionError> {
-        let tcp = TcpStream::connect_timeout(&peer.addr, Duration::from_secs(5))?;
-        let connection = PeerConnection::handshake(tcp, &self.info.info_hash, &self.peer_id)?;
+    /// Connects over any of `addrs`, which are the addresses the same peer is known by
+    fn connect(&self, addrs: &[SocketAddr]) -> Result<PeerConnection<MseStream>, ConnectionError> {
+        let connector = Connector::new(self.config.address_preference(), Duration::from_secs(5));
+        let mut connected_addr = None;
+        let stream = MseStream::establish(
+            || {
+                let (addr, stream) = connector.connect(addrs, |addr, timeout| {
+                    let stream = tcp_connect(addr, self.config.bind_address(), timeout)?;
+                    stream.set_nodelay(self.config.tcp_nodelay())?;
+                    Ok(stream)
+                })?;
+                connected_addr = Some(addr);
+                Ok(stream)
+            },
+            &self.info.info_hash,
+            self.config.encryption(),
+            self.config.handshake_timeout(),
+        )?;
+        let mut connection = PeerConnection::handshake_within(
+            stream,
+            &self.info.info_hash,
+            &self.peer_id,
+            self.config.handshake_timeout(),
+        )?;
+        if self.config.reject_bogus_peers() && connection.is_peer_id_bogus() {
+            return Err(ConnectionError::BogusPeerId(connection.peer_id().clone()));
+        }
+        if let Some(addr) = connected_addr {
+            connection.set_addr(addr);
+        }
+        connection.set_pieces_count(self.info.pieces.len());
         Ok(connection)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::announcer::Announcer;
+    use crate::client::piece::{PieceError, BLOCK_SIZE};
+    use crate::client::worker::{
+        reannounce, BitfieldPolicy, BlockOutcome, Downloader, PauseHandle, PeerState, PieceOutcome,
+        ENDGAME_STALL_TIMEOUT,
+    };
+    use crate::client::Config;
+    use crate::file::{File, Info};
+    use crate::peer::connection::{
+        BlockRequest, ConnectionError, HandshakeMessage, Message, PeerConnection, Piece,
+        ReservedBits,
+    };
+    use crate::peer::extension::PeerExtensionInfo;
+    use crate::peer::{Peer, PeerId};
+    use crate::storage::cache::CachedStorage;
+    use crate::storage::{PieceStorage, StorageError, StorageWriter};
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::AnnounceParameters;
+    use crate::util::{BitField, PieceBitfield, Sha1};
+    use sha1::Digest;
+    use std::fs;
+    use std::io;
+    use std::io::{Cursor, Read, Write};
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use url::Url;
+
+    /// Torrent with a file of every length, named by its index
+    fn info(lengths: &[usize], piece_length: usize, pieces: Vec<Sha1>) -> Info {
+        Info {
+            files: lengths
+                .iter()
+                .enumerate()
+                .map(|(index, length)| File::new(*length, PathBuf::from(index.to_string())))
+                .collect(),
+            name: PathBuf::from("torrent"),
+            info_hash: [1; 20],
+            piece_length,
+            pieces,
+        }
+    }
+
+    #[test]
+    fn corrupted_piece_requeued_instead_of_served() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..8).collect();
+        let info = info(
+            &[8],
+            4,
+            content
+                .chunks(4)
+                .map(|piece| sha1::Sha1::digest(piece).into())
+                .collect(),
+        );
+        let writer = StorageWriter::new(dir.path(), &info);
+        writer.create_files().unwrap();
+        writer.write_piece(0, &content[..4]).unwrap();
+        writer.write_piece(1, &content[4..]).unwrap();
+        let mut storage = CachedStorage::new(writer, 16);
+        storage.set_piece_hashes(info.info_hash, info.pieces.clone());
+
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        downloader.piece_verified(0);
+        downloader.piece_verified(1);
+        assert!(downloader.is_finished());
+
+        let path = dir.path().join("torrent").join("0");
+        fs::write(&path, [0, 1, 2, 3, 4, 5, 0xff, 7]).unwrap();
+        assert_eq!(
+            downloader.serve_block(&storage, 0, 0, 2).unwrap(),
+            vec![0, 1]
+        );
+        assert!(matches!(
+            downloader.serve_block(&storage, 1, 0, 2),
+            Err(StorageError::CorruptPiece(1))
+        ));
+        assert!(matches!(
+            downloader.serve_block(&storage, 1, 0, 2),
+            Err(StorageError::MissingPiece(1))
+        ));
+        assert!(!downloader.is_finished());
+        let seeder = PieceBitfield::from_bytes(&[0b1100_0000], 2);
+        assert_eq!(downloader.picker.pick(&seeder), Some(1));
+    }
+
+    #[test]
+    fn recheck_requeues_corrupted_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..12).collect();
+        let info = info(
+            &[12],
+            4,
+            content
+                .chunks(4)
+                .map(|piece| sha1::Sha1::digest(piece).into())
+                .collect(),
+        );
+        let writer = StorageWriter::new(dir.path(), &info);
+        writer.create_files().unwrap();
+        for (index, piece) in content.chunks(4).enumerate() {
+            writer.write_piece(index, piece).unwrap();
+        }
+
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let cancel = AtomicBool::new(false);
+        let mut reports = Vec::new();
+        let lost = downloader
+            .recheck(&writer, &cancel, |hashed, total| {
+                reports.push((hashed, total))
+            })
+            .unwrap();
+        assert!(lost.is_empty());
+        assert!(downloader.is_finished());
+        assert_eq!(reports, vec![(1, 3), (2, 3), (3, 3)]);
+
+        let path = dir.path().join("torrent").join("0");
+        let mut corrupted = content.clone();
+        corrupted[5] = 0xff;
+        fs::write(&path, corrupted).unwrap();
+        assert_eq!(
+            downloader.recheck(&writer, &cancel, |_, _| {}).unwrap(),
+            vec![1]
+        );
+        assert!(!downloader.picker.have().has(1));
+        assert!(downloader.picker.have().has(2));
+        let seeder = PieceBitfield::from_bytes(&[0b1110_0000], 3);
+        assert_eq!(downloader.picker.pick(&seeder), Some(1));
+
+        cancel.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            downloader.recheck(&writer, &cancel, |_, _| {}),
+            Err(StorageError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn piece_source_recorded() {
+        let content: Vec<u8> = (0..BLOCK_SIZE as u32 * 3).map(|i| i as u8).collect();
+        let piece_length = BLOCK_SIZE * 2;
+        let info = info(
+            &[content.len()],
+            piece_length,
+            content
+                .chunks(piece_length)
+                .map(|piece| sha1::Sha1::digest(piece).into())
+                .collect(),
+        );
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let [first, second, liar]: [SocketAddr; 3] =
+            ["1.1.1.1:1", "2.2.2.2:2", "3.3.3.3:3"].map(|addr| addr.parse().unwrap());
+
+        let (head, tail) = content.split_at(BLOCK_SIZE);
+        assert_eq!(
+            downloader.block_received(first, 0, 0, head),
+            Ok(BlockOutcome::Partial)
+        );
+        assert_eq!(
+            downloader.block_received(second, 0, 5, head),
+            Err(PieceError::OutOfRange(5))
+        );
+        assert_eq!(
+            downloader.block_received(second, 0, BLOCK_SIZE, &tail[..BLOCK_SIZE]),
+            Ok(BlockOutcome::Hashing)
+        );
+        // the piece is being verified, a duplicate block is dropped
+        assert_eq!(
+            downloader.block_received(second, 0, 0, head),
+            Ok(BlockOutcome::Partial)
+        );
+        assert_eq!(
+            downloader.wait_hashed(),
+            Some(PieceOutcome::Verified(0, content[..piece_length].to_vec()))
+        );
+        assert_eq!(
+            downloader.block_received(liar, 1, 0, &[0; BLOCK_SIZE]),
+            Ok(BlockOutcome::Hashing)
+        );
+        assert_eq!(
+            downloader.wait_hashed(),
+            Some(PieceOutcome::Corrupt(1, vec![liar]))
+        );
+        assert_eq!(
+            downloader.block_received(second, 1, 0, &content[piece_length..]),
+            Ok(BlockOutcome::Hashing)
+        );
+        assert_eq!(
+            downloader.wait_hashed(),
+            Some(PieceOutcome::Verified(1, content[piece_length..].to_vec()))
+        );
+        assert_eq!(downloader.wait_hashed(), None);
+        assert_eq!(
+            downloader.block_received(second, 2, 0, head),
+            Err(PieceError::InvalidIndex(2))
+        );
+
+        assert_eq!(
+            downloader.piece_sources(0),
+            Some([first, second].as_slice())
+        );
+        let progress = downloader.progress();
+        assert_eq!(progress.pieces_have, 2);
+        assert_eq!(progress.piece_sources[&1], vec![second]);
+        assert_eq!(downloader.ratio().downloaded(), BLOCK_SIZE as u64 * 4);
+    }
+
+    #[test]
+    fn hash_failure_strikes_implicated_peers() {
+        let piece_length = BLOCK_SIZE * 2;
+        let info = info(
+            &[piece_length],
+            piece_length,
+            vec![sha1::Sha1::digest(vec![1; piece_length]).into()],
+        );
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let [honest, liar, other]: [SocketAddr; 3] =
+            ["1.1.1.1:1", "2.2.2.2:2", "3.3.3.3:3"].map(|addr| addr.parse().unwrap());
+        let good = vec![1; BLOCK_SIZE];
+        let bad = vec![0; BLOCK_SIZE];
+
+        downloader.block_received(honest, 0, 0, &good).unwrap();
+        downloader
+            .block_received(liar, 0, BLOCK_SIZE, &bad)
+            .unwrap();
+        assert_eq!(
+            downloader.wait_hashed(),
+            Some(PieceOutcome::Corrupt(0, vec![honest, liar]))
+        );
+        // can't tell which of the two lied yet
+        assert_eq!(downloader.peers.strikes(&honest), 0);
+        assert_eq!(downloader.peers.strikes(&liar), 0);
+
+        downloader.block_received(other, 0, 0, &good).unwrap();
+        downloader
+            .block_received(liar, 0, BLOCK_SIZE, &bad)
+            .unwrap();
+        downloader.wait_hashed();
+        assert_eq!(downloader.peers.strikes(&liar), 1);
+        assert_eq!(downloader.peers.strikes(&other), 0);
+        assert_eq!(downloader.peers.strikes(&honest), 0);
+
+        // a peer that sent the whole piece is to blame on its own
+        downloader.block_received(liar, 0, 0, &bad).unwrap();
+        downloader
+            .block_received(liar, 0, BLOCK_SIZE, &bad)
+            .unwrap();
+        downloader.wait_hashed();
+        assert_eq!(downloader.peers.strikes(&liar), 2);
+    }
+
+    #[test]
+    fn seeding_stops_at_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..8).collect();
+        let info = info(
+            &[8],
+            4,
+            content
+                .chunks(4)
+                .map(|piece| sha1::Sha1::digest(piece).into())
+                .collect(),
+        );
+        let writer = StorageWriter::new(dir.path(), &info);
+        writer.create_files().unwrap();
+        writer.write_piece(0, &content[..4]).unwrap();
+        writer.write_piece(1, &content[4..]).unwrap();
+        let storage = CachedStorage::new(writer, 16);
+        let mut config = Config::new(1).unwrap();
+        config.set_seed_ratio_limit(Some(0.5));
+        let mut downloader = Downloader::new([], info, Arc::new(PeerId::random()), config);
+        let peer: SocketAddr = "1.1.1.1:1".parse().unwrap();
+
+        for (index, piece) in content.chunks(4).enumerate() {
+            downloader.block_received(peer, index, 0, piece).unwrap();
+            downloader.wait_hashed();
+        }
+        assert!(downloader.is_finished());
+        downloader.serve_block(&storage, 0, 0, 2).unwrap();
+        assert!(!downloader.seed_limit_reached());
+        downloader.serve_block(&storage, 1, 2, 2).unwrap();
+        assert_eq!(downloader.ratio().ratio(), Some(0.5));
+        assert!(downloader.seed_limit_reached());
+    }
+
+    #[test]
+    fn paused_download_sends_no_requests() {
+        let info = info(&[BLOCK_SIZE * 3], BLOCK_SIZE * 3, vec![[0; 20]]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let pause = PauseHandle::default();
+        downloader.set_pause(pause.clone());
+        let mut peer = PeerState::new(1);
+        peer.has.set(0);
+        peer.peer_choking = false;
+
+        pause.pause();
+        assert!(downloader.next_requests(&mut peer, 2).is_empty());
+        assert!(downloader.progress().paused);
+
+        pause.resume();
+        let begins: Vec<u32> = downloader
+            .next_requests(&mut peer, 2)
+            .iter()
+            .map(|message| match message {
+                Message::Request(request) => request.begin(),
+                other => panic!("unexpected {other}"),
+            })
+            .collect();
+        assert_eq!(begins, vec![0, BLOCK_SIZE as u32]);
+        assert!(!downloader.progress().paused);
+        // pipeline is full until a block arrives
+        assert!(downloader.next_requests(&mut peer, 2).is_empty());
+    }
+
+    #[test]
+    fn requests_alternate_between_pieces() {
+        let info = info(&[BLOCK_SIZE * 6], BLOCK_SIZE * 3, vec![[0; 20]; 2]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let unchoked = |pieces: &[usize]| {
+            let mut peer = PeerState::new(2);
+            pieces.iter().for_each(|index| peer.has.set(*index));
+            peer.peer_choking = false;
+            peer
+        };
+        let blocks = |requests: Vec<Message>| -> Vec<(u32, u32)> {
+            requests
+                .iter()
+                .map(|message| match message {
+                    Message::Request(request) => {
+                        (request.index(), request.begin() / BLOCK_SIZE as u32)
+                    }
+                    other => panic!("unexpected {other}"),
+                })
+                .collect()
+        };
+        // other peers put both pieces in flight
+        downloader.next_requests(&mut unchoked(&[0]), 1);
+        downloader.next_requests(&mut unchoked(&[1]), 1);
+
+        let mut peer = unchoked(&[0, 1]);
+        assert_eq!(
+            blocks(downloader.next_requests(&mut peer, 4)),
+            vec![(0, 0), (1, 0), (0, 1), (1, 1)]
+        );
+        assert_eq!(
+            blocks(downloader.next_requests(&mut peer, 6)),
+            vec![(0, 2), (1, 2)]
+        );
+    }
 
-    fn run(&mut self) {
-        let ch = self.received.lock().unwrap();
-        if let Ok(peer) = ch.recv() {
-            // if let Ok(conn) = self.connect(&peer) {
-            //     self.work(conn);
-            // }
+    #[test]
+    fn rejected_request_requeued() {
+        let info = info(&[BLOCK_SIZE * 2], BLOCK_SIZE * 2, vec![[0; 20]]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let mut peer = PeerState::new(1);
+        peer.has.set(0);
+        peer.peer_choking = false;
+        assert_eq!(downloader.next_requests(&mut peer, 2).len(), 2);
+        assert!(downloader.next_requests(&mut peer, 2).is_empty());
+
+        let reject = Message::RejectRequest(BlockRequest::new(0, BLOCK_SIZE as u32, 42));
+        assert!(downloader
+            .handle_message(&mut peer, &reject)
+            .unwrap()
+            .is_none());
+        assert_eq!(peer.stats.pending(), 1);
+        let requests = downloader.next_requests(&mut peer, 2);
+        assert!(matches!(
+            requests.as_slice(),
+            [Message::Request(request)] if request.begin() == BLOCK_SIZE as u32
+        ));
+    }
+
+    #[test]
+    fn left_counts_selected_pieces() {
+        let info = info(&[10, 30, 9], 16, vec![[0; 20]; 4]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        assert_eq!(downloader.left(), 49);
+        downloader.piece_verified(2);
+        assert_eq!(downloader.left(), 33);
+
+        // last file is bytes 40..49, the tail of piece 2 and the short piece 3
+        downloader.select_files(&[2]);
+        assert_eq!(downloader.left(), 1);
+        assert!(!downloader.is_finished());
+        assert!(downloader.piece_verified(3));
+        assert_eq!(downloader.left(), 0);
+    }
+
+    #[test]
+    fn partial_piece_salvaged_after_disconnect() {
+        let content: Vec<u8> = (0..BLOCK_SIZE as u32 * 4).map(|i| i as u8).collect();
+        let info = info(
+            &[content.len()],
+            content.len(),
+            vec![sha1::Sha1::digest(&content).into()],
+        );
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let [first, second]: [SocketAddr; 2] =
+            ["1.1.1.1:1", "2.2.2.2:2"].map(|addr| addr.parse().unwrap());
+        let begins = |requests: Vec<Message>| -> Vec<usize> {
+            requests
+                .iter()
+                .map(|message| match message {
+                    Message::Request(request) => request.begin() as usize,
+                    other => panic!("unexpected {other}"),
+                })
+                .collect()
+        };
+        let unchoked = || {
+            let mut peer = PeerState::new(1);
+            peer.has.set(0);
+            peer.peer_choking = false;
+            peer
+        };
+
+        let mut peer = unchoked();
+        assert_eq!(begins(downloader.next_requests(&mut peer, 4)).len(), 4);
+        for begin in [0, BLOCK_SIZE] {
+            let block = &content[begin..begin + BLOCK_SIZE];
+            downloader.block_received(first, 0, begin, block).unwrap();
         }
+        // connection reset before the other half arrived
+        downloader.peer_disconnected(&first);
+
+        let mut peer = unchoked();
+        let requested = begins(downloader.next_requests(&mut peer, 4));
+        assert_eq!(requested, vec![BLOCK_SIZE * 2, BLOCK_SIZE * 3]);
+        downloader
+            .block_received(
+                second,
+                0,
+                BLOCK_SIZE * 2,
+                &content[BLOCK_SIZE * 2..BLOCK_SIZE * 3],
+            )
+            .unwrap();
+        assert_eq!(
+            downloader.block_received(second, 0, BLOCK_SIZE * 3, &content[BLOCK_SIZE * 3..]),
+            Ok(BlockOutcome::Hashing)
+        );
+        assert_eq!(
+            downloader.wait_hashed(),
+            Some(PieceOutcome::Verified(0, content))
+        );
+        assert_eq!(
+            downloader.piece_sources(0),
+            Some([first, second].as_slice())
+        );
     }
 
-    fn work(&mut self, conn: PeerConnection) {}
+    struct ScriptedPeer {
+        input: Cursor<Vec<u8>>,
+    }
+
+    impl Read for ScriptedPeer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for ScriptedPeer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keep_alive_only_peer_dropped() {
+        let info_hash = [1; 20];
+        let info = info(&[4], 4, vec![[0; 20]]);
+        let mut config = Config::new(1).unwrap();
+        config.set_peer_idle_timeout(Duration::from_secs(120));
+        let mut downloader = Downloader::new([], info, Arc::new(PeerId::random()), config);
+
+        let mut input = HandshakeMessage::new(ReservedBits::default(), info_hash, PeerId::random())
+            .to_bytes()
+            .to_vec();
+        input.extend_from_slice(&[0; 4 * 5]);
+        let transport = ScriptedPeer {
+            input: Cursor::new(input),
+        };
+        let mut connection =
+            PeerConnection::handshake(transport, info_hash, &PeerId::random()).unwrap();
+        let addr: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let mut peer = PeerState::new(1);
+        while let Ok(message) = connection.recv() {
+            assert!(matches!(message, Message::KeepAlive));
+            assert!(downloader
+                .handle_message(&mut peer, &message)
+                .unwrap()
+                .is_none());
+        }
+
+        let start = peer.last_progress;
+        assert!(!downloader.drop_if_idle(addr, &peer, start + Duration::from_secs(60)));
+        assert!(downloader.drop_if_idle(addr, &peer, start + Duration::from_secs(120)));
+
+        // unchoking counts as progress
+        downloader
+            .handle_message(&mut peer, &Message::UnChoke)
+            .unwrap();
+        assert!(!downloader.is_idle(&peer, peer.last_progress + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn have_makes_us_interested() {
+        let info = info(&[12], 4, vec![[0; 20]; 3]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        downloader.piece_verified(0);
+        let mut peer = PeerState::new(3);
+
+        let empty = Message::Bitfield(vec![BitField::new(0)]);
+        assert!(downloader
+            .handle_message(&mut peer, &empty)
+            .unwrap()
+            .is_none());
+        // a piece we already have doesn't change anything
+        assert!(downloader
+            .handle_message(&mut peer, &Message::Have(0))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            downloader.handle_message(&mut peer, &Message::Have(2)),
+            Ok(Some(Message::Interested))
+        ));
+        assert!(peer.am_interested);
+        assert!(peer.has.has(2));
+        assert!(downloader
+            .handle_message(&mut peer, &Message::Have(2))
+            .unwrap()
+            .is_none());
+        assert!(downloader
+            .handle_message(&mut peer, &Message::Have(7))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn stalled_endgame_drops_slowest_peers() {
+        let info = info(&[8], 4, vec![[0; 20]; 2]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        downloader.piece_verified(0);
+        let [fast, slow, stalled]: [SocketAddr; 3] =
+            ["1.1.1.1:1", "2.2.2.2:2", "3.3.3.3:3"].map(|addr| addr.parse().unwrap());
+        for addr in [fast, slow, stalled] {
+            downloader.peer_connected(addr);
+            downloader
+                .handle_peer_message(addr, &Message::HaveAll)
+                .unwrap();
+        }
+        downloader
+            .handle_peer_message(fast, &Message::UnChoke)
+            .unwrap();
+        assert!(!downloader.requests_for(fast, 1).is_empty());
+        let piece = Message::Piece(Piece::new(1, 0, vec![0; 2]));
+        downloader.handle_peer_message(fast, &piece).unwrap();
+        assert_eq!(downloader.connected[&fast].downloaded, 2);
+        downloader.connected.get_mut(&fast).unwrap().downloaded = 6000;
+        downloader.connected.get_mut(&slow).unwrap().downloaded = 600;
+
+        let now = Instant::now();
+        assert_eq!(downloader.rotate_stalled_peers(now), None);
+        let rotation = downloader
+            .rotate_stalled_peers(now + ENDGAME_STALL_TIMEOUT)
+            .unwrap();
+        assert_eq!(rotation.dropped, vec![stalled, slow]);
+        assert!(rotation.reannounce);
+        // the dropped peers cool down before they may be queued again
+        assert_eq!(downloader.add_peers([Peer::new(None, stalled)]), 0);
+        assert_eq!(downloader.add_peers([Peer::new(None, slow)]), 0);
+    }
+
+    #[test]
+    fn protocol_violations_ban_peer() {
+        let info = info(&[12], 4, vec![[0; 20]; 3]);
+        let mut config = Config::new(1).unwrap();
+        config.set_max_peer_strikes(Some(1));
+        let mut downloader = Downloader::new([], info, Arc::new(PeerId::random()), config);
+        let addr: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let bitfield = Message::Bitfield(vec![BitField::new(0b1010_0000)]);
+
+        downloader.peer_connected(addr);
+        downloader
+            .handle_peer_message(addr, &Message::UnChoke)
+            .unwrap();
+        assert!(downloader.handle_peer_message(addr, &bitfield).is_err());
+        assert!(!downloader.is_banned(&addr));
+        assert!(downloader.handle_peer_message(addr, &bitfield).is_err());
+        assert!(downloader.is_banned(&addr));
+        assert!(!downloader.peer_has_piece(&addr, 0));
+        assert_eq!(downloader.peers.merge([Peer::new(None, addr)]), 0);
+    }
+
+    #[test]
+    fn malformed_bitfield_rejected() {
+        let info = info(&[40], 4, vec![[0; 20]; 10]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let [long, spare]: [SocketAddr; 2] =
+            ["1.1.1.1:1", "2.2.2.2:2"].map(|addr| addr.parse().unwrap());
+        downloader.peer_connected(long);
+        downloader.peer_connected(spare);
+
+        let over_long = Message::Bitfield(vec![BitField::new(0xff); 3]);
+        assert!(matches!(
+            downloader.handle_peer_message(long, &over_long),
+            Err(ConnectionError::InvalidBitfield(3, 10))
+        ));
+        // pieces 10 to 15 don't exist
+        let spare_bits = Message::Bitfield(vec![BitField::new(0xff), BitField::new(0b1100_0001)]);
+        assert!(matches!(
+            downloader.handle_peer_message(spare, &spare_bits),
+            Err(ConnectionError::InvalidBitfield(2, 10))
+        ));
+        assert!(!downloader.peer_has_piece(&long, 0));
+        assert!(!downloader.peer_has_piece(&spare, 0));
+        // rejected bitfields count against the peer
+        assert_eq!(downloader.peers.strikes(&long), 1);
+    }
+
+    #[test]
+    fn late_bitfield_policy() {
+        let info = || info(&[12], 4, vec![[0; 20]; 3]);
+        let bitfield = Message::Bitfield(vec![BitField::new(0b1010_0000)]);
+        let mut strict = Downloader::new(
+            [],
+            info(),
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+
+        let mut peer = PeerState::new(3);
+        assert!(matches!(
+            strict.handle_message(&mut peer, &bitfield),
+            Ok(Some(Message::Interested))
+        ));
+        assert_eq!(peer.has.iter_set().collect::<Vec<_>>(), vec![0, 2]);
+
+        let mut peer = PeerState::new(3);
+        strict.handle_message(&mut peer, &Message::UnChoke).unwrap();
+        assert!(matches!(
+            strict.handle_message(&mut peer, &bitfield),
+            Err(ConnectionError::LateBitfield)
+        ));
+
+        let mut config = Config::new(1).unwrap();
+        config.set_bitfield_policy(BitfieldPolicy::Lenient);
+        let mut lenient = Downloader::new([], info(), Arc::new(PeerId::random()), config);
+        let mut peer = PeerState::new(3);
+        lenient
+            .handle_message(&mut peer, &Message::UnChoke)
+            .unwrap();
+        lenient
+            .handle_message(&mut peer, &Message::Have(1))
+            .unwrap();
+        lenient.handle_message(&mut peer, &bitfield).unwrap();
+        assert_eq!(peer.has.iter_set().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn reported_ips_in_regular_announce() {
+        let info = info(&[8], 4, vec![[0; 20]; 2]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let reported: IpAddr = "203.0.113.7".parse().unwrap();
+        for port in 1..=3 {
+            let handshake = PeerExtensionInfo {
+                your_ip: Some(reported),
+                ..PeerExtensionInfo::default()
+            };
+            downloader.extension_handshake(SocketAddr::new(reported, port), &handshake);
+        }
+        let tracker = MockTracker::default();
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut announcer = Announcer::new(vec![vec![url]], AnnounceParameters::new([1; 20]));
+        announcer.set_announce_external_ip(true);
+
+        reannounce(&Mutex::new(&mut downloader), &mut announcer, &tracker);
+        assert_eq!(tracker.announces()[0].1.ip(), Some(reported));
+    }
+
+    #[test]
+    fn query_connected_peer_pieces() {
+        let info = info(&[40], 4, vec![[0; 20]; 10]);
+        let mut downloader = Downloader::new(
+            [],
+            info,
+            Arc::new(PeerId::random()),
+            Config::new(1).unwrap(),
+        );
+        let [peer, other]: [SocketAddr; 2] =
+            ["1.1.1.1:1", "2.2.2.2:2"].map(|addr| addr.parse().unwrap());
+        downloader.peer_connected(peer);
+        downloader.peer_connected(other);
+
+        let bitfield = Message::Bitfield(vec![BitField::new(0b1000_0001), BitField::new(0)]);
+        downloader.handle_peer_message(peer, &bitfield).unwrap();
+        downloader
+            .handle_peer_message(peer, &Message::Have(9))
+            .unwrap();
+        for (index, has) in [(0, true), (1, false), (7, true), (8, false), (9, true)] {
+            assert_eq!(
+                downloader.peer_has_piece(&peer, index),
+                has,
+                "piece {index}"
+            );
+        }
+        assert!(!downloader.peer_has_piece(&other, 0));
+
+        downloader.peer_disconnected(&peer);
+        assert!(!downloader.peer_has_piece(&peer, 0));
+    }
 }
@@ -0,0 +1,213 @@
+use crate::file::TorrentError::{EmptyTorrent, IntegerOutOfBound, NonUtf8Path};
+use crate::file::{Result, TorrentFile};
+use bencode::{BencodeDict, BencodeList, Value};
+use sha1::Digest;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// Auto-chosen piece length aims for roughly this many pieces
+const TARGET_PIECE_COUNT: usize = 1500;
+
+/// Power of two piece length giving about [`TARGET_PIECE_COUNT`] pieces
+pub fn auto_piece_length(total_length: usize) -> usize {
+    (total_length / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Creates a torrent of a single file, or of every file under a directory in path order.
+/// Symlinks inside the directory are skipped. Piece length is chosen from the total size
+/// unless given
+pub fn create_torrent(
+    root: &Path,
+    piece_length: Option<usize>,
+    announce: Url,
+) -> Result<TorrentFile> {
+    if piece_length == Some(0) {
+        return Err(IntegerOutOfBound("piece length".to_string()));
+    }
+    let name = path_string(root.file_name().map(Path::new).unwrap_or(root))?;
+    let single_file = fs::metadata(root)?.is_file();
+    let files = if single_file {
+        vec![root.to_path_buf()]
+    } else {
+        let mut files = Vec::new();
+        walk(root, &mut files)?;
+        files
+    };
+    if files.is_empty() {
+        return Err(EmptyTorrent);
+    }
+    let lengths = files
+        .iter()
+        .map(|file| Ok(fs::metadata(file)?.len() as usize))
+        .collect::<Result<Vec<usize>>>()?;
+    let piece_length = piece_length.unwrap_or_else(|| auto_piece_length(lengths.iter().sum()));
+
+    let mut info = BencodeDict::new();
+    info.insert(b"name".to_vec(), Value::String(name.into_bytes()));
+    info.insert(b"piece length".to_vec(), Value::Int(piece_length as i64));
+    info.insert(
+        b"pieces".to_vec(),
+        Value::String(hash_pieces(&files, piece_length)?),
+    );
+    if single_file {
+        info.insert(b"length".to_vec(), Value::Int(lengths[0] as i64));
+    } else {
+        let mut entries = BencodeList::new();
+        for (file, length) in files.iter().zip(lengths) {
+            let path = file
+                .strip_prefix(root)
+                .expect("walked files are under the root")
+                .iter()
+                .map(|component| Ok(Value::String(path_string(Path::new(component))?.into())))
+                .collect::<Result<BencodeList>>()?;
+            let mut entry = BencodeDict::new();
+            entry.insert(b"length".to_vec(), Value::Int(length as i64));
+            entry.insert(b"path".to_vec(), Value::List(path));
+            entries.push(Value::Dict(entry));
+        }
+        info.insert(b"files".to_vec(), Value::List(entries));
+    }
+
+    let mut dict = BencodeDict::new();
+    dict.insert(
+        b"announce".to_vec(),
+        Value::String(announce.as_str().into()),
+    );
+    dict.insert(b"info".to_vec(), Value::Dict(info));
+    TorrentFile::from_bencode(dict)
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for path in entries {
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_dir() {
+            walk(&path, files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Concatenated SHA-1 of every piece, pieces span file boundaries
+fn hash_pieces(files: &[PathBuf], piece_length: usize) -> Result<Vec<u8>> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+    for path in files {
+        let mut file = fs::File::open(path)?;
+        loop {
+            let missing = piece_length - piece.len();
+            let read = (&mut file).take(missing as u64).read_to_end(&mut piece)?;
+            if piece.len() == piece_length {
+                pieces.extend_from_slice(&sha1::Sha1::digest(&piece));
+                piece.clear();
+            }
+            if read < missing {
+                break;
+            }
+        }
+    }
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&sha1::Sha1::digest(&piece));
+    }
+    Ok(pieces)
+}
+
+fn path_string(path: &Path) -> Result<String> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| NonUtf8Path(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::create::{auto_piece_length, create_torrent};
+    use crate::file::TorrentError;
+    use sha1::Digest;
+    use std::fs;
+    use std::path::PathBuf;
+    use url::Url;
+
+    #[test]
+    fn torrent_from_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let first: Vec<u8> = (0..10).collect();
+        let second: Vec<u8> = (10..30).collect();
+        fs::write(root.join("a.bin"), &first).unwrap();
+        fs::write(root.join("sub").join("b.bin"), &second).unwrap();
+
+        let announce = Url::parse("http://tracker.example/announce").unwrap();
+        let torrent = create_torrent(&root, Some(16), announce.clone()).unwrap();
+        assert_eq!(torrent.announce, Some(announce));
+        let info = torrent.info;
+        assert_eq!(info.name, PathBuf::from("content"));
+        let files: Vec<(PathBuf, usize)> = info
+            .files
+            .iter()
+            .map(|file| (file.path.clone(), file.length))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("a.bin"), 10),
+                (PathBuf::from("sub/b.bin"), 20),
+            ]
+        );
+        let content = [first, second].concat();
+        let expected: Vec<[u8; 20]> = content
+            .chunks(16)
+            .map(|piece| sha1::Sha1::digest(piece).into())
+            .collect();
+        assert_eq!(info.pieces, expected);
+
+        assert_eq!(auto_piece_length(0), 16 * 1024);
+        assert_eq!(auto_piece_length(4 << 30), 4 << 20);
+    }
+
+    #[test]
+    fn zero_piece_length_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.bin");
+        fs::write(&file, [1, 2, 3]).unwrap();
+        let announce = Url::parse("http://tracker.example/announce").unwrap();
+        assert!(matches!(
+            create_torrent(&file, Some(0), announce),
+            Err(TorrentError::IntegerOutOfBound(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a.bin"), [1, 2, 3]).unwrap();
+        fs::write(dir.path().join("secret"), [4, 5, 6]).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret"), root.join("link")).unwrap();
+        // a loop would recurse forever if followed
+        std::os::unix::fs::symlink(&root, root.join("loop")).unwrap();
+
+        let announce = Url::parse("http://tracker.example/announce").unwrap();
+        let torrent = create_torrent(&root, Some(16), announce).unwrap();
+        let paths: Vec<PathBuf> = torrent
+            .info
+            .files
+            .iter()
+            .map(|file| file.path.clone())
+            .collect();
+        assert_eq!(paths, vec![PathBuf::from("a.bin")]);
+    }
+}
@@ -1,4 +1,8 @@
-use std::path::PathBuf;
+pub mod create;
+pub mod validate;
+
+use std::ops::Range;
+use std::path::{Component, PathBuf};
 
 use sha1::Digest;
 use thiserror::Error;
@@ -6,14 +10,22 @@ use url::Url;
 
 use bencode::{BencodeEncoder, BencodeError, BencodeList, BencodeString, Value};
 
-use crate::file::TorrentError::{IntegerOutOfBound, InvalidInfoHash, MissingField};
-use crate::util::Sha1;
+use crate::file::TorrentError::{
+    AmbiguousFileMode, IntegerOutOfBound, InvalidInfoHash, MissingField, NoPeerSource,
+    PieceLengthExceedsContent, UnsafePath,
+};
+use crate::util::{PieceBitfield, Sha1};
 
 type Result<T> = std::result::Result<T, TorrentError>;
 
 #[derive(Debug)]
 pub struct TorrentFile {
-    pub announce: Url,
+    /// `None` for a trackerless torrent, its peers come from the DHT
+    pub announce: Option<Url>,
+    /// Tiers of trackers, see BEP 12. Takes the place of `announce` when present
+    pub announce_list: Vec<Vec<Url>>,
+    /// DHT bootstrap nodes as host and port, see BEP 5
+    pub nodes: Vec<(String, u16)>,
     pub info: Info,
 }
 
@@ -30,6 +42,45 @@ pub struct Info {
 pub struct File {
     pub length: usize,
     pub path: PathBuf,
+    pub attr: FileAttributes,
+    /// Target of the symlink relative to the torrent root, see BEP 47
+    pub symlink_path: Option<PathBuf>,
+}
+
+/// File of a torrent as shown for selection, see [`TorrentFile::file_list`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    /// Position in [`Info::files`], what file selection and priorities refer to
+    pub index: usize,
+    /// Path on disk relative to the download directory, the torrent name included
+    pub path: PathBuf,
+    pub length: usize,
+}
+
+/// BEP 47 `attr` flags of a file
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FileAttributes {
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl From<&str> for FileAttributes {
+    fn from(value: &str) -> Self {
+        let mut attr = Self::default();
+        for flag in value.chars() {
+            match flag {
+                'p' => attr.padding = true,
+                'x' => attr.executable = true,
+                'h' => attr.hidden = true,
+                'l' => attr.symlink = true,
+                // unknown flags must be ignored
+                _ => {}
+            }
+        }
+        attr
+    }
 }
 
 #[derive(Error, Debug)]
@@ -48,6 +99,20 @@ pub enum TorrentError {
     InvalidFileList,
     #[error("Integer out of bounds for field {0}")]
     IntegerOutOfBound(String),
+    #[error("Info has both 'length' and 'files', it's neither single- nor multi-file")]
+    AmbiguousFileMode,
+    #[error("Io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Path {0:?} is not valid UTF-8")]
+    NonUtf8Path(PathBuf),
+    #[error("No files to create a torrent from")]
+    EmptyTorrent,
+    #[error("Torrent has neither 'announce' nor 'nodes', there is no way to find peers")]
+    NoPeerSource,
+    #[error("Piece length {0} exceeds the content length {1}")]
+    PieceLengthExceedsContent(usize, usize),
+    #[error("Path {0:?} escapes the download directory")]
+    UnsafePath(PathBuf),
 }
 
 // Byte sequence as slice :)
@@ -59,28 +124,102 @@ macro_rules! bss {
 
 impl TorrentFile {
     pub fn from_bencode(mut dict: bencode::BencodeDict) -> Result<Self> {
-        let announce = Url::parse(&String::try_from(
-            dict.remove(bss!(b"announce"))
-                .ok_or(MissingField("announce".to_string()))?,
-        )?)?;
+        let announce = match dict.remove(bss!(b"announce")) {
+            Some(announce) => Some(Url::parse(&String::try_from(announce)?)?),
+            None => None,
+        };
+        let announce_list = match dict.remove(bss!(b"announce-list")) {
+            Some(tiers) => Self::parse_announce_list(tiers.try_into()?),
+            None => Vec::new(),
+        };
+        let nodes = match dict.remove(bss!(b"nodes")) {
+            Some(nodes) => Self::parse_nodes(nodes.try_into()?),
+            None => Vec::new(),
+        };
+        if announce.is_none() && announce_list.is_empty() && nodes.is_empty() {
+            return Err(NoPeerSource);
+        }
         let info = Info::from_bencode(
             dict.remove(bss!(b"info"))
                 .ok_or(MissingField("info".to_string()))?
                 .try_into()?,
         )?;
-        Ok(Self { announce, info })
+        Ok(Self {
+            announce,
+            announce_list,
+            nodes,
+            info,
+        })
+    }
+
+    /// Lists of tracker URLs, malformed URLs and the tiers left empty are skipped
+    fn parse_announce_list(tiers: BencodeList) -> Vec<Vec<Url>> {
+        tiers
+            .into_iter()
+            .filter_map(|tier| BencodeList::try_from(tier).ok())
+            .map(|tier| {
+                tier.into_iter()
+                    .filter_map(|url| Url::parse(&String::try_from(url).ok()?).ok())
+                    .collect::<Vec<Url>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect()
+    }
+
+    /// Tiers the announces go to, `announce` alone is the only tier of a torrent
+    /// without an announce list
+    pub fn tracker_tiers(&self) -> Vec<Vec<Url>> {
+        if !self.announce_list.is_empty() {
+            return self.announce_list.clone();
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+
+    /// `[host, port]` pairs, malformed entries are skipped
+    fn parse_nodes(nodes: BencodeList) -> Vec<(String, u16)> {
+        nodes
+            .into_iter()
+            .filter_map(|node| {
+                let mut node = BencodeList::try_from(node).ok()?.into_iter();
+                let host = String::try_from(node.next()?).ok()?;
+                let port = u16::try_from(node.next()?).ok()?;
+                Some((host, port))
+            })
+            .collect()
+    }
+
+    /// Files of the torrent, padding files left out
+    pub fn file_list(&self) -> Vec<FileEntry> {
+        self.info
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| !file.attr.padding)
+            .map(|(index, file)| FileEntry {
+                index,
+                path: self.info.name.join(&file.path),
+                length: file.length,
+            })
+            .collect()
+    }
+
+    /// Length of the content, padding files included
+    pub fn total_length(&self) -> usize {
+        self.info.files.iter().map(|file| file.length).sum()
     }
 }
 
 impl Info {
     pub fn from_bencode(mut dict: bencode::BencodeDict) -> Result<Self> {
+        // a decoded dict is sorted already, a torrent whose raw info isn't canonical
+        // hashes differently, which is what `TorrentFile::validate` reports
         let mut raw_info = Vec::new();
         BencodeEncoder::new(&mut raw_info).encode_dict(&dict);
         let info_hash = sha1::Sha1::digest(raw_info.as_slice()).into();
-        let mut name = PathBuf::from(String::try_from(
+        let mut name = safe_path(PathBuf::from(String::try_from(
             dict.remove(bss!(b"name"))
                 .ok_or(MissingField("name".to_string()))?,
-        )?);
+        )?))?;
         let piece_length = usize::try_from(i64::try_from(
             dict.remove(bss!(b"piece length"))
                 .ok_or(MissingField("piece length".to_string()))?,
@@ -90,7 +229,7 @@ impl Info {
             .remove(bss!(b"pieces"))
             .ok_or(MissingField("pieces".to_string()))?
             .try_into()?;
-        if pieces.len() % 20 != 0 {
+        if !pieces.len().is_multiple_of(20) {
             return Err(InvalidInfoHash);
         }
         let pieces: Vec<[u8; 20]> = pieces
@@ -98,12 +237,22 @@ impl Info {
             .map(|chunk| <[u8; 20]>::try_from(chunk).unwrap())
             .collect();
 
+        if dict.contains_key(bss!(b"length")) && dict.contains_key(bss!(b"files")) {
+            return Err(AmbiguousFileMode);
+        }
         let mut files = vec![];
-        if let Some(Value::Int(length)) = dict.get(bss!(b"length")) {
+        if let Some(length) = dict.get(bss!(b"length")).and_then(Value::as_int) {
             // Single file mode
             let length =
-                usize::try_from(*length).map_err(|_| IntegerOutOfBound(String::from("length")))?;
-            files.push(File { length, path: name });
+                usize::try_from(length).map_err(|_| IntegerOutOfBound(String::from("length")))?;
+            let attr = match dict.remove(bss!(b"attr")) {
+                Some(attr) => FileAttributes::from(String::try_from(attr)?.as_str()),
+                None => FileAttributes::default(),
+            };
+            files.push(File {
+                attr,
+                ..File::new(length, name)
+            });
             name = PathBuf::default();
         } else {
             // Multi file mode
@@ -123,9 +272,80 @@ impl Info {
             pieces,
         })
     }
+
+    /// Where the content ends up relative to the download directory, the file of a
+    /// single-file torrent or the directory of a multi-file one
+    pub fn content_path(&self) -> PathBuf {
+        match self.files.as_slice() {
+            [file] if self.name.as_os_str().is_empty() => file.path.clone(),
+            _ => self.name.clone(),
+        }
+    }
+
+    /// Handles a piece length larger than the whole content. Strict mode rejects it.
+    /// Otherwise the piece length is clamped to the content, leaving one exact-sized piece
+    pub fn fit_piece_length(&mut self, strict: bool) -> Result<()> {
+        let total_length: usize = self.files.iter().map(|file| file.length).sum();
+        if total_length == 0 || self.piece_length <= total_length {
+            return Ok(());
+        }
+        if strict {
+            return Err(PieceLengthExceedsContent(self.piece_length, total_length));
+        }
+        self.piece_length = total_length;
+        Ok(())
+    }
+
+    /// Pieces holding any byte of the given files, what downloading only them takes
+    pub fn pieces_for_files(&self, files: &[usize]) -> PieceBitfield {
+        let mut pieces = PieceBitfield::new(self.pieces.len());
+        for &file_index in files {
+            let length = self.files.get(file_index).map_or(0, |file| file.length);
+            for index in self.pieces_for_byte_range(file_index, 0, length) {
+                pieces.set(index);
+            }
+        }
+        pieces
+    }
+
+    /// Pieces covering `len` bytes of the file starting at `start` within the file.
+    /// The range is empty for an empty byte range or an unknown file
+    pub fn pieces_for_byte_range(
+        &self,
+        file_index: usize,
+        start: usize,
+        len: usize,
+    ) -> Range<usize> {
+        let Some(file) = self.files.get(file_index) else {
+            return 0..0;
+        };
+        if self.piece_length == 0 {
+            return 0..0;
+        }
+        let file_offset: usize = self.files[..file_index]
+            .iter()
+            .map(|file| file.length)
+            .sum();
+        let end = file_offset + start.saturating_add(len).min(file.length);
+        let start = file_offset + start.min(file.length);
+        if start >= end {
+            let piece = start / self.piece_length;
+            return piece..piece;
+        }
+        start / self.piece_length..end.div_ceil(self.piece_length)
+    }
 }
 
 impl File {
+    pub fn new(length: usize, path: PathBuf) -> Self {
+        Self {
+            length,
+            path,
+            attr: FileAttributes::default(),
+            symlink_path: None,
+        }
+    }
+
     fn from_bencode(mut dict: bencode::BencodeDict) -> Result<Self> {
         let length = usize::try_from(i64::try_from(
             dict.remove(bss!(b"length"))
@@ -136,10 +356,311 @@ impl File {
             .remove(bss!(b"path"))
             .ok_or(MissingField("path".to_string()))?
             .try_into()?;
-        let path = path
-            .into_iter()
-            .map(String::try_from)
-            .collect::<std::result::Result<PathBuf, _>>()?;
-        Ok(File { length, path })
+        let path = safe_path(
+            path.into_iter()
+                .map(String::try_from)
+                .collect::<std::result::Result<PathBuf, _>>()?,
+        )?;
+        let attr = match dict.remove(bss!(b"attr")) {
+            Some(attr) => FileAttributes::from(String::try_from(attr)?.as_str()),
+            None => FileAttributes::default(),
+        };
+        let symlink_path = match dict.remove(bss!(b"symlink path")) {
+            Some(symlink_path) => Some(safe_path(
+                BencodeList::try_from(symlink_path)?
+                    .into_iter()
+                    .map(String::try_from)
+                    .collect::<std::result::Result<PathBuf, _>>()?,
+            )?),
+            None => None,
+        };
+        Ok(File {
+            length,
+            path,
+            attr,
+            symlink_path,
+        })
+    }
+}
+
+/// Paths of a torrent are joined to the download directory, a parent, root or prefix
+/// component would let the torrent write anywhere
+fn safe_path(path: PathBuf) -> Result<PathBuf> {
+    let escapes = path.components().any(|component| {
+        matches!(
+            component,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_)
+        )
+    });
+    if escapes {
+        return Err(UnsafePath(path));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::{File, FileAttributes, FileEntry, Info, TorrentError, TorrentFile};
+    use bencode::{bencode, Value};
+    use std::path::PathBuf;
+    use url::Url;
+
+    #[test]
+    fn trackerless_torrent_nodes() {
+        let info = || {
+            bencode!({
+            "length" => 4,
+            "name" => "file",
+            "piece length" => 4,
+            "pieces" => "aaaaaaaaaaaaaaaaaaaa",
+            })
+        };
+        let dict = bencode!({
+            "info" => info(),
+            "nodes" => [["router.bittorrent.com", 6881], ["10.0.0.1", 6882], ["broken"]],
+        });
+        let torrent = TorrentFile::from_bencode(dict.try_into().unwrap()).unwrap();
+        assert_eq!(torrent.announce, None);
+        assert_eq!(
+            torrent.nodes,
+            vec![
+                ("router.bittorrent.com".to_string(), 6881),
+                ("10.0.0.1".to_string(), 6882),
+            ]
+        );
+
+        let dict = bencode!({ "info" => info() });
+        assert!(matches!(
+            TorrentFile::from_bencode(dict.try_into().unwrap()),
+            Err(TorrentError::NoPeerSource)
+        ));
+    }
+
+    #[test]
+    fn announce_list_tiers() {
+        let info = bencode!({
+            "length" => 4,
+            "name" => "file",
+            "piece length" => 4,
+            "pieces" => "aaaaaaaaaaaaaaaaaaaa",
+        });
+        let dict = bencode!({
+            "announce" => "http://fallback.example/announce",
+            "announce-list" => [
+                ["udp://a.example:6969/announce", "not a url"],
+                ["not a url"],
+                ["http://b.example/announce", "wss://c.example/announce"],
+            ],
+            "info" => info,
+        });
+        let torrent = TorrentFile::from_bencode(dict.try_into().unwrap()).unwrap();
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert_eq!(
+            torrent.tracker_tiers(),
+            vec![
+                vec![url("udp://a.example:6969/announce")],
+                vec![
+                    url("http://b.example/announce"),
+                    url("wss://c.example/announce")
+                ],
+            ]
+        );
+
+        let single = TorrentFile {
+            announce_list: Vec::new(),
+            ..torrent
+        };
+        assert_eq!(
+            single.tracker_tiers(),
+            vec![vec![url("http://fallback.example/announce")]]
+        );
+    }
+
+    #[test]
+    fn path_traversal_rejected() {
+        let torrent = |name: &str, file: Value| {
+            let dict = bencode!({
+                "announce" => "http://tracker.example/announce",
+                "info" => {
+                    "files" => [file],
+                    "name" => name,
+                    "piece length" => 16,
+                    "pieces" => "aaaaaaaaaaaaaaaaaaaa",
+                },
+            });
+            TorrentFile::from_bencode(dict.try_into().unwrap())
+        };
+        let unsafe_paths = [
+            (
+                "album",
+                bencode!({ "length" => 10, "path" => ["..", "evil"] }),
+            ),
+            (
+                "album",
+                bencode!({ "length" => 10, "path" => ["sub", "../../evil"] }),
+            ),
+            (
+                "album",
+                bencode!({ "length" => 10, "path" => ["/etc", "passwd"] }),
+            ),
+            ("..", bencode!({ "length" => 10, "path" => ["a.txt"] })),
+            ("/tmp", bencode!({ "length" => 10, "path" => ["a.txt"] })),
+            (
+                "album",
+                bencode!({
+                    "attr" => "l",
+                    "length" => 0,
+                    "path" => ["link"],
+                    "symlink path" => ["..", "..", "etc"],
+                }),
+            ),
+        ];
+        for (name, file) in unsafe_paths {
+            assert!(
+                matches!(torrent(name, file), Err(TorrentError::UnsafePath(_))),
+                "{name}"
+            );
+        }
+        let file = bencode!({ "length" => 10, "path" => ["sub", "..a", "b.."] });
+        assert!(torrent("album", file).is_ok());
+    }
+
+    #[test]
+    fn multi_file_list() {
+        let dict = bencode!({
+            "announce" => "http://tracker.example/announce",
+            "info" => {
+                "files" => [
+                    { "length" => 10, "path" => ["a.txt"] },
+                    { "attr" => "p", "length" => 6, "path" => [".pad", "6"] },
+                    { "length" => 20, "path" => ["sub", "b.bin"] },
+                ],
+                "name" => "album",
+                "piece length" => 16,
+                "pieces" => "aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbcccccccccccccccccccc",
+            },
+        });
+        let torrent = TorrentFile::from_bencode(dict.try_into().unwrap()).unwrap();
+        assert_eq!(
+            torrent.announce,
+            Some(Url::parse("http://tracker.example/announce").unwrap())
+        );
+        assert_eq!(
+            torrent.file_list(),
+            vec![
+                FileEntry {
+                    index: 0,
+                    path: PathBuf::from("album/a.txt"),
+                    length: 10,
+                },
+                FileEntry {
+                    index: 2,
+                    path: PathBuf::from("album/sub/b.bin"),
+                    length: 20,
+                },
+            ]
+        );
+        assert_eq!(torrent.total_length(), 36);
+    }
+
+    #[test]
+    fn padding_file_entry() {
+        let entry = bencode!({
+            "attr" => "p",
+            "length" => 100,
+            "path" => [".pad", "100"],
+        });
+        let file = File::from_bencode(entry.try_into().unwrap()).unwrap();
+        assert!(file.attr.padding);
+        assert!(!file.attr.executable);
+        assert_eq!(file.length, 100);
+        assert_eq!(file.path, PathBuf::from(".pad/100"));
+    }
+
+    fn info(lengths: &[usize], piece_length: usize) -> Info {
+        let total: usize = lengths.iter().sum();
+        Info {
+            files: lengths
+                .iter()
+                .enumerate()
+                .map(|(index, length)| File::new(*length, PathBuf::from(index.to_string())))
+                .collect(),
+            name: PathBuf::from("torrent"),
+            info_hash: [0; 20],
+            piece_length,
+            pieces: vec![[0; 20]; total.div_ceil(piece_length)],
+        }
+    }
+
+    #[test]
+    fn byte_range_within_one_piece() {
+        let info = info(&[10, 30], 16);
+        // second file starts at content offset 10, bytes 10..14 of it are 20..24
+        assert_eq!(info.pieces_for_byte_range(1, 10, 4), 1..2);
+        assert_eq!(info.pieces_for_byte_range(0, 0, 10), 0..1);
+        assert_eq!(info.pieces_for_byte_range(1, 3, 0), 0..0);
+    }
+
+    #[test]
+    fn byte_range_across_file_boundary() {
+        let info = info(&[10, 30, 9], 16);
+        assert_eq!(info.pieces_for_byte_range(1, 0, 30), 0..3);
+        assert_eq!(info.pieces_for_byte_range(1, 5, 100), 0..3);
+        assert_eq!(info.pieces_for_byte_range(2, 0, 9), 2..4);
+        assert_eq!(info.pieces_for_byte_range(3, 0, 1), 0..0);
+    }
+
+    #[test]
+    fn oversized_piece_length() {
+        let mut lenient = info(&[10, 6], 64);
+        lenient.fit_piece_length(false).unwrap();
+        assert_eq!(lenient.piece_length, 16);
+        assert_eq!(lenient.pieces.len(), 1);
+        assert_eq!(lenient.pieces_for_byte_range(1, 0, 6), 0..1);
+
+        let mut strict = info(&[10, 6], 64);
+        assert!(matches!(
+            strict.fit_piece_length(true),
+            Err(TorrentError::PieceLengthExceedsContent(64, 16))
+        ));
+        let mut exact = info(&[10, 6], 16);
+        exact.fit_piece_length(true).unwrap();
+        assert_eq!(exact.piece_length, 16);
+    }
+
+    #[test]
+    fn length_and_files_rejected() {
+        let info = bencode!({
+            "files" => [{ "length" => 4, "path" => ["a"] }],
+            "length" => 4,
+            "name" => "torrent",
+            "piece length" => 4,
+            "pieces" => vec![0; 20],
+        });
+        assert!(matches!(
+            Info::from_bencode(info.try_into().unwrap()),
+            Err(TorrentError::AmbiguousFileMode)
+        ));
+    }
+
+    #[test]
+    fn symlink_file_entry() {
+        let entry = bencode!({
+            "attr" => "lx?",
+            "length" => 0,
+            "path" => ["bin", "tool"],
+            "symlink path" => ["lib", "tool-1.0"],
+        });
+        let file = File::from_bencode(entry.try_into().unwrap()).unwrap();
+        assert_eq!(
+            file.attr,
+            FileAttributes {
+                padding: false,
+                executable: true,
+                hidden: false,
+                symlink: true,
+            }
+        );
+        assert_eq!(file.symlink_path, Some(PathBuf::from("lib/tool-1.0")));
     }
 }
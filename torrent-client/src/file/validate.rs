@@ -0,0 +1,339 @@
+use crate::file::TorrentFile;
+use bencode::{BencodeDict, BencodeEncoder, Value};
+use sha1::Digest;
+use std::ops::Range;
+use thiserror::Error;
+
+/// Keys a metainfo file may carry besides `info`
+const KNOWN_TOP_LEVEL_KEYS: &[&[u8]] = &[
+    b"announce",
+    b"announce-list",
+    b"comment",
+    b"created by",
+    b"creation date",
+    b"encoding",
+    b"httpseeds",
+    b"info",
+    b"nodes",
+    b"url-list",
+];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ValidationWarning {
+    #[error("Malformed bencode: {0}")]
+    Malformed(String),
+    #[error("{0} bytes of trailing data after the torrent")]
+    TrailingData(usize),
+    #[error("Dictionary keys are not sorted in {0}")]
+    UnsortedKeys(String),
+    #[error("Duplicate key {1:?} in {0}")]
+    DuplicateKey(String, String),
+    #[error("Integer {1:?} in {0} is not canonical")]
+    NonCanonicalInteger(String, String),
+    #[error("Info dictionary isn't canonical, its info hash differs from the re-encoded one")]
+    NonCanonicalInfo,
+    #[error("Missing field {0}")]
+    MissingField(String),
+    #[error("Unknown top-level key {0:?}")]
+    UnknownKey(String),
+    #[error("Pieces length {0} is not a multiple of 20")]
+    PiecesLength(usize),
+    #[error("Content needs {expected} pieces, but torrent has {actual}")]
+    PieceCount { expected: usize, actual: usize },
+}
+
+impl TorrentFile {
+    /// Checks raw .torrent bytes against the spec, reporting every issue found.
+    /// Parsing throws away key order and integer spelling, hence this works on bytes
+    pub fn validate(data: &[u8]) -> Vec<ValidationWarning> {
+        let mut scanner = Scanner::new(data);
+        if let Err(reason) = scanner.value(String::new(), 0) {
+            scanner.warnings.push(ValidationWarning::Malformed(reason));
+            return scanner.warnings;
+        }
+        if scanner.position < data.len() {
+            let trailing = data.len() - scanner.position;
+            scanner
+                .warnings
+                .push(ValidationWarning::TrailingData(trailing));
+        }
+        let mut warnings = scanner.warnings;
+
+        let root = match bencode::from_slice(&data[..scanner.position]) {
+            Ok(Value::Dict(root)) => root,
+            Ok(value) => {
+                let reason = format!("torrent is {}, expected dict", value.name());
+                warnings.push(ValidationWarning::Malformed(reason));
+                return warnings;
+            }
+            Err(e) => {
+                warnings.push(ValidationWarning::Malformed(e.to_string()));
+                return warnings;
+            }
+        };
+        for key in root.keys() {
+            if !KNOWN_TOP_LEVEL_KEYS.contains(&key.as_slice()) {
+                warnings.push(ValidationWarning::UnknownKey(key_name(key)));
+            }
+        }
+        // trackerless torrents find peers through their DHT nodes instead
+        if !root.contains_key(b"announce".as_slice()) && !root.contains_key(b"nodes".as_slice()) {
+            warnings.push(ValidationWarning::MissingField("announce".to_string()));
+        }
+        match (root.get(b"info".as_slice()), scanner.info) {
+            (Some(Value::Dict(info)), Some(raw_info)) => {
+                let raw_hash = sha1::Sha1::digest(&data[raw_info]);
+                let mut canonical_info = Vec::new();
+                BencodeEncoder::new(&mut canonical_info).encode_dict(info);
+                let canonical_hash = sha1::Sha1::digest(canonical_info.as_slice());
+                if raw_hash != canonical_hash {
+                    warnings.push(ValidationWarning::NonCanonicalInfo);
+                }
+                validate_info(info, &mut warnings);
+            }
+            _ => warnings.push(ValidationWarning::MissingField("info".to_string())),
+        }
+        warnings
+    }
+}
+
+/// Keys are arbitrary bytes, invalid UTF-8 is replaced. Warnings print keys with `{:?}`,
+/// which escapes control characters
+fn key_name(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
+
+fn validate_info(info: &BencodeDict, warnings: &mut Vec<ValidationWarning>) {
+    let pieces = match info.get(b"pieces".as_slice()).and_then(Value::as_bytes) {
+        Some(pieces) => pieces,
+        None => {
+            warnings.push(ValidationWarning::MissingField("info.pieces".to_string()));
+            return;
+        }
+    };
+    if !pieces.len().is_multiple_of(20) {
+        warnings.push(ValidationWarning::PiecesLength(pieces.len()));
+    }
+    let piece_length = match info.get(b"piece length".as_slice()).and_then(Value::as_int) {
+        Some(piece_length) if piece_length > 0 => piece_length,
+        _ => {
+            let field = "info.piece length".to_string();
+            warnings.push(ValidationWarning::MissingField(field));
+            return;
+        }
+    };
+    let total_length = match (
+        info.get(b"length".as_slice()),
+        info.get(b"files".as_slice()),
+    ) {
+        (Some(Value::Int(length)), _) => *length,
+        (_, Some(Value::List(files))) => files
+            .iter()
+            .filter_map(|file| file.as_dict()?.get(b"length".as_slice())?.as_int())
+            .sum(),
+        _ => {
+            let field = "info.length or info.files".to_string();
+            warnings.push(ValidationWarning::MissingField(field));
+            return;
+        }
+    };
+    let expected = usize::try_from(total_length.max(0)).unwrap_or_default();
+    let expected = expected.div_ceil(piece_length as usize);
+    let actual = pieces.len() / 20;
+    if expected != actual {
+        warnings.push(ValidationWarning::PieceCount { expected, actual });
+    }
+}
+
+/// Walks raw bencode noting everything that isn't canonical
+struct Scanner<'a> {
+    data: &'a [u8],
+    position: usize,
+    warnings: Vec<ValidationWarning>,
+    /// Raw bytes of the top-level `info` value
+    info: Option<Range<usize>>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            warnings: Vec::new(),
+            info: None,
+        }
+    }
+
+    fn peek(&self) -> std::result::Result<u8, String> {
+        self.data
+            .get(self.position)
+            .copied()
+            .ok_or(format!("unexpected end of data at {}", self.position))
+    }
+
+    fn until(&mut self, terminator: u8) -> std::result::Result<&'a [u8], String> {
+        let start = self.position;
+        let length = self.data[start..]
+            .iter()
+            .position(|byte| *byte == terminator)
+            .ok_or(format!("unterminated value at {start}"))?;
+        self.position += length + 1;
+        Ok(&self.data[start..start + length])
+    }
+
+    fn string(&mut self) -> std::result::Result<&'a [u8], String> {
+        let length = std::str::from_utf8(self.until(b':')?)
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .ok_or(format!("invalid string length at {}", self.position))?;
+        let start = self.position;
+        let end = start
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(format!("string at {start} is out of data"))?;
+        self.position = end;
+        Ok(&self.data[start..end])
+    }
+
+    fn value(&mut self, path: String, depth: usize) -> std::result::Result<(), String> {
+        if depth > bencode::MAX_DEPTH {
+            return Err(format!("nesting deeper than {}", bencode::MAX_DEPTH));
+        }
+        match self.peek()? {
+            b'i' => {
+                self.position += 1;
+                let digits = self.until(b'e')?;
+                let canonical = !matches!(digits, [b'-', b'0', ..] | [b'0', _, ..]);
+                if !canonical {
+                    let digits = String::from_utf8_lossy(digits).to_string();
+                    self.warnings
+                        .push(ValidationWarning::NonCanonicalInteger(path, digits));
+                }
+            }
+            b'l' => {
+                self.position += 1;
+                let mut index = 0;
+                while self.peek()? != b'e' {
+                    self.value(format!("{path}[{index}]"), depth + 1)?;
+                    index += 1;
+                }
+                self.position += 1;
+            }
+            b'd' => {
+                self.position += 1;
+                let mut previous: Option<&[u8]> = None;
+                let mut reported = false;
+                while self.peek()? != b'e' {
+                    let key = self.string()?;
+                    let name = key_name(key);
+                    match previous {
+                        Some(previous) if previous == key => self
+                            .warnings
+                            .push(ValidationWarning::DuplicateKey(path.clone(), name.clone())),
+                        Some(previous) if previous > key && !reported => {
+                            reported = true;
+                            let dict = if path.is_empty() { "root" } else { &path };
+                            self.warnings
+                                .push(ValidationWarning::UnsortedKeys(dict.to_string()));
+                        }
+                        _ => {}
+                    }
+                    previous = Some(key);
+                    let start = self.position;
+                    let child = if path.is_empty() {
+                        name
+                    } else {
+                        format!("{path}.{name}")
+                    };
+                    self.value(child, depth + 1)?;
+                    if depth == 0 && key == b"info" {
+                        self.info = Some(start..self.position);
+                    }
+                }
+                self.position += 1;
+            }
+            b'0'..=b'9' => {
+                self.string()?;
+            }
+            byte => return Err(format!("unexpected byte {byte:#x} at {}", self.position)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::validate::ValidationWarning;
+    use crate::file::TorrentFile;
+
+    fn torrent(info: &str) -> Vec<u8> {
+        format!("d8:announce18:http://tracker/ann4:info{info}e").into_bytes()
+    }
+
+    #[test]
+    fn canonical_torrent_has_no_warnings() {
+        let data = torrent(&format!(
+            "d6:lengthi10e4:name4:file12:piece lengthi8e6:pieces40:{}e",
+            "a".repeat(40)
+        ));
+        assert_eq!(TorrentFile::validate(&data), vec![]);
+    }
+
+    #[test]
+    fn binary_keys_escaped() {
+        let mut data = torrent(&format!(
+            "d6:lengthi10e4:name4:file12:piece lengthi8e6:pieces40:{}2:\u{7f}ki1e2:\u{7f}ki2ee",
+            "a".repeat(40)
+        ));
+        data.pop();
+        data.extend(b"6:\xe5\x90\x8d\xe5\x89\x8di1e2:\xff\xfei2ee");
+        let warnings = TorrentFile::validate(&data);
+        assert_eq!(
+            warnings,
+            vec![
+                ValidationWarning::DuplicateKey("info".to_string(), "\u{7f}k".to_string()),
+                ValidationWarning::UnknownKey("名前".to_string()),
+                ValidationWarning::UnknownKey("\u{fffd}\u{fffd}".to_string()),
+                ValidationWarning::NonCanonicalInfo,
+            ]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            r#"Duplicate key "\u{7f}k" in info"#
+        );
+        assert_eq!(warnings[1].to_string(), r#"Unknown top-level key "名前""#);
+    }
+
+    #[test]
+    fn unsorted_info_keys() {
+        let data = torrent(&format!(
+            "d4:name4:file6:lengthi10e12:piece lengthi8e6:pieces40:{}e",
+            "a".repeat(40)
+        ));
+        assert_eq!(
+            TorrentFile::validate(&data),
+            vec![
+                ValidationWarning::UnsortedKeys("info".to_string()),
+                ValidationWarning::NonCanonicalInfo,
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_pieces_length() {
+        let data = torrent(&format!(
+            "d6:lengthi10e4:name4:file12:piece lengthi8e6:pieces30:{}e",
+            "a".repeat(30)
+        ));
+        assert_eq!(
+            TorrentFile::validate(&data),
+            vec![
+                ValidationWarning::PiecesLength(30),
+                ValidationWarning::PieceCount {
+                    expected: 2,
+                    actual: 1
+                },
+            ]
+        );
+    }
+}
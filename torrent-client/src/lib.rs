@@ -0,0 +1,6 @@
+pub mod client;
+pub mod file;
+pub mod peer;
+pub mod storage;
+pub mod tracker;
+pub mod util;
@@ -1,33 +1,33 @@
-use crate::client::{Client, Config};
-use crate::file::TorrentFile;
-use crate::peer::PeerId;
-use crate::tracker::HttpTracker;
-use bencode::BencodeDict;
+use crate::cli::AppError;
+use bencode::{BencodeDict, Trailing};
 use clap::Parser;
-use std::fs::File;
-use std::io::Read;
+use std::process::ExitCode;
+use torrent_client::client::{Client, Config};
+use torrent_client::file::TorrentFile;
+use torrent_client::peer::PeerId;
+use torrent_client::tracker::factory::TrackerFactory;
 
 mod cli;
-mod client;
-mod file;
-mod peer;
-mod tracker;
-mod util;
 
-fn main() {
-    let cli = cli::Args::parse();
-    let mut file = File::open(cli.torrent_file).unwrap();
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
-    let value: BencodeDict = bencode::from_slice(data.as_mut_slice())
-        .unwrap()
-        .try_into()
-        .unwrap();
-    let torrent = TorrentFile::from_bencode(value).unwrap();
-    let client_id = PeerId::random();
-    let tracker = Box::new(HttpTracker::new(&client_id).unwrap());
-    let client = Client::new(client_id, Config::new(25), tracker).unwrap();
+fn main() -> ExitCode {
+    match run(cli::Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(cli: cli::Args) -> Result<(), AppError> {
+    let value: BencodeDict =
+        bencode::from_path(&cli.torrent_file, Trailing::Whitespace)?.try_into()?;
+    let torrent = TorrentFile::from_bencode(value)?;
+    let client_id = cli.peer_id.unwrap_or_else(PeerId::random);
+    let config = Config::default();
+    let tracker = TrackerFactory::new(&client_id, config.tracker_timeout())?;
+    let client = Client::new(client_id, config, Box::new(tracker))?;
 
-    let res = client.download(torrent);
-    println!("{res:#?}");
+    client.download(torrent)?;
+    Ok(())
 }
@@ -1,48 +1,119 @@
 use crate::peer::connection::ConnectionError::*;
 use crate::peer::connection::HandshakeMessageError::{ProtocolString, ProtocolStringLen};
+use crate::peer::extension::PeerExtensionInfo;
 use crate::peer::PeerId;
-use crate::util::{BitField, Sha1};
+use crate::util::{BitField, InfoHash, PieceBitfield, Sha1};
 use bytes::Buf;
 use std::borrow::Cow;
 use std::cmp::PartialEq;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
 use thiserror::Error;
 
 type Result<T> = std::result::Result<T, ConnectionError>;
 
 static BIT_TORRENT_PROTOCOL_STRING: &[u8; 19] = b"BitTorrent protocol";
+/// Byte and mask of the capabilities in the reserved handshake bytes
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+const DHT_BIT: (usize, u8) = (7, 0x01);
+const FAST_BIT: (usize, u8) = (7, 0x04);
+/// Queued messages are written out once this many bytes pile up, even without a flush
+const WRITE_BUFFER_LIMIT: usize = 64 * 1024;
+/// Longest message a peer may send, a piece message carrying a 16 KiB block.
+/// Only the bitfield of a torrent with many pieces may be longer
+const MAX_MESSAGE_LENGTH: usize = 9 + 16 * 1024;
 
 #[derive(Error, Debug)]
-enum HandshakeMessageError {
+pub enum HandshakeMessageError {
     #[error("Invalid protocol string(pstr) length, expected 19, but got {0}")]
     ProtocolStringLen(u8),
     #[error("Unexpected protocol string, expected \"BitTorrent protocol\", but got {0}")]
     ProtocolString(Cow<'static, str>),
 }
 
+/// Reserved bytes of the handshake, each set bit advertises a protocol extension
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ReservedBits([u8; 8]);
+
+impl ReservedBits {
+    pub fn new(bytes: [u8; 8]) -> Self {
+        Self(bytes)
+    }
+
+    /// What we advertise in our handshakes
+    pub fn ours() -> Self {
+        let mut reserved = Self::default();
+        reserved.set_fast(true).set_extension_protocol(true);
+        reserved
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 8] {
+        &self.0
+    }
+
+    /// BEP 5
+    pub fn supports_dht(&self) -> bool {
+        self.get(DHT_BIT)
+    }
+
+    pub fn set_dht(&mut self, value: bool) -> &mut Self {
+        self.set(DHT_BIT, value)
+    }
+
+    /// BEP 6
+    pub fn supports_fast(&self) -> bool {
+        self.get(FAST_BIT)
+    }
+
+    pub fn set_fast(&mut self, value: bool) -> &mut Self {
+        self.set(FAST_BIT, value)
+    }
+
+    /// BEP 10
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.get(EXTENSION_PROTOCOL_BIT)
+    }
+
+    pub fn set_extension_protocol(&mut self, value: bool) -> &mut Self {
+        self.set(EXTENSION_PROTOCOL_BIT, value)
+    }
+
+    fn get(&self, (byte, mask): (usize, u8)) -> bool {
+        self.0[byte] & mask != 0
+    }
+
+    fn set(&mut self, (byte, mask): (usize, u8), value: bool) -> &mut Self {
+        if value {
+            self.0[byte] |= mask;
+        } else {
+            self.0[byte] &= !mask;
+        }
+        self
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
-struct HandshakeMessage {
-    // need to replace with appropriate structure
-    extension_bytes: [u8; 8],
+pub struct HandshakeMessage {
+    reserved: ReservedBits,
     info_hash: Sha1,
     peer_id: PeerId,
 }
 
 impl HandshakeMessage {
-    fn to_bytes(&self) -> Box<[u8; 68]> {
+    pub fn to_bytes(&self) -> Box<[u8; 68]> {
         let mut res = Box::new([0; 68]);
         res[0] = 19u8;
         res[1..20].copy_from_slice(BIT_TORRENT_PROTOCOL_STRING.as_slice());
-        res[20..28].copy_from_slice(self.extension_bytes.as_slice());
+        res[20..28].copy_from_slice(self.reserved.as_bytes());
         res[28..48].copy_from_slice(self.info_hash.as_slice());
         res[48..68].copy_from_slice(self.peer_id.as_slice());
         res
     }
 
-    fn from_bytes(raw: Box<[u8; 68]>) -> std::result::Result<Self, HandshakeMessageError> {
+    pub fn from_bytes(raw: &[u8; 68]) -> std::result::Result<Self, HandshakeMessageError> {
         let pstr_len = raw[0];
         if pstr_len != 19 {
             return Err(ProtocolStringLen(pstr_len));
@@ -53,20 +124,32 @@ impl HandshakeMessage {
                 String::from_utf8_lossy(pstr.as_slice()).to_string(),
             )));
         }
-        let extension_bytes: [u8; 8] = raw[20..28].try_into().expect("Slice with incorrect length");
+        let reserved: [u8; 8] = raw[20..28].try_into().expect("Slice with incorrect length");
         let info_hash: [u8; 20] = raw[28..48].try_into().expect("Slice with incorrect length");
         let peer_id: [u8; 20] = raw[48..68].try_into().expect("Slice with incorrect length");
 
-        Ok(Self::new(extension_bytes, info_hash, PeerId::new(peer_id)))
+        Ok(Self::new(
+            ReservedBits::new(reserved),
+            info_hash,
+            PeerId::new(peer_id),
+        ))
     }
 
-    pub fn new(extension_bytes: [u8; 8], info_hash: Sha1, peer_id: PeerId) -> Self {
+    pub fn new(reserved: ReservedBits, info_hash: Sha1, peer_id: PeerId) -> Self {
         Self {
-            extension_bytes,
+            reserved,
             info_hash,
             peer_id,
         }
     }
+
+    pub fn info_hash(&self) -> &Sha1 {
+        &self.info_hash
+    }
+
+    pub fn reserved(&self) -> &ReservedBits {
+        &self.reserved
+    }
 }
 
 impl From<HandshakeMessage> for Box<[u8; 68]> {
@@ -89,50 +172,277 @@ pub enum ConnectionError {
     MessageId(u8),
     #[error("Unexpected payload length {0}")]
     PayloadLength(usize),
+    #[error("Peer sent bogus peer id {0:?}")]
+    BogusPeerId(PeerId),
+    #[error("Peer didn't complete the handshake within {0:?}")]
+    HandshakeTimeout(Duration),
+    #[error("Encryption handshake failed {0}")]
+    Encryption(Cow<'static, str>),
+    #[error("Peer doesn't support the required encryption")]
+    PlaintextPeer,
+    #[error("Peer asked for unknown info hash {}", hex::encode(.0))]
+    UnknownInfoHash(Sha1),
+    #[error("Malformed extended handshake {0}")]
+    ExtendedHandshake(Cow<'static, str>),
+    #[error("Peer sent its bitfield after other messages")]
+    LateBitfield,
+    #[error("Peer sent a malformed bitfield of {0} bytes for {1} pieces")]
+    InvalidBitfield(usize, usize),
+    #[error("Peer sent a message of {0} bytes")]
+    OversizedMessage(usize),
     #[error("todo")]
     Todo,
 }
 
+/// Transport whose blocking reads and writes can be bounded in time
+pub trait IoTimeout {
+    fn set_io_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl IoTimeout for TcpStream {
+    fn set_io_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
 pub struct PeerConnection<T: Read + Write = TcpStream> {
     transport: T,
     peer_id: PeerId,
+    extension_info: Option<PeerExtensionInfo>,
+    /// Only used to tell peers apart in traces
+    addr: Option<SocketAddr>,
+    /// Both sides advertised BEP 6
+    fast_extension: bool,
+    /// Both sides advertised BEP 10
+    extension_protocol: bool,
+    /// Messages queued with [`PeerConnection::queue`] and not yet written
+    write_buffer: Vec<u8>,
+    max_message_length: usize,
 }
 
 impl<T: Read + Write> PeerConnection<T> {
-    pub fn handshake(mut transport: T, info_hash: &Sha1, peer_id: &PeerId) -> Result<Self> {
-        let mut bytes =
-            HandshakeMessage::new([0; 8], *info_hash, peer_id.clone()).to_bytes();
+    /// Initiates the handshake, a v2 info hash goes on the wire truncated to 20 bytes
+    pub fn handshake(
+        mut transport: T,
+        info_hash: impl Into<InfoHash>,
+        peer_id: &PeerId,
+    ) -> Result<Self> {
+        if peer_id.is_bogus() {
+            return Err(HandshakeFailed(Cow::Borrowed(
+                "refusing to handshake with bogus own peer id",
+            )));
+        }
+        let mut bytes = HandshakeMessage::new(
+            ReservedBits::ours(),
+            info_hash.into().wire(),
+            peer_id.clone(),
+        )
+        .to_bytes();
         transport.write_all(bytes.as_ref())?;
         transport.read_exact(bytes.as_mut())?;
-        let response = HandshakeMessage::from_bytes(bytes)?;
+        let response = HandshakeMessage::from_bytes(&bytes)?;
+
+        let mut connection = Self::from_handshaked(transport, response.peer_id.clone());
+        connection.fast_extension = response.reserved().supports_fast();
+        connection.extension_protocol = response.reserved().supports_extension_protocol();
+        Ok(connection)
+    }
 
-        Ok(Self {
+    /// Wraps a transport whose handshake has already been exchanged, e.g. an inbound peer
+    /// whose handshake was read to route it by info hash
+    pub fn from_handshaked(transport: T, peer_id: PeerId) -> Self {
+        Self {
             transport,
-            peer_id: response.peer_id,
-        })
+            peer_id,
+            extension_info: None,
+            addr: None,
+            fast_extension: false,
+            extension_protocol: false,
+            write_buffer: Vec::new(),
+            max_message_length: MAX_MESSAGE_LENGTH,
+        }
+    }
+
+    /// Answers a handshake initiated by the remote side. The info hash the peer asked for
+    /// is checked with `accepts` before we reveal anything about ourselves
+    pub fn accept<F>(mut transport: T, peer_id: &PeerId, accepts: F) -> Result<(Self, Sha1)>
+    where
+        F: FnOnce(&Sha1) -> bool,
+    {
+        let mut bytes = Box::new([0; 68]);
+        transport.read_exact(bytes.as_mut())?;
+        let request = HandshakeMessage::from_bytes(&bytes)?;
+        if !accepts(&request.info_hash) {
+            return Err(UnknownInfoHash(request.info_hash));
+        }
+        let response =
+            HandshakeMessage::new(ReservedBits::ours(), request.info_hash, peer_id.clone());
+        transport.write_all(response.to_bytes().as_ref())?;
+
+        let mut connection = Self::from_handshaked(transport, request.peer_id.clone());
+        connection.fast_extension = request.reserved().supports_fast();
+        connection.extension_protocol = request.reserved().supports_extension_protocol();
+        Ok((connection, request.info_hash))
+    }
+
+    /// Same as [`PeerConnection::handshake`], but drops peers that answered with a bogus peer id
+    pub fn handshake_strict(
+        transport: T,
+        info_hash: impl Into<InfoHash>,
+        peer_id: &PeerId,
+    ) -> Result<Self> {
+        let connection = Self::handshake(transport, info_hash, peer_id)?;
+        if connection.is_peer_id_bogus() {
+            return Err(BogusPeerId(connection.peer_id));
+        }
+        Ok(connection)
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Transport of the connection, e.g. to shut its socket down from another thread
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn set_addr(&mut self, addr: SocketAddr) -> &mut Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Lets the peer's bitfield through when it's longer than any other message
+    pub fn set_pieces_count(&mut self, pieces_count: usize) -> &mut Self {
+        self.max_message_length = MAX_MESSAGE_LENGTH.max(1 + pieces_count.div_ceil(8));
+        self
+    }
+
+    /// Address if known, the peer id otherwise
+    fn peer_label(&self) -> String {
+        match self.addr {
+            Some(addr) => addr.to_string(),
+            None => hex::encode(self.peer_id.as_ref()),
+        }
+    }
+
+    pub fn is_peer_id_bogus(&self) -> bool {
+        self.peer_id.is_bogus()
+    }
+
+    pub fn fast_extension(&self) -> bool {
+        self.fast_extension
+    }
+
+    /// Extended messages may be sent, starting with the extended handshake
+    pub fn extension_protocol(&self) -> bool {
+        self.extension_protocol
     }
 
+    /// Tells the peer which pieces we have right after the handshake, see [`Message::availability`]
+    pub fn send_availability(&mut self, have: &PieceBitfield) -> Result<()> {
+        match Message::availability(have, self.fast_extension) {
+            Some(message) => self.send(message),
+            None => Ok(()),
+        }
+    }
+
+    /// Fields of the peer's extended handshake, once it has been received
+    pub fn extension_info(&self) -> Option<&PeerExtensionInfo> {
+        self.extension_info.as_ref()
+    }
+
+    /// Number of outstanding requests to keep with this peer, never more than its `reqq`
+    pub fn pipeline_depth(&self, ours: usize) -> usize {
+        match self.extension_info.as_ref().and_then(|info| info.reqq) {
+            Some(reqq) => ours.min(reqq),
+            None => ours,
+        }
+    }
+
+    /// Receives the next message, an extended handshake is also remembered by the connection.
+    /// Queued messages are flushed first, the peer may be waiting for them. A message longer
+    /// than any valid one fails with [`ConnectionError::OversizedMessage`] before it's read
     pub fn recv(&mut self) -> Result<Message> {
+        self.flush()?;
         let mut length_prefix = [0u8; 4];
         self.transport.read_exact(&mut length_prefix)?;
         let length_prefix = u32::from_be_bytes(length_prefix);
         if length_prefix == 0 {
+            log::trace!("{} received {}", self.peer_label(), Message::KeepAlive);
             return Ok(Message::KeepAlive);
         }
-        let mut data = Vec::with_capacity(length_prefix as usize);
-        data.resize(length_prefix as usize, 0);
+        let length = length_prefix as usize;
+        if length > self.max_message_length {
+            return Err(OversizedMessage(length));
+        }
+        let mut data = vec![0; length];
         self.transport.read_exact(data.as_mut_slice())?;
         let message = Message::try_from(data.as_slice())?;
+        log::trace!("{} received {message}", self.peer_label());
+        if let Message::Extended { ext_id: 0, payload } = &message {
+            self.extension_info = Some(PeerExtensionInfo::from_handshake(payload)?);
+        }
         Ok(message)
     }
 
+    /// Sends the message right away, together with anything queued before it
     pub fn send(&mut self, message: Message) -> Result<()> {
-        let bytes: Vec<u8> = message.into();
-        self.transport.write_all(bytes.as_slice())?;
+        self.queue(message)?;
+        self.flush()
+    }
+
+    /// Buffers the message, so a burst of small ones like requests or haves goes out
+    /// in a single write on [`PeerConnection::flush`]
+    pub fn queue(&mut self, message: Message) -> Result<()> {
+        log::trace!("{} sent {message}", self.peer_label());
+        self.write_buffer.extend_from_slice(&message.to_bytes());
+        if self.write_buffer.len() >= WRITE_BUFFER_LIMIT {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes out every queued message
+    pub fn flush(&mut self) -> Result<()> {
+        if self.write_buffer.is_empty() {
+            return Ok(());
+        }
+        let result = self.transport.write_all(&self.write_buffer);
+        self.write_buffer.clear();
+        result?;
+        self.transport.flush()?;
         Ok(())
     }
 }
 
+impl<T: Read + Write + IoTimeout> PeerConnection<T> {
+    /// Same as [`PeerConnection::handshake`], but a peer that accepted the connection and
+    /// then went silent is dropped after `timeout`. The transport is left without timeouts
+    pub fn handshake_within(
+        mut transport: T,
+        info_hash: impl Into<InfoHash>,
+        peer_id: &PeerId,
+        timeout: Duration,
+    ) -> Result<Self> {
+        transport.set_io_timeout(Some(timeout))?;
+        let mut connection = match Self::handshake(transport, info_hash, peer_id) {
+            Err(IoKind(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(HandshakeTimeout(timeout))
+            }
+            result => result?,
+        };
+        connection.transport.set_io_timeout(None)?;
+        Ok(connection)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockRequest {
     index: u32,
@@ -149,11 +459,23 @@ impl BlockRequest {
         }
     }
 
-    pub fn to_bytes(self) -> [u8; 12] {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn begin(&self) -> u32 {
+        self.begin
+    }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    pub fn to_bytes(&self) -> [u8; 12] {
         let mut bytes = [0u8; 12];
-        bytes[0..4].copy_from_slice(&self.index.to_le_bytes());
-        bytes[4..8].copy_from_slice(&self.begin.to_le_bytes());
-        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes[0..4].copy_from_slice(&self.index.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.begin.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
         bytes
     }
 }
@@ -166,9 +488,9 @@ impl TryFrom<&[u8]> for BlockRequest {
             return Err(PayloadLength(value.len()));
         }
         Ok(BlockRequest::new(
-            value.get_u32_ne(),
-            value.get_u32_ne(),
-            value.get_u32_ne(),
+            value.get_u32(),
+            value.get_u32(),
+            value.get_u32(),
         ))
     }
 }
@@ -184,6 +506,18 @@ impl Piece {
     pub fn new(index: u32, begin: u32, data: Vec<u8>) -> Self {
         Self { index, begin, data }
     }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn begin(&self) -> u32 {
+        self.begin
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
 }
 
 impl TryFrom<&[u8]> for Piece {
@@ -193,11 +527,7 @@ impl TryFrom<&[u8]> for Piece {
         if value.len() < 8 {
             return Err(PayloadLength(value.len()));
         }
-        Ok(Piece::new(
-            value.get_u32_ne(),
-            value.get_u32_ne(),
-            value.to_vec(),
-        ))
+        Ok(Piece::new(value.get_u32(), value.get_u32(), value.to_vec()))
     }
 }
 
@@ -214,9 +544,37 @@ pub enum Message {
     Piece(Piece),
     Cancel(BlockRequest),
     Port(u16),
+    /// BEP 6 messages, only sent once both sides advertised the fast extension
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(BlockRequest),
+    AllowedFast(u32),
+    /// BEP 10 message, `ext_id` 0 is the extended handshake
+    Extended {
+        ext_id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl Message {
+    /// First message telling a peer which pieces we have. With the fast extension an empty or
+    /// complete set goes out as `HaveNone`/`HaveAll`, without it nothing is sent while we have
+    /// no pieces, as the bitfield is optional then
+    pub fn availability(have: &PieceBitfield, fast_extension: bool) -> Option<Message> {
+        match (fast_extension, have.count()) {
+            (true, 0) => Some(Message::HaveNone),
+            (true, count) if count == have.len() => Some(Message::HaveAll),
+            (false, 0) => None,
+            _ => Some(Message::Bitfield(
+                have.as_bytes()
+                    .iter()
+                    .map(|byte| BitField::new(*byte))
+                    .collect(),
+            )),
+        }
+    }
+
     pub fn get_id(&self) -> u8 {
         match self {
             Message::KeepAlive => panic!("KeepAlive doesn't have id"),
@@ -230,27 +588,45 @@ impl Message {
             Message::Piece(_) => 7,
             Message::Cancel(_) => 8,
             Message::Port(_) => 9,
+            Message::SuggestPiece(_) => 13,
+            Message::HaveAll => 14,
+            Message::HaveNone => 15,
+            Message::RejectRequest(_) => 16,
+            Message::AllowedFast(_) => 17,
+            Message::Extended { .. } => 20,
         }
     }
 
-    pub fn to_bytes(self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
         use Message::*;
         let mut result = vec![0; 4];
         if let KeepAlive = self {
             return result;
         }
-        result.extend_from_slice(self.get_id().to_ne_bytes().as_slice());
+        result.push(self.get_id());
         match self {
             KeepAlive => unreachable!(),
-            Choke | UnChoke | Interested | NotInterested => {}
-            Have(have) => result.extend_from_slice(have.to_ne_bytes().as_slice()),
-            Bitfield(_) => todo!(),
-            Request(req) | Cancel(req) => result.extend_from_slice(req.to_bytes().as_slice()),
-            Piece(_) => todo!(),
-            Port(port) => result.extend_from_slice(port.to_ne_bytes().as_slice()),
+            Choke | UnChoke | Interested | NotInterested | HaveAll | HaveNone => {}
+            Have(index) | SuggestPiece(index) | AllowedFast(index) => {
+                result.extend_from_slice(index.to_be_bytes().as_slice())
+            }
+            Bitfield(bits) => result.extend(bits.iter().map(BitField::get_value)),
+            Request(req) | Cancel(req) | RejectRequest(req) => {
+                result.extend_from_slice(req.to_bytes().as_slice())
+            }
+            Piece(piece) => {
+                result.extend_from_slice(piece.index.to_be_bytes().as_slice());
+                result.extend_from_slice(piece.begin.to_be_bytes().as_slice());
+                result.extend_from_slice(&piece.data);
+            }
+            Port(port) => result.extend_from_slice(port.to_be_bytes().as_slice()),
+            Extended { ext_id, payload } => {
+                result.push(*ext_id);
+                result.extend_from_slice(payload);
+            }
         }
         let len = (result.len() - 4) as u32;
-        result[0..4].copy_from_slice(len.to_ne_bytes().as_slice());
+        result[0..4].copy_from_slice(len.to_be_bytes().as_slice());
 
         result
     }
@@ -270,6 +646,12 @@ impl Display for Message {
             Message::Piece(_) => write!(f, "Piece"),
             Message::Cancel(_) => write!(f, "Cancel"),
             Message::Port(port) => write!(f, "Port({})", port),
+            Message::SuggestPiece(index) => write!(f, "SuggestPiece({})", index),
+            Message::HaveAll => write!(f, "HaveAll"),
+            Message::HaveNone => write!(f, "HaveNone"),
+            Message::RejectRequest(_) => write!(f, "RejectRequest"),
+            Message::AllowedFast(index) => write!(f, "AllowedFast({})", index),
+            Message::Extended { ext_id, .. } => write!(f, "Extended({})", ext_id),
         }
     }
 }
@@ -288,17 +670,11 @@ impl TryFrom<&[u8]> for Message {
         value = &value[1..];
 
         let message: Message = match id {
-            0 => Message::Choke,
-            1 => Message::UnChoke,
-            2 => Message::Interested,
-            3 => Message::NotInterested,
-            4 => Message::Have(u32::from_be_bytes(
-                value
-                    .get(0..4)
-                    .ok_or(UnexpectedEOF)?
-                    .try_into()
-                    .map_err(|_| UnexpectedEOF)?,
-            )),
+            0 => no_payload(value, Message::Choke)?,
+            1 => no_payload(value, Message::UnChoke)?,
+            2 => no_payload(value, Message::Interested)?,
+            3 => no_payload(value, Message::NotInterested)?,
+            4 => Message::Have(u32::from_be_bytes(fixed_payload(value)?)),
             5 => Message::Bitfield(
                 value
                     .iter()
@@ -308,13 +684,16 @@ impl TryFrom<&[u8]> for Message {
             6 => Message::Request(BlockRequest::try_from(value)?),
             7 => Message::Piece(Piece::try_from(value)?),
             8 => Message::Cancel(BlockRequest::try_from(value)?),
-            9 => Message::Port(u16::from_be_bytes(
-                value
-                    .get(0..2)
-                    .ok_or(UnexpectedEOF)?
-                    .try_into()
-                    .map_err(|_| UnexpectedEOF)?,
-            )),
+            9 => Message::Port(u16::from_be_bytes(fixed_payload(value)?)),
+            13 => Message::SuggestPiece(u32::from_be_bytes(fixed_payload(value)?)),
+            14 => no_payload(value, Message::HaveAll)?,
+            15 => no_payload(value, Message::HaveNone)?,
+            16 => Message::RejectRequest(BlockRequest::try_from(value)?),
+            17 => Message::AllowedFast(u32::from_be_bytes(fixed_payload(value)?)),
+            20 => Message::Extended {
+                ext_id: *value.first().ok_or(UnexpectedEOF)?,
+                payload: value[1..].to_vec(),
+            },
             _ => return Err(MessageId(id)),
         };
 
@@ -322,12 +701,65 @@ impl TryFrom<&[u8]> for Message {
     }
 }
 
+/// Payload of a message whose layout has exactly `N` bytes
+fn fixed_payload<const N: usize>(payload: &[u8]) -> Result<[u8; N]> {
+    payload.try_into().map_err(|_| PayloadLength(payload.len()))
+}
+
+fn no_payload(payload: &[u8], message: Message) -> Result<Message> {
+    match payload.len() {
+        0 => Ok(message),
+        length => Err(PayloadLength(length)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::peer::connection::{HandshakeMessage, Message, BIT_TORRENT_PROTOCOL_STRING};
+    use crate::peer::connection::{
+        BlockRequest, ConnectionError, HandshakeMessage, IoTimeout, Message, PeerConnection, Piece,
+        ReservedBits, BIT_TORRENT_PROTOCOL_STRING,
+    };
+    use crate::peer::extension::PeerExtensionInfo;
     use crate::peer::PeerId;
+    use crate::util::{test_log, InfoHash, PieceBitfield};
     use bytes::{BufMut, BytesMut};
     use rand::RngCore;
+    use std::io::{Cursor, Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct MockTransport {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        writes: usize,
+    }
+
+    impl MockTransport {
+        fn new(input: Vec<u8>) -> Self {
+            Self {
+                input: Cursor::new(input),
+                output: Vec::new(),
+                writes: 0,
+            }
+        }
+    }
+
+    impl Read for MockTransport {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockTransport {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
     #[test]
     fn handshake_message_as_bytes() {
@@ -344,7 +776,8 @@ mod tests {
         bytes.extend_from_slice(info_hash.as_slice());
         bytes.extend_from_slice(peed_id.as_ref());
 
-        let message = HandshakeMessage::new(extensions_bytes, info_hash, peed_id);
+        let message =
+            HandshakeMessage::new(ReservedBits::new(extensions_bytes), info_hash, peed_id);
         let message_bytes = message.to_bytes();
 
         assert_eq!(bytes.as_ref(), message_bytes.as_slice());
@@ -365,14 +798,36 @@ mod tests {
         bytes.extend_from_slice(info_hash.as_slice());
         bytes.extend_from_slice(peed_id.as_ref());
 
-        let message = HandshakeMessage::new(extensions_bytes, info_hash, peed_id);
+        let message =
+            HandshakeMessage::new(ReservedBits::new(extensions_bytes), info_hash, peed_id);
 
         let message_from_bytes =
-            HandshakeMessage::from_bytes(bytes.to_vec().try_into().unwrap()).unwrap();
+            HandshakeMessage::from_bytes(&bytes.to_vec().try_into().unwrap()).unwrap();
 
         assert_eq!(message_from_bytes, message)
     }
 
+    #[test]
+    fn reserved_bit_positions() {
+        let mut reserved = ReservedBits::default();
+        reserved.set_dht(true);
+        assert_eq!(reserved.as_bytes(), &[0, 0, 0, 0, 0, 0, 0, 0x01]);
+        reserved.set_fast(true);
+        assert_eq!(reserved.as_bytes(), &[0, 0, 0, 0, 0, 0, 0, 0x05]);
+        reserved.set_extension_protocol(true).set_dht(false);
+        assert_eq!(reserved.as_bytes(), &[0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+
+        let parsed = ReservedBits::new([0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert!(parsed.supports_dht());
+        assert!(!parsed.supports_fast());
+        assert!(parsed.supports_extension_protocol());
+        assert!(ReservedBits::new([0, 0, 0, 0, 0, 0, 0, 0x04]).supports_fast());
+        assert!(
+            !ReservedBits::new([0xff, 0xff, 0xff, 0xff, 0xff, 0xef, 0xff, 0xfa])
+                .supports_extension_protocol()
+        );
+    }
+
     #[test]
     fn keep_alive_to_bytes() {
         let msg = Message::KeepAlive;
@@ -380,6 +835,34 @@ mod tests {
         assert_eq!(bytes, vec![0; 4])
     }
 
+    #[test]
+    fn messages_framed_big_endian() {
+        let frames = [
+            (Message::Have(0x0102), vec![0, 0, 0, 5, 4, 0, 0, 1, 2]),
+            (Message::Port(6881), vec![0, 0, 0, 3, 9, 0x1a, 0xe1]),
+            (
+                Message::Request(BlockRequest::new(1, 0x4000, 0x4000)),
+                vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (
+                Message::Piece(Piece::new(2, 0x4000, vec![0xaa, 0xbb])),
+                vec![0, 0, 0, 11, 7, 0, 0, 0, 2, 0, 0, 0x40, 0, 0xaa, 0xbb],
+            ),
+        ];
+        for (message, frame) in frames {
+            assert_eq!(message.to_bytes(), frame, "{message}");
+            let parsed = Message::try_from(&frame[4..]).unwrap();
+            assert_eq!(parsed.to_bytes(), frame, "{message}");
+        }
+        match Message::try_from([7, 0, 0, 0, 2, 0, 0, 0x40, 0, 0xaa].as_slice()).unwrap() {
+            Message::Piece(piece) => {
+                assert_eq!((piece.index(), piece.begin()), (2, 0x4000));
+                assert_eq!(piece.data(), [0xaa]);
+            }
+            other => panic!("unexpected {other}"),
+        }
+    }
+
     #[test]
     fn empty_body_messages_test() {
         use Message::*;
@@ -394,4 +877,307 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn extended_messages_round_trip() {
+        let handshake = b"d1:md6:ut_pexi1eee".to_vec();
+        let messages = [(0, handshake), (1, vec![0xde, 0xad]), (3, vec![])];
+        for (ext_id, payload) in messages {
+            let message = Message::Extended {
+                ext_id,
+                payload: payload.clone(),
+            };
+            let bytes = message.to_bytes();
+            assert_eq!(bytes[4..6], [20, ext_id]);
+            match Message::try_from(&bytes[4..]).unwrap() {
+                Message::Extended {
+                    ext_id: parsed_id,
+                    payload: parsed,
+                } => assert_eq!((parsed_id, parsed), (ext_id, payload)),
+                other => panic!("unexpected {other}"),
+            }
+        }
+        assert!(matches!(
+            Message::try_from([20].as_slice()),
+            Err(ConnectionError::UnexpectedEOF)
+        ));
+    }
+
+    #[test]
+    fn fixed_size_payloads_checked() {
+        assert!(matches!(
+            Message::try_from([4, 0, 0, 0, 9, 0].as_slice()),
+            Err(ConnectionError::PayloadLength(5))
+        ));
+        assert!(matches!(
+            Message::try_from([4, 0, 0, 9].as_slice()),
+            Err(ConnectionError::PayloadLength(3))
+        ));
+        assert!(matches!(
+            Message::try_from([0, 1].as_slice()),
+            Err(ConnectionError::PayloadLength(1))
+        ));
+        assert!(matches!(
+            Message::try_from([9, 0x1a, 0xe1, 0].as_slice()),
+            Err(ConnectionError::PayloadLength(3))
+        ));
+        assert!(matches!(
+            Message::try_from([0].as_slice()),
+            Ok(Message::Choke)
+        ));
+        assert!(matches!(
+            Message::try_from([9, 0x1a, 0xe1].as_slice()),
+            Ok(Message::Port(6881))
+        ));
+    }
+
+    #[test]
+    fn handshake_flags_zero_peer_id() {
+        let info_hash = [7; 20];
+        let response =
+            HandshakeMessage::new(ReservedBits::default(), info_hash, PeerId::default()).to_bytes();
+        let transport = MockTransport::new(response.to_vec());
+
+        let connection =
+            PeerConnection::handshake(transport, info_hash, &PeerId::random()).unwrap();
+        assert!(connection.is_peer_id_bogus());
+
+        let transport = MockTransport::new(response.to_vec());
+        let result = PeerConnection::handshake_strict(transport, info_hash, &PeerId::random());
+        assert!(matches!(result, Err(ConnectionError::BogusPeerId(_))));
+    }
+
+    #[test]
+    fn initial_availability() {
+        let mut have = PieceBitfield::new(10);
+        assert!(Message::availability(&have, false).is_none());
+        assert!(matches!(
+            Message::availability(&have, true),
+            Some(Message::HaveNone)
+        ));
+
+        have.set(0);
+        have.set(9);
+        for fast_extension in [false, true] {
+            let message = Message::availability(&have, fast_extension).unwrap();
+            assert!(matches!(message, Message::Bitfield(_)));
+            assert_eq!(message.to_bytes()[4..], [5, 0b1000_0000, 0b0100_0000]);
+        }
+
+        (1..9).for_each(|index| have.set(index));
+        assert!(matches!(
+            Message::availability(&have, true),
+            Some(Message::HaveAll)
+        ));
+        let message = Message::availability(&have, false).unwrap();
+        assert_eq!(message.to_bytes()[4..], [5, 0xff, 0b1100_0000]);
+    }
+
+    #[test]
+    fn v2_info_hash_truncated_on_wire() {
+        let info_hash = InfoHash::V2([9; 32]);
+        let response = HandshakeMessage::new(ReservedBits::default(), [9; 20], PeerId::random());
+        let transport = MockTransport::new(response.to_bytes().to_vec());
+        let connection =
+            PeerConnection::handshake(transport, info_hash, &PeerId::random()).unwrap();
+        assert_eq!(connection.transport.output[28..48], [9; 20]);
+        assert_eq!(connection.transport.output.len(), 68);
+    }
+
+    #[test]
+    fn connection_from_handshaked_stream() {
+        let peer_id = PeerId::random();
+        let transport = MockTransport::new(vec![0, 0, 0, 5, 4, 0, 0, 0, 9]);
+        let mut connection = PeerConnection::from_handshaked(transport, peer_id.clone());
+        assert_eq!(connection.peer_id(), &peer_id);
+
+        assert!(matches!(connection.recv(), Ok(Message::Have(9))));
+        connection.send(Message::Interested).unwrap();
+        assert_eq!(connection.transport.output[4..], [2]);
+    }
+
+    #[test]
+    fn queued_messages_flushed_together() {
+        let transport = MockTransport::new(vec![0, 0, 0, 1, 1]);
+        let mut connection = PeerConnection::from_handshaked(transport, PeerId::random());
+        connection.queue(Message::Interested).unwrap();
+        connection.queue(Message::Have(3)).unwrap();
+        connection
+            .queue(Message::Request(BlockRequest::new(3, 0, 16384)))
+            .unwrap();
+        assert!(connection.transport.output.is_empty());
+
+        connection.flush().unwrap();
+        assert_eq!(connection.transport.writes, 1);
+        assert_eq!(
+            connection.transport.output,
+            [
+                vec![0, 0, 0, 1, 2],
+                vec![0, 0, 0, 5, 4, 0, 0, 0, 3],
+                vec![0, 0, 0, 13, 6, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0x40, 0],
+            ]
+            .concat()
+        );
+        connection.flush().unwrap();
+        assert_eq!(connection.transport.writes, 1);
+
+        // waiting for the peer's answer sends what is queued
+        connection.queue(Message::NotInterested).unwrap();
+        assert!(matches!(connection.recv(), Ok(Message::UnChoke)));
+        assert_eq!(connection.transport.writes, 2);
+    }
+
+    #[test]
+    fn oversized_message_refused() {
+        let mut input = vec![0xff, 0xff, 0xff, 0xf0, 7];
+        input.extend_from_slice(&[0, 0, 0, 3, 5, 0xff, 0xff]);
+        let transport = MockTransport::new(input.clone());
+        let mut connection = PeerConnection::from_handshaked(transport, PeerId::random());
+        assert!(matches!(
+            connection.recv(),
+            Err(ConnectionError::OversizedMessage(0xffff_fff0))
+        ));
+
+        // bitfield of 200000 pieces
+        let mut input = (25_001u32).to_be_bytes().to_vec();
+        input.push(5);
+        input.extend_from_slice(&[0xff; 25_000]);
+        let mut connection =
+            PeerConnection::from_handshaked(MockTransport::new(input.clone()), PeerId::random());
+        assert!(matches!(
+            connection.recv(),
+            Err(ConnectionError::OversizedMessage(25_001))
+        ));
+        let mut connection =
+            PeerConnection::from_handshaked(MockTransport::new(input), PeerId::random());
+        connection.set_pieces_count(200_000);
+        assert!(matches!(connection.recv(), Ok(Message::Bitfield(_))));
+    }
+
+    #[test]
+    fn extended_handshake_caps_pipeline() {
+        let info_hash = [7; 20];
+        let mut input = HandshakeMessage::new(ReservedBits::default(), info_hash, PeerId::random())
+            .to_bytes()
+            .to_vec();
+        let payload =
+            b"d1:md6:ut_pexi1ee1:pi51413e4:reqqi16e1:v12:uTorrent 3.56:yourip4:\xcb\x00\x71\x07e";
+        input.extend_from_slice(&(payload.len() as u32 + 2).to_be_bytes());
+        input.extend_from_slice(&[20, 0]);
+        input.extend_from_slice(payload);
+
+        let mut connection =
+            PeerConnection::handshake(MockTransport::new(input), info_hash, &PeerId::random())
+                .unwrap();
+        assert_eq!(connection.pipeline_depth(64), 64);
+        assert!(matches!(
+            connection.recv(),
+            Ok(Message::Extended { ext_id: 0, .. })
+        ));
+
+        let info = connection.extension_info().unwrap();
+        assert_eq!(info.client.as_deref(), Some("uTorrent 3.5"));
+        assert_eq!(info.reqq, Some(16));
+        assert_eq!(info.listen_port, Some(51413));
+        assert_eq!(info.your_ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(info.extensions.get("ut_pex"), Some(&1));
+        assert_eq!(connection.pipeline_depth(64), 16);
+        assert_eq!(connection.pipeline_depth(4), 4);
+        assert!(!connection.extension_protocol());
+        assert_eq!(
+            PeerExtensionInfo::from_handshake(&info.to_handshake()).unwrap(),
+            *info
+        );
+    }
+
+    #[test]
+    fn messages_traced_both_ways() {
+        test_log::install();
+
+        let info_hash = [7; 20];
+        let mut input = HandshakeMessage::new(ReservedBits::default(), info_hash, PeerId::random())
+            .to_bytes()
+            .to_vec();
+        input.extend_from_slice(&[0, 0, 0, 5, 4, 0, 0, 0, 3]);
+        let mut connection =
+            PeerConnection::handshake(MockTransport::new(input), info_hash, &PeerId::random())
+                .unwrap();
+        connection.set_addr("10.0.0.1:6881".parse().unwrap());
+
+        connection.send(Message::Interested).unwrap();
+        assert!(matches!(connection.recv(), Ok(Message::Have(3))));
+        // other tests of the module run concurrently, their connections have no address
+        assert_eq!(
+            test_log::lines("10.0.0.1:6881"),
+            vec![
+                "10.0.0.1:6881 sent Interested".to_string(),
+                "10.0.0.1:6881 received Have(3)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn handshake_accepts_random_peer_id() {
+        let info_hash = [7; 20];
+        let response =
+            HandshakeMessage::new(ReservedBits::default(), info_hash, PeerId::random()).to_bytes();
+        let transport = MockTransport::new(response.to_vec());
+
+        let connection =
+            PeerConnection::handshake_strict(transport, info_hash, &PeerId::random()).unwrap();
+        assert!(!connection.is_peer_id_bogus());
+    }
+
+    /// Peer that accepted the connection but never answers the handshake
+    struct SilentTransport {
+        timeout: Option<Duration>,
+    }
+
+    impl Read for SilentTransport {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            let timeout = self
+                .timeout
+                .expect("read without timeout would block forever");
+            thread::sleep(timeout);
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for SilentTransport {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl IoTimeout for SilentTransport {
+        fn set_io_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_times_out() {
+        let timeout = Duration::from_millis(50);
+        let started = Instant::now();
+        let result = PeerConnection::handshake_within(
+            SilentTransport { timeout: None },
+            [7; 20],
+            &PeerId::random(),
+            timeout,
+        );
+        assert!(matches!(result, Err(ConnectionError::HandshakeTimeout(t)) if t == timeout));
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn handshake_refuses_default_own_peer_id() {
+        let transport = MockTransport::new(Vec::new());
+        let result = PeerConnection::handshake(transport, [7; 20], &PeerId::default());
+        assert!(matches!(result, Err(ConnectionError::HandshakeFailed(_))));
+    }
 }
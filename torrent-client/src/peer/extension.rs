@@ -0,0 +1,89 @@
+use crate::peer::connection::ConnectionError;
+use bencode::{BencodeDict, Value};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Fields of a BEP 10 extended handshake
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerExtensionInfo {
+    /// Extension names mapped to the message ids the peer expects for them
+    pub extensions: BTreeMap<String, u8>,
+    /// Client name and version, `v`
+    pub client: Option<String>,
+    /// Number of outstanding requests the peer accepts, `reqq`
+    pub reqq: Option<usize>,
+    /// Port the peer listens on, `p`
+    pub listen_port: Option<u16>,
+    /// Our address as the peer sees it, `yourip`
+    pub your_ip: Option<IpAddr>,
+}
+
+impl PeerExtensionInfo {
+    /// Parses the payload of the extended message with id 0. Fields of the wrong type are ignored
+    pub fn from_handshake(payload: &[u8]) -> Result<Self, ConnectionError> {
+        let dict: BencodeDict = bencode::from_slice(payload)
+            .and_then(BencodeDict::try_from)
+            .map_err(|e| ConnectionError::ExtendedHandshake(Cow::Owned(e.to_string())))?;
+        let field = |key: &str| dict.get(key.as_bytes());
+
+        let extensions = field("m")
+            .and_then(Value::as_dict)
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(name, id)| {
+                        let id = u8::try_from(id.as_int()?).ok()?;
+                        Some((String::from_utf8_lossy(name).to_string(), id))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let your_ip = field("yourip")
+            .and_then(Value::as_bytes)
+            .and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?))),
+                16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?))),
+                _ => None,
+            });
+        Ok(Self {
+            extensions,
+            client: field("v").and_then(Value::as_str).map(str::to_string),
+            reqq: field("reqq")
+                .and_then(Value::as_int)
+                .and_then(|reqq| usize::try_from(reqq).ok())
+                .filter(|reqq| *reqq > 0),
+            listen_port: field("p")
+                .and_then(Value::as_int)
+                .and_then(|port| u16::try_from(port).ok()),
+            your_ip,
+        })
+    }
+
+    /// Payload of our own extended handshake, only the fields that are set
+    pub fn to_handshake(&self) -> Vec<u8> {
+        let mut dict = BencodeDict::new();
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Int(*id as i64)))
+            .collect();
+        dict.insert(b"m".to_vec(), Value::Dict(extensions));
+        if let Some(client) = &self.client {
+            dict.insert(b"v".to_vec(), Value::String(client.as_bytes().to_vec()));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), Value::Int(reqq as i64));
+        }
+        if let Some(port) = self.listen_port {
+            dict.insert(b"p".to_vec(), Value::Int(port as i64));
+        }
+        if let Some(ip) = self.your_ip {
+            let ip = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            dict.insert(b"yourip".to_vec(), Value::String(ip));
+        }
+        bencode::into_vec(&Value::Dict(dict))
+    }
+}
@@ -1,14 +1,19 @@
 pub mod connection;
+pub mod extension;
+pub mod mse;
 
 use rand::RngCore;
 use std::borrow::Borrow;
-use std::net::SocketAddr;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
+use std::str::FromStr;
+use thiserror::Error;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PeerId([u8; 20]);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Peer {
     pub peer_id: Option<PeerId>,
     pub addr: SocketAddr,
@@ -21,8 +26,74 @@ impl PeerId {
 
     pub fn random() -> Self {
         let mut peer_id = [0; 20];
-        rand::thread_rng().fill_bytes(&mut peer_id);
-        Self::new(peer_id)
+        loop {
+            rand::thread_rng().fill_bytes(&mut peer_id);
+            let peer_id = Self::new(peer_id);
+            if !peer_id.is_bogus() {
+                return peer_id;
+            }
+        }
+    }
+
+    /// Client name from an Azureus-style `-XX1234-` prefix, for the clients we know
+    pub fn client_name(&self) -> Option<&'static str> {
+        if self.0[0] != b'-' || self.0[7] != b'-' {
+            return None;
+        }
+        let name = match &self.0[1..3] {
+            b"AZ" => "Vuze",
+            b"BC" => "BitComet",
+            b"BT" => "BitTorrent",
+            b"DE" => "Deluge",
+            b"KT" => "KTorrent",
+            b"LT" => "libtorrent",
+            b"lt" => "libTorrent",
+            b"qB" => "qBittorrent",
+            b"TR" => "Transmission",
+            b"UM" => "\u{b5}Torrent Mac",
+            b"UT" => "\u{b5}Torrent",
+            b"WW" => "WebTorrent",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    /// Peer id made of a single repeated byte, e.g. the all-zero default,
+    /// is never produced by a sane client
+    pub fn is_bogus(&self) -> bool {
+        self.0.iter().all(|byte| *byte == self.0[0])
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum PeerIdError {
+    #[error("Peer id must be 20 ascii bytes or 40 hex characters, got {0} bytes")]
+    Length(usize),
+    #[error("Invalid hex peer id: {0}")]
+    Hex(#[from] hex::FromHexError),
+    #[error("Peer id of 20 characters must be ascii")]
+    NonAscii,
+    #[error("Peer id of a single repeated byte would be refused by peers")]
+    Bogus,
+}
+
+impl FromStr for PeerId {
+    type Err = PeerIdError;
+
+    /// Accepts either 40 hex characters or 20 ascii characters taken verbatim,
+    /// see [`PeerId::is_bogus`] for the ids refused
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let peer_id = match s.len() {
+            40 => hex::decode(s)?,
+            20 if s.is_ascii() => s.as_bytes().to_vec(),
+            20 => return Err(PeerIdError::NonAscii),
+            len => return Err(PeerIdError::Length(len)),
+        };
+        let peer_id = Self::new(peer_id.try_into().unwrap());
+        if peer_id.is_bogus() {
+            return Err(PeerIdError::Bogus);
+        }
+        Ok(peer_id)
     }
 }
 
@@ -56,4 +127,84 @@ impl Peer {
     pub fn new(peer_id: Option<PeerId>, addr: SocketAddr) -> Self {
         Self { peer_id, addr }
     }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn peer_id(&self) -> Option<&PeerId> {
+        self.peer_id.as_ref()
+    }
+
+    /// Whether connecting is worth a try, `0.0.0.0`, port 0, multicast and broadcast
+    /// addresses some trackers hand out never answer
+    pub fn is_connectable(&self, allow_loopback: bool) -> bool {
+        let ip = self.addr.ip();
+        let broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+        self.addr.port() != 0
+            && !ip.is_unspecified()
+            && !ip.is_multicast()
+            && !broadcast
+            && (allow_loopback || !ip.is_loopback())
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.peer_id.as_ref().and_then(PeerId::client_name) {
+            Some(client) => write!(f, "{} ({client})", self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::{Peer, PeerId, PeerIdError};
+    use std::str::FromStr;
+
+    #[test]
+    fn peer_id_from_ascii() {
+        let peer_id = PeerId::from_str("-VD0001-123456789012").unwrap();
+        assert_eq!(peer_id.as_ref(), b"-VD0001-123456789012");
+    }
+
+    #[test]
+    fn peer_id_from_hex() {
+        let peer_id = PeerId::from_str("2d56443030303129ffffffffffffffffffffffff").unwrap();
+        let mut expected = [0xff; 20];
+        expected[..8].copy_from_slice(b"-VD0001)");
+        assert_eq!(*peer_id, expected);
+    }
+
+    #[test]
+    fn peer_display() {
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let transmission = PeerId::from_str("-TR3000-123456789012").unwrap();
+        assert_eq!(
+            Peer::new(Some(transmission), addr).to_string(),
+            "10.0.0.1:6881 (Transmission)"
+        );
+        assert_eq!(Peer::new(None, addr).to_string(), "10.0.0.1:6881");
+        let unknown = PeerId::from_str("-ZZ0001-123456789012").unwrap();
+        assert_eq!(Peer::new(Some(unknown), addr).to_string(), "10.0.0.1:6881");
+    }
+
+    #[test]
+    fn peer_id_wrong_length() {
+        assert_eq!(PeerId::from_str("-VD0001-"), Err(PeerIdError::Length(8)));
+        assert!(matches!(
+            PeerId::from_str(&"zz".repeat(20)),
+            Err(PeerIdError::Hex(_))
+        ));
+        assert_eq!(
+            PeerId::from_str("-VD0001-12345678901\u{e9}"),
+            Err(PeerIdError::Length(21))
+        );
+        assert_eq!(
+            PeerId::from_str("-VD0001-1234567890\u{e9}"),
+            Err(PeerIdError::NonAscii)
+        );
+        assert_eq!(PeerId::from_str(&"0".repeat(40)), Err(PeerIdError::Bogus));
+    }
 }
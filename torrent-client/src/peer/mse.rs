@@ -0,0 +1,513 @@
+use crate::peer::connection::ConnectionError::{Encryption, PlaintextPeer};
+use crate::peer::connection::{ConnectionError, IoTimeout};
+use crate::util::Sha1;
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use sha1::Digest;
+use std::borrow::Cow;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, ConnectionError>;
+
+/// 768-bit safe prime of the Message Stream Encryption key exchange, generator is 2
+const PRIME: &[u8; 96] = &[
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const KEY_LENGTH: usize = 96;
+const MAX_PADDING: usize = 512;
+const VERIFICATION_CONSTANT: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum EncryptionMode {
+    /// Plain BitTorrent handshake only
+    #[default]
+    Disabled,
+    /// Encrypted handshake first, plaintext reconnect when the peer doesn't speak it
+    Prefer,
+    /// Peers that can't encrypt the whole stream are dropped
+    Require,
+}
+
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// MSE drops the first 1024 bytes of the key stream
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        let mut rc4 = Self { state, i: 0, j: 0 };
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = sha1::Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut result = a;
+    result.iter_mut().zip(b).for_each(|(a, b)| *a ^= b);
+    result
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    fn random() -> Self {
+        let mut private = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(2u8).modpow(&private, &BigUint::from_bytes_be(PRIME));
+        Self {
+            public: to_key(&public),
+            private,
+        }
+    }
+
+    fn secret(&self, remote: &[u8; KEY_LENGTH]) -> [u8; KEY_LENGTH] {
+        let remote = BigUint::from_bytes_be(remote);
+        to_key(&remote.modpow(&self.private, &BigUint::from_bytes_be(PRIME)))
+    }
+}
+
+fn to_key(value: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut padding = vec![0; rand::thread_rng().gen_range(0..=MAX_PADDING)];
+    rand::thread_rng().fill_bytes(&mut padding);
+    padding
+}
+
+/// Reads until the stream ends with `pattern`, which must show up within `limit` bytes
+fn sync<T: Read>(transport: &mut T, pattern: &[u8], limit: usize) -> Result<()> {
+    let mut window = Vec::with_capacity(limit);
+    let mut byte = [0u8];
+    while window.len() < limit {
+        transport.read_exact(&mut byte)?;
+        window.push(byte[0]);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(Encryption(Cow::Borrowed(
+        "no synchronization point in the stream",
+    )))
+}
+
+/// Peer stream after the encryption handshake, it stays plaintext when the handshake
+/// never happened or the peers agreed on plaintext
+pub struct MseStream<T = TcpStream> {
+    inner: T,
+    encryptor: Option<Rc4>,
+    decryptor: Option<Rc4>,
+    /// Decrypted initial payload the initiator sent along with the handshake,
+    /// read before anything else
+    initial_payload: Vec<u8>,
+}
+
+impl<T: Read + Write> MseStream<T> {
+    pub fn plain(inner: T) -> Self {
+        Self {
+            inner,
+            encryptor: None,
+            decryptor: None,
+            initial_payload: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+
+    /// Underlying stream, reading or writing it directly breaks the encryption
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Encryption handshake of the connecting side. Plaintext is only offered when
+    /// `allow_plaintext` is set, a peer that picks it anyway is refused
+    pub fn initiate(inner: T, info_hash: &Sha1, allow_plaintext: bool) -> Result<Self> {
+        Self::initiate_with(inner, info_hash, allow_plaintext, &[])
+    }
+
+    /// [`MseStream::initiate`] sending `initial_payload` together with the handshake
+    fn initiate_with(
+        mut inner: T,
+        info_hash: &Sha1,
+        allow_plaintext: bool,
+        initial_payload: &[u8],
+    ) -> Result<Self> {
+        let keys = KeyPair::random();
+        inner.write_all(&[keys.public.as_slice(), &random_padding()].concat())?;
+        let mut remote = [0u8; KEY_LENGTH];
+        inner.read_exact(&mut remote)?;
+        let secret = keys.secret(&remote);
+
+        let mut encryptor = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+        let mut decryptor = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+        let provide = if allow_plaintext {
+            CRYPTO_RC4 | CRYPTO_PLAINTEXT
+        } else {
+            CRYPTO_RC4
+        };
+        let padding = random_padding();
+        let mut header = VERIFICATION_CONSTANT.to_vec();
+        header.extend_from_slice(&provide.to_be_bytes());
+        header.extend_from_slice(&(padding.len() as u16).to_be_bytes());
+        header.extend_from_slice(&padding);
+        header.extend_from_slice(&(initial_payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(initial_payload);
+        encryptor.apply(&mut header);
+        let request = hash(&[b"req1", &secret]);
+        let skey = xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret]));
+        inner.write_all(&[request.as_slice(), &skey, &header].concat())?;
+
+        let mut verification = VERIFICATION_CONSTANT;
+        decryptor.apply(&mut verification);
+        sync(
+            &mut inner,
+            &verification,
+            MAX_PADDING + VERIFICATION_CONSTANT.len(),
+        )?;
+        let mut response = [0u8; 6];
+        inner.read_exact(&mut response)?;
+        decryptor.apply(&mut response);
+        let select = u32::from_be_bytes(response[..4].try_into().unwrap());
+        let padding = u16::from_be_bytes(response[4..].try_into().unwrap()) as usize;
+        if padding > MAX_PADDING {
+            return Err(Encryption(Cow::Owned(format!(
+                "padding {padding} too long"
+            ))));
+        }
+        let mut padding = vec![0u8; padding];
+        inner.read_exact(&mut padding)?;
+        decryptor.apply(&mut padding);
+
+        match select {
+            CRYPTO_RC4 => Ok(Self {
+                inner,
+                encryptor: Some(encryptor),
+                decryptor: Some(decryptor),
+                initial_payload: Vec::new(),
+            }),
+            CRYPTO_PLAINTEXT if allow_plaintext => Ok(Self::plain(inner)),
+            CRYPTO_PLAINTEXT => Err(PlaintextPeer),
+            select => Err(Encryption(Cow::Owned(format!(
+                "peer selected unknown crypto method {select:#x}"
+            )))),
+        }
+    }
+
+    /// Encryption handshake of the accepting side, `info_hashes` are the torrents we serve.
+    /// Returns the stream with the info hash the peer asked for. An initial payload sent
+    /// by the peer, usually its BitTorrent handshake, is the first thing read from the stream
+    pub fn respond(
+        mut inner: T,
+        info_hashes: &[Sha1],
+        allow_plaintext: bool,
+    ) -> Result<(Self, Sha1)> {
+        let mut remote = [0u8; KEY_LENGTH];
+        inner.read_exact(&mut remote)?;
+        let keys = KeyPair::random();
+        inner.write_all(&[keys.public.as_slice(), &random_padding()].concat())?;
+        let secret = keys.secret(&remote);
+
+        sync(
+            &mut inner,
+            &hash(&[b"req1", &secret]),
+            MAX_PADDING + sha1::Sha1::output_size(),
+        )?;
+        let mut skey = [0u8; 20];
+        inner.read_exact(&mut skey)?;
+        let skey = xor(skey, hash(&[b"req3", &secret]));
+        let info_hash = *info_hashes
+            .iter()
+            .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]) == skey)
+            .ok_or(Encryption(Cow::Borrowed("peer asked for unknown torrent")))?;
+
+        let mut decryptor = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut encryptor = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+        let mut request = [0u8; 14];
+        inner.read_exact(&mut request)?;
+        decryptor.apply(&mut request);
+        if request[..8] != VERIFICATION_CONSTANT {
+            return Err(Encryption(Cow::Borrowed("invalid verification constant")));
+        }
+        let provide = u32::from_be_bytes(request[8..12].try_into().unwrap());
+        let padding = u16::from_be_bytes(request[12..].try_into().unwrap()) as usize;
+        if padding > MAX_PADDING {
+            return Err(Encryption(Cow::Owned(format!(
+                "padding {padding} too long"
+            ))));
+        }
+        let mut padding = vec![0u8; padding + 2];
+        inner.read_exact(&mut padding)?;
+        decryptor.apply(&mut padding);
+        let payload_length =
+            u16::from_be_bytes(padding[padding.len() - 2..].try_into().unwrap()) as usize;
+        let mut initial_payload = vec![0u8; payload_length];
+        inner.read_exact(&mut initial_payload)?;
+        decryptor.apply(&mut initial_payload);
+
+        let select = if provide & CRYPTO_RC4 != 0 {
+            CRYPTO_RC4
+        } else if provide & CRYPTO_PLAINTEXT != 0 && allow_plaintext {
+            CRYPTO_PLAINTEXT
+        } else {
+            return Err(PlaintextPeer);
+        };
+        let mut response = VERIFICATION_CONSTANT.to_vec();
+        response.extend_from_slice(&select.to_be_bytes());
+        response.extend_from_slice(&0u16.to_be_bytes());
+        encryptor.apply(&mut response);
+        inner.write_all(&response)?;
+
+        let mut stream = if select == CRYPTO_RC4 {
+            Self {
+                inner,
+                encryptor: Some(encryptor),
+                decryptor: Some(decryptor),
+                initial_payload: Vec::new(),
+            }
+        } else {
+            Self::plain(inner)
+        };
+        stream.initial_payload = initial_payload;
+        Ok((stream, info_hash))
+    }
+}
+
+impl<T: Read + Write + IoTimeout> MseStream<T> {
+    /// Opens a stream according to the encryption mode, `connect` is called once more
+    /// for the plaintext retry of [`EncryptionMode::Prefer`]. A peer silent for `timeout`
+    /// during the encryption handshake fails it, the stream is left without timeouts
+    pub fn establish<F>(
+        mut connect: F,
+        info_hash: &Sha1,
+        mode: EncryptionMode,
+        timeout: Duration,
+    ) -> Result<Self>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        let initiate = |mut inner: T, allow_plaintext| {
+            inner.set_io_timeout(Some(timeout))?;
+            let mut stream = Self::initiate(inner, info_hash, allow_plaintext)?;
+            stream.set_io_timeout(None)?;
+            Ok::<_, ConnectionError>(stream)
+        };
+        match mode {
+            EncryptionMode::Disabled => Ok(Self::plain(connect()?)),
+            EncryptionMode::Require => initiate(connect()?, false),
+            EncryptionMode::Prefer => match initiate(connect()?, true) {
+                Ok(stream) => Ok(stream),
+                Err(_) => Ok(Self::plain(connect()?)),
+            },
+        }
+    }
+}
+
+impl<T: Read> Read for MseStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.initial_payload.is_empty() {
+            let read = buf.len().min(self.initial_payload.len());
+            buf[..read].copy_from_slice(&self.initial_payload[..read]);
+            self.initial_payload.drain(..read);
+            return Ok(read);
+        }
+        let read = self.inner.read(buf)?;
+        if let Some(decryptor) = &mut self.decryptor {
+            decryptor.apply(&mut buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for MseStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encryptor {
+            Some(encryptor) => {
+                // cipher state moves on with every byte, so the whole buffer has to go out
+                let mut encrypted = buf.to_vec();
+                encryptor.apply(&mut encrypted);
+                self.inner.write_all(&encrypted)?;
+                Ok(buf.len())
+            }
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: IoTimeout> IoTimeout for MseStream<T> {
+    fn set_io_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_io_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::connection::{ConnectionError, IoTimeout};
+    use crate::peer::mse::{EncryptionMode, MseStream};
+    use std::cell::Cell;
+    use std::io;
+    use std::io::{Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Peer that only speaks plaintext BitTorrent and hangs up on anything else
+    struct PlaintextOnly {
+        input: Cursor<Vec<u8>>,
+    }
+
+    impl Read for PlaintextOnly {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for PlaintextOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl IoTimeout for PlaintextOnly {
+        fn set_io_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut stream, info_hash) =
+                MseStream::respond(stream, &[[1; 20], [2; 20]], false).unwrap();
+            let mut message = [0u8; 5];
+            stream.read_exact(&mut message).unwrap();
+            stream.write_all(b"world").unwrap();
+            (info_hash, message)
+        });
+
+        let tcp = TcpStream::connect(addr).unwrap();
+        let mut stream = MseStream::initiate(tcp, &[2; 20], false).unwrap();
+        assert!(stream.is_encrypted());
+        stream.write_all(b"hello").unwrap();
+        let mut message = [0u8; 5];
+        stream.read_exact(&mut message).unwrap();
+
+        assert_eq!(&message, b"world");
+        assert_eq!(remote.join().unwrap(), ([2; 20], *b"hello"));
+    }
+
+    #[test]
+    fn initial_payload_read_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut stream, _) = MseStream::respond(stream, &[[1; 20]], false).unwrap();
+            let mut message = [0u8; 11];
+            stream.read_exact(&mut message).unwrap();
+            message
+        });
+
+        let tcp = TcpStream::connect(addr).unwrap();
+        let mut stream = MseStream::initiate_with(tcp, &[1; 20], false, b"hello").unwrap();
+        stream.write_all(b" world").unwrap();
+
+        assert_eq!(&remote.join().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn require_drops_plaintext_peer() {
+        let connects = Cell::new(0);
+        let result = MseStream::establish(
+            || {
+                connects.set(connects.get() + 1);
+                Ok(PlaintextOnly {
+                    input: Cursor::new(Vec::new()),
+                })
+            },
+            &[1; 20],
+            EncryptionMode::Require,
+            Duration::from_secs(5),
+        );
+        assert!(matches!(result, Err(ConnectionError::IoKind(_))));
+        assert_eq!(connects.get(), 1);
+    }
+
+    #[test]
+    fn prefer_falls_back_to_plaintext() {
+        let connects = Cell::new(0);
+        let stream = MseStream::establish(
+            || {
+                connects.set(connects.get() + 1);
+                Ok(PlaintextOnly {
+                    input: Cursor::new(b"plain".to_vec()),
+                })
+            },
+            &[1; 20],
+            EncryptionMode::Prefer,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(!stream.is_encrypted());
+        assert_eq!(connects.get(), 2);
+    }
+}
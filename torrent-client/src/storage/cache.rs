@@ -0,0 +1,224 @@
+use crate::storage::{PieceStorage, Result, StorageError};
+use crate::util::{InfoHash, Sha1};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Least recently used pieces, bounded by the total amount of cached bytes
+#[derive(Debug)]
+struct LruPieces {
+    pieces: HashMap<usize, Arc<Vec<u8>>>,
+    order: VecDeque<usize>,
+    size: usize,
+    capacity: usize,
+}
+
+impl LruPieces {
+    fn new(capacity: usize) -> Self {
+        Self {
+            pieces: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, index: usize) -> Option<Arc<Vec<u8>>> {
+        let piece = self.pieces.get(&index)?.clone();
+        self.touch(index);
+        Some(piece)
+    }
+
+    fn insert(&mut self, index: usize, piece: Arc<Vec<u8>>) {
+        if piece.len() > self.capacity {
+            return;
+        }
+        self.remove(index);
+        while self.size + piece.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    if let Some(evicted) = self.pieces.remove(&oldest) {
+                        self.size -= evicted.len();
+                    }
+                }
+                None => break,
+            }
+        }
+        self.size += piece.len();
+        self.pieces.insert(index, piece);
+        self.order.push_back(index);
+    }
+
+    fn remove(&mut self, index: usize) {
+        if let Some(piece) = self.pieces.remove(&index) {
+            self.size -= piece.len();
+            self.order.retain(|cached| *cached != index);
+        }
+    }
+
+    fn touch(&mut self, index: usize) {
+        if let Some(position) = self.order.iter().position(|cached| *cached == index) {
+            self.order.remove(position);
+            self.order.push_back(index);
+        }
+    }
+}
+
+/// Serves hot pieces from memory while seeding, writes go straight to the inner storage
+/// and drop the stale copy from the cache. With piece hashes set, every piece read
+/// from the inner storage is verified before it gets cached
+pub struct CachedStorage<S: PieceStorage> {
+    inner: S,
+    cache: Mutex<LruPieces>,
+    piece_hashes: Option<(InfoHash, Vec<Sha1>)>,
+}
+
+impl<S: PieceStorage> CachedStorage<S> {
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(LruPieces::new(capacity)),
+            piece_hashes: None,
+        }
+    }
+
+    /// Enables verification of pieces read from disk, catching corruption while seeding.
+    /// The version of the info hash decides how pieces are hashed
+    pub fn set_piece_hashes(
+        &mut self,
+        info_hash: impl Into<InfoHash>,
+        piece_hashes: Vec<Sha1>,
+    ) -> &mut Self {
+        self.piece_hashes = Some((info_hash.into(), piece_hashes));
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn cached_piece(&self, index: usize) -> Result<Arc<Vec<u8>>> {
+        if let Some(piece) = self.cache.lock().unwrap().get(index) {
+            return Ok(piece);
+        }
+        let piece = Arc::new(self.inner.read_piece(index)?);
+        if let Some((info_hash, piece_hashes)) = &self.piece_hashes {
+            let expected = piece_hashes
+                .get(index)
+                .ok_or(StorageError::PieceOutOfRange(index))?;
+            if !info_hash.verifies(&piece, expected) {
+                return Err(StorageError::CorruptPiece(index));
+            }
+        }
+        self.cache.lock().unwrap().insert(index, piece.clone());
+        Ok(piece)
+    }
+
+    /// Reads a block of the piece, as requested by a peer
+    pub fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        let piece = self.cached_piece(index)?;
+        let block = begin
+            .checked_add(length)
+            .and_then(|end| piece.get(begin..end))
+            .ok_or(StorageError::BlockOutOfRange(index, begin, length))?;
+        Ok(block.to_vec())
+    }
+}
+
+impl<S: PieceStorage> PieceStorage for CachedStorage<S> {
+    fn read_piece(&self, index: usize) -> Result<Vec<u8>> {
+        Ok(self.cached_piece(index)?.as_ref().clone())
+    }
+
+    fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        self.cache.lock().unwrap().remove(index);
+        self.inner.write_piece(index, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::cache::CachedStorage;
+    use crate::storage::{PieceStorage, Result, StorageError};
+    use sha1::Digest;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct CountingStorage {
+        pieces: Mutex<HashMap<usize, Vec<u8>>>,
+        reads: AtomicUsize,
+    }
+
+    impl PieceStorage for CountingStorage {
+        fn read_piece(&self, index: usize) -> Result<Vec<u8>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(self.pieces.lock().unwrap()[&index].clone())
+        }
+
+        fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+            self.pieces.lock().unwrap().insert(index, data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn second_read_served_from_cache() {
+        let storage = CachedStorage::new(CountingStorage::default(), 16);
+        storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(storage.read_block(0, 1, 2).unwrap(), vec![2, 3]);
+        assert_eq!(storage.read_block(0, 0, 4).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(storage.inner().reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn rewrite_invalidates_cache() {
+        let storage = CachedStorage::new(CountingStorage::default(), 16);
+        storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(storage.read_piece(0).unwrap(), vec![1, 2, 3, 4]);
+
+        storage.write_piece(0, &[5, 6, 7, 8]).unwrap();
+        assert_eq!(storage.read_piece(0).unwrap(), vec![5, 6, 7, 8]);
+        assert_eq!(storage.inner().reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let storage = CachedStorage::new(CountingStorage::default(), 8);
+        for index in 0..3 {
+            storage.write_piece(index, &[index as u8; 4]).unwrap();
+        }
+        storage.read_piece(0).unwrap();
+        storage.read_piece(1).unwrap();
+        storage.read_piece(0).unwrap();
+        // piece 1 is the least recently used one and leaves the cache
+        storage.read_piece(2).unwrap();
+        storage.read_piece(0).unwrap();
+        assert_eq!(storage.inner().reads.load(Ordering::SeqCst), 3);
+        storage.read_piece(1).unwrap();
+        assert_eq!(storage.inner().reads.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn block_out_of_piece_range() {
+        let storage = CachedStorage::new(CountingStorage::default(), 16);
+        storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+        assert!(storage.read_block(0, 3, 2).is_err());
+    }
+
+    #[test]
+    fn corrupt_piece_not_served() {
+        let mut storage = CachedStorage::new(CountingStorage::default(), 16);
+        let hashes = vec![sha1::Sha1::digest([1; 4]).into(); 2];
+        storage.set_piece_hashes([0; 20], hashes);
+        storage.write_piece(0, &[1; 4]).unwrap();
+        storage.write_piece(1, &[2; 4]).unwrap();
+
+        assert_eq!(storage.read_piece(0).unwrap(), vec![1; 4]);
+        assert!(matches!(
+            storage.read_piece(1),
+            Err(StorageError::CorruptPiece(1))
+        ));
+    }
+}
@@ -0,0 +1,570 @@
+pub mod cache;
+pub mod resume;
+
+use crate::file::{FileAttributes, Info};
+use crate::storage::StorageError::{DataLength, PieceOutOfRange};
+use crate::util::{InfoHash, PieceBitfield, Sha1};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+
+type Result<T> = std::result::Result<T, StorageError>;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Piece index {0} is out of range")]
+    PieceOutOfRange(usize),
+    #[error("Piece {0} has length {1}, expected {2}")]
+    DataLength(usize, usize, usize),
+    #[error("Block at offset {1} with length {2} is out of piece {0} range")]
+    BlockOutOfRange(usize, usize, usize),
+    #[error("Piece {0} on disk doesn't match its hash")]
+    CorruptPiece(usize),
+    #[error("Piece {0} is not available")]
+    MissingPiece(usize),
+    #[error("Malformed resume state {0}")]
+    ResumeState(String),
+    #[error("Verification cancelled")]
+    Cancelled,
+}
+
+/// How space for the files of a torrent is reserved
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AllocationStrategy {
+    /// Files grow as pieces are written, holes stay unallocated
+    #[default]
+    Sparse,
+    /// Files are zero-filled up to their full length before downloading
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: usize,
+    /// Offset of the first byte of the file in the torrent content
+    pub offset: usize,
+    pub attr: FileAttributes,
+    pub symlink_path: Option<PathBuf>,
+}
+
+/// Part of a piece that lands in a single file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    pub file_offset: usize,
+    /// Offset of the slice inside the mapped range
+    pub range_offset: usize,
+    pub length: usize,
+}
+
+/// Maps torrent content offsets onto the files of the torrent
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    files: Vec<FileEntry>,
+    piece_length: usize,
+    pieces_count: usize,
+    total_length: usize,
+}
+
+impl StorageLayout {
+    pub fn new(info: &Info) -> Self {
+        let mut offset = 0;
+        let files = info
+            .files
+            .iter()
+            .map(|file| {
+                let entry = FileEntry {
+                    path: info.name.join(&file.path),
+                    length: file.length,
+                    offset,
+                    attr: file.attr,
+                    symlink_path: file.symlink_path.as_ref().map(|path| info.name.join(path)),
+                };
+                offset += file.length;
+                entry
+            })
+            .collect();
+        Self {
+            files,
+            piece_length: info.piece_length,
+            pieces_count: info.pieces.len(),
+            total_length: offset,
+        }
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn total_length(&self) -> usize {
+        self.total_length
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.pieces_count
+    }
+
+    /// Length of the piece, only the last piece may be shorter than `piece_length`
+    pub fn piece_size(&self, index: usize) -> Result<usize> {
+        if index >= self.pieces_count {
+            return Err(PieceOutOfRange(index));
+        }
+        let start = index * self.piece_length;
+        Ok(self
+            .piece_length
+            .min(self.total_length.saturating_sub(start)))
+    }
+
+    /// Splits a content range into per-file slices. Zero-length files never take part
+    /// in the mapping, so they can't shift offsets of the files that follow them.
+    /// Padding files are skipped too, their content is zeroes and never hits the disk
+    pub fn map_range(&self, offset: usize, length: usize) -> Vec<FileSlice> {
+        let end = offset + length;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.length > 0 && !file.attr.padding)
+            .filter_map(|(file_index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                (start < stop).then(|| FileSlice {
+                    file_index,
+                    file_offset: start - file.offset,
+                    range_offset: start - offset,
+                    length: stop - start,
+                })
+            })
+            .collect()
+    }
+
+    pub fn piece_slices(&self, index: usize) -> Result<Vec<FileSlice>> {
+        let size = self.piece_size(index)?;
+        Ok(self.map_range(index * self.piece_length, size))
+    }
+}
+
+/// Piece-granular access to torrent content
+pub trait PieceStorage {
+    fn read_piece(&self, index: usize) -> Result<Vec<u8>>;
+    fn write_piece(&self, index: usize, data: &[u8]) -> Result<()>;
+}
+
+/// Writes verified pieces into the files of the torrent under `root`
+pub struct StorageWriter {
+    root: PathBuf,
+    layout: StorageLayout,
+    /// Picks the hash function pieces are verified with
+    info_hash: InfoHash,
+    piece_hashes: Vec<Sha1>,
+    allocation: AllocationStrategy,
+    /// Pieces whose every byte was written and synced to disk
+    durable: Mutex<PieceBitfield>,
+}
+
+impl StorageWriter {
+    pub fn new(root: &Path, info: &Info) -> Self {
+        let layout = StorageLayout::new(info);
+        Self {
+            root: root.to_path_buf(),
+            durable: Mutex::new(PieceBitfield::new(layout.pieces_count())),
+            layout,
+            info_hash: info.info_hash.into(),
+            piece_hashes: info.pieces.clone(),
+            allocation: AllocationStrategy::default(),
+        }
+    }
+
+    pub fn set_allocation(&mut self, allocation: AllocationStrategy) -> &mut Self {
+        self.allocation = allocation;
+        self
+    }
+
+    pub fn allocation(&self) -> AllocationStrategy {
+        self.allocation
+    }
+
+    pub fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    /// Pieces that survive a crash, only those go into the resume state
+    pub fn durable_pieces(&self) -> PieceBitfield {
+        self.durable.lock().unwrap().clone()
+    }
+
+    pub(crate) fn mark_durable(&self, pieces: &PieceBitfield) {
+        let mut durable = self.durable.lock().unwrap();
+        pieces.iter_set().for_each(|index| durable.set(index));
+    }
+
+    pub fn file_path(&self, file_index: usize) -> PathBuf {
+        self.root.join(&self.layout.files[file_index].path)
+    }
+
+    /// Creates every file of the torrent, including the empty ones nothing will ever be written to.
+    /// Padding files are not created, symlinks are created only on Unix.
+    /// With [`AllocationStrategy::Full`] files are zero-filled to their declared length
+    pub fn create_files(&self) -> Result<()> {
+        for (file_index, file) in self.layout.files.iter().enumerate() {
+            if file.attr.padding {
+                continue;
+            }
+            let path = self.file_path(file_index);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if let (true, Some(target)) = (file.attr.symlink, &file.symlink_path) {
+                self.create_symlink(&path, &self.root.join(target))?;
+                continue;
+            }
+            let file_handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            if self.allocation == AllocationStrategy::Full {
+                Self::preallocate(&file_handle, file.length)?;
+            }
+            #[cfg(unix)]
+            if file.attr.executable {
+                use std::os::unix::fs::PermissionsExt;
+                let mut permissions = file_handle.metadata()?.permissions();
+                permissions.set_mode(permissions.mode() | 0o111);
+                file_handle.set_permissions(permissions)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks pieces already on disk, e.g. after a restart. Pieces with missing or short
+    /// files, like the one interrupted mid-write, are reported as not present
+    pub fn verify_existing(&self) -> Result<PieceBitfield> {
+        self.verify_existing_with(|_, _| {}, &AtomicBool::new(false))
+    }
+
+    /// Same as [`StorageWriter::verify_existing`], reporting pieces hashed and total after
+    /// every piece. Setting `cancel` stops the scan with [`StorageError::Cancelled`]
+    pub fn verify_existing_with<F>(
+        &self,
+        mut progress: F,
+        cancel: &AtomicBool,
+    ) -> Result<PieceBitfield>
+    where
+        F: FnMut(usize, usize),
+    {
+        let total = self.piece_hashes.len();
+        let mut have = PieceBitfield::new(self.layout.pieces_count);
+        for (index, hash) in self.piece_hashes.iter().enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(StorageError::Cancelled);
+            }
+            if let Some(piece) = self.read_stored_piece(index)? {
+                if self.info_hash.verifies(&piece, hash) {
+                    have.set(index);
+                }
+            }
+            progress(index + 1, total);
+        }
+        Ok(have)
+    }
+
+    /// Writes zeroes past the current end of the file, unlike `set_len` alone this
+    /// actually reserves the disk blocks
+    fn preallocate(mut file: &fs::File, length: usize) -> Result<()> {
+        let current = file.metadata()?.len();
+        let length = length as u64;
+        if current < length {
+            file.seek(SeekFrom::Start(current))?;
+            io::copy(&mut io::repeat(0).take(length - current), &mut file)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the piece only if every byte of it has been stored
+    fn read_stored_piece(&self, index: usize) -> Result<Option<Vec<u8>>> {
+        let mut data = vec![0; self.layout.piece_size(index)?];
+        for slice in self.layout.piece_slices(index)? {
+            let mut file = match fs::File::open(self.file_path(slice.file_index)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if file.metadata()?.len() < (slice.file_offset + slice.length) as u64 {
+                return Ok(None);
+            }
+            file.seek(SeekFrom::Start(slice.file_offset as u64))?;
+            file.read_exact(&mut data[slice.range_offset..slice.range_offset + slice.length])?;
+        }
+        Ok(Some(data))
+    }
+
+    #[cfg(unix)]
+    fn create_symlink(&self, path: &Path, target: &Path) -> Result<()> {
+        if path.symlink_metadata().is_err() {
+            std::os::unix::fs::symlink(target, path)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn create_symlink(&self, _path: &Path, _target: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl PieceStorage for StorageWriter {
+    fn read_piece(&self, index: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; self.layout.piece_size(index)?];
+        for slice in self.layout.piece_slices(index)? {
+            let mut file = fs::File::open(self.file_path(slice.file_index))?;
+            file.seek(SeekFrom::Start(slice.file_offset as u64))?;
+            file.read_exact(&mut data[slice.range_offset..slice.range_offset + slice.length])?;
+        }
+        Ok(data)
+    }
+
+    /// The final piece is only as long as the content left, slices never reach past
+    /// the declared end of a file, so writes can't grow a file beyond its length.
+    /// The piece counts as durable once every file it touches is synced
+    fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        let size = self.layout.piece_size(index)?;
+        if data.len() != size {
+            return Err(DataLength(index, data.len(), size));
+        }
+        for slice in self.layout.piece_slices(index)? {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(self.file_path(slice.file_index))?;
+            file.seek(SeekFrom::Start(slice.file_offset as u64))?;
+            file.write_all(&data[slice.range_offset..slice.range_offset + slice.length])?;
+            file.sync_data()?;
+        }
+        self.durable.lock().unwrap().set(index);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::{File, Info};
+    use crate::storage::{
+        AllocationStrategy, FileSlice, PieceStorage, StorageError, StorageWriter,
+    };
+    use sha1::Digest;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn info(files: &[(&str, usize)], piece_length: usize) -> Info {
+        let total: usize = files.iter().map(|(_, length)| length).sum();
+        Info {
+            files: files
+                .iter()
+                .map(|(path, length)| File::new(*length, PathBuf::from(path)))
+                .collect(),
+            name: PathBuf::from("torrent"),
+            info_hash: [0; 20],
+            piece_length,
+            pieces: vec![[0; 20]; total.div_ceil(piece_length)],
+        }
+    }
+
+    #[test]
+    fn zero_length_file_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = info(&[("a", 5), ("b", 0), ("c", 7)], 4);
+        let writer = StorageWriter::new(dir.path(), &info);
+
+        let layout = writer.layout();
+        assert_eq!(layout.files()[2].offset, 5);
+        assert_eq!(
+            layout.piece_slices(1).unwrap(),
+            vec![
+                FileSlice {
+                    file_index: 0,
+                    file_offset: 4,
+                    range_offset: 0,
+                    length: 1
+                },
+                FileSlice {
+                    file_index: 2,
+                    file_offset: 0,
+                    range_offset: 1,
+                    length: 3
+                },
+            ]
+        );
+        assert_eq!(layout.piece_size(2).unwrap(), 4);
+
+        writer.create_files().unwrap();
+        let content: Vec<u8> = (0..12).collect();
+        for (index, piece) in content.chunks(4).enumerate() {
+            writer.write_piece(index, piece).unwrap();
+        }
+
+        let root = dir.path().join("torrent");
+        assert_eq!(fs::read(root.join("a")).unwrap(), content[..5]);
+        assert!(root.join("b").exists());
+        assert_eq!(fs::read(root.join("b")).unwrap(), Vec::<u8>::new());
+        assert_eq!(fs::read(root.join("c")).unwrap(), content[5..]);
+        assert_eq!(writer.read_piece(1).unwrap(), content[4..8]);
+    }
+
+    #[test]
+    fn padding_file_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut info = info(&[("a", 3), (".pad/1", 1), ("c", 4)], 4);
+        info.files[1].attr.padding = true;
+        let writer = StorageWriter::new(dir.path(), &info);
+        writer.create_files().unwrap();
+
+        writer.write_piece(0, &[1, 2, 3, 0]).unwrap();
+        writer.write_piece(1, &[4, 5, 6, 7]).unwrap();
+        assert_eq!(writer.read_piece(0).unwrap(), vec![1, 2, 3, 0]);
+
+        let root = dir.path().join("torrent");
+        assert!(!root.join(".pad").exists());
+        assert_eq!(fs::read(root.join("a")).unwrap(), vec![1, 2, 3]);
+        assert_eq!(fs::read(root.join("c")).unwrap(), vec![4, 5, 6, 7]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn executable_and_symlink_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut info = info(&[("tool", 4), ("link", 0)], 4);
+        info.files[0].attr.executable = true;
+        info.files[1].attr.symlink = true;
+        info.files[1].symlink_path = Some(PathBuf::from("tool"));
+        let writer = StorageWriter::new(dir.path(), &info);
+        writer.create_files().unwrap();
+
+        let root = dir.path().join("torrent");
+        let mode = fs::metadata(root.join("tool"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o111, 0o111);
+        assert_eq!(fs::read_link(root.join("link")).unwrap(), root.join("tool"));
+    }
+
+    #[test]
+    fn full_allocation_before_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = info(&[("a", 5), ("b", 100_000)], 4);
+        let root = dir.path().join("torrent");
+
+        StorageWriter::new(dir.path(), &info)
+            .create_files()
+            .unwrap();
+        assert_eq!(fs::metadata(root.join("b")).unwrap().len(), 0);
+
+        let mut writer = StorageWriter::new(dir.path(), &info);
+        writer.set_allocation(AllocationStrategy::Full);
+        writer.create_files().unwrap();
+        assert_eq!(fs::metadata(root.join("a")).unwrap().len(), 5);
+        assert_eq!(fs::read(root.join("b")).unwrap(), vec![0; 100_000]);
+
+        writer.write_piece(0, &[1, 2, 3, 4]).unwrap();
+        writer.create_files().unwrap();
+        assert_eq!(writer.read_piece(0).unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn short_final_piece_within_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..12).collect();
+        let info = info(&[("a", 5), ("b", 7)], 5);
+        let writer = StorageWriter::new(dir.path(), &info);
+        assert_eq!(writer.layout().piece_size(2).unwrap(), 2);
+        writer.create_files().unwrap();
+
+        // a final piece padded up to the piece length is refused
+        let padded = [10, 11, 0, 0, 0];
+        assert!(matches!(
+            writer.write_piece(2, &padded),
+            Err(StorageError::DataLength(2, 5, 2))
+        ));
+        for (index, piece) in content.chunks(5).enumerate().rev() {
+            writer.write_piece(index, piece).unwrap();
+        }
+
+        let root = dir.path().join("torrent");
+        assert_eq!(fs::metadata(root.join("a")).unwrap().len(), 5);
+        assert_eq!(fs::read(root.join("b")).unwrap(), content[5..]);
+        assert_eq!(writer.read_piece(2).unwrap(), vec![10, 11]);
+    }
+
+    #[test]
+    fn truncated_last_piece_requeued() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10).collect();
+        let mut info = info(&[("a", 10)], 4);
+        info.pieces = content
+            .chunks(4)
+            .map(|piece| sha1::Sha1::digest(piece).into())
+            .collect();
+        let writer = StorageWriter::new(dir.path(), &info);
+        writer.create_files().unwrap();
+        writer.write_piece(0, &content[..4]).unwrap();
+        writer.write_piece(1, &content[4..8]).unwrap();
+        // interrupted write of the final piece
+        let path = dir.path().join("torrent").join("a");
+        fs::write(&path, &content[..9]).unwrap();
+
+        let have = writer.verify_existing().unwrap();
+        assert_eq!(have.iter_set().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(have.iter_unset().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn verify_progress_and_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..16).collect();
+        let mut info = info(&[("a", 16)], 4);
+        info.pieces = content
+            .chunks(4)
+            .map(|piece| sha1::Sha1::digest(piece).into())
+            .collect();
+        let writer = StorageWriter::new(dir.path(), &info);
+        writer.create_files().unwrap();
+        writer.write_piece(1, &content[4..8]).unwrap();
+
+        let cancel = AtomicBool::new(false);
+        let mut reports = Vec::new();
+        let have = writer
+            .verify_existing_with(|hashed, total| reports.push((hashed, total)), &cancel)
+            .unwrap();
+        assert_eq!(reports, vec![(1, 4), (2, 4), (3, 4), (4, 4)]);
+        assert_eq!(have.iter_set().collect::<Vec<_>>(), vec![1]);
+
+        reports.clear();
+        let result = writer.verify_existing_with(
+            |hashed, total| {
+                reports.push((hashed, total));
+                if hashed == 2 {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            },
+            &cancel,
+        );
+        assert!(matches!(result, Err(StorageError::Cancelled)));
+        assert_eq!(reports, vec![(1, 4), (2, 4)]);
+    }
+}
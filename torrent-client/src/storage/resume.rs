@@ -0,0 +1,258 @@
+use crate::storage::{Result, StorageError, StorageWriter};
+use crate::util::PieceBitfield;
+use bencode::{bencode, BencodeDict, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Size and modification time of a file when the resume state was written
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileState {
+    pub length: u64,
+    /// Nanoseconds since the Unix epoch
+    pub mtime: i64,
+}
+
+/// Progress of a torrent saved between runs, so a restart doesn't have to re-hash
+/// everything that's already on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeState {
+    pub have: PieceBitfield,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub files: Vec<FileState>,
+}
+
+impl ResumeState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let files: Vec<Value> = self
+            .files
+            .iter()
+            .map(|file| bencode!({ "length" => file.length as i64, "mtime" => file.mtime }))
+            .collect();
+        bencode::into_vec(&bencode!({
+            "downloaded" => self.downloaded as i64,
+            "files" => Value::List(files),
+            "have" => self.have.as_bytes().to_vec(),
+            "pieces" => self.have.len() as i64,
+            "uploaded" => self.uploaded as i64,
+        }))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let malformed = |field: &str| StorageError::ResumeState(format!("invalid field {field}"));
+        let dict: BencodeDict = bencode::from_slice(data)
+            .and_then(BencodeDict::try_from)
+            .map_err(|e| StorageError::ResumeState(e.to_string()))?;
+        let int = |dict: &BencodeDict, field: &str| {
+            dict.get(field.as_bytes())
+                .and_then(Value::as_int)
+                .ok_or_else(|| malformed(field))
+        };
+        let pieces = usize::try_from(int(&dict, "pieces")?).map_err(|_| malformed("pieces"))?;
+        let have = dict
+            .get(b"have".as_slice())
+            .and_then(Value::as_bytes)
+            .filter(|have| have.len() == pieces.div_ceil(8))
+            .ok_or_else(|| malformed("have"))?;
+        let files = dict
+            .get(b"files".as_slice())
+            .and_then(Value::as_list)
+            .ok_or_else(|| malformed("files"))?
+            .iter()
+            .map(|file| {
+                let file = file.as_dict().ok_or_else(|| malformed("files"))?;
+                Ok(FileState {
+                    length: int(file, "length")? as u64,
+                    mtime: int(file, "mtime")?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            have: PieceBitfield::from_bytes(have, pieces),
+            downloaded: int(&dict, "downloaded")? as u64,
+            uploaded: int(&dict, "uploaded")? as u64,
+            files,
+        })
+    }
+
+    /// Writes the state next to its final place first, so a crash never leaves a torn file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, self.to_bytes())?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Reads a saved state, `None` if there is none yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(data) => Self::from_bytes(&data).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl StorageWriter {
+    /// Current size and modification time of every file, missing files have the default state
+    pub fn file_states(&self) -> Result<Vec<FileState>> {
+        (0..self.layout.files().len())
+            .map(
+                |file_index| match fs::metadata(self.file_path(file_index)) {
+                    Ok(metadata) => {
+                        let mtime = metadata
+                            .modified()?
+                            .duration_since(UNIX_EPOCH)
+                            .map(|mtime| mtime.as_nanos() as i64)
+                            .unwrap_or_default();
+                        Ok(FileState {
+                            length: metadata.len(),
+                            mtime,
+                        })
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(FileState::default()),
+                    Err(e) => Err(e.into()),
+                },
+            )
+            .collect()
+    }
+
+    /// Pieces of `have` still being written aren't recorded, after a crash they could be torn
+    pub fn resume_state(
+        &self,
+        mut have: PieceBitfield,
+        downloaded: u64,
+        uploaded: u64,
+    ) -> Result<ResumeState> {
+        let durable = self.durable_pieces();
+        let pending: Vec<usize> = have
+            .iter_set()
+            .filter(|index| !durable.has(*index))
+            .collect();
+        pending.into_iter().for_each(|index| have.unset(index));
+        Ok(ResumeState {
+            have,
+            downloaded,
+            uploaded,
+            files: self.file_states()?,
+        })
+    }
+
+    /// Pieces on disk, taken from the saved state while no file changed since it was written,
+    /// otherwise every piece is hashed again
+    pub fn resume(&self, state: Option<&ResumeState>) -> Result<PieceBitfield> {
+        let have = match state {
+            Some(state)
+                if state.have.len() == self.layout.pieces_count()
+                    && state.files == self.file_states()? =>
+            {
+                state.have.clone()
+            }
+            _ => self.verify_existing()?,
+        };
+        // written by an earlier run, they are on disk already
+        self.mark_durable(&have);
+        Ok(have)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::{File, Info};
+    use crate::storage::resume::ResumeState;
+    use crate::storage::{PieceStorage, StorageWriter};
+    use crate::util::PieceBitfield;
+    use sha1::Digest;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    fn info() -> Info {
+        let content: Vec<u8> = (0..8).collect();
+        Info {
+            files: vec![
+                File::new(6, PathBuf::from("a")),
+                File::new(2, PathBuf::from("b")),
+            ],
+            name: PathBuf::from("torrent"),
+            info_hash: [0; 20],
+            piece_length: 4,
+            pieces: content
+                .chunks(4)
+                .map(|piece| sha1::Sha1::digest(piece).into())
+                .collect(),
+        }
+    }
+
+    fn writer(root: &std::path::Path) -> StorageWriter {
+        let content: Vec<u8> = (0..8).collect();
+        let writer = StorageWriter::new(root, &info());
+        writer.create_files().unwrap();
+        writer.write_piece(0, &content[..4]).unwrap();
+        writer.write_piece(1, &content[4..]).unwrap();
+        writer
+    }
+
+    #[test]
+    fn state_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer = writer(dir.path());
+        let have = PieceBitfield::from_bytes(&[0b1000_0000], 2);
+        let state = writer.resume_state(have, 4, 1 << 40).unwrap();
+        assert_eq!(state.files[0].length, 6);
+
+        let path = dir.path().join("torrent.resume");
+        assert_eq!(ResumeState::load(&path).unwrap(), None);
+        state.save(&path).unwrap();
+        let loaded = ResumeState::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, state);
+        // state is trusted as is, even though both pieces are on disk
+        assert_eq!(writer.resume(Some(&loaded)).unwrap(), loaded.have);
+
+        assert!(ResumeState::from_bytes(b"d6:piecesi9ee").is_err());
+    }
+
+    #[test]
+    fn torn_piece_requested_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..8).collect();
+        let writer = StorageWriter::new(dir.path(), &info());
+        writer.create_files().unwrap();
+        writer.write_piece(0, &content[..4]).unwrap();
+        // crash while writing the second piece, only its part in "a" hit the disk
+        let root = dir.path().join("torrent");
+        fs::write(root.join("a"), &content[..6]).unwrap();
+        let have = PieceBitfield::from_bytes(&[0b1100_0000], 2);
+        let path = dir.path().join("torrent.resume");
+        writer
+            .resume_state(have, 8, 0)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        drop(writer);
+
+        let writer = StorageWriter::new(dir.path(), &info());
+        let state = ResumeState::load(&path).unwrap();
+        let have = writer.resume(state.as_ref()).unwrap();
+        assert_eq!(have.iter_set().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(have.iter_unset().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(writer.durable_pieces(), have);
+    }
+
+    #[test]
+    fn changed_file_forces_rehash() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer = writer(dir.path());
+        let state = writer.resume_state(PieceBitfield::new(2), 0, 0).unwrap();
+
+        let path = dir.path().join("torrent").join("b");
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        let have = writer.resume(Some(&state)).unwrap();
+        assert_eq!(have.iter_set().collect::<Vec<_>>(), vec![0, 1]);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::peer::PeerId;
+use crate::tracker::websocket::WebSocketTracker;
+use crate::tracker::TrackerError::UnsupportedProtocol;
+use crate::tracker::{
+    AnnounceParameters, AnnounceResponse, HttpTracker, Result, ScrapeResponse, TrackerClient,
+};
+use crate::util::Sha1;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// Picks the tracker client of an announce URL by its scheme, so a single announce-list
+/// tier may mix protocols
+#[derive(Default, Clone)]
+pub struct TrackerFactory {
+    clients: HashMap<String, Arc<dyn TrackerClient>>,
+}
+
+impl TrackerFactory {
+    /// HTTP(S) and WebSocket trackers, other schemes have to be registered
+    pub fn new(peer_id: &PeerId, timeout: Duration) -> Result<Self> {
+        let http: Arc<dyn TrackerClient> = Arc::new(HttpTracker::with_timeout(peer_id, timeout)?);
+        let websocket: Arc<dyn TrackerClient> = Arc::new(WebSocketTracker::new(peer_id, timeout));
+        let mut factory = Self::default();
+        factory
+            .register("http", http.clone())
+            .register("https", http)
+            .register("ws", websocket.clone())
+            .register("wss", websocket);
+        Ok(factory)
+    }
+
+    pub fn register(&mut self, scheme: &str, client: Arc<dyn TrackerClient>) -> &mut Self {
+        self.clients.insert(scheme.to_ascii_lowercase(), client);
+        self
+    }
+
+    pub fn client_for(&self, url: &Url) -> Result<Arc<dyn TrackerClient>> {
+        self.clients
+            .get(url.scheme())
+            .cloned()
+            .ok_or_else(|| UnsupportedProtocol(url.scheme().to_string()))
+    }
+}
+
+impl TrackerClient for TrackerFactory {
+    fn announce(&self, url: &Url, params: AnnounceParameters) -> Result<AnnounceResponse> {
+        self.client_for(url)?.announce(url, params)
+    }
+
+    fn scrape(&self, url: &Url, info_hashes: &[Sha1]) -> Result<ScrapeResponse> {
+        self.client_for(url)?.scrape(url, info_hashes)
+    }
+}
+
+/// Announces to the trackers of a tier in order until one answers, that one moves to the
+/// front of the tier, see BEP 12. Fails with the error of the last tracker tried
+pub fn announce_tier(
+    tracker: &dyn TrackerClient,
+    tier: &mut [Url],
+    params: &AnnounceParameters,
+) -> Result<AnnounceResponse> {
+    let mut last_error = UnsupportedProtocol(String::from("empty tier"));
+    for position in 0..tier.len() {
+        match tracker.announce(&tier[position], params.clone()) {
+            Ok(response) => {
+                tier[..=position].rotate_right(1);
+                return Ok(response);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::PeerId;
+    use crate::tracker::factory::{announce_tier, TrackerFactory};
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::{AnnounceParameters, TrackerError};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn mixed_tier_dispatched_by_scheme() {
+        let (udp, http) = (MockTracker::default(), MockTracker::default());
+        udp.push_response(Err(TrackerError::AnnounceRequestError("timed out".into())));
+        let mut factory = TrackerFactory::new(&PeerId::random(), Duration::from_secs(1)).unwrap();
+        factory
+            .register("udp", Arc::new(udp.clone()))
+            .register("http", Arc::new(http.clone()));
+        let udp_url = Url::parse("udp://tracker.example:6969/announce").unwrap();
+        let http_url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut tier = vec![udp_url.clone(), http_url.clone()];
+
+        announce_tier(&factory, &mut tier, &AnnounceParameters::new([1; 20])).unwrap();
+        assert_eq!(udp.announces()[0].0, udp_url);
+        assert_eq!(http.announces()[0].0, http_url);
+        assert_eq!(tier, vec![http_url, udp_url]);
+
+        let unknown = Url::parse("gopher://tracker.example/").unwrap();
+        assert!(matches!(
+            factory.client_for(&unknown),
+            Err(TrackerError::UnsupportedProtocol(scheme)) if scheme == "gopher"
+        ));
+    }
+}
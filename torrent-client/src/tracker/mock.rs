@@ -0,0 +1,122 @@
+use crate::peer::Peer;
+use crate::tracker::{AnnounceParameters, AnnounceResponse, Result, ScrapeResponse, TrackerClient};
+use crate::util::Sha1;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+#[derive(Default)]
+struct MockState {
+    responses: VecDeque<Result<AnnounceResponse>>,
+    announces: Vec<(Url, AnnounceParameters)>,
+    scrapes: VecDeque<Result<ScrapeResponse>>,
+}
+
+/// Tracker for tests, replays queued responses and records every announce.
+/// Clones share their state, so a test keeps a handle to a tracker it gave away
+#[derive(Default, Clone)]
+pub struct MockTracker {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockTracker {
+    /// Queues the result of a future announce, once the queue runs dry announces get
+    /// an empty peer list
+    pub fn push_response(&self, response: Result<AnnounceResponse>) -> &Self {
+        self.state.lock().unwrap().responses.push_back(response);
+        self
+    }
+
+    pub fn push_peers(&self, peers: Vec<Peer>) -> &Self {
+        self.push_response(Ok(Self::response(peers)))
+    }
+
+    /// Queues the result of a future scrape, once the queue runs dry every requested
+    /// torrent is missing from the scrape
+    pub fn push_scrape(&self, response: Result<ScrapeResponse>) -> &Self {
+        self.state.lock().unwrap().scrapes.push_back(response);
+        self
+    }
+
+    /// Every announce so far, oldest first
+    pub fn announces(&self) -> Vec<(Url, AnnounceParameters)> {
+        self.state.lock().unwrap().announces.clone()
+    }
+
+    fn response(peers: Vec<Peer>) -> AnnounceResponse {
+        AnnounceResponse {
+            interval: Duration::from_secs(1800),
+            min_interval: None,
+            complete: None,
+            incomplete: None,
+            peers,
+            external_ip: None,
+            tracker_id: None,
+        }
+    }
+}
+
+impl TrackerClient for MockTracker {
+    fn announce(&self, url: &Url, params: AnnounceParameters) -> Result<AnnounceResponse> {
+        let mut state = self.state.lock().unwrap();
+        state.announces.push((url.clone(), params));
+        state
+            .responses
+            .pop_front()
+            .unwrap_or_else(|| Ok(Self::response(Vec::new())))
+    }
+
+    fn scrape(&self, _url: &Url, info_hashes: &[Sha1]) -> Result<ScrapeResponse> {
+        self.state
+            .lock()
+            .unwrap()
+            .scrapes
+            .pop_front()
+            .unwrap_or_else(|| {
+                Ok(ScrapeResponse {
+                    missing: info_hashes.to_vec(),
+                    ..ScrapeResponse::default()
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::Peer;
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::{AnnounceParameters, TrackerClient, TrackerError, TrackerEvent};
+    use crate::util::InfoHash;
+    use url::Url;
+
+    #[test]
+    fn records_and_replays() {
+        let tracker = MockTracker::default();
+        let peer = Peer::new(None, "10.0.0.1:6881".parse().unwrap());
+        tracker
+            .push_peers(vec![peer.clone()])
+            .push_response(Err(TrackerError::TrackerResponse("overloaded".into())));
+        let client: Box<dyn TrackerClient> = Box::new(tracker.clone());
+
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let mut params = AnnounceParameters::new([1; 20]);
+        params.set_event(Some(TrackerEvent::Started)).set_left(100);
+        assert_eq!(
+            client.announce(&url, params.clone()).unwrap().peers,
+            vec![peer]
+        );
+        assert!(client.announce(&url, params.clone()).is_err());
+        params.set_event(None);
+        assert!(client.announce(&url, params).unwrap().peers.is_empty());
+
+        let announces = tracker.announces();
+        assert_eq!(announces.len(), 3);
+        assert!(announces.iter().all(|(sent, _)| *sent == url));
+        let (_, first) = &announces[0];
+        assert_eq!(first.info_hash(), &InfoHash::V1([1; 20]));
+        assert_eq!(first.left(), 100);
+        assert_eq!(first.event(), Some(&TrackerEvent::Started));
+        assert_eq!(announces[2].1.event(), None);
+    }
+}
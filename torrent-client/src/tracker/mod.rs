@@ -1,19 +1,49 @@
+pub mod factory;
+#[cfg(test)]
+pub mod mock;
+pub mod websocket;
+
 use crate::peer::{Peer, PeerId};
 use crate::tracker::TrackerError::{
-    AnnounceRequestError, InternalError, ResponseFormat, TrackerResponse, UnsupportedProtocol,
+    AnnounceRequestError, Decompression, InternalError, NonBencodeResponse, ResponseFormat,
+    ResponseTooLarge, TrackerResponse, UnsupportedProtocol,
 };
-use crate::util::Sha1;
-use bencode::{BencodeDict, Value};
+use crate::util::{InfoHash, Sha1};
+use bencode::{BencodeDict, BencodeString, Value};
 use bytes::Buf;
-use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::time::Duration;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use url::Url;
 
 type Result<T> = std::result::Result<T, TrackerError>;
 
+const BODY_SNIPPET_LENGTH: usize = 120;
+/// Largest tracker response we inflate, a compressed body could expand without bound
+const MAX_DECODED_BODY_LENGTH: u64 = 8 * 1024 * 1024;
+
+/// Bytes the tracker spec requires escaped in `info_hash` and `peer_id`, which is everything
+/// except the RFC 3986 unreserved set `0-9a-zA-Z-._~`
+pub const TRACKER_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Peers that have to report the same address before we take it as our external one
+pub const EXTERNAL_IP_QUORUM: usize = 3;
+/// Whole-request timeout of a tracker announce
+pub const DEFAULT_TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Error, Debug)]
 pub enum TrackerError {
     #[error("Bencode error: {0}")]
@@ -36,12 +66,24 @@ pub enum TrackerError {
 
     #[error("Mismatching type of field {0}")]
     TypeMismatch(String),
+
+    #[error("Tracker response is not bencode, body starts with {0:?}")]
+    NonBencodeResponse(String),
+
+    #[error("Failed to decompress {0} tracker response")]
+    Decompression(String),
+
+    #[error("Decompressed tracker response exceeds {0} bytes")]
+    ResponseTooLarge(u64),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum TrackerEvent {
     Started,
     Stopped,
     Completed,
+    /// BEP 21, sent while the download is paused
+    Paused,
 }
 
 impl Display for TrackerEvent {
@@ -50,19 +92,32 @@ impl Display for TrackerEvent {
             TrackerEvent::Started => "started",
             TrackerEvent::Stopped => "stopped",
             TrackerEvent::Completed => "completed",
+            TrackerEvent::Paused => "paused",
         };
         write!(f, "{string}")
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum RequestMode {
     Verbose,
     NoPeerId,
     Compact,
 }
 
-pub struct AnnounceParameters<'a> {
-    info_hash: &'a Sha1,
+/// How a compact `peers` string with a length that isn't a multiple of 6 is treated
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PeersParsing {
+    /// Whole announce fails
+    #[default]
+    Strict,
+    /// Complete entries are kept and the trailing bytes are dropped with a warning
+    Lenient,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceParameters {
+    info_hash: InfoHash,
     port: u16,
     uploaded: usize,
     downloaded: usize,
@@ -71,12 +126,16 @@ pub struct AnnounceParameters<'a> {
     event: Option<TrackerEvent>,
     num_want: Option<usize>,
     ip: Option<IpAddr>,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    key: Option<u32>,
+    tracker_id: Option<Vec<u8>>,
 }
 
-impl<'a> AnnounceParameters<'a> {
-    pub fn new(info_hash: &'a Sha1) -> Self {
+impl AnnounceParameters {
+    pub fn new(info_hash: impl Into<InfoHash>) -> Self {
         Self {
-            info_hash,
+            info_hash: info_hash.into(),
             port: 0,
             uploaded: 0,
             downloaded: 0,
@@ -85,6 +144,10 @@ impl<'a> AnnounceParameters<'a> {
             event: None,
             num_want: None,
             ip: None,
+            ipv4: None,
+            ipv6: None,
+            key: None,
+            tracker_id: None,
         }
     }
 
@@ -120,163 +183,716 @@ impl<'a> AnnounceParameters<'a> {
         self.ip = ip;
         self
     }
+    pub fn set_ipv4(&mut self, ipv4: Option<Ipv4Addr>) -> &mut Self {
+        self.ipv4 = ipv4;
+        self
+    }
+    pub fn set_ipv6(&mut self, ipv6: Option<Ipv6Addr>) -> &mut Self {
+        self.ipv6 = ipv6;
+        self
+    }
+    /// Random number identifying us to the tracker across IP changes
+    pub fn set_key(&mut self, key: Option<u32>) -> &mut Self {
+        self.key = key;
+        self
+    }
+    /// `tracker id` of a previous response, sent back on every following announce
+    pub fn set_tracker_id(&mut self, tracker_id: Option<Vec<u8>>) -> &mut Self {
+        self.tracker_id = tracker_id;
+        self
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    pub fn uploaded(&self) -> usize {
+        self.uploaded
+    }
+
+    pub fn left(&self) -> usize {
+        self.left
+    }
+
+    pub fn event(&self) -> Option<&TrackerEvent> {
+        self.event.as_ref()
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    pub fn key(&self) -> Option<u32> {
+        self.key
+    }
+
+    pub fn tracker_id(&self) -> Option<&[u8]> {
+        self.tracker_id.as_deref()
+    }
+
+    /// Query string of an HTTP announce, binary `info_hash` and `peer_id` are
+    /// percent-encoded byte by byte
+    pub fn query(&self, peer_id: &PeerId) -> String {
+        let mut query = QueryBuilder::default();
+        query
+            .bytes("info_hash", &self.info_hash.wire())
+            .bytes("peer_id", peer_id.as_ref())
+            .pair("port", self.port)
+            .pair("uploaded", self.uploaded)
+            .pair("downloaded", self.downloaded)
+            .pair("left", self.left);
+        match self.request_mode {
+            RequestMode::Verbose => {}
+            RequestMode::NoPeerId => {
+                query.key("no_peer_id");
+            }
+            RequestMode::Compact => {
+                query.pair("compact", 1);
+            }
+        }
+        if let Some(event) = &self.event {
+            query.pair("event", event);
+        }
+        if let Some(num_want) = self.num_want {
+            query.pair("numwant", num_want);
+        }
+        if let Some(ip) = self.ip {
+            query.pair("ip", ip);
+        }
+        if let Some(ipv4) = self.ipv4 {
+            query.pair("ipv4", ipv4);
+        }
+        if let Some(ipv6) = self.ipv6 {
+            query.pair("ipv6", ipv6);
+        }
+        if let Some(key) = self.key {
+            query.pair("key", format_args!("{key:08X}"));
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            query.bytes("trackerid", tracker_id);
+        }
+        query.finish()
+    }
+}
+
+/// Builds a query string out of values that are encoded exactly once
+#[derive(Default)]
+struct QueryBuilder {
+    query: String,
+}
+
+/// Percent-encodes raw bytes for an announce or scrape query, see [`TRACKER_ENCODE_SET`]
+pub fn encode_query_value(value: &[u8]) -> String {
+    percent_encode(value, TRACKER_ENCODE_SET).to_string()
+}
+
+impl QueryBuilder {
+    fn key(&mut self, key: &str) -> &mut Self {
+        if !self.query.is_empty() {
+            self.query.push('&');
+        }
+        self.query.push_str(key);
+        self
+    }
+
+    fn bytes(&mut self, key: &str, value: &[u8]) -> &mut Self {
+        self.key(key);
+        self.query.push('=');
+        self.query.push_str(&encode_query_value(value));
+        self
+    }
+
+    fn pair(&mut self, key: &str, value: impl Display) -> &mut Self {
+        self.bytes(key, value.to_string().as_bytes())
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.query)
+    }
 }
 
 #[derive(Debug)]
 pub struct AnnounceResponse {
+    /// Never shorter than `min_interval`, see [`AnnounceResponse::from_bencode`]
     pub interval: Duration,
     pub min_interval: Option<Duration>,
     pub complete: Option<i64>,
     pub incomplete: Option<i64>,
     pub peers: Vec<Peer>,
+    /// Our address as the tracker sees it, see BEP 24
+    pub external_ip: Option<IpAddr>,
+    pub tracker_id: Option<Vec<u8>>,
 }
 
 impl AnnounceResponse {
-    pub fn from_bencode(mut bencode_dict: BencodeDict) -> Result<Self> {
-        let interval: u64 = bencode_dict
-            .remove(b"interval".as_slice())
-            .ok_or(ResponseFormat("No 'interval' field".to_string()))?
-            .try_into()?;
-        let interval = Duration::from_secs(interval);
+    pub fn from_bencode(bencode_dict: BencodeDict) -> Result<Self> {
+        Self::from_bencode_with(bencode_dict, PeersParsing::Strict)
+    }
+
+    /// Fields are looked up by key, their order doesn't matter and unknown ones are ignored
+    pub fn from_bencode_with(
+        mut bencode_dict: BencodeDict,
+        peers_parsing: PeersParsing,
+    ) -> Result<Self> {
+        let interval = match bencode_dict.remove(b"interval".as_slice()) {
+            Some(interval) => Duration::from_secs(interval.try_into()?),
+            None => DEFAULT_ANNOUNCE_INTERVAL,
+        };
+        // zero or tiny interval would make us hammer the tracker
+        let interval = interval.max(MIN_ANNOUNCE_INTERVAL);
+        let min_interval = bencode_dict
+            .remove(b"min interval".as_slice())
+            .map(u64::try_from)
+            .transpose()?
+            .map(Duration::from_secs);
+        // some trackers send min interval bigger than interval, the tracker still
+        // won't accept announces more often than min interval
+        let interval = interval.max(min_interval.unwrap_or_default());
+        let complete = bencode_dict
+            .remove(b"complete".as_slice())
+            .map(i64::try_from)
+            .transpose()?;
+        let incomplete = bencode_dict
+            .remove(b"incomplete".as_slice())
+            .map(i64::try_from)
+            .transpose()?;
+        // responses to `numwant=0`, e.g. a stop announce, legitimately carry no peers
         let peers = bencode_dict
             .remove(b"peers".as_slice())
-            .ok_or(ResponseFormat("No 'peers' field".to_string()))?;
-
-        let mut peers_result: Vec<Peer> = Vec::new();
-        match peers {
-            Value::String(string) => {
-                if string.len() % 6 != 0 {
-                    return Err(ResponseFormat(
-                        "peers binary string length is not a multiple of 6".to_string(),
-                    ));
-                }
-                let peers_count = string.len() / 6;
-                let mut bytes = bytes::Bytes::from(string);
-                for _ in 0..peers_count {
-                    let ip = bytes.get_u32();
-                    let port = bytes.get_u16();
-                    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from_bits(ip), port));
-                    peers_result.push(Peer::new(None, addr));
-                }
-            }
+            .unwrap_or(Value::List(Vec::new()));
+
+        let peers_result = match peers {
+            Value::String(string) => Self::parse_compact_peers(string, peers_parsing)?,
             Value::List(list) => {
+                // Some trackers mix compact strings and dicts in one list,
+                // so parse every entry on its own and keep whatever is valid
+                let mut peers_result: Vec<Peer> = Vec::new();
                 for value in list {
                     match value {
-                        Value::Dict(mut dict) => {
-                            let peer_id = dict
-                                .remove(b"peer id".as_slice())
-                                .and_then(|x| {
-                                    if let Value::String(s) = x {
-                                        Some(PeerId::new(s.try_into().ok()?))
-                                    } else {
-                                        None
-                                    }
-                                });
-                            let ip: String = dict
-                                .remove(b"ip".as_slice())
-                                .ok_or(ResponseFormat(
-                                    "No 'ip' field found in dictionary form".to_string(),
-                                ))?
-                                .try_into()?;
-                            let ip = ip.parse::<IpAddr>().map_err(|_| {
-                                ResponseFormat(format!("{ip} is not valid ip address"))
-                            })?;
-                            let port: u16 = dict
-                                .remove(b"port".as_slice())
-                                .ok_or(ResponseFormat(
-                                    "No 'port' filed found in dictionary form".to_string(),
-                                ))?
-                                .try_into()?;
-                            let addr = SocketAddr::new(ip, port);
-                            peers_result.push(Peer::new(peer_id, addr));
+                        Value::Dict(dict) => {
+                            if let Ok(peer) = Self::parse_dict_peer(dict) {
+                                peers_result.push(peer);
+                            }
                         }
-                        v => {
-                            return Err(ResponseFormat(format!(
-                                "peers list of dicts format error, unexpected {}",
-                                v.name()
-                            )))
+                        Value::String(string) => {
+                            if let Ok(peers) = Self::parse_compact_peers(string, peers_parsing) {
+                                peers_result.extend(peers);
+                            }
                         }
+                        _ => continue,
                     }
                 }
+                peers_result
             }
-            _ => println!("unknown peers format"),
-        }
+            _ => {
+                log::warn!("unknown peers format");
+                Vec::new()
+            }
+        };
 
+        // loopback peers are up to the client, e.g. for a local test swarm
+        let peers_result = peers_result
+            .into_iter()
+            .filter(|peer| peer.is_connectable(true))
+            .collect();
         Ok(AnnounceResponse {
             interval,
-            min_interval: None,
-            complete: None,
-            incomplete: None,
+            min_interval,
+            complete,
+            incomplete,
             peers: peers_result,
+            external_ip: Self::parse_external_ip(bencode_dict.remove(b"external ip".as_slice())),
+            tracker_id: bencode_dict
+                .remove(b"tracker id".as_slice())
+                .map(BencodeString::try_from)
+                .transpose()?,
         })
     }
+
+    /// Raw 4 or 16 byte address, a malformed one is dropped rather than failing the announce
+    fn parse_external_ip(value: Option<Value>) -> Option<IpAddr> {
+        match value? {
+            Value::String(bytes) => match bytes.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Peers with duplicate addresses collapsed, keeping the first-seen order.
+    /// An entry that carries a peer id wins over one that doesn't
+    pub fn unique_peers(&self) -> Vec<Peer> {
+        let mut unique: Vec<Peer> = Vec::with_capacity(self.peers.len());
+        let mut positions: HashMap<SocketAddr, usize> = HashMap::new();
+        for peer in &self.peers {
+            match positions.get(&peer.addr) {
+                Some(&position) => {
+                    if unique[position].peer_id.is_none() {
+                        unique[position].peer_id.clone_from(&peer.peer_id);
+                    }
+                }
+                None => {
+                    positions.insert(peer.addr, unique.len());
+                    unique.push(peer.clone());
+                }
+            }
+        }
+        unique
+    }
+
+    /// Parses raw tracker response body, recognizing bodies that are obviously not bencode
+    pub fn from_body(body: &[u8]) -> Result<Self> {
+        Self::from_body_with(body, PeersParsing::Strict)
+    }
+
+    pub fn from_body_with(body: &[u8], peers_parsing: PeersParsing) -> Result<Self> {
+        let trimmed = body.trim_ascii_start();
+        if let Some(b'<' | b'{' | b'[') = trimmed.first() {
+            let snippet: String = String::from_utf8_lossy(trimmed)
+                .chars()
+                .take(BODY_SNIPPET_LENGTH)
+                .collect();
+            return Err(NonBencodeResponse(snippet));
+        }
+
+        Self::from_bencode_with(response_dict(body)?, peers_parsing)
+    }
+
+    fn parse_compact_peers(string: Vec<u8>, peers_parsing: PeersParsing) -> Result<Vec<Peer>> {
+        let trailing = string.len() % 6;
+        if trailing != 0 {
+            if peers_parsing == PeersParsing::Strict {
+                return Err(ResponseFormat(
+                    "peers binary string length is not a multiple of 6".to_string(),
+                ));
+            }
+            log::warn!("ignoring {trailing} trailing bytes of compact peers string");
+        }
+        let peers_count = string.len() / 6;
+        let mut bytes = bytes::Bytes::from(string);
+        let mut peers = Vec::with_capacity(peers_count);
+        for _ in 0..peers_count {
+            let ip = bytes.get_u32();
+            let port = bytes.get_u16();
+            let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from_bits(ip), port));
+            peers.push(Peer::new(None, addr));
+        }
+        Ok(peers)
+    }
+
+    fn parse_dict_peer(mut dict: BencodeDict) -> Result<Peer> {
+        let peer_id = dict
+            .remove(b"peer id".as_slice())
+            .and_then(|peer_id| peer_id.try_into().ok())
+            .map(PeerId::new);
+        let ip: String = dict
+            .remove(b"ip".as_slice())
+            .ok_or(ResponseFormat(
+                "No 'ip' field found in dictionary form".to_string(),
+            ))?
+            .try_into()?;
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| ResponseFormat(format!("{ip} is not valid ip address")))?;
+        let port: u16 = dict
+            .remove(b"port".as_slice())
+            .ok_or(ResponseFormat(
+                "No 'port' filed found in dictionary form".to_string(),
+            ))?
+            .try_into()?;
+        Ok(Peer::new(peer_id, SocketAddr::new(ip, port)))
+    }
+}
+
+/// State one announce hands over to the next, see [`TrackerSession::apply`], and when
+/// the next one is due
+#[derive(Debug, Clone)]
+pub struct TrackerSession {
+    key: u32,
+    /// Opaque bytes, trackers aren't required to send UTF-8
+    tracker_id: Option<Vec<u8>>,
+    interval: Duration,
+    complete: Option<i64>,
+    incomplete: Option<i64>,
+    /// Our address as the tracker reported it
+    external_ip: Option<IpAddr>,
+    /// Our address as enough peers agreed on it
+    peer_reported_ip: Option<IpAddr>,
+    next_announce: Instant,
+    dead_swarm_policy: Option<DeadSwarmPolicy>,
+    /// Multiplier of the announce interval, 1 while the swarm is alive
+    backoff: u32,
+}
+
+impl TrackerSession {
+    /// First announce is due right away
+    pub fn new(key: u32, now: Instant) -> Self {
+        Self {
+            key,
+            tracker_id: None,
+            interval: DEFAULT_ANNOUNCE_INTERVAL,
+            complete: None,
+            incomplete: None,
+            external_ip: None,
+            peer_reported_ip: None,
+            next_announce: now,
+            dead_swarm_policy: None,
+            backoff: 1,
+        }
+    }
+
+    pub fn set_dead_swarm_policy(&mut self, policy: Option<DeadSwarmPolicy>) -> &mut Self {
+        self.dead_swarm_policy = policy;
+        self
+    }
+
+    /// Takes over the interval of a response and schedules the next announce, it's never
+    /// earlier than the tracker's `min interval` and a dead swarm pushes it further out.
+    /// Swarm counts, the tracker id and our external address are kept until the tracker
+    /// reports new ones
+    pub fn update(&mut self, response: &AnnounceResponse, now: Instant) {
+        self.interval = response.interval;
+        self.complete = response.complete.or(self.complete);
+        self.incomplete = response.incomplete.or(self.incomplete);
+        if let (Some(complete), Some(incomplete)) = (response.complete, response.incomplete) {
+            self.swarm_reported(complete.max(0) as u64, incomplete.max(0) as u64);
+        }
+        if let Some(ip) = response.external_ip.filter(is_public) {
+            self.external_ip = Some(ip);
+        }
+        if let Some(tracker_id) = &response.tracker_id {
+            self.tracker_id = Some(tracker_id.clone());
+        }
+        self.next_announce = now + self.interval * self.backoff;
+    }
+
+    /// No tracker answered, the announce is tried again after [`MIN_ANNOUNCE_INTERVAL`]
+    pub fn announce_failed(&mut self, now: Instant) {
+        self.next_announce = now + MIN_ANNOUNCE_INTERVAL;
+    }
+
+    /// Takes the swarm size of a scrape into account for the following announces
+    pub fn scraped(&mut self, stats: &ScrapeStats) {
+        self.swarm_reported(stats.complete, stats.incomplete);
+    }
+
+    fn swarm_reported(&mut self, complete: u64, incomplete: u64) {
+        self.backoff = match self.dead_swarm_policy {
+            Some(policy) if policy.is_dead(complete, incomplete) => {
+                (self.backoff * 2).min(policy.max_backoff.max(1))
+            }
+            _ => 1,
+        };
+    }
+
+    /// Puts the key and tracker id into the parameters of the next announce
+    pub fn apply(&self, params: &mut AnnounceParameters) {
+        params
+            .set_key(Some(self.key))
+            .set_tracker_id(self.tracker_id.clone());
+    }
+
+    pub fn key(&self) -> u32 {
+        self.key
+    }
+
+    /// Our address as the connected peers reported it in their `yourip`, one address per
+    /// peer. An address only counts once [`EXTERNAL_IP_QUORUM`] peers agree on it, so a
+    /// single peer can't make us announce whatever it likes. Private addresses are ignored
+    pub fn observe_peer_ips<I>(&mut self, ips: I)
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let mut reports: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in ips.into_iter().filter(is_public) {
+            *reports.entry(ip).or_default() += 1;
+        }
+        self.peer_reported_ip = reports
+            .into_iter()
+            .filter(|(_, count)| *count >= EXTERNAL_IP_QUORUM)
+            .max_by_key(|(ip, count)| (*count, *ip))
+            .map(|(ip, _)| ip);
+    }
+
+    /// Our public address, the tracker's view takes precedence over the peers'
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip.or(self.peer_reported_ip)
+    }
+
+    pub fn tracker_id(&self) -> Option<&[u8]> {
+        self.tracker_id.as_deref()
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Seeders and leechers of the last response that reported them
+    pub fn counts(&self) -> (Option<i64>, Option<i64>) {
+        (self.complete, self.incomplete)
+    }
+
+    /// Whether the last report found the swarm dead
+    pub fn is_backing_off(&self) -> bool {
+        self.backoff > 1
+    }
+
+    /// Share of `connections` worth attempting, a dead swarm gets fewer attempts
+    pub fn connection_attempts(&self, connections: usize) -> usize {
+        (connections / self.backoff as usize).max(1)
+    }
+
+    pub fn next_announce(&self) -> Instant {
+        self.next_announce
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_announce
+    }
+}
+
+/// When a swarm counts as dead and how far apart announces get then
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadSwarmPolicy {
+    /// Leechers a swarm without seeders may have and still be dead, we are one of them
+    pub max_leechers: u64,
+    /// Cap of the multiplier of the announce interval, it doubles with every dead report
+    pub max_backoff: u32,
+}
+
+impl Default for DeadSwarmPolicy {
+    fn default() -> Self {
+        Self {
+            max_leechers: 1,
+            max_backoff: 8,
+        }
+    }
+}
+
+impl DeadSwarmPolicy {
+    pub fn is_dead(&self, complete: u64, incomplete: u64) -> bool {
+        complete == 0 && incomplete <= self.max_leechers
+    }
+}
+
+/// Address others could reach us at, private, link-local and shared NAT ranges only mean
+/// something inside one network
+fn is_public(ip: &IpAddr) -> bool {
+    let v4_public = |ip: Ipv4Addr| {
+        let [first, second, ..] = ip.octets();
+        let shared = first == 100 && second & 0xc0 == 64;
+        !(ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_private()
+            || ip.is_link_local()
+            || ip.is_multicast()
+            || ip.is_broadcast()
+            || shared)
+    };
+    match ip {
+        IpAddr::V4(ip) => v4_public(*ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => v4_public(ip),
+            None => {
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Parses a bencoded response body, turning the tracker's `failure reason` into an error
+fn response_dict(body: &[u8]) -> Result<BencodeDict> {
+    let mut bencode: BencodeDict = bencode::from_slice(body)?.try_into()?;
+    if let Some(failure_reason) = bencode.remove(b"failure reason".as_ref()) {
+        let error = match failure_reason {
+            Value::String(string) => String::from_utf8(string)
+                .unwrap_or_else(|e| e.as_bytes().escape_ascii().to_string()),
+            x => format!(
+                "error getting tracker 'failure_reason' reason expected string got {}",
+                x.name()
+            ),
+        };
+        return Err(TrackerResponse(error));
+    }
+    Ok(bencode)
+}
+
+/// Swarm counters of one torrent in a scrape response
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ScrapeStats {
+    pub complete: u64,
+    pub downloaded: u64,
+    pub incomplete: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrapeResponse {
+    pub files: HashMap<Sha1, ScrapeStats>,
+    /// Requested torrents the tracker said nothing about
+    pub missing: Vec<Sha1>,
+}
+
+impl ScrapeResponse {
+    /// Keeps only the torrents in `requested`, a buggy tracker may report ones we never
+    /// asked about
+    pub fn from_bencode(mut bencode_dict: BencodeDict, requested: &[Sha1]) -> Result<Self> {
+        let reported: BencodeDict = match bencode_dict.remove(b"files".as_slice()) {
+            Some(files) => files.try_into()?,
+            None => BencodeDict::new(),
+        };
+        let mut files = HashMap::new();
+        for (key, value) in reported {
+            let Some(info_hash) = requested.iter().find(|hash| hash[..] == key[..]) else {
+                continue;
+            };
+            let mut stats: BencodeDict = value.try_into()?;
+            let mut counter = |name: &[u8]| -> Result<u64> {
+                Ok(stats
+                    .remove(name)
+                    .map(u64::try_from)
+                    .transpose()?
+                    .unwrap_or_default())
+            };
+            let stats = ScrapeStats {
+                complete: counter(b"complete")?,
+                downloaded: counter(b"downloaded")?,
+                incomplete: counter(b"incomplete")?,
+            };
+            files.insert(*info_hash, stats);
+        }
+        let missing = requested
+            .iter()
+            .filter(|info_hash| !files.contains_key(*info_hash))
+            .copied()
+            .collect();
+        Ok(Self { files, missing })
+    }
+
+    pub fn from_body(body: &[u8], requested: &[Sha1]) -> Result<Self> {
+        Self::from_bencode(response_dict(body)?, requested)
+    }
 }
 
-pub struct ScrapeResponse;
+/// Undoes the `Content-Encoding` of a tracker response body. HTTP `deflate` is meant to be
+/// zlib-wrapped, but some servers send raw deflate streams, so both are accepted.
+/// Bodies inflating past [`MAX_DECODED_BODY_LENGTH`] are refused
+pub fn decode_body<'a>(content_encoding: Option<&str>, body: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    let encoding = match content_encoding.map(str::trim) {
+        None => return Ok(Cow::Borrowed(body)),
+        Some(encoding) if encoding.is_empty() || encoding.eq_ignore_ascii_case("identity") => {
+            return Ok(Cow::Borrowed(body))
+        }
+        Some(encoding) => encoding.to_ascii_lowercase(),
+    };
+    let limit = MAX_DECODED_BODY_LENGTH + 1;
+    let mut decoded = Vec::new();
+    let result = match encoding.as_str() {
+        "gzip" | "x-gzip" => GzDecoder::new(body).take(limit).read_to_end(&mut decoded),
+        "deflate" => ZlibDecoder::new(body)
+            .take(limit)
+            .read_to_end(&mut decoded)
+            .or_else(|_| {
+                decoded.clear();
+                DeflateDecoder::new(body)
+                    .take(limit)
+                    .read_to_end(&mut decoded)
+            }),
+        _ => return Err(Decompression(encoding)),
+    };
+    result.map_err(|_| Decompression(encoding))?;
+    if decoded.len() as u64 > MAX_DECODED_BODY_LENGTH {
+        return Err(ResponseTooLarge(MAX_DECODED_BODY_LENGTH));
+    }
+    Ok(Cow::Owned(decoded))
+}
 
-pub trait TrackerClient {
+pub trait TrackerClient: Send + Sync {
     fn announce(&self, url: &Url, params: AnnounceParameters) -> Result<AnnounceResponse>;
-    fn scrape(&self) -> Result<ScrapeResponse>;
+    /// Swarm counters of the torrents in `info_hashes` from the tracker of an announce URL
+    fn scrape(&self, url: &Url, info_hashes: &[Sha1]) -> Result<ScrapeResponse>;
+}
+
+/// Scrape URL of a tracker, by convention the last path segment of its announce URL starts
+/// with `announce`, which becomes `scrape`. Other trackers don't support scraping, see BEP 48
+pub fn scrape_url(announce: &Url) -> Result<Url> {
+    let (base, last) = announce.path().rsplit_once('/').unwrap_or(("", ""));
+    let Some(rest) = last.strip_prefix("announce") else {
+        return Err(UnsupportedProtocol(format!("scrape of {announce}")));
+    };
+    let mut url = announce.clone();
+    url.set_path(&format!("{base}/scrape{rest}"));
+    Ok(url)
+}
+
+/// Appends `query` to the query of `url`, a private tracker passkey already there is kept
+/// byte for byte, it's never decoded and encoded again
+fn append_query(mut url: Url, query: &str) -> Url {
+    let new_query = match url.query().map(|url_query| url_query.trim_end_matches('&')) {
+        Some(url_query) if !url_query.is_empty() => format!("{url_query}&{query}"),
+        _ => query.to_string(),
+    };
+    url.set_query(Some(new_query.as_str()));
+    url
 }
 
 pub struct HttpTracker {
     http_client: reqwest::blocking::Client,
-    encoded_peer_id: String,
+    peer_id: PeerId,
+    peers_parsing: PeersParsing,
 }
 
 impl HttpTracker {
     pub fn new(peer_id: &PeerId) -> Result<Self> {
+        Self::with_timeout(peer_id, DEFAULT_TRACKER_TIMEOUT)
+    }
+
+    /// Tracker whose requests fail once `timeout` passes, so a hung tracker can't stall us
+    pub fn with_timeout(peer_id: &PeerId, timeout: Duration) -> Result<Self> {
         let http_client = reqwest::blocking::ClientBuilder::new()
             .user_agent("reqwest/0.12")
+            .timeout(timeout)
             .build()
             .map_err(|x| InternalError(format!("failed to create http client {}", x)))?;
-        let encoded_peer_id = percent_encode(peer_id.as_ref(), NON_ALPHANUMERIC).to_string();
         Ok(Self {
             http_client,
-            encoded_peer_id,
+            peer_id: peer_id.clone(),
+            peers_parsing: PeersParsing::default(),
         })
     }
 
-    fn build_announce_url(&self, mut url: Url, request: AnnounceParameters) -> Url {
-        let info_hash = percent_encode(request.info_hash.as_slice(), NON_ALPHANUMERIC);
-
-        let query = format!("info_hash={}&peer_id={}", info_hash, self.encoded_peer_id);
-        let new_query = if let Some(url_query) = url.query() {
-            format!("{url_query}&{query}")
-        } else {
-            query
-        };
-        url.set_query(Some(new_query.as_str()));
-        url.query_pairs_mut()
-            .append_pair("port", request.port.to_string().as_str())
-            .append_pair("uploaded", request.uploaded.to_string().as_str())
-            .append_pair("downloaded", request.downloaded.to_string().as_str())
-            .append_pair("left", request.left.to_string().as_str());
-        match request.request_mode {
-            RequestMode::Verbose => {}
-            RequestMode::NoPeerId => {
-                url.query_pairs_mut().append_key_only("no_peer_id");
-            }
-            RequestMode::Compact => {
-                url.query_pairs_mut().append_pair("compact", "1");
-            }
-        }
+    pub fn set_peers_parsing(&mut self, peers_parsing: PeersParsing) -> &mut Self {
+        self.peers_parsing = peers_parsing;
+        self
+    }
 
-        if let Some(event) = request.event {
-            url.query_pairs_mut()
-                .append_pair("event", event.to_string().as_str());
-        }
+    /// Appends the announce parameters to the query of `url`, see [`append_query`]
+    fn build_announce_url(&self, url: Url, request: AnnounceParameters) -> Url {
+        append_query(url, &request.query(&self.peer_id))
+    }
 
-        if let Some(num_want) = request.num_want {
-            url.query_pairs_mut()
-                .append_pair("numwant", num_want.to_string().as_str());
-        }
+    /// Body of a tracker response, with its `Content-Encoding` undone
+    fn get(&self, url: Url) -> Result<Vec<u8>> {
+        let tracker_response = self
+            .http_client
+            .get(url)
+            .header(ACCEPT_ENCODING, "gzip, deflate")
+            .send()
+            .map_err(|e| AnnounceRequestError(format!("send request to tracker failed {e}")))?;
 
-        if let Some(ip) = request.ip {
-            url.query_pairs_mut()
-                .append_pair("ip", ip.to_string().as_str());
-        }
-        url
+        let content_encoding = tracker_response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        let body = tracker_response
+            .bytes()
+            .map_err(|e| AnnounceRequestError(format!("failed to retrieve response body {e}")))?;
+        Ok(decode_body(content_encoding.as_deref(), body.as_ref())?.into_owned())
     }
 }
 
@@ -285,37 +901,600 @@ impl TrackerClient for HttpTracker {
         if !(url.scheme() != "http" || url.scheme() != "https") {
             return Err(UnsupportedProtocol(String::from(url.scheme())));
         }
-        let tracker_response = self
-            .http_client
-            .get(self.build_announce_url(url.clone(), params))
-            .send()
-            .map_err(|e| AnnounceRequestError(format!("send request to tracker failed {e}")))?;
+        let body = self.get(self.build_announce_url(url.clone(), params))?;
+        AnnounceResponse::from_body_with(&body, self.peers_parsing)
+    }
 
-        let mut bencode: BencodeDict = bencode::from_slice(
-            tracker_response
-                .bytes()
-                .map_err(|e| AnnounceRequestError(format!("failed to retrieve response body {e}")))?
-                .to_vec()
-                .as_slice(),
-        )?
-        .try_into()?;
-
-        if let Some(failure_reason) = bencode.remove(b"failure reason".as_ref()) {
-            let error = match failure_reason {
-                Value::String(string) => String::from_utf8(string).unwrap_or(String::from(
-                    "tracker response error, unknown string format",
-                )),
-                x => format!(
-                    "error getting tracker 'failure_reason' reason expected string got {}",
-                    x.name()
-                ),
-            };
-            return Err(TrackerResponse(error));
+    fn scrape(&self, url: &Url, info_hashes: &[Sha1]) -> Result<ScrapeResponse> {
+        let mut query = QueryBuilder::default();
+        for info_hash in info_hashes {
+            query.bytes("info_hash", info_hash);
+        }
+        let body = self.get(append_query(scrape_url(url)?, &query.finish()))?;
+        ScrapeResponse::from_body(&body, info_hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::{Peer, PeerId};
+    use crate::tracker::{
+        decode_body, encode_query_value, scrape_url, AnnounceParameters, AnnounceResponse,
+        DeadSwarmPolicy, HttpTracker, PeersParsing, RequestMode, Result, ScrapeResponse,
+        ScrapeStats, TrackerClient, TrackerError, TrackerEvent, TrackerSession,
+        DEFAULT_ANNOUNCE_INTERVAL, EXTERNAL_IP_QUORUM, MAX_DECODED_BODY_LENGTH,
+        MIN_ANNOUNCE_INTERVAL,
+    };
+    use crate::util::{InfoHash, Sha1};
+    use bencode::bencode;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{IpAddr, SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use url::Url;
+
+    struct EchoTracker;
+
+    impl TrackerClient for EchoTracker {
+        fn announce(&self, _url: &Url, params: AnnounceParameters) -> Result<AnnounceResponse> {
+            Ok(AnnounceResponse {
+                interval: Duration::from_secs(params.info_hash.wire()[0] as u64),
+                min_interval: None,
+                complete: None,
+                incomplete: None,
+                peers: Vec::new(),
+                external_ip: None,
+                tracker_id: None,
+            })
+        }
+
+        fn scrape(&self, _url: &Url, _info_hashes: &[Sha1]) -> Result<ScrapeResponse> {
+            Ok(ScrapeResponse::default())
+        }
+    }
+
+    #[test]
+    fn mixed_peers_list() {
+        let dict = bencode!({
+            "interval" => 1800,
+            "peers" => [
+                { "ip" => "10.0.0.1", "port" => 6881 },
+                vec![192, 168, 0, 1, 0x1a, 0xe1, 192, 168, 0, 2, 0x1a, 0xe2],
+                { "ip" => "not an ip", "port" => 6881 },
+                vec![1, 2, 3],
+                42,
+                { "ip" => "::1", "port" => 51413 },
+            ],
+        });
+
+        let response = AnnounceResponse::from_bencode(dict.try_into().unwrap()).unwrap();
+        let addrs: Vec<SocketAddr> = response.peers.iter().map(|p| p.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "192.168.0.1:6881".parse().unwrap(),
+                "192.168.0.2:6882".parse().unwrap(),
+                "[::1]:51413".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn announce_url_dual_stack() {
+        let tracker = HttpTracker::new(&PeerId::random()).unwrap();
+        let info_hash = [1; 20];
+        let mut params = AnnounceParameters::new(info_hash);
+        params
+            .set_ipv4(Some("203.0.113.7".parse().unwrap()))
+            .set_ipv6(Some("2001:db8::1".parse().unwrap()));
+
+        let url = tracker.build_announce_url(
+            Url::parse("http://[2001:db8::2]:8080/announce").unwrap(),
+            params,
+        );
+        assert_eq!(url.host_str(), Some("[2001:db8::2]"));
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert!(pairs.contains(&("ipv4".to_string(), "203.0.113.7".to_string())));
+        assert!(pairs.contains(&("ipv6".to_string(), "2001:db8::1".to_string())));
+    }
+
+    #[test]
+    fn announce_query_encodes_binary_once() {
+        let mut info_hash = [0; 20];
+        info_hash[..8].copy_from_slice(b"\x00\xff %&=.~");
+        info_hash[8..].copy_from_slice(b"abcdefABCDEF");
+        let peer_id = PeerId::new(*b"-VD0001-a_b.c~d/e+f\x80");
+        let mut params = AnnounceParameters::new(info_hash);
+        params
+            .set_port(6881)
+            .set_left(1024)
+            .set_request_mode(RequestMode::Compact)
+            .set_event(Some(TrackerEvent::Started));
+
+        assert_eq!(
+            params.query(&peer_id),
+            "info_hash=%00%FF%20%25%26%3D.~abcdefABCDEF\
+             &peer_id=-VD0001-a_b.c~d%2Fe%2Bf%80\
+             &port=6881&uploaded=0&downloaded=0&left=1024&compact=1&event=started"
+        );
+
+        let tracker = HttpTracker::new(&peer_id).unwrap();
+        let url = Url::parse("http://tracker/announce?key=1").unwrap();
+        let url = tracker.build_announce_url(url, params.clone());
+        assert_eq!(
+            url.query(),
+            Some(format!("key=1&{}", params.query(&peer_id)).as_str())
+        );
+    }
+
+    #[test]
+    fn passkey_kept_verbatim() {
+        let peer_id = PeerId::new(*b"-VD0001-abcdefghijkl");
+        let params = AnnounceParameters::new([0xab; 20]);
+        let tracker = HttpTracker::new(&peer_id).unwrap();
+        let expected = format!(
+            "http://t/announce?passkey=abc%2Bd+e/f&uid=7&{}",
+            params.query(&peer_id)
+        );
+        for announce in [
+            "http://t/announce?passkey=abc%2Bd+e/f&uid=7",
+            "http://t/announce?passkey=abc%2Bd+e/f&uid=7&",
+        ] {
+            let url = tracker.build_announce_url(Url::parse(announce).unwrap(), params.clone());
+            assert_eq!(url.as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn announce_on_worker_thread() {
+        let tracker: Arc<dyn TrackerClient> = Arc::new(EchoTracker);
+        let url = Url::parse("http://localhost/announce").unwrap();
+        let params = {
+            let info_hash = [42; 20];
+            let mut params = AnnounceParameters::new(info_hash);
+            params.set_left(10);
+            params
+        };
+
+        let handle = thread::spawn(move || tracker.announce(&url, params));
+        let response = handle.join().unwrap().unwrap();
+        assert_eq!(response.interval, Duration::from_secs(42));
+    }
+
+    #[test]
+    fn html_body_is_reported() {
+        let body = b"\r\n<!DOCTYPE html><html><body>Tracker is under maintenance</body></html>";
+        match AnnounceResponse::from_body(body) {
+            Err(TrackerError::NonBencodeResponse(snippet)) => {
+                assert!(snippet.starts_with("<!DOCTYPE html>"));
+                assert!(snippet.contains("under maintenance"));
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn json_body_is_reported() {
+        let body = br#"{"error": "torrent not registered"}"#;
+        assert!(matches!(
+            AnnounceResponse::from_body(body),
+            Err(TrackerError::NonBencodeResponse(_))
+        ));
+    }
+
+    #[test]
+    fn failure_reason_body() {
+        let body = b"d14:failure reason17:torrent not founde";
+        assert!(matches!(
+            AnnounceResponse::from_body(body),
+            Err(TrackerError::TrackerResponse(reason)) if reason == "torrent not found"
+        ));
+    }
+
+    #[test]
+    fn missing_interval_defaults() {
+        let dict = bencode!({ "peers" => "" });
+        let response = AnnounceResponse::from_bencode(dict.try_into().unwrap()).unwrap();
+        assert_eq!(response.interval, DEFAULT_ANNOUNCE_INTERVAL);
+    }
+
+    #[test]
+    fn zero_interval_clamped() {
+        let dict = bencode!({ "interval" => 0, "min interval" => 10, "peers" => "" });
+        let response = AnnounceResponse::from_bencode(dict.try_into().unwrap()).unwrap();
+        assert_eq!(response.interval, MIN_ANNOUNCE_INTERVAL);
+        assert_eq!(response.min_interval, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn min_interval_longer_than_interval() {
+        let dict = bencode!({ "interval" => 120, "min interval" => 900, "peers" => "" });
+        let response = AnnounceResponse::from_bencode(dict.try_into().unwrap()).unwrap();
+        assert_eq!(response.interval, Duration::from_secs(900));
+
+        let now = Instant::now();
+        let mut session = TrackerSession::new(1, now);
+        assert!(session.is_due(now));
+        session.update(&response, now);
+        assert!(!session.is_due(now + Duration::from_secs(120)));
+        assert!(session.is_due(now + Duration::from_secs(900)));
+    }
+
+    #[test]
+    fn external_ip_needs_public_address() {
+        let now = Instant::now();
+        let mut session = TrackerSession::new(1, now);
+        let ips =
+            |ips: &[&str]| -> Vec<IpAddr> { ips.iter().map(|ip| ip.parse().unwrap()).collect() };
+        let private = ips(&[
+            "10.1.2.3",
+            "172.16.0.9",
+            "169.254.1.1",
+            "100.64.0.1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.0.1",
+        ]);
+        for ip in &private {
+            session.observe_peer_ips([*ip; EXTERNAL_IP_QUORUM]);
+            assert_eq!(session.external_ip(), None, "{ip}");
         }
-        AnnounceResponse::from_bencode(bencode)
+
+        session.observe_peer_ips(ips(&[
+            "203.0.113.7",
+            "2001:db8::1",
+            "203.0.113.7",
+            "203.0.113.7",
+        ]));
+        assert_eq!(session.external_ip(), Some("203.0.113.7".parse().unwrap()));
+
+        // the tracker's view wins, unless it's a private address too
+        let private_view = b"d11:external ip4:\xc0\xa8\x00\x018:intervali600ee";
+        session.update(&AnnounceResponse::from_body(private_view).unwrap(), now);
+        assert_eq!(session.external_ip(), Some("203.0.113.7".parse().unwrap()));
+        let public_view = b"d11:external ip4:\xc6\x33\x64\x018:intervali600ee";
+        session.update(&AnnounceResponse::from_body(public_view).unwrap(), now);
+        assert_eq!(session.external_ip(), Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn dead_swarm_backs_off() {
+        let dead = bencode!({ "interval" => 600, "complete" => 0, "incomplete" => 0 });
+        let dead = AnnounceResponse::from_bencode(dead.try_into().unwrap()).unwrap();
+        assert_eq!((dead.complete, dead.incomplete), (Some(0), Some(0)));
+        let alive = bencode!({ "interval" => 600, "complete" => 2, "incomplete" => 5 });
+        let alive = AnnounceResponse::from_bencode(alive.try_into().unwrap()).unwrap();
+
+        let now = Instant::now();
+        let minutes = |minutes: u64| now + Duration::from_secs(minutes * 60);
+        let mut session = TrackerSession::new(1, now);
+        session.update(&dead, now);
+        assert!(!session.is_backing_off());
+        assert!(session.is_due(minutes(10)));
+
+        session.set_dead_swarm_policy(Some(DeadSwarmPolicy {
+            max_leechers: 1,
+            max_backoff: 4,
+        }));
+        session.update(&dead, now);
+        assert!(session.is_backing_off());
+        assert!(!session.is_due(minutes(10)));
+        assert!(session.is_due(minutes(20)));
+        assert_eq!(session.connection_attempts(50), 25);
+        session.update(&dead, now);
+        session.update(&dead, now);
+        assert!(!session.is_due(minutes(39)));
+        assert!(session.is_due(minutes(40)));
+
+        session.update(&alive, now);
+        assert!(!session.is_backing_off());
+        assert!(session.is_due(minutes(10)));
+        assert_eq!(session.connection_attempts(50), 50);
+    }
+
+    #[test]
+    fn compact_peers_with_stray_byte() {
+        let body =
+            b"d8:intervali1800e5:peers13:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2\xffe";
+        assert!(matches!(
+            AnnounceResponse::from_body(body),
+            Err(TrackerError::ResponseFormat(_))
+        ));
+
+        let response = AnnounceResponse::from_body_with(body, PeersParsing::Lenient).unwrap();
+        let addrs: Vec<SocketAddr> = response.peers.iter().map(|p| p.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_peers_collapsed() {
+        let peer_id = PeerId::new(*b"-VD0001-abcdefghijkl");
+        let dict = bencode!({
+            "interval" => 1800,
+            "peers" => [
+                vec![10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2],
+                { "ip" => "10.0.0.1", "peer id" => "-VD0001-abcdefghijkl", "port" => 6881 },
+                vec![10, 0, 0, 1, 0x1a, 0xe1],
+            ],
+        });
+
+        let response = AnnounceResponse::from_bencode(dict.try_into().unwrap()).unwrap();
+        assert_eq!(response.peers.len(), 4);
+        assert_eq!(
+            response.unique_peers(),
+            vec![
+                Peer::new(Some(peer_id), "10.0.0.1:6881".parse().unwrap()),
+                Peer::new(None, "10.0.0.2:6882".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unresponsive_tracker_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // accepts the connection and never answers
+        thread::spawn(move || {
+            let _connections: Vec<_> = listener.incoming().collect();
+        });
+
+        let timeout = Duration::from_millis(300);
+        let tracker = HttpTracker::with_timeout(&PeerId::random(), timeout).unwrap();
+        let url = Url::parse(&format!("http://{addr}/announce")).unwrap();
+        let started = Instant::now();
+        let result = tracker.announce(&url, AnnounceParameters::new([1; 20]));
+        assert!(matches!(result, Err(TrackerError::AnnounceRequestError(_))));
+        assert!(started.elapsed() >= timeout);
+        assert!(started.elapsed() < timeout * 10);
+    }
+
+    #[test]
+    fn scrape_url_by_convention() {
+        let scrape =
+            |announce: &str| scrape_url(&Url::parse(announce).unwrap()).map(|url| url.to_string());
+        assert_eq!(
+            scrape("http://tracker.example/announce").unwrap(),
+            "http://tracker.example/scrape"
+        );
+        assert_eq!(
+            scrape("http://tracker.example/x/announce.php?passkey=a%2Fb").unwrap(),
+            "http://tracker.example/x/scrape.php?passkey=a%2Fb"
+        );
+        for announce in [
+            "http://tracker.example/a",
+            "http://tracker.example/announce/",
+            "http://tracker.example/x/Announce",
+        ] {
+            assert!(matches!(
+                scrape(announce),
+                Err(TrackerError::UnsupportedProtocol(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn http_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            let body = b"d5:filesd20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01d8:completei5e10:downloadedi9e10:incompletei2eeee";
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+            request
+        });
+
+        let tracker = HttpTracker::new(&PeerId::random()).unwrap();
+        let url = Url::parse(&format!("http://{addr}/announce?passkey=abc")).unwrap();
+        let requested: [Sha1; 2] = [[1; 20], [b' '; 20]];
+        let response = tracker.scrape(&url, &requested).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with(&format!(
+            "GET /scrape?passkey=abc&info_hash={}&info_hash={} ",
+            "%01".repeat(20),
+            "%20".repeat(20)
+        )));
+        assert_eq!(
+            response.files[&[1; 20]],
+            ScrapeStats {
+                complete: 5,
+                downloaded: 9,
+                incomplete: 2,
+            }
+        );
+        assert_eq!(response.missing, vec![[b' '; 20]]);
+    }
+
+    #[test]
+    fn gzip_response_decompressed() {
+        let body = b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe1e";
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(body).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let headers: Vec<String> = BufReader::new(&stream)
+                .lines()
+                .map(|line| line.unwrap())
+                .take_while(|line| !line.is_empty())
+                .collect();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                gzip.len()
+            )
+            .unwrap();
+            stream.write_all(&gzip).unwrap();
+            headers
+        });
+
+        let tracker = HttpTracker::new(&PeerId::random()).unwrap();
+        let url = Url::parse(&format!("http://{addr}/announce")).unwrap();
+        let response = tracker
+            .announce(&url, AnnounceParameters::new([1; 20]))
+            .unwrap();
+        assert_eq!(response.interval, Duration::from_secs(900));
+        assert_eq!(
+            response.peers,
+            vec![Peer::new(None, "10.0.0.1:6881".parse().unwrap())]
+        );
+        let headers = server.join().unwrap();
+        assert!(headers
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case("accept-encoding: gzip, deflate")));
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(body).unwrap();
+        let zlib = zlib.finish().unwrap();
+        assert_eq!(decode_body(Some("deflate"), &zlib).unwrap().as_ref(), body);
+        assert_eq!(decode_body(None, body).unwrap().as_ref(), body);
+        assert!(matches!(
+            decode_body(Some("gzip"), body),
+            Err(TrackerError::Decompression(encoding)) if encoding == "gzip"
+        ));
+    }
+
+    #[test]
+    fn decompression_bomb_refused() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        let zeros = vec![0; 1024 * 1024];
+        for _ in 0..=MAX_DECODED_BODY_LENGTH / zeros.len() as u64 {
+            gzip.write_all(&zeros).unwrap();
+        }
+        let gzip = gzip.finish().unwrap();
+        assert!(matches!(
+            decode_body(Some("gzip"), &gzip),
+            Err(TrackerError::ResponseTooLarge(MAX_DECODED_BODY_LENGTH))
+        ));
+    }
+
+    #[test]
+    fn scrape_ignores_unrequested_hashes() {
+        let body = [
+            b"d5:filesd20:".as_slice(),
+            &[1; 20],
+            b"d8:completei5e10:downloadedi50e10:incompletei10ee20:",
+            &[9; 20],
+            b"d8:completei1eeee",
+        ]
+        .concat();
+        let response = ScrapeResponse::from_body(&body, &[[1; 20], [2; 20]]).unwrap();
+        assert_eq!(response.files.len(), 1);
+        assert_eq!(
+            response.files[&[1; 20]],
+            ScrapeStats {
+                complete: 5,
+                downloaded: 50,
+                incomplete: 10,
+            }
+        );
+        assert!(!response.files.contains_key(&[9; 20]));
+        assert_eq!(response.missing, vec![[2; 20]]);
+    }
+
+    #[test]
+    fn unknown_fields_ignored() {
+        let body = [
+            b"d8:completei4e11:external ip4:\xcb\x00\x71\x07".as_slice(),
+            b"10:incompletei2e8:intervali900e",
+            b"5:peers6:\x0a\x00\x00\x01\x1a\xe1",
+            b"10:tracker id3:\xffab15:warning message4:slow",
+            b"5:x-fooli1ed3:bar3:bazee",
+            b"e",
+        ]
+        .concat();
+        let response = AnnounceResponse::from_body(&body).unwrap();
+        assert_eq!(response.interval, Duration::from_secs(900));
+        assert_eq!((response.complete, response.incomplete), (Some(4), Some(2)));
+        assert_eq!(
+            response.peers,
+            vec![Peer::new(None, "10.0.0.1:6881".parse().unwrap())]
+        );
+        assert_eq!(response.external_ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(response.tracker_id, Some(b"\xffab".to_vec()));
+
+        let v6 = [b"d11:external ip16:".as_slice(), &[0x20; 16], b"e"].concat();
+        let response = AnnounceResponse::from_body(&v6).unwrap();
+        assert_eq!(response.external_ip, Some([0x20u8; 16].into()));
+        let short = AnnounceResponse::from_body(b"d11:external ip2:abe").unwrap();
+        assert_eq!(short.external_ip, None);
+    }
+
+    #[test]
+    fn unroutable_peers_dropped() {
+        let peers = [
+            [0, 0, 0, 0, 0, 0],
+            [10, 0, 0, 1, 0x1a, 0xe1],
+            [10, 0, 0, 2, 0, 0],
+            [224, 0, 0, 1, 0x1a, 0xe1],
+            [127, 0, 0, 1, 0x1a, 0xe1],
+        ]
+        .concat();
+        let body = [b"d5:peers30:".as_slice(), &peers, b"e"].concat();
+        let addrs: Vec<String> = AnnounceResponse::from_body(&body)
+            .unwrap()
+            .peers
+            .iter()
+            .map(|peer| peer.addr.to_string())
+            .collect();
+        assert_eq!(addrs, vec!["10.0.0.1:6881", "127.0.0.1:6881"]);
+
+        let local = Peer::new(None, "127.0.0.1:6881".parse().unwrap());
+        assert!(local.is_connectable(true));
+        assert!(!local.is_connectable(false));
+        let broadcast = Peer::new(None, "255.255.255.255:6881".parse().unwrap());
+        assert!(!broadcast.is_connectable(true));
+    }
+
+    #[test]
+    fn response_without_peers() {
+        let response = AnnounceResponse::from_body(b"d8:intervali1800ee").unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.interval, Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn only_unreserved_bytes_pass_through() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let encoded = encode_query_value(&bytes);
+        let unescaped: String = encoded
+            .split('%')
+            .enumerate()
+            .flat_map(|(i, part)| part.chars().skip(if i == 0 { 0 } else { 2 }))
+            .collect();
+        assert_eq!(
+            unescaped,
+            "-.0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ_abcdefghijklmnopqrstuvwxyz~"
+        );
+        assert_eq!(encode_query_value(b"-._~ /+"), "-._~%20%2F%2B");
     }
 
-    fn scrape(&self) -> Result<ScrapeResponse> {
-        unimplemented!("Tracker scraping not implemented for http client")
+    #[test]
+    fn v2_info_hash_announced_truncated() {
+        let mut sha256 = [0x41; 32];
+        sha256[20..].fill(0x42);
+        let params = AnnounceParameters::new(InfoHash::V2(sha256));
+        let query = params.query(&PeerId::new(*b"-VD0001-abcdefghijkl"));
+        assert!(query.starts_with(&format!("info_hash={}&", "A".repeat(20))));
     }
 }
@@ -0,0 +1,312 @@
+use crate::peer::PeerId;
+use crate::tracker::TrackerError::{
+    AnnounceRequestError, ResponseFormat, TrackerResponse, UnsupportedProtocol,
+};
+use crate::tracker::{AnnounceParameters, AnnounceResponse, Result, ScrapeResponse, TrackerClient};
+use crate::util::Sha1;
+use serde_json::{json, Map, Value};
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+use url::Url;
+
+/// Offer forwarded by a WebSocket tracker on behalf of a WebRTC peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketOffer {
+    pub peer_id: PeerId,
+    pub offer_id: String,
+    pub sdp: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WebSocketMessage {
+    Announce {
+        interval: Duration,
+        complete: Option<i64>,
+        incomplete: Option<i64>,
+    },
+    Offer(WebSocketOffer),
+}
+
+/// WebTorrent strings carry raw bytes with every byte mapped to a single char
+fn bytes_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| *byte as char).collect()
+}
+
+fn string_to_bytes(string: &str) -> Option<Vec<u8>> {
+    string.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+pub fn encode_announce(params: &AnnounceParameters, peer_id: &PeerId) -> String {
+    let mut message = Map::new();
+    message.insert("action".to_string(), json!("announce"));
+    message.insert(
+        "info_hash".to_string(),
+        json!(bytes_to_string(&params.info_hash.wire())),
+    );
+    message.insert(
+        "peer_id".to_string(),
+        json!(bytes_to_string(peer_id.as_ref())),
+    );
+    message.insert("uploaded".to_string(), json!(params.uploaded));
+    message.insert("downloaded".to_string(), json!(params.downloaded));
+    message.insert("left".to_string(), json!(params.left));
+    message.insert(
+        "numwant".to_string(),
+        json!(params.num_want.unwrap_or_default()),
+    );
+    if let Some(event) = &params.event {
+        message.insert("event".to_string(), json!(event.to_string()));
+    }
+    // We can't produce WebRTC offers, so only ask the tracker to forward offers of others
+    message.insert("offers".to_string(), json!([]));
+    Value::Object(message).to_string()
+}
+
+pub fn decode_message(text: &str) -> Result<WebSocketMessage> {
+    let value: Value = serde_json::from_str(text)
+        .map_err(|e| ResponseFormat(format!("tracker sent invalid json {e}")))?;
+    let message = value
+        .as_object()
+        .ok_or(ResponseFormat("json message is not an object".to_string()))?;
+    if let Some(failure_reason) = message.get("failure reason") {
+        return Err(TrackerResponse(
+            failure_reason
+                .as_str()
+                .unwrap_or("tracker response error, unknown string format")
+                .to_string(),
+        ));
+    }
+    if message.get("action").and_then(Value::as_str) != Some("announce") {
+        return Err(ResponseFormat(format!(
+            "unexpected action in message {}",
+            message.get("action").unwrap_or(&Value::Null)
+        )));
+    }
+
+    if let Some(offer) = message.get("offer") {
+        let sdp = offer
+            .get("sdp")
+            .and_then(Value::as_str)
+            .ok_or(ResponseFormat("No 'sdp' field in offer".to_string()))?
+            .to_string();
+        let offer_id = message
+            .get("offer_id")
+            .and_then(Value::as_str)
+            .ok_or(ResponseFormat("No 'offer_id' field".to_string()))?
+            .to_string();
+        let peer_id = message
+            .get("peer_id")
+            .and_then(Value::as_str)
+            .and_then(string_to_bytes)
+            .and_then(|bytes| <[u8; 20]>::try_from(bytes).ok())
+            .ok_or(ResponseFormat("No valid 'peer_id' field".to_string()))?;
+        return Ok(WebSocketMessage::Offer(WebSocketOffer {
+            peer_id: PeerId::new(peer_id),
+            offer_id,
+            sdp,
+        }));
+    }
+
+    let interval = message
+        .get("interval")
+        .and_then(Value::as_u64)
+        .ok_or(ResponseFormat("No 'interval' field".to_string()))?;
+    Ok(WebSocketMessage::Announce {
+        interval: Duration::from_secs(interval),
+        complete: message.get("complete").and_then(Value::as_i64),
+        incomplete: message.get("incomplete").and_then(Value::as_i64),
+    })
+}
+
+pub struct WebSocketTracker {
+    peer_id: PeerId,
+    read_timeout: Duration,
+}
+
+impl WebSocketTracker {
+    pub fn new(peer_id: &PeerId, read_timeout: Duration) -> Self {
+        Self {
+            peer_id: peer_id.clone(),
+            read_timeout,
+        }
+    }
+
+    /// Announces and collects the offers forwarded by the tracker until it goes quiet
+    pub fn announce_offers(
+        &self,
+        url: &Url,
+        params: AnnounceParameters,
+    ) -> Result<(AnnounceResponse, Vec<WebSocketOffer>)> {
+        if url.scheme() != "ws" && url.scheme() != "wss" {
+            return Err(UnsupportedProtocol(String::from(url.scheme())));
+        }
+        let (mut socket, _) = tungstenite::connect(url.as_str())
+            .map_err(|e| AnnounceRequestError(format!("websocket connect failed {e}")))?;
+        self.set_read_timeout(&socket)
+            .map_err(|e| AnnounceRequestError(format!("failed to set read timeout {e}")))?;
+        socket
+            .send(Message::text(encode_announce(&params, &self.peer_id)))
+            .map_err(|e| AnnounceRequestError(format!("send announce failed {e}")))?;
+
+        let mut response = None;
+        let mut offers = Vec::new();
+        loop {
+            let text = match socket.read() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(_)) => break,
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(e))
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(e) => return Err(AnnounceRequestError(format!("websocket read failed {e}"))),
+            };
+            match decode_message(&text)? {
+                WebSocketMessage::Announce {
+                    interval,
+                    complete,
+                    incomplete,
+                } => {
+                    response = Some(AnnounceResponse {
+                        interval,
+                        min_interval: None,
+                        complete,
+                        incomplete,
+                        peers: Vec::new(),
+                        external_ip: None,
+                        tracker_id: None,
+                    })
+                }
+                WebSocketMessage::Offer(offer) => offers.push(offer),
+            }
+        }
+        let _ = socket.close(None);
+
+        let response = response.ok_or(ResponseFormat(
+            "tracker didn't answer the announce".to_string(),
+        ))?;
+        Ok((response, offers))
+    }
+
+    fn set_read_timeout(&self, socket: &WebSocket<MaybeTlsStream<TcpStream>>) -> io::Result<()> {
+        match socket.get_ref() {
+            MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(self.read_timeout)),
+            MaybeTlsStream::NativeTls(stream) => {
+                stream.get_ref().set_read_timeout(Some(self.read_timeout))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TrackerClient for WebSocketTracker {
+    /// WebRTC peers can't be reached over TCP, so the returned peer list is always empty,
+    /// use [`WebSocketTracker::announce_offers`] to get them
+    fn announce(&self, url: &Url, params: AnnounceParameters) -> Result<AnnounceResponse> {
+        self.announce_offers(url, params)
+            .map(|(response, _)| response)
+    }
+
+    /// WebTorrent trackers have no scrape convention worth supporting
+    fn scrape(&self, _url: &Url, _info_hashes: &[Sha1]) -> Result<ScrapeResponse> {
+        Err(UnsupportedProtocol(String::from("scrape over websocket")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::PeerId;
+    use crate::tracker::websocket::{
+        decode_message, encode_announce, WebSocketMessage, WebSocketOffer, WebSocketTracker,
+    };
+    use crate::tracker::{AnnounceParameters, TrackerClient, TrackerError, TrackerEvent};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn encode_announce_message() {
+        let info_hash = [0xff; 20];
+        let peer_id = PeerId::new(*b"-VD0001-abcdefghijkl");
+        let mut params = AnnounceParameters::new(info_hash);
+        params
+            .set_left(1000)
+            .set_num_want(Some(50))
+            .set_event(Some(TrackerEvent::Started));
+
+        let encoded: Value = serde_json::from_str(&encode_announce(&params, &peer_id)).unwrap();
+        assert_eq!(
+            encoded,
+            json!({
+                "action": "announce",
+                "info_hash": "\u{ff}".repeat(20),
+                "peer_id": "-VD0001-abcdefghijkl",
+                "uploaded": 0,
+                "downloaded": 0,
+                "left": 1000,
+                "numwant": 50,
+                "event": "started",
+                "offers": [],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_announce_response() {
+        let message = decode_message(
+            r#"{"action":"announce","interval":120,"info_hash":"abc","complete":3,"incomplete":7}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            message,
+            WebSocketMessage::Announce {
+                interval: Duration::from_secs(120),
+                complete: Some(3),
+                incomplete: Some(7),
+            }
+        );
+    }
+
+    #[test]
+    fn decode_offer() {
+        let message = decode_message(
+            r#"{"action":"announce","offer":{"type":"offer","sdp":"v=0"},"offer_id":"xyz","peer_id":"-WW0001-ÿÿÿÿÿÿÿÿÿÿÿÿ","info_hash":"abc"}"#,
+        )
+        .unwrap();
+        let mut peer_id = [0xff; 20];
+        peer_id[..8].copy_from_slice(b"-WW0001-");
+        assert_eq!(
+            message,
+            WebSocketMessage::Offer(WebSocketOffer {
+                peer_id: PeerId::new(peer_id),
+                offer_id: "xyz".to_string(),
+                sdp: "v=0".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn decode_failure_reason() {
+        let message = decode_message(r#"{"failure reason":"unknown torrent"}"#);
+        assert!(
+            matches!(message, Err(TrackerError::TrackerResponse(reason)) if reason == "unknown torrent")
+        );
+    }
+
+    #[test]
+    fn scrape_unsupported() {
+        let tracker = WebSocketTracker::new(&PeerId::random(), Duration::from_secs(1));
+        let url = Url::parse("wss://tracker.example/announce").unwrap();
+        assert!(matches!(
+            tracker.scrape(&url, &[[1; 20]]),
+            Err(TrackerError::UnsupportedProtocol(_))
+        ));
+    }
+}
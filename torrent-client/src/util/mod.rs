@@ -1,5 +1,68 @@
+#[cfg(test)]
+pub mod test_log;
+
+use sha1::Digest;
+use std::fmt::{Display, Formatter};
+
 pub type Sha1 = [u8; 20];
 
+/// Info hash of a v1 torrent (SHA-1) or a v2 torrent (SHA-256)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InfoHash {
+    V1(Sha1),
+    V2([u8; 32]),
+}
+
+impl InfoHash {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            InfoHash::V1(hash) => hash,
+            InfoHash::V2(hash) => hash,
+        }
+    }
+
+    /// 20 bytes used in handshakes and tracker requests, v2 hashes are truncated (BEP 52)
+    pub fn wire(&self) -> Sha1 {
+        match self {
+            InfoHash::V1(hash) => *hash,
+            InfoHash::V2(hash) => hash[..20].try_into().expect("v2 hash is 32 bytes"),
+        }
+    }
+
+    /// Checks a piece with the hash function of the torrent's version, SHA-1 for v1 and
+    /// SHA-256 for the piece layers of v2 (BEP 52)
+    pub fn verifies(&self, piece: &[u8], expected: &[u8]) -> bool {
+        match self {
+            InfoHash::V1(_) => sha1::Sha1::digest(piece).as_slice() == expected,
+            InfoHash::V2(_) => sha2::Sha256::digest(piece).as_slice() == expected,
+        }
+    }
+}
+
+impl From<Sha1> for InfoHash {
+    fn from(value: Sha1) -> Self {
+        InfoHash::V1(value)
+    }
+}
+
+impl From<&Sha1> for InfoHash {
+    fn from(value: &Sha1) -> Self {
+        InfoHash::V1(*value)
+    }
+}
+
+impl From<&InfoHash> for InfoHash {
+    fn from(value: &InfoHash) -> Self {
+        *value
+    }
+}
+
+impl Display for InfoHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.as_bytes()))
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct BitField {
     value: u8,
@@ -62,9 +125,140 @@ impl From<BitField> for BitFieldIterator {
     }
 }
 
+/// Pieces a peer has, as sent in the `bitfield` message: the high bit of the first byte is piece 0
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PieceBitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl PieceBitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Spare bits of the last byte are cleared, so they never show up as pieces
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        let copied = bitfield.bytes.len().min(bytes.len());
+        bitfield.bytes[..copied].copy_from_slice(&bytes[..copied]);
+        if !len.is_multiple_of(8) {
+            if let Some(last) = bitfield.bytes.last_mut() {
+                *last &= 0xff << (8 - len % 8);
+            }
+        }
+        bitfield
+    }
+
+    /// Like [`PieceBitfield::from_bytes`], but `None` unless `bytes` is exactly as long as
+    /// `len` pieces need and its spare bits are cleared, as BEP 3 requires of peers
+    pub fn from_bytes_exact(bytes: &[u8], len: usize) -> Option<Self> {
+        let bitfield = Self::from_bytes(bytes, len);
+        (bitfield.bytes == bytes).then_some(bitfield)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Indices of the pieces present in the bitfield
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| self.has(*index))
+    }
+
+    /// Indices of the pieces still missing
+    pub fn iter_unset(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| !self.has(*index))
+    }
+
+    /// Indices set here but not in `previous`, used to update piece availability
+    /// without rescanning the whole bitfield
+    pub fn newly_set<'a>(
+        &'a self,
+        previous: &'a PieceBitfield,
+    ) -> impl Iterator<Item = usize> + 'a {
+        self.bytes
+            .iter()
+            .enumerate()
+            .flat_map(move |(byte_index, byte)| {
+                let added = byte & !previous.bytes.get(byte_index).copied().unwrap_or(0);
+                (0..8)
+                    .filter(move |bit| added & (0x80 >> bit) != 0)
+                    .map(move |bit| byte_index * 8 + bit)
+            })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::util::{BitField, BitFieldIterator};
+    use crate::util::{BitField, BitFieldIterator, InfoHash, PieceBitfield};
+    use sha1::Digest;
+
+    #[test]
+    fn info_hash_versions() {
+        let v1 = InfoHash::from([0xab; 20]);
+        assert_eq!(v1, InfoHash::V1([0xab; 20]));
+        assert_eq!(v1.wire(), [0xab; 20]);
+        assert_eq!(v1.to_string(), "ab".repeat(20));
+
+        let mut sha256 = [0; 32];
+        sha256
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte = i as u8);
+        let v2 = InfoHash::V2(sha256);
+        assert_eq!(v2.as_bytes().len(), 32);
+        assert_eq!(v2.wire().as_slice(), &sha256[..20]);
+        assert_ne!(InfoHash::V1(v2.wire()), v2);
+    }
+
+    #[test]
+    fn piece_hash_by_version() {
+        let piece = b"piece data";
+        let sha1 = sha1::Sha1::digest(piece);
+        let sha256 = sha2::Sha256::digest(piece);
+        let v1 = InfoHash::V1([0; 20]);
+        let v2 = InfoHash::V2([0; 32]);
+        assert!(v1.verifies(piece, &sha1));
+        assert!(!v1.verifies(piece, &sha256));
+        assert!(v2.verifies(piece, &sha256));
+        assert!(!v2.verifies(piece, &sha256[..20]));
+        assert!(!v2.verifies(piece, &sha1));
+    }
 
     #[test]
     fn bitfield_get() {
@@ -107,4 +301,16 @@ mod tests {
         assert!(!iterator.next().unwrap());
         assert_eq!(iterator.next(), None);
     }
+
+    #[test]
+    fn piece_bitfield_delta() {
+        let previous = PieceBitfield::from_bytes(&[0b1010_0000, 0b1000_0000], 10);
+        let current = PieceBitfield::from_bytes(&[0b1110_0001, 0b1111_1111], 10);
+        assert_eq!(current.count(), 6);
+        assert_eq!(
+            current.newly_set(&previous).collect::<Vec<_>>(),
+            vec![1, 7, 9]
+        );
+        assert_eq!(previous.newly_set(&current).count(), 0);
+    }
 }